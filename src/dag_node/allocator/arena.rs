@@ -2,11 +2,27 @@
 
 An arena allocator for `DagNode`s.
 
+On Linux, `set_huge_pages(true)` makes new arenas come from 2MB regions, mapped on 2MB boundaries and advised with
+`madvise(MADV_HUGEPAGE)` before anything touches them, so that the kernel can back each region with one transparent
+huge page, which reduces TLB misses while marking and sweeping large heaps. A region holds as many arenas as fit, and
+arenas freed from a region are kept for new arenas rather than returned to the kernel. The kernel may decline, so
+this is advice rather than a guarantee. Elsewhere, or if a region cannot be mapped, arenas come from the global
+allocator as usual.
+
+The `node_align_16`, `node_align_32`, and `node_align_64` features over-align every node to `NODE_ALIGNMENT` bytes
+for data nodes read with SIMD loads. `DagNode` is padded to a multiple of the boundary, so that stepping from the
+first node of an arena by whole nodes stays aligned, at the cost of larger nodes and fewer nodes per page.
+
 */
 
+#[cfg(target_os = "linux")]
 use std::{
-  mem::MaybeUninit,
-  ptr::null_mut
+  collections::BTreeSet,
+  sync::Mutex
+};
+use std::{
+  ptr::null_mut,
+  sync::atomic::{AtomicBool, Ordering::Relaxed}
 };
 
 use crate::{
@@ -16,38 +32,304 @@ use crate::{
   }
 };
 
+/// The boundary, in bytes, every node of an arena is aligned to.
+#[cfg(feature = "node_align_64")]
+pub const NODE_ALIGNMENT: usize = 64;
+#[cfg(all(feature = "node_align_32", not(feature = "node_align_64")))]
+pub const NODE_ALIGNMENT: usize = 32;
+#[cfg(all(feature = "node_align_16", not(any(feature = "node_align_32", feature = "node_align_64"))))]
+pub const NODE_ALIGNMENT: usize = 16;
+#[cfg(not(any(feature = "node_align_16", feature = "node_align_32", feature = "node_align_64")))]
+pub const NODE_ALIGNMENT: usize = 8;
+
+#[cfg(test)]
+thread_local! {
+  /// Number of arenas this thread has freed.
+  pub(crate) static FREED_ARENAS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Whether new arenas are advised to use huge pages.
+static USE_HUGE_PAGES: AtomicBool = AtomicBool::new(false);
+
+/// The size and alignment of the regions huge page arenas are carved from, that of a huge page on x86-64 and AArch64.
+#[cfg(target_os = "linux")]
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+#[cfg(target_os = "linux")]
+const _: () = assert!(size_of::<Arena>() <= HUGE_PAGE_SIZE, "an arena no longer fits in a huge page");
+
+/// The huge page regions mapped so far and the arena slots in them not in use, by address.
+#[cfg(target_os = "linux")]
+struct HugePagePool {
+  regions   : BTreeSet<usize>,
+  free_slots: Vec<usize>,
+}
+
+#[cfg(target_os = "linux")]
+static HUGE_PAGE_POOL: Mutex<HugePagePool> = Mutex::new(HugePagePool {
+  regions   : BTreeSet::new(),
+  free_slots: Vec::new(),
+});
+
+/// Sets whether arenas allocated from now on are backed by huge pages where the platform supports it.
+pub fn set_huge_pages(enabled: bool) {
+  USE_HUGE_PAGES.store(enabled, Relaxed);
+}
+
+/// Whether arenas allocated from now on are backed by huge pages where the platform supports it.
+pub fn huge_pages() -> bool {
+  USE_HUGE_PAGES.load(Relaxed)
+}
+
 #[repr(align(8))]
+#[cfg_attr(feature = "node_align_16", repr(align(16)))]
+#[cfg_attr(feature = "node_align_32", repr(align(32)))]
+#[cfg_attr(feature = "node_align_64", repr(align(64)))]
 pub struct Arena {
   pub(crate) next_arena: *mut Arena,
   data: [DagNode; ARENA_SIZE],
 }
 
 impl Arena {
+  /// Allocates a new unlinked arena on the heap with every node free. Diagnostics read the flags of nodes past the
+  /// allocation cursor, so those nodes must be initialized too.
   #[inline(always)]
   pub fn allocate_new_arena() -> *mut Arena {
-
-    // Create an uninitialized array
-    let data: [MaybeUninit<DagNode>; ARENA_SIZE] = unsafe { MaybeUninit::uninit().assume_init() };
-    
-    /* Each node is initialized on allocation, so we don't bother with this.
-    // Initialize each element
-    for elem in &mut data {
-      unsafe {
-        std::ptr::write(elem.as_mut_ptr(), DagNode::default());
+    // Building the arena in place, rather than moving an array of nodes into a box, never produces a `DagNode`
+    // value from uninitialized memory and never puts the arena on the stack.
+    let arena: *mut Arena = match huge_pages() {
+      true  => take_huge_page_slot(),
+      false => None,
+    }.unwrap_or_else(|| Box::into_raw(Box::<Arena>::new_uninit()).cast());
+    unsafe {
+      std::ptr::addr_of_mut!((*arena).next_arena).write(null_mut());
+      let first_node = std::ptr::addr_of_mut!((*arena).data).cast::<DagNode>();
+      for offset in 0..ARENA_SIZE {
+        first_node.add(offset).write(DagNode::default());
       }
     }
-    */
-    
-    let arena = Box::new(Arena{
-      next_arena: null_mut(),
-      data      : unsafe { std::mem::transmute::<_, [DagNode; ARENA_SIZE]>(data) }
-    });
+    arena
+  }
 
-    Box::into_raw(arena)
+  /// Frees an arena allocated by `allocate_new_arena` or `allocate_copy`. Its nodes are not destroyed.
+  ///
+  /// # Safety
+  ///
+  /// `arena` must not be used afterward, nor any pointer to one of its nodes.
+  pub unsafe fn free(arena: *mut Arena) {
+    if !return_huge_page_slot(arena) {
+      drop(Box::from_raw(arena));
+    }
+    #[cfg(test)]
+    FREED_ARENAS.with(|freed| freed.set(freed.get() + 1));
   }
 
+  /// Allocates a new unlinked arena holding a bitwise copy of this arena's nodes, whose pointers still refer to the
+  /// original heap.
+  pub fn allocate_copy(&self) -> *mut Arena {
+    let arena = Arena::allocate_new_arena();
+    unsafe {
+      std::ptr::copy_nonoverlapping(self.data.as_ptr(), (*arena).data.as_mut_ptr(), ARENA_SIZE);
+    }
+    arena
+  }
+
+  /// The first node, on a `NODE_ALIGNMENT` boundary like every node after it.
   #[inline(always)]
   pub fn first_node(&mut self) -> *mut DagNode {
     &mut self.data[0]
   }
+
+  /// Returns a pointer to the node at `offset`, where `offset == ARENA_SIZE` gives the one-past-the-end pointer.
+  /// With the `gc_bounds_check` feature, asserts that `offset` is in that range.
+  #[inline(always)]
+  pub fn node_at(&mut self, offset: usize) -> *mut DagNode {
+    #[cfg(feature = "gc_bounds_check")]
+    assert!(offset <= ARENA_SIZE, "node offset {} outside of arena", offset);
+    unsafe { self.first_node().add(offset) }
+  }
+
+  /// Whether `node` points to a node of this arena.
+  #[inline(always)]
+  pub fn contains(&self, node: *const DagNode) -> bool {
+    let first = self.data.as_ptr();
+    node >= first && node < first.wrapping_add(ARENA_SIZE)
+  }
+
+  /// Whether `node` points to a node of this arena or one past its last node.
+  #[inline(always)]
+  pub fn in_bounds(&self, node: *const DagNode) -> bool {
+    let first = self.data.as_ptr();
+    node >= first && node <= first.wrapping_add(ARENA_SIZE)
+  }
+}
+
+/// An arena slot from a huge page region, mapping a new region if none is free. `None` if mapping fails.
+#[cfg(target_os = "linux")]
+fn take_huge_page_slot() -> Option<*mut Arena> {
+  let mut pool = HUGE_PAGE_POOL.lock().unwrap();
+  if pool.free_slots.is_empty() {
+    let region    = map_huge_page_region()?;
+    let slot_size = size_of::<Arena>();
+    pool.regions.insert(region);
+    // Reversed, so that slots are handed out from the start of the region.
+    pool.free_slots.extend((0..HUGE_PAGE_SIZE / slot_size).rev().map(|slot| region + slot * slot_size));
+  }
+  pool.free_slots.pop().map(|slot| slot as *mut Arena)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn take_huge_page_slot() -> Option<*mut Arena> {
+  None
+}
+
+/// Returns `arena` to the free slots of its huge page region, if it came from one. Its memory stays mapped.
+#[cfg(target_os = "linux")]
+fn return_huge_page_slot(arena: *mut Arena) -> bool {
+  let mut pool = HUGE_PAGE_POOL.lock().unwrap();
+  // Regions are aligned to their size, so rounding down finds the region of any slot.
+  let region = arena as usize / HUGE_PAGE_SIZE * HUGE_PAGE_SIZE;
+  if !pool.regions.contains(&region) {
+    return false;
+  }
+  pool.free_slots.push(arena as usize);
+  true
+}
+
+#[cfg(not(target_os = "linux"))]
+fn return_huge_page_slot(_arena: *mut Arena) -> bool {
+  false
+}
+
+/// Maps a region of `HUGE_PAGE_SIZE` bytes on a `HUGE_PAGE_SIZE` boundary and advises the kernel to back it with a
+/// huge page. The advice comes before anything touches the region, so the first fault can already take a huge page.
+/// The advice can fail, for instance when transparent huge pages are disabled, which leaves the region with normal
+/// pages.
+#[cfg(target_os = "linux")]
+fn map_huge_page_region() -> Option<usize> {
+  // `mmap` only aligns to a page, so map twice the size and unmap what lies outside the aligned region.
+  let length = 2 * HUGE_PAGE_SIZE;
+  let mapped = unsafe {
+    libc::mmap(
+      null_mut(),
+      length,
+      libc::PROT_READ | libc::PROT_WRITE,
+      libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+      -1,
+      0
+    )
+  };
+  if mapped == libc::MAP_FAILED {
+    return None;
+  }
+
+  let mapped = mapped as usize;
+  let region = mapped.next_multiple_of(HUGE_PAGE_SIZE);
+  let end    = region + HUGE_PAGE_SIZE;
+  unsafe {
+    if region > mapped {
+      libc::munmap(mapped as *mut libc::c_void, region - mapped);
+    }
+    if mapped + length > end {
+      libc::munmap(end as *mut libc::c_void, mapped + length - end);
+    }
+    libc::madvise(region as *mut libc::c_void, HUGE_PAGE_SIZE, libc::MADV_HUGEPAGE);
+  }
+  Some(region)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use crate::{
+    abstractions::IString,
+    dag_node::DagNodeKind,
+    symbol::Symbol
+  };
+  use super::{set_huge_pages, Arena, ARENA_SIZE};
+
+  // Small enough to run under Miri, which checks that no node is read uninitialized.
+  #[test]
+  fn test_arena_construction() {
+    let a = Symbol::new(IString::from("a"), 0);
+    let arena = Arena::allocate_new_arena();
+    let arena_mut = unsafe { &mut *arena };
+    assert!(arena_mut.next_arena.is_null());
+    for offset in 0..ARENA_SIZE {
+      let node = unsafe { &*arena_mut.node_at(offset) };
+      assert!(node.symbol.is_null() && node.flags.is_empty() && node.kind == DagNodeKind::Free);
+    }
+
+    for offset in [0, ARENA_SIZE - 1] {
+      let node = arena_mut.node_at(offset);
+      unsafe { &mut *node }.symbol = &a;
+      assert!(arena_mut.contains(node));
+    }
+    let end = arena_mut.node_at(ARENA_SIZE);
+    assert!(!arena_mut.contains(end));
+    assert!(arena_mut.in_bounds(end));
+
+    let copy = arena_mut.allocate_copy();
+    let last = unsafe { &*(*copy).node_at(ARENA_SIZE - 1) };
+    assert_eq!(last.kind, DagNodeKind::default());
+    assert_eq!(last.symbol().name, a.name);
+
+    unsafe {
+      Arena::free(copy);
+      Arena::free(arena);
+    }
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn test_huge_page_arena() {
+    use crate::dag_node::{allocator::lock_global_heap, DagNode};
+    use super::{huge_pages, HUGE_PAGE_SIZE};
+
+    let _heap = lock_global_heap();
+    set_huge_pages(true);
+    assert!(huge_pages());
+    let arena = Arena::allocate_new_arena();
+    let next  = Arena::allocate_new_arena();
+    set_huge_pages(false);
+
+    let arena_mut = unsafe { &mut *arena };
+    assert_eq!(arena as usize % align_of::<Arena>(), 0);
+    for offset in [0, 1, ARENA_SIZE - 1] {
+      let node = arena_mut.node_at(offset);
+      assert_eq!(node as usize % align_of::<DagNode>(), 0);
+      assert!(arena_mut.contains(node));
+      assert_eq!(unsafe { &*node }.kind, DagNodeKind::Free);
+    }
+
+    // Both come from the same region, and a freed arena's slot is reused.
+    assert_eq!(arena as usize / HUGE_PAGE_SIZE, next as usize / HUGE_PAGE_SIZE);
+    unsafe { Arena::free(next); }
+    set_huge_pages(true);
+    assert_eq!(Arena::allocate_new_arena(), next);
+    set_huge_pages(false);
+
+    unsafe {
+      Arena::free(next);
+      Arena::free(arena);
+    }
+  }
+
+  #[cfg(any(feature = "node_align_16", feature = "node_align_32", feature = "node_align_64"))]
+  #[test]
+  fn test_node_alignment() {
+    use crate::dag_node::DagNode;
+    use super::NODE_ALIGNMENT;
+
+    assert_eq!(align_of::<DagNode>(), NODE_ALIGNMENT);
+    assert_eq!(size_of::<DagNode>() % NODE_ALIGNMENT, 0);
+
+    let arena     = Arena::allocate_new_arena();
+    let arena_mut = unsafe { &mut *arena };
+    for offset in 0..=ARENA_SIZE {
+      assert_eq!(arena_mut.node_at(offset) as usize % NODE_ALIGNMENT, 0, "node {} is misaligned", offset);
+    }
+
+    unsafe { Arena::free(arena); }
+  }
 }
@@ -0,0 +1,195 @@
+/*!
+
+An opt-in collector thread for programs that would rather not call `ok_to_collect_garbage` themselves.
+`start_background_gc` spawns a thread that wakes at a fixed interval and, if the node allocator or the storage
+allocator wants a collection, runs one with every mutator paused. These collections are counted by
+`background_gc_count` rather than `sync_gc_count`.
+
+A collection frees every node not reachable from a root, so a thread holding unrooted nodes must not be running
+while it happens. Mutators hold the guard returned by `mutator_region` for as long as they work with unrooted nodes.
+The collector waits until no such guard is held, so leaving a region is a safe point, and no guard can be taken
+while it collects. Whether a collector that is waiting also keeps new guards from being taken depends on the
+platform's `RwLock`, so mutators that leave their regions only briefly, with another region always held, may delay
+it indefinitely. A thread must not nest regions, as where waiting writers are preferred a second guard taken while
+the collector is waiting for the first would never be granted.
+
+The collector thread contends with mutators for the allocator lock, so starting it sets `LockPolicy::Block`, and
+stopping it restores the policy in force before.
+
+*/
+
+use std::{
+  sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+    Arc,
+    Mutex,
+    RwLock,
+    RwLockReadGuard
+  },
+  thread::{self, JoinHandle},
+  time::Duration
+};
+
+use crate::dag_node::allocator::{
+  lock_policy,
+  node_allocator::{collect_if_needed_uncounted, collection_wanted},
+  set_lock_policy,
+  LockPolicy
+};
+
+/// Held for reading by mutators working with unrooted nodes and for writing by the collector thread.
+static MUTATOR_REGION: RwLock<()> = RwLock::new(());
+static BACKGROUND_GC: Mutex<Option<BackgroundGc>> = Mutex::new(None);
+/// Number of collections the background collector has run. See `background_gc_count`.
+static BACKGROUND_GC_COUNT: AtomicU64 = AtomicU64::new(0);
+
+struct BackgroundGc {
+  stop        : Arc<AtomicBool>,
+  thread      : JoinHandle<()>,
+  /// The lock policy to restore when the collector stops.
+  lock_policy : LockPolicy,
+}
+
+/// Enters a region in which the calling thread may hold unrooted nodes. The background collector does not collect
+/// until the returned guard is dropped. Regions must not be nested.
+pub fn mutator_region() -> RwLockReadGuard<'static, ()> {
+  // The region guards no data, so a panic inside one leaves nothing inconsistent.
+  MUTATOR_REGION.read().unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// Starts a thread that checks every `interval` whether either allocator wants a collection and collects if one
+/// does. Fails if the background collector is already running.
+pub fn start_background_gc(interval: Duration) -> Result<(), String> {
+  let mut background_gc = BACKGROUND_GC.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+  if background_gc.is_some() {
+    return Err("the background collector is already running".to_string());
+  }
+
+  let previous_policy = lock_policy();
+  set_lock_policy(LockPolicy::Block);
+
+  let stop   = Arc::new(AtomicBool::new(false));
+  let thread = {
+    let stop = stop.clone();
+    thread::Builder::new()
+      .name("background-gc".to_string())
+      .spawn(move || {
+        while !stop.load(Relaxed) {
+          thread::park_timeout(interval);
+          if stop.load(Relaxed) || !collection_wanted() {
+            continue;
+          }
+          let _paused = MUTATOR_REGION.write().unwrap_or_else(std::sync::PoisonError::into_inner);
+          if collect_if_needed_uncounted().is_some() {
+            BACKGROUND_GC_COUNT.fetch_add(1, Relaxed);
+          }
+        }
+      })
+      .map_err(|error| {
+        set_lock_policy(previous_policy);
+        error.to_string()
+      })?
+  };
+
+  *background_gc = Some(BackgroundGc { stop, thread, lock_policy: previous_policy });
+  Ok(())
+}
+
+/// Stops the background collector, waiting for a collection in progress to finish. Does nothing if it is not
+/// running.
+pub fn stop_background_gc() {
+  let background_gc = BACKGROUND_GC.lock().unwrap_or_else(std::sync::PoisonError::into_inner).take();
+  if let Some(BackgroundGc { stop, thread, lock_policy }) = background_gc {
+    stop.store(true, Relaxed);
+    thread.thread().unpark();
+    // A panic in the collector has already been reported on its thread.
+    let _ = thread.join();
+    set_lock_policy(lock_policy);
+  }
+}
+
+/// The number of collections the background collector has run, which `sync_gc_count` does not count.
+pub fn background_gc_count() -> u64 {
+  BACKGROUND_GC_COUNT.load(Relaxed)
+}
+
+/// Whether the background collector is running.
+pub fn background_gc_running() -> bool {
+  BACKGROUND_GC.lock().unwrap_or_else(std::sync::PoisonError::into_inner).is_some()
+}
+
+
+#[cfg(test)]
+mod tests {
+  use std::time::{Duration, Instant};
+
+  use crate::{
+    abstractions::IString,
+    dag_node::{
+      allocator::{gc_cycle_count, lock_global_heap, lock_policy, sync_gc_count, want_to_collect_garbage, LockPolicy},
+      allocator::{lock_policy::LockPolicyGuard, node_vector::NodeVector},
+      deref_node,
+      DagNode,
+      DagNodeKind,
+      RootContainer
+    },
+    symbol::Symbol
+  };
+  use super::{background_gc_count, background_gc_running, mutator_region, start_background_gc, stop_background_gc};
+
+  #[test]
+  fn test_background_gc() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+    // Starting the collector switches from this policy, and stopping it switches back.
+    let _policy = LockPolicyGuard::set(LockPolicy::Panic);
+    let (background_collections, cycles) = (background_gc_count(), gc_cycle_count());
+
+    start_background_gc(Duration::from_millis(1)).unwrap();
+    assert!(background_gc_running());
+    assert!(start_background_gc(Duration::from_millis(1)).is_err());
+    assert_eq!(lock_policy(), LockPolicy::Block);
+
+    let kept = {
+      let _region = mutator_region();
+      let leaf    = DagNode::new(&a);
+      RootContainer::new(DagNode::with_args(&f, &[leaf], DagNodeKind::default()))
+    };
+
+    // Churn garbage, leaving the region between batches, until the collector has run a few times.
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while gc_cycle_count() < cycles + 3 {
+      assert!(Instant::now() < deadline, "the background collector never collected");
+      let _region = mutator_region();
+      for _ in 0..1000 {
+        DagNode::new(&a);
+      }
+    }
+
+    // Churning bucket storage alone also wakes the collector.
+    let collections = background_gc_count();
+    while background_gc_count() == collections {
+      assert!(Instant::now() < deadline, "the background collector never collected bucket storage");
+      let _region = mutator_region();
+      for _ in 0..100 {
+        NodeVector::with_capacity(1024);
+      }
+      assert!(!want_to_collect_garbage());
+    }
+
+    stop_background_gc();
+    assert!(!background_gc_running());
+    // The mutator never reached a safe point of its own, so every collection was the background collector's.
+    assert_eq!(background_gc_count() - background_collections, gc_cycle_count() - cycles);
+    assert_eq!(sync_gc_count(), 0);
+    // Stopping a stopped collector does nothing.
+    stop_background_gc();
+    assert_eq!(lock_policy(), LockPolicy::Panic);
+
+    // The rooted term survived every collection.
+    let kept_ref = unsafe { deref_node(kept.node()) };
+    assert_eq!(kept_ref.len(), 1);
+    assert_eq!(unsafe { deref_node(*kept_ref.iter_children().next().unwrap()) }.symbol().name, a.name);
+  }
+}
@@ -7,6 +7,13 @@ A `Bucket` is a small arena. We might use bumpalo or something instead.
 use std::ptr::{null_mut, NonNull};
 use crate::dag_node::Void;
 
+/// With `gc_debug`, reclaimed bucket memory is overwritten with this byte, so that anything still pointing into it
+/// reads `TOMBSTONE` words.
+#[cfg(feature = "gc_debug")]
+pub(crate) const TOMBSTONE_BYTE: u8    = 0xDB;
+#[cfg(feature = "gc_debug")]
+pub(crate) const TOMBSTONE     : usize = usize::from_ne_bytes([TOMBSTONE_BYTE; size_of::<usize>()]);
+
 pub struct Bucket {
   pub(crate) data: Box<[Void]>,
   pub(crate) bytes_free : usize,
@@ -27,31 +34,55 @@ impl Bucket {
     bucket
   }
 
+  /// Whether `bytes_needed` bytes fit in this bucket together with the padding that keeps `next_free` aligned on an
+  /// 8 byte boundary after them.
+  pub fn has_room(&self, bytes_needed: usize) -> bool {
+    let align_offset = self.next_free.wrapping_add(bytes_needed).align_offset(8);
+    bytes_needed.saturating_add(align_offset) <= self.bytes_free
+  }
+
   pub fn allocate(&mut self, bytes_needed: usize) -> *mut Void {
-    assert!(self.bytes_free >= bytes_needed);
+    assert!(self.has_room(bytes_needed), "bucket has no room for {} bytes and their padding", bytes_needed);
 
     let allocation    = self.next_free;
     let new_next_free = unsafe { self.next_free.add(bytes_needed) };
     let align_offset  = new_next_free.align_offset(8);
-    if align_offset == usize::MAX {
-      panic!("Cannot align memory to 8 byte boundary")
-    }
-    
+
     // next_free is always aligned on an 8 byte boundary.
-    self.next_free = unsafe { new_next_free.add(align_offset) };
-    let bytes_used = bytes_needed + align_offset;
-    if bytes_used > self.bytes_free {
-      // This probably should happen due to how capacity for new buckets is 
-      // computed, but it's conceivable.
-      self.bytes_free = 0;
-    } else {
-      self.bytes_free -= bytes_used;
-    }
-    
+    self.next_free   = unsafe { new_next_free.add(align_offset) };
+    self.bytes_free -= bytes_needed + align_offset;
+
     allocation
   }
-  
+
+  /// Makes an unlinked copy of this bucket's memory and allocation state.
+  pub fn copy(&self) -> Self {
+    let mut bucket = Bucket {
+      data       : self.data.clone(),
+      bytes_free : self.bytes_free,
+      next_free  : null_mut(),
+      next_bucket: None,
+    };
+    let offset       = self.next_free as usize - self.data.as_ptr() as usize;
+    bucket.next_free = unsafe { bucket.data.as_mut_ptr().add(offset) };
+
+    bucket
+  }
+
+  /// Whether `bytes` bytes starting at `ptr` lie inside the allocated part of this bucket.
+  pub fn contains(&self, ptr: *const Void, bytes: usize) -> bool {
+    let start = self.data.as_ptr() as usize;
+    let end   = self.next_free as usize;
+    let ptr   = ptr as usize;
+    ptr >= start && ptr.saturating_add(bytes) <= end
+  }
+
   pub fn reset(&mut self) {
+    #[cfg(feature = "gc_debug")]
+    {
+      let bytes_used = self.next_free as usize - self.data.as_ptr() as usize;
+      self.data[..bytes_used].fill(TOMBSTONE_BYTE);
+    }
     self.next_free  = self.data.as_mut_ptr();
     self.bytes_free = self.data.len()
   }
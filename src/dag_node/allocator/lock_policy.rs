@@ -0,0 +1,136 @@
+/*!
+
+How the global node allocator, storage allocator, root list, and permanent roots are locked. By default a lock that
+is already held is a bug, as in a single threaded program only reentrance can cause it, so acquiring it panics at
+once instead of deadlocking. Multithreaded programs set `LockPolicy::Block` so that contending threads wait their turn.
+
+*/
+
+use std::sync::{
+  atomic::{AtomicU8, Ordering::Relaxed},
+  Mutex,
+  MutexGuard,
+  TryLockError
+};
+
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum LockPolicy {
+  /// Panic if the lock is held, which reports reentrance immediately.
+  #[default]
+  Panic = 0,
+  /// Wait for the lock, for programs with more than one thread using the heap.
+  Block = 1,
+}
+
+static LOCK_POLICY: AtomicU8 = AtomicU8::new(LockPolicy::Panic as u8);
+
+/// Sets how the global allocators and root lists are locked from now on.
+pub fn set_lock_policy(policy: LockPolicy) {
+  LOCK_POLICY.store(policy as u8, Relaxed);
+}
+
+#[inline(always)]
+pub fn lock_policy() -> LockPolicy {
+  match LOCK_POLICY.load(Relaxed) {
+    0 => LockPolicy::Panic,
+    _ => LockPolicy::Block,
+  }
+}
+
+/// Locks `mutex` according to the current `LockPolicy`. `name` names the lock and `caller` the function acquiring
+/// it in panic messages.
+#[inline(always)]
+pub(crate) fn acquire_lock<T>(mutex: &'static Mutex<T>, name: &str, caller: &str) -> MutexGuard<'static, T> {
+  match lock_policy() {
+    LockPolicy::Panic => {
+      match mutex.try_lock() {
+        Ok(guard)                      => guard,
+        Err(TryLockError::WouldBlock)  => panic!("Deadlocked acquiring {} in {}.", name, caller),
+        Err(TryLockError::Poisoned(_)) => panic!("{} is poisoned, found in {}.", name, caller),
+      }
+    }
+    LockPolicy::Block => mutex.lock().unwrap_or_else(|_| panic!("{} is poisoned, found in {}.", name, caller)),
+  }
+}
+
+/// Sets the lock policy until dropped, then restores the policy in force before, so that a failing test does not
+/// leave its policy behind.
+#[cfg(test)]
+pub(crate) struct LockPolicyGuard {
+  previous_policy: LockPolicy,
+}
+
+#[cfg(test)]
+impl LockPolicyGuard {
+  pub(crate) fn set(policy: LockPolicy) -> LockPolicyGuard {
+    let previous_policy = lock_policy();
+    set_lock_policy(policy);
+    LockPolicyGuard { previous_policy }
+  }
+}
+
+#[cfg(test)]
+impl Drop for LockPolicyGuard {
+  fn drop(&mut self) {
+    set_lock_policy(self.previous_policy);
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use std::{collections::HashSet, thread};
+
+  use crate::{
+    abstractions::IString,
+    dag_node::{
+      allocator::{active_node_count, lock_global_heap},
+      DagNode
+    },
+    symbol::Symbol
+  };
+  use super::{lock_policy, LockPolicy, LockPolicyGuard};
+
+  #[test]
+  fn test_blocking_policy_serializes_allocations() {
+    let _heap = lock_global_heap();
+    assert_eq!(LockPolicy::default(), LockPolicy::Panic);
+    let _policy = LockPolicyGuard::set(LockPolicy::Block);
+    assert_eq!(lock_policy(), LockPolicy::Block);
+
+    let a = Symbol::new(IString::from("a"), 0);
+    let threads          = 4;
+    let nodes_per_thread = 5000;
+    let nodes: Vec<usize> = thread::scope(|scope| {
+      let handles = (0..threads).map(|_| {
+        scope.spawn(|| {
+          (0..nodes_per_thread).map(|_| DagNode::new(&a) as usize).collect::<Vec<_>>()
+        })
+      }).collect::<Vec<_>>();
+      handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    });
+
+    // No node was handed out twice.
+    let distinct = nodes.iter().collect::<HashSet<_>>();
+    assert_eq!(distinct.len(), threads * nodes_per_thread);
+    assert!(active_node_count() >= threads * nodes_per_thread);
+  }
+
+  #[test]
+  fn test_lock_policy_guard_restores_policy() {
+    let _heap = lock_global_heap();
+    let policy = lock_policy();
+    {
+      let _panic = LockPolicyGuard::set(LockPolicy::Panic);
+      assert_eq!(lock_policy(), LockPolicy::Panic);
+      {
+        let _block = LockPolicyGuard::set(LockPolicy::Block);
+        assert_eq!(lock_policy(), LockPolicy::Block);
+      }
+      assert_eq!(lock_policy(), LockPolicy::Panic);
+    }
+    assert_eq!(lock_policy(), policy);
+  }
+}
@@ -8,21 +8,108 @@ The allocator for garbage collected memory. This is really two different allocat
 */
 
 mod arena;
+mod background_gc;
 mod bucket;
+mod lock_policy;
 pub(crate) mod node_vector;
 mod node_allocator;
 mod storage_allocator;
 
-use node_allocator::acquire_node_allocator;
+pub(crate) use node_allocator::acquire_node_allocator;
+pub(crate) use lock_policy::acquire_lock;
+pub use arena::{huge_pages, set_huge_pages, NODE_ALIGNMENT};
+pub use lock_policy::{lock_policy, set_lock_policy, LockPolicy};
+pub use background_gc::{
+  background_gc_count,
+  background_gc_running,
+  mutator_region,
+  start_background_gc,
+  stop_background_gc
+};
 
 pub(crate) use node_allocator::increment_active_node_count;
+#[cfg(feature = "gc_debug")]
+pub(crate) use node_allocator::record_mark;
+#[cfg(feature = "gc_timing")]
+pub use node_allocator::max_alloc_stall;
+#[cfg(feature = "gc_safe_mark")]
+pub(crate) use node_allocator::is_markable;
+#[cfg(feature = "gc_safe_mark")]
+pub use node_allocator::bad_child_count;
+#[cfg(test)]
+pub(crate) use node_allocator::{active_node_count, lock_global_heap};
+#[cfg(test)]
+pub(crate) use storage_allocator::acquire_storage_allocator;
 
 
 
 pub use node_allocator::{
   ok_to_collect_garbage, 
+  collect_if_needed,
   want_to_collect_garbage, 
-  allocate_dag_node 
+  allocate_dag_node,
+  try_allocate_dag_node,
+  checkpoint,
+  rollback,
+  nodes_until_gc,
+  live_nodes_by_kind,
+  live_bytes_by_kind,
+  arena_count,
+  cursor_position,
+  arena_ranges,
+  set_max_arenas,
+  set_gc_limit,
+  gc_limit_reached,
+  set_min_arenas,
+  set_on_pre_collect,
+  clear_all_marks,
+  set_on_arena_allocated,
+  mark_external,
+  clear_mark_external,
+  reserve_nodes,
+  for_each_live_node,
+  collect_storage_only,
+  last_gc_stats,
+  live_count_history,
+  gc_cycle_count,
+  sync_gc_count,
+  reset_sync_gc_count,
+  mark_from,
+  collect_garbage_relocating,
+  collect_and_compact,
+  last_gc_forwarding,
+  set_sweep_mode,
+  set_show_gc,
+  set_collect_stats,
+  set_destruction_order,
+  set_on_finalize,
+  set_hashcons_on_gc,
+  lazily_swept_nodes,
+  fork_heap,
+  GcStats,
+  ArenaAllocatedHook,
+  Checkpoint,
+  DestructionOrder,
+  ExternalRootScan,
+  FinalizeHook,
+  HeapFork,
+  NodeAllocator,
+  PreCollectHook,
+  SweepMode
+};
+pub use storage_allocator::{
+  bucket_fast_hits,
+  bucket_report,
+  bucket_slow_allocs,
+  bytes_until_gc,
+  in_use_bucket_bytes,
+  set_initial_storage_target,
+  set_max_total_bytes,
+  set_storage_target_multiplier,
+  try_allocate_storage,
+  unused_bucket_bytes,
+  BucketInfo,
+  StorageAllocator
 };
 
 
@@ -7,47 +7,76 @@ The arena allocator manages memory by organizing it into arenas, which are fixed
 
 When garbage collection is triggered, the allocator then sweeps the remaining (not yet searched) part of the arena(s). Then it begins the mark phase. During marking, the allocator requests all node roots to flag nodes that are actively in use so that they’re preserved. During this phase, the number of active nodes is computed. After marking, the allocator compares it's total node capacity to the number of active nodes and, if the available capacity is less than a certain "slop factor," more arenas are allocated from system memory. The "cursor" for the linear search is then reset to the first node of the first arena.
 
-Since the sweep phase is done lazily, the time it takes to sweep the arenas is amortized between garbage collection events. Because garbage collection is triggered when the linear search for free nodes nears the end of the last arena, allocating a "slop factor" of extra arenas keeps garbage collection events low.
+Since the sweep phase is done lazily, the time it takes to sweep the arenas is amortized between garbage collection events. With `SweepMode::Eager`, each collection instead sweeps every arena at once and lists the free nodes, so that allocation does no sweeping. Because garbage collection is triggered when the linear search for free nodes nears the end of the last arena, allocating a "slop factor" of extra arenas keeps garbage collection events low.
+
+The free function `allocate_dag_node` does not lock the global allocator for every node. Each thread takes free nodes from the global allocator in small batches and hands them out from a thread local cache. A collection invalidates every cache.
 
 */
 
 use std::{
+  cell::RefCell,
+  fmt::Write,
+  cmp::{max, min},
+  collections::{BTreeMap, HashMap, HashSet, VecDeque},
   sync::{
     atomic::{
-      Ordering::Relaxed,
+      Ordering::{Acquire, Relaxed, Release},
+      AtomicU64,
       AtomicUsize
     },
     Mutex,
     MutexGuard,
   },
-  ptr::drop_in_place,
+  ptr::{drop_in_place, null_mut},
 };
+#[cfg(feature = "gc_timing")]
+use std::time::{Duration, Instant};
 
 use once_cell::sync::Lazy;
 
 use crate::{
   dag_node::{
     allocator::{
-      arena::Arena,
-      storage_allocator::acquire_storage_allocator
+      acquire_lock,
+      arena::{Arena, NODE_ALIGNMENT},
+      storage_allocator::{acquire_storage_allocator, StorageAllocator}
     },
+    deref_node,
+    deref_node_mut,
     DagNode,
     DagNodeFlag,
     DagNodeFlags,
-    root_container::mark_roots,
+    DagNodeKind,
+    root_container::{for_each_root, forward_roots, mark_roots},
+    RootContainer,
+    Void,
   }
 };
 use crate::dag_node::DagNodePtr;
+use crate::symbol::SymbolPtr;
 
 // Constant Allocator Parameters
 const SMALL_MODEL_SLOP: f64   = 8.0;
 const BIG_MODEL_SLOP  : f64   = 2.0;
 const LOWER_BOUND     : usize =  4 * 1024 * 1024; // Use small model if <= 4 million nodes
 const UPPER_BOUND     : usize = 32 * 1024 * 1024; // Use big model if >= 32 million nodes
-// It looks like Maude assumes DagNodes are 6 words in size, but ours are 3 words,
-// at least so far.
-pub(crate) const ARENA_SIZE: usize = 5460; // Arena size in nodes; 5460 * 6 + 1 + new/malloc_overhead <= 32768 words
+// Maude sizes arenas to 32768 words, 5460 * 6 + 1 + new/malloc_overhead <= 32768 words for its 6 word nodes. We keep
+// the 32768 words and fill them with as many of our nodes as fit after the link to the next arena, which is padded to
+// `NODE_ALIGNMENT`: 8190 nodes of `DAG_NODE_WORDS` == 4 words on 64 bit targets, fewer if the `node_align_*` features
+// pad nodes further.
+const ARENA_BYTES    : usize = 32768 * size_of::<usize>();
+const MALLOC_OVERHEAD: usize = 7 * size_of::<usize>();
+pub(crate) const ARENA_SIZE: usize = (ARENA_BYTES - MALLOC_OVERHEAD - NODE_ALIGNMENT) / size_of::<DagNode>(); // In nodes
 const RESERVE_SIZE         : usize = 256; // If fewer nodes left call GC when allowed
+const THREAD_CACHE_SIZE    : usize = 64;  // Nodes a thread takes from the global allocator at once
+const LIVE_COUNT_HISTORY   : usize = 32;  // Collections whose live node counts are kept
+
+// An arena, its nodes and the link to the next with any padding, must fit in 32768 words with room for malloc's
+// overhead.
+const _: () = assert!(
+  size_of::<Arena>() + MALLOC_OVERHEAD <= ARENA_BYTES,
+  "an arena no longer fits in 32768 words"
+);
 
 
 pub(crate) static ACTIVE_NODE_COUNT: AtomicUsize = AtomicUsize::new(0);
@@ -55,15 +84,55 @@ static GLOBAL_NODE_ALLOCATOR: Lazy<Mutex<NodeAllocator>> = Lazy::new(|| {
   Mutex::new(NodeAllocator::new())
 });
 
-/// Acquire the global node allocator. The `caller_msg` is for debugging purposes.
+/// Incremented whenever previously allocated nodes may be handed out again (garbage collection, rollback), which
+/// invalidates every thread's node cache.
+static ALLOCATION_EPOCH: AtomicU64 = AtomicU64::new(0);
+/// Number of collections that have run.
+static GC_COUNT: AtomicU64 = AtomicU64::new(0);
+/// Number of collections run because an allocator asked for one. See `sync_gc_count`.
+static SYNC_GC_COUNT: AtomicU64 = AtomicU64::new(0);
+/// The longest time spent in one call to `allocate_dag_node` or `ok_to_collect_garbage`, in nanoseconds.
+#[cfg(feature = "gc_timing")]
+static MAX_ALLOC_STALL_NANOS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+  static THREAD_NODE_CACHE: RefCell<ThreadNodeCache> = const { RefCell::new(ThreadNodeCache::new()) };
+}
+
+#[cfg(test)]
+thread_local! {
+  /// Number of times this thread acquired the global node allocator.
+  static LOCK_ACQUISITIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Acquire the global node allocator according to the `LockPolicy`. The `caller_msg` is for debugging purposes.
 #[inline(always)]
 pub fn acquire_node_allocator(caller_msg: &str) -> MutexGuard<'static, NodeAllocator> {
-  GLOBAL_NODE_ALLOCATOR.lock().expect(caller_msg)
+  #[cfg(test)]
+  LOCK_ACQUISITIONS.with(|count| count.set(count.get() + 1));
+  acquire_lock(&GLOBAL_NODE_ALLOCATOR, "node allocator", caller_msg)
 }
 
 #[inline(always)]
 pub fn ok_to_collect_garbage() {
+  #[cfg(feature = "gc_timing")]
+  let start = Instant::now();
   acquire_node_allocator("ok_to_collect_garbage").ok_to_collect_garbage();
+  #[cfg(feature = "gc_timing")]
+  record_alloc_stall(start);
+}
+
+/// Collects garbage if either allocator wants to, checking and collecting under one acquisition of the allocator
+/// lock, so that no other thread can collect in between. Returns the statistics of the collection, or `None` if
+/// none ran.
+#[inline(always)]
+pub fn collect_if_needed() -> Option<GcStats> {
+  #[cfg(feature = "gc_timing")]
+  let start = Instant::now();
+  let stats = acquire_node_allocator("collect_if_needed").collect_if_needed();
+  #[cfg(feature = "gc_timing")]
+  record_alloc_stall(start);
+  stats
 }
 
 #[inline(always)]
@@ -71,16 +140,620 @@ pub fn want_to_collect_garbage() -> bool {
   acquire_node_allocator("want_to_collect_garbage").want_to_collect_garbage()
 }
 
+/// Whether either allocator wants a collection, the condition `collect_if_needed` checks.
+pub(crate) fn collection_wanted() -> bool {
+  let node_allocator = acquire_node_allocator("collection_wanted");
+  node_allocator.want_to_collect_garbage() || acquire_storage_allocator().want_to_collect_garbage()
+}
+
+/// Like `collect_if_needed`, but the collection is not counted by `sync_gc_count`. For the background collector,
+/// which counts its own.
+pub(crate) fn collect_if_needed_uncounted() -> Option<GcStats> {
+  acquire_node_allocator("collect_if_needed_uncounted").collect_if_wanted()
+}
+
+/// Allocates a new `DagNode`. Nodes are taken from a small thread local cache, so the global allocator is only
+/// locked when the cache needs refilling. Once the allocator wants to collect, the cache takes one node at a time.
 #[inline(always)]
 pub fn allocate_dag_node() -> DagNodePtr {
-  acquire_node_allocator("want_to_collect_garbage").allocate_dag_node()
+  #[cfg(feature = "gc_timing")]
+  let start = Instant::now();
+  let node = THREAD_NODE_CACHE.with(|cache| cache.borrow_mut().allocate_dag_node());
+  #[cfg(feature = "gc_timing")]
+  record_alloc_stall(start);
+  node
+}
+
+/// Allocates a new `DagNode` like `allocate_dag_node`, but fails instead of adding an arena that would take the
+/// heap past the ceiling set by `set_max_total_bytes`. A failure asks for a collection, after which trying again
+/// may succeed.
+pub fn try_allocate_dag_node() -> Result<DagNodePtr, String> {
+  THREAD_NODE_CACHE.with(|cache| {
+    let mut cache = cache.borrow_mut();
+    match cache.has_fresh_nodes() {
+      true  => Ok(cache.allocate_dag_node()),
+      // Refilling the cache could grow the heap by more than this node needs, so take it straight from the allocator.
+      false => acquire_node_allocator("try_allocate_dag_node").try_allocate_dag_node(),
+    }
+  })
+}
+
+#[cfg(feature = "gc_timing")]
+fn record_alloc_stall(start: Instant) {
+  let nanos = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+  MAX_ALLOC_STALL_NANOS.fetch_max(nanos, Relaxed);
+}
+
+/// The longest time any single call to `allocate_dag_node` or `ok_to_collect_garbage` has taken, including any
+/// collection it ran and any wait for the allocator lock. Only recorded with the `gc_timing` feature, which reads
+/// the clock twice per allocation.
+#[cfg(feature = "gc_timing")]
+pub fn max_alloc_stall() -> Duration {
+  Duration::from_nanos(MAX_ALLOC_STALL_NANOS.load(Relaxed))
+}
+
+/// The number of node slots that can be handed out before the global allocator asks for a collection. See
+/// `NodeAllocator::nodes_until_gc`.
+pub fn nodes_until_gc() -> usize {
+  acquire_node_allocator("nodes_until_gc").nodes_until_gc()
+}
+
+/// The nodes in use in the global allocator's arenas, grouped by kind. See `NodeAllocator::live_nodes_by_kind`.
+pub fn live_nodes_by_kind() -> HashMap<DagNodeKind, Vec<DagNodePtr>> {
+  acquire_node_allocator("live_nodes_by_kind").live_nodes_by_kind()
+}
+
+/// The bytes held by nodes reachable from the roots, grouped by kind: each node's own size plus the size of the
+/// `NodeVector` holding its arguments, if it has one. Each node is counted once however often it is shared.
+pub fn live_bytes_by_kind() -> HashMap<DagNodeKind, usize> {
+  let mut bytes  : HashMap<DagNodeKind, usize> = HashMap::new();
+  let mut visited: HashSet<DagNodePtr>         = HashSet::new();
+  let mut stack  : Vec<DagNodePtr>             = Vec::new();
+  for_each_root(|root| stack.push(root));
+
+  while let Some(node) = stack.pop() {
+    if !visited.insert(node) {
+      continue;
+    }
+    let node_ref    = unsafe { deref_node(node) };
+    let owned_bytes = node_ref.node_vector()
+                              .map_or(0, |node_vector| size_of_val(node_vector) + node_vector.capacity_bytes());
+    *bytes.entry(node_ref.kind).or_default() += size_of::<DagNode>() + owned_bytes;
+    stack.extend(node_ref.iter_children());
+  }
+
+  bytes
+}
+
+/// The first node and node count of each of the global allocator's arenas, in allocation order.
+pub fn arena_ranges() -> Vec<(*const DagNode, usize)> {
+  acquire_node_allocator("arena_ranges").arena_ranges()
+}
+
+/// The number of arenas the global allocator has allocated.
+pub fn arena_count() -> u32 {
+  acquire_node_allocator("arena_count").arena_count()
+}
+
+/// The index of the global allocator's current arena and the offset of its next node within it. Threads take nodes
+/// in batches, so the cursor runs ahead of the nodes handed out. See `NodeAllocator::cursor_position`.
+pub fn cursor_position() -> (usize, usize) {
+  acquire_node_allocator("cursor_position").cursor_position()
+}
+
+/// Sets the largest number of arenas a collection grows the global allocator to. `None` removes the cap. See
+/// `NodeAllocator::set_max_arenas`.
+pub fn set_max_arenas(max_arenas: Option<u32>) {
+  acquire_node_allocator("set_max_arenas").set_max_arenas(max_arenas);
+}
+
+/// Stops the global allocator collecting once `limit` more collections have run. See `NodeAllocator::set_gc_limit`.
+pub fn set_gc_limit(limit: Option<u64>) {
+  acquire_node_allocator("set_gc_limit").set_gc_limit(limit);
+}
+
+/// Whether the global allocator has stopped collecting because its collection limit was reached.
+pub fn gc_limit_reached() -> bool {
+  acquire_node_allocator("gc_limit_reached").gc_limit_reached()
+}
+
+/// Sets the number of arenas a collection always grows the global allocator to. See `NodeAllocator::set_min_arenas`.
+pub fn set_min_arenas(min_arenas: u32) {
+  acquire_node_allocator("set_min_arenas").set_min_arenas(min_arenas);
+}
+
+/// Calls `f` on each node that survived the global allocator's most recent collection. The survivors are copied out
+/// first, so `f` may allocate. Like `NodeAllocator::last_live_nodes`, this needs statistics to be on.
+pub fn for_each_live_node(f: impl FnMut(DagNodePtr)) {
+  let live_nodes = acquire_node_allocator("for_each_live_node").last_live_nodes().to_vec();
+  live_nodes.into_iter().for_each(f);
+}
+
+/// Collects bucket storage without collecting nodes. See `NodeAllocator::collect_storage_only`.
+pub fn collect_storage_only() {
+  unsafe { acquire_node_allocator("collect_storage_only").collect_storage_only(); }
+}
+
+/// Grows the global allocator to hold at least `node_count` nodes. See `NodeAllocator::reserve_nodes`.
+pub fn reserve_nodes(node_count: usize) {
+  acquire_node_allocator("reserve_nodes").reserve_nodes(node_count);
+}
+
+/// Clears stray marks from the nodes behind the global allocator's allocation cursor, the only nodes that can be
+/// cleared without losing the survivors of the last collection. See `NodeAllocator::clear_all_marks`.
+pub fn clear_all_marks() -> usize {
+  acquire_node_allocator("clear_all_marks").clear_all_marks()
+}
+
+/// Installs a hook the global allocator consults before each collection. See `NodeAllocator::set_on_pre_collect`.
+pub fn set_on_pre_collect(hook: Option<PreCollectHook>) {
+  acquire_node_allocator("set_on_pre_collect").set_on_pre_collect(hook);
+}
+
+/// Installs a hook the global allocator calls after each new arena. See `NodeAllocator::set_on_arena_allocated`.
+pub fn set_on_arena_allocated(hook: Option<ArenaAllocatedHook>) {
+  acquire_node_allocator("set_on_arena_allocated").set_on_arena_allocated(hook);
+}
+
+/// Installs `scan` to contribute roots held outside the root list to every collection of the global allocator,
+/// replacing any scan installed before. See `NodeAllocator::set_external_root_scan`.
+pub fn mark_external(scan: impl Fn() -> Vec<DagNodePtr> + Send + 'static) {
+  acquire_node_allocator("mark_external").set_external_root_scan(Some(Box::new(scan)));
+}
+
+/// Removes the scan installed by `mark_external`.
+pub fn clear_mark_external() {
+  acquire_node_allocator("clear_mark_external").set_external_root_scan(None);
+}
+
+/// Sets whether the global allocator's relocating collections merge structurally equal survivors. See
+/// `NodeAllocator::set_hashcons_on_gc`.
+pub fn set_hashcons_on_gc(hashcons_on_gc: bool) {
+  acquire_node_allocator("set_hashcons_on_gc").set_hashcons_on_gc(hashcons_on_gc);
+}
+
+/// Sets the order in which the global allocator destroys the nodes a collection reclaims. See `DestructionOrder`.
+pub fn set_destruction_order(destruction_order: DestructionOrder) {
+  acquire_node_allocator("set_destruction_order").set_destruction_order(destruction_order);
+}
+
+/// Installs a hook the global allocator calls for each `Data` node it reclaims. See `NodeAllocator::set_on_finalize`.
+pub fn set_on_finalize(hook: Option<FinalizeHook>) {
+  acquire_node_allocator("set_on_finalize").set_on_finalize(hook);
+}
+
+/// Sets whether the global allocator's collections print a report of the arenas.
+pub fn set_show_gc(show_gc: bool) {
+  acquire_node_allocator("set_show_gc").set_show_gc(show_gc);
+}
+
+/// Sets whether the global allocator's collections gather statistics. See `NodeAllocator::set_collect_stats`.
+pub fn set_collect_stats(collect_stats: bool) {
+  acquire_node_allocator("set_collect_stats").set_collect_stats(collect_stats);
+}
+
+/// Sets whether the global allocator sweeps lazily or eagerly from its next collection on. See `SweepMode`.
+pub fn set_sweep_mode(sweep_mode: SweepMode) {
+  acquire_node_allocator("set_sweep_mode").set_sweep_mode(sweep_mode);
+}
+
+/// The number of survivors whose marks the global allocator's lazy sweep has cleared while allocating. See
+/// `NodeAllocator::lazily_swept_nodes`.
+pub fn lazily_swept_nodes() -> u64 {
+  acquire_node_allocator("lazily_swept_nodes").lazily_swept_nodes()
+}
+
+/// Captures the allocation cursors of the global allocators. See `NodeAllocator::checkpoint`.
+pub fn checkpoint() -> Checkpoint {
+  acquire_node_allocator("checkpoint").checkpoint()
+}
+
+/// Discards every allocation made since `checkpoint` was taken.
+///
+/// # Safety
+///
+/// See `NodeAllocator::rollback`.
+pub unsafe fn rollback(checkpoint: Checkpoint) {
+  acquire_node_allocator("rollback").rollback(checkpoint)
+}
+
+/// Statistics about the global allocator's most recent collection. See `GcStats`.
+pub fn last_gc_stats() -> GcStats {
+  acquire_node_allocator("last_gc_stats").last_gc_stats()
+}
+
+/// The live node counts of the global allocator's most recent collections, oldest first. See
+/// `NodeAllocator::live_count_history`.
+pub fn live_count_history() -> Vec<usize> {
+  acquire_node_allocator("live_count_history").live_count_history()
+}
+
+/// Runs a collection in which the nodes reachable from `roots` survive along with those reachable from the registered
+/// roots, without registering `roots`. Returns the number of distinct nodes reachable from `roots`, whether or not
+/// they are also reachable from a registered root, or `None` if no collection ran.
+pub fn mark_from(roots: &[DagNodePtr]) -> Option<usize> {
+  unsafe { acquire_node_allocator("mark_from").collect_garbage_from(roots) }
+}
+
+/// Runs a collection that copies the survivors into fresh arenas in depth first order from the roots. Only
+/// registered and permanent roots are updated, so any other pointer to a node is invalid afterward. Returns the
+/// statistics of the collection, or `None` if no collection ran. See `NodeAllocator::collect_garbage_relocating`.
+pub fn collect_garbage_relocating() -> Option<GcStats> {
+  unsafe { acquire_node_allocator("collect_garbage_relocating").collect_garbage_relocating() }
+}
+
+/// Runs a relocating collection that keeps what is reachable from `root` and the registered roots, and returns
+/// where `root` now is, for a caller holding a single term that should not need `last_gc_forwarding`. If no
+/// collection ran, returns `root` itself. Every other unrooted pointer to a node is invalid afterward.
+pub fn collect_and_compact(root: DagNodePtr) -> DagNodePtr {
+  let handle = RootContainer::new(root);
+  collect_garbage_relocating();
+  handle.node()
+}
+
+/// Where the most recent collection moved `old`, so that caches holding nodes that are not roots can follow them
+/// after a relocating collection. `None` if `old` did not survive or the most recent collection was not a relocating
+/// one, or if `old` is now the address of a node of an arena allocated since, which may reuse the freed memory. `old`
+/// itself must not be dereferenced, as its memory may have been freed.
+pub fn last_gc_forwarding(old: DagNodePtr) -> Option<DagNodePtr> {
+  acquire_node_allocator("last_gc_forwarding").last_gc_forwarding(old)
+}
+
+/// The number of garbage collections that have run.
+#[inline(always)]
+pub fn gc_cycle_count() -> u64 {
+  GC_COUNT.load(Relaxed)
+}
+
+/// The number of collections run because allocation pushed an allocator past its threshold, as opposed to those a
+/// caller ran itself with `mark_from` and the like and those the background collector ran, which
+/// `background_gc_count` counts. Allocation never collects on its own; it asks for a collection,
+/// which then runs inline in the next `ok_to_collect_garbage` or `collect_if_needed`, so each of these is a pause
+/// that the allocations since the previous collection caused.
+#[inline(always)]
+pub fn sync_gc_count() -> u64 {
+  SYNC_GC_COUNT.load(Relaxed)
+}
+
+/// Sets the count returned by `sync_gc_count` back to zero, for measuring one phase of a program.
+pub fn reset_sync_gc_count() {
+  SYNC_GC_COUNT.store(0, Relaxed);
+}
+
+/// Deep copies the global heap into an independent `HeapFork`.
+pub fn fork_heap() -> HeapFork {
+  let node_allocator = acquire_node_allocator("fork_heap");
+  node_allocator.fork(&acquire_storage_allocator())
+}
+
+/// Statistics gathered by one garbage collection.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct GcStats {
+  /// Which collection this was, counting from 1.
+  pub collection   : u64,
+  /// Nodes in use when the collection started, reachable or not.
+  pub nodes_before : usize,
+  /// Nodes that survived the collection.
+  pub live_nodes   : usize,
+  /// Nodes allocated since the previous collection that were garbage by this one. A high number relative to
+  /// `live_nodes` is normal churn, but can also mean that a root is missing. Only computed with the `gc_debug`
+  /// feature; otherwise zero.
+  pub churned_nodes: usize,
+  /// The fraction of all bucket storage holding live data after the sweep. See
+  /// `StorageAllocator::bucket_utilization`.
+  pub bucket_utilization: f64,
+}
+
+/// When the allocator sweeps the arenas, clearing the marks of survivors and reclaiming the garbage.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum SweepMode {
+  /// Allocation sweeps the arenas as it searches them for free nodes, which spreads the work of a sweep over the
+  /// allocations that follow a collection.
+  #[default]
+  Lazy,
+  /// Each collection sweeps every arena at once and lists the free nodes, so that allocation never sweeps.
+  Eager,
+}
+
+/// The order in which a collection destroys the nodes it found dead. Either order makes the collection destroy every
+/// dead node right after marking rather than as the sweep reaches it, when a finalizer is installed or the order is
+/// `ParentsFirst`. Relocating collections free the old arenas without destroying anything.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum DestructionOrder {
+  /// Dead nodes are destroyed in the order they sit in the arenas, so a node can be destroyed after its children.
+  #[default]
+  Scan,
+  /// A dead node is destroyed before any dead node it points to, so its finalizer can still read its children. Dead
+  /// cycles, which have no such order, are destroyed last, in arena order.
+  ParentsFirst,
+}
+
+/// A snapshot of the allocation cursors, used to roll back speculative allocations.
+///
+/// A `Checkpoint` is only meaningful until the next garbage collection. See `NodeAllocator::rollback`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Checkpoint {
+  current_arena                  : *mut Arena,
+  next_node                      : *mut DagNode,
+  end_pointer                    : *mut DagNode,
+  current_arena_past_active_arena: bool,
+  need_to_collect_garbage        : bool,
+  next_free                      : usize,
+  active_node_count              : usize,
+  storage_in_use                 : usize,
+  swept_survivors                : usize,
+}
+
+
+/// A per-thread cache of free nodes taken from the global allocator in batches. Every cached node has already
+/// been swept by the global allocator, so handing one out requires no further work. Garbage collection bumps
+/// `ALLOCATION_EPOCH`, which discards the cached nodes, as they are no longer reserved for this thread.
+struct ThreadNodeCache {
+  epoch: u64,
+  len  : usize,
+  nodes: [DagNodePtr; THREAD_CACHE_SIZE],
+}
+
+impl ThreadNodeCache {
+  const fn new() -> Self {
+    ThreadNodeCache {
+      epoch: 0,
+      len  : 0,
+      nodes: [null_mut(); THREAD_CACHE_SIZE],
+    }
+  }
+
+  #[inline(always)]
+  fn allocate_dag_node(&mut self) -> DagNodePtr {
+    if !self.has_fresh_nodes() {
+      self.refill();
+    }
+
+    self.len -= 1;
+    increment_active_node_count();
+    self.nodes[self.len]
+  }
+
+  /// Whether the cache holds nodes that no collection has invalidated.
+  #[inline(always)]
+  fn has_fresh_nodes(&self) -> bool {
+    self.len > 0 && self.epoch == ALLOCATION_EPOCH.load(Acquire)
+  }
+
+  fn refill(&mut self) {
+    let mut allocator = acquire_node_allocator("ThreadNodeCache::refill");
+
+    // Read under the lock, as collections bump the epoch while holding it.
+    self.epoch = ALLOCATION_EPOCH.load(Acquire);
+    // Once the allocator wants to collect, it is handing out its reserve, and nodes are taken one at a time so that
+    // no cache holds on to what is left of the reserve.
+    let mut len = 0;
+    while len < THREAD_CACHE_SIZE {
+      let node = allocator.next_free_node();
+      // A cached slot reads as free until it is handed out and initialized.
+      unsafe { node.write(DagNode::default()); }
+      self.nodes[len] = node;
+      len += 1;
+      if allocator.want_to_collect_garbage() {
+        break;
+      }
+    }
+    // Nodes are handed out from the back, so reverse them to hand them out in allocation order.
+    self.nodes[..len].reverse();
+    self.len = len;
+  }
+}
+
+/// An independent deep copy of the heap made by `fork_heap`, for speculative execution. Node pointers taken from
+/// the original heap point into the original heap, so they must be passed through `translate` before they can be
+/// used with the fork. Mutating nodes reached through translated pointers leaves the original heap unchanged.
+/// New nodes for the fork are allocated from the fork with `new_node` and `with_args`, and the fork is collected with
+/// `collect`. A node of the fork must not point into another heap, as no other heap's collector sees the fork's nodes
+/// as roots: `with_args` refuses such children, and `collect` refuses to run if a reachable node has one. Dropping
+/// the fork frees its arenas and bucket storage.
+pub struct HeapFork {
+  node_allocator   : NodeAllocator,
+  storage_allocator: StorageAllocator,
+  /// Maps the address of the first node of each original arena to the address of the first node of its copy.
+  arena_map        : BTreeMap<usize, usize>,
+  /// Maps the start address of each original bucket to its length and the start address of its copy.
+  bucket_map       : BTreeMap<usize, (usize, usize)>,
+}
+
+impl HeapFork {
+  /// Gives the node of the fork that corresponds to `node` of the original heap, or null if `node` does not point into
+  /// the original heap's arenas.
+  pub fn translate(&self, node: DagNodePtr) -> DagNodePtr {
+    translate_node(&self.arena_map, node)
+  }
+
+  /// Whether `node` is a node of the fork.
+  pub fn contains_node(&self, node: *const DagNode) -> bool {
+    self.node_allocator.contains_node(node)
+  }
+
+  #[inline(always)]
+  pub fn arena_count(&self) -> u32 {
+    self.node_allocator.arena_count
+  }
+
+  /// Bytes of bucket storage in use in the fork.
+  #[inline(always)]
+  pub fn storage_in_use(&self) -> usize {
+    self.storage_allocator.storage_in_use()
+  }
+
+  /// Allocates a node of the fork, like `DagNode::new`.
+  pub fn new_node(&mut self, symbol: SymbolPtr) -> DagNodePtr {
+    DagNode::new_in(&mut self.node_allocator, &mut self.storage_allocator, symbol)
+  }
+
+  /// Allocates a node of the fork with the given children, like `DagNode::with_args`. Fails if a child is not a node
+  /// of the fork.
+  pub fn with_args(
+    &mut self,
+    symbol: SymbolPtr,
+    args  : &[DagNodePtr],
+    kind  : DagNodeKind
+  ) -> Result<DagNodePtr, String> {
+    if let Some(&child) = args.iter().find(|&&child| !self.contains_node(child)) {
+      return Err(format!("cannot make a child of a node of the fork from {:p}, which is not in the fork", child));
+    }
+    Ok(DagNode::with_args_in(&mut self.node_allocator, &mut self.storage_allocator, symbol, args, kind))
+  }
+
+  /// Collects the garbage of the fork, keeping the nodes reachable from `roots`, which must be nodes of the fork.
+  /// Returns the number of survivors, or an error, without collecting, if a node reachable from `roots` is not a node
+  /// of the fork.
+  pub fn collect(&mut self, roots: &[DagNodePtr]) -> Result<usize, String> {
+    let mut visited: HashSet<DagNodePtr> = HashSet::new();
+    let mut stack  : Vec<DagNodePtr>     = roots.to_vec();
+    while let Some(node) = stack.pop() {
+      if !self.contains_node(node) {
+        return Err(format!("node {:p} is reachable from the roots of the fork but is not in the fork", node));
+      }
+      if visited.insert(node) {
+        stack.extend(unsafe { deref_node(node) }.iter_children());
+      }
+    }
+
+    Ok(unsafe { self.node_allocator.collect_isolated(&mut self.storage_allocator, roots) })
+  }
+}
+
+/// Translates `node`, a pointer into an arena of the original heap or one past its end, using `arena_map`.
+fn translate_node(arena_map: &BTreeMap<usize, usize>, node: DagNodePtr) -> DagNodePtr {
+  if node.is_null() {
+    return node;
+  }
+
+  let address = node as usize;
+  match arena_map.range(..=address).next_back() {
+    Some((&first, &copy)) if address <= first + ARENA_SIZE * size_of::<DagNode>() => {
+      (copy + (address - first)) as DagNodePtr
+    }
+    _ => null_mut(),
+  }
+}
+
+/// Translates `ptr`, a pointer into a bucket of the original heap, using `bucket_map`.
+fn translate_storage(bucket_map: &BTreeMap<usize, (usize, usize)>, ptr: *const Void) -> *mut Void {
+  let address = ptr as usize;
+  match bucket_map.range(..=address).next_back() {
+    Some((&start, &(len, copy))) if address < start + len => (copy + (address - start)) as *mut Void,
+    _ => null_mut(),
+  }
+}
+
+/// Orders `dead` so that each node comes before every node of `dead` it points to. The nodes on a cycle and the nodes
+/// below them have no such order and come last, in arena order.
+fn parents_first(dead: Vec<DagNodePtr>) -> Vec<DagNodePtr> {
+  // The number of dead parents of each dead node that have not been ordered yet.
+  let mut pending: HashMap<DagNodePtr, usize> = dead.iter().map(|&node| (node, 0)).collect();
+  for &node in &dead {
+    for child in unsafe { deref_node(node) }.iter_children() {
+      if let Some(count) = pending.get_mut(child) {
+        *count += 1;
+      }
+    }
+  }
+
+  let mut ordered = Vec::with_capacity(dead.len());
+  let mut ready: Vec<DagNodePtr> = dead.iter().rev().copied().filter(|node| pending[node] == 0).collect();
+  while let Some(node) = ready.pop() {
+    ordered.push(node);
+    for child in unsafe { deref_node(node) }.iter_children() {
+      if let Some(count) = pending.get_mut(child) {
+        *count -= 1;
+        if *count == 0 {
+          ready.push(*child);
+        }
+      }
+    }
+  }
+
+  if ordered.len() < dead.len() {
+    let on_cycle = dead.into_iter().filter(|node| pending[node] > 0);
+    ordered.extend(on_cycle);
+  }
+
+  ordered
+}
+
+/// Maps every node reachable from `roots` to a representative of the nodes structurally equal to it, the first such
+/// node finished in a post-order walk. See `NodeAllocator::set_hashcons_on_gc`.
+unsafe fn hash_cons_representatives(roots: &[DagNodePtr]) -> HashMap<DagNodePtr, DagNodePtr> {
+  type Key = (SymbolPtr, DagNodeKind, DagNodeFlags, Option<usize>, Vec<DagNodePtr>);
+
+  let mut representatives: HashMap<DagNodePtr, DagNodePtr> = HashMap::new();
+  let mut by_structure   : HashMap<Key, DagNodePtr>        = HashMap::new();
+  // Each entry is a node and whether its children have been pushed.
+  let mut stack: Vec<(DagNodePtr, bool)> = roots.iter()
+                                                .filter(|root| !root.is_null())
+                                                .map(|&root| (root, false))
+                                                .collect();
+
+  while let Some((node, expanded)) = stack.pop() {
+    if representatives.contains_key(&node) {
+      continue;
+    }
+    let node_ref = deref_node(node);
+    if !expanded {
+      stack.push((node, true));
+      stack.extend(node_ref.iter_children().filter(|child| !child.is_null()).map(|&child| (child, false)));
+      continue;
+    }
+
+    let children = node_ref.iter_children()
+                           .map(|child| representatives.get(child).copied().unwrap_or(*child))
+                           .collect();
+    let flags    = node_ref.flags & !(DagNodeFlag::Marked | DagNodeFlag::Copied);
+    let key      = (node_ref.symbol, node_ref.kind, flags, node_ref.user_word(), children);
+    let representative = *by_structure.entry(key).or_insert(node);
+    representatives.insert(node, representative);
+  }
+
+  representatives
 }
 
+/// Called before a collection starts. Returning `false` cancels the collection.
+pub type PreCollectHook = Box<dyn FnMut() -> bool + Send>;
+
+/// Called with the new arena count each time the allocator grows by an arena.
+pub type ArenaAllocatedHook = Box<dyn FnMut(u32) + Send>;
+
+/// Called with each dead `Data` node just before a collection destroys it.
+pub type FinalizeHook = Box<dyn FnMut(&DagNode) + Send>;
 
-pub(crate) struct NodeAllocator {
+/// Called during each collection's mark phase for the nodes a host holds outside the root list.
+pub type ExternalRootScan = Box<dyn Fn() -> Vec<DagNodePtr> + Send>;
+
+pub struct NodeAllocator {
   // General settings
   show_gc   : bool, // Do we report GC stats to user
-  early_quit: u64,  // Do we quit early for profiling purposes
+  collect_stats: bool, // Gather statistics nothing but the report and the caller need
+  gc_limit  : Option<u64>, // Collections stop once this many have run since the limit was set
+  gc_limit_count  : u64,  // Collections run since the limit was set
+  gc_limit_reached: bool,
+  min_arenas: u32,  // Collections grow the arena count to at least this
+  max_arenas: Option<u32>, // Collections never grow the arena count past this
+  on_pre_collect: Option<PreCollectHook>, // Can veto a collection
+  on_arena_allocated: Option<ArenaAllocatedHook>, // Observes heap growth
+  on_finalize   : Option<FinalizeHook>,   // Releases what dead `Data` nodes hold
+  external_roots: Option<ExternalRootScan>, // Roots held outside the root list
+  last_live_nodes: Vec<DagNodePtr>,       // Survivors of the most recent collection
+  last_gc_stats  : GcStats,
+  live_count_history: VecDeque<usize>, // Live node counts of the last `LIVE_COUNT_HISTORY` collections
+  last_forwarding: HashMap<DagNodePtr, DagNodePtr>, // Where the most recent collection moved each survivor
+  sweep_mode     : SweepMode,
+  destruction_order: DestructionOrder,
+  hashcons_on_gc : bool,            // Relocating collections merge structurally equal survivors
+  free_nodes     : Vec<DagNodePtr>, // Free nodes found by the last eager sweep, handed out in order
+  next_free      : usize,           // Index in `free_nodes` of the next node to hand out
+  lazily_swept_nodes: u64,          // Marks cleared by the lazy sweep, for instrumentation
+  swept_since_checkpoint: Option<Vec<DagNodePtr>>, // Survivors swept since the first checkpoint after a collection
+  #[cfg(test)]
+  sweep_scan_histogram: Vec<usize>, // Nodes found after skipping each number of survivors
 
   need_to_collect_garbage        : bool,
 
@@ -100,11 +773,39 @@ pub(crate) struct NodeAllocator {
 unsafe impl Send for NodeAllocator {}
 // unsafe impl Sync for Allocator {}
 
+impl Default for NodeAllocator {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 impl NodeAllocator {
   pub fn new() -> Self {
     NodeAllocator {
       show_gc    : true,
-      early_quit : 0,
+      collect_stats: true,
+      gc_limit   : None,
+      gc_limit_count  : 0,
+      gc_limit_reached: false,
+      min_arenas : 0,
+      max_arenas : None,
+      on_pre_collect: None,
+      on_arena_allocated: None,
+      on_finalize   : None,
+      external_roots: None,
+      last_live_nodes: Vec::new(),
+      last_gc_stats  : GcStats::default(),
+      live_count_history: VecDeque::with_capacity(LIVE_COUNT_HISTORY),
+      last_forwarding: HashMap::new(),
+      sweep_mode     : SweepMode::default(),
+      destruction_order: DestructionOrder::default(),
+      hashcons_on_gc : false,
+      free_nodes     : Vec::new(),
+      next_free      : 0,
+      lazily_swept_nodes: 0,
+      swept_since_checkpoint: None,
+      #[cfg(test)]
+      sweep_scan_histogram: Vec::new(),
       arena_count: 0,
 
       current_arena_past_active_arena: true,
@@ -125,10 +826,27 @@ impl NodeAllocator {
   /// but this isn't necessary.
   #[inline(always)]
   pub fn ok_to_collect_garbage(&mut self) {
+    self.collect_if_needed();
+  }
+
+  /// Collects garbage if this allocator or the storage allocator wants to, returning the statistics of the
+  /// collection, or `None` if no collection was wanted or the collection did not run.
+  pub fn collect_if_needed(&mut self) -> Option<GcStats> {
+    let stats = self.collect_if_wanted();
+    if stats.is_some() {
+      SYNC_GC_COUNT.fetch_add(1, Relaxed);
+    }
+    stats
+  }
+
+  /// `collect_if_needed` without counting the collection in `sync_gc_count`.
+  fn collect_if_wanted(&mut self) -> Option<GcStats> {
     if self.need_to_collect_garbage
         || acquire_storage_allocator().want_to_collect_garbage()
     {
-      unsafe{ self.collect_garbage(); }
+      unsafe{ self.collect_garbage_from(&[]) }.map(|_| self.last_gc_stats)
+    } else {
+      None
     }
   }
 
@@ -138,87 +856,633 @@ impl NodeAllocator {
     self.need_to_collect_garbage
   }
 
-  /// Allocates a new `DagNode`
-  pub fn allocate_dag_node(&mut self) -> *mut DagNode {
-    // ToDo: I think we can replace these pointers with indices into the current arena's data array.
-    //       Includes next_node, end_pointer, end_node.
-    let mut current_node = self.next_node;
-
-    unsafe{
-      loop {
-        if (current_node.is_null() && self.end_pointer.is_null()) || current_node == self.end_pointer {
-          // Arena is full. Allocate a new one.
-          current_node = self.slow_new_dag_node();
-          break;
-        }
-
-        { // Scope of `current_node_mut: &mut DagNode`
-          let current_node_mut = current_node.as_mut_unchecked();
-          if current_node_mut.simple_reuse() {
-            break;
-          }
-          if !current_node_mut.is_marked() {
-            // Not marked, but needs destruction because it's not simple reuse.
-            drop_in_place(current_node_mut);
-            break;
-          }
-          // current_node_mut.flags.remove(DagNodeFlag::Marked);
-          current_node_mut.flags = DagNodeFlags::default();
-        }
+  /// The number of arenas allocated so far.
+  #[inline(always)]
+  pub fn arena_count(&self) -> u32 {
+    self.arena_count
+  }
 
-        current_node = current_node.add(1);
-      }
+  /// The first node and node count of each arena, in allocation order.
+  pub fn arena_ranges(&self) -> Vec<(*const DagNode, usize)> {
+    let mut ranges = Vec::with_capacity(self.arena_count as usize);
+    let mut arena  = self.first_arena;
 
-      self.next_node = current_node.add(1);
-    } // end of unsafe block
+    while !arena.is_null() {
+      let arena_mut = unsafe { arena.as_mut_unchecked() };
+      ranges.push((arena_mut.first_node().cast_const(), ARENA_SIZE));
+      arena = arena_mut.next_arena;
+    }
 
-    increment_active_node_count();
-    current_node
+    ranges
   }
 
-
-  /// Allocates a new arena, adding it to the linked list of arenas, and
-  /// returns (a pointer to) the new arena.
-  unsafe fn allocate_new_arena(&mut self) -> *mut Arena {
-    #[cfg(feature = "gc_debug")]
-    {
-      eprintln!("allocate_new_arena()");
-      self.dump_memory_variables();
+  /// The index, in allocation order, of the arena the allocator is carving nodes from, and the offset of `next_node`
+  /// within it. Returns `(0, 0)` before the first arena is allocated.
+  pub fn cursor_position(&self) -> (usize, usize) {
+    if self.current_arena.is_null() {
+      return (0, 0);
     }
 
-    let arena = Arena::allocate_new_arena();
-    match self.last_arena.as_mut() {
-      None => {
-        // Allocating the first arena
-        self.first_arena = arena;
-      }
-      Some(last_arena) => {
-        last_arena.next_arena = arena;
-      }
+    let mut index = 0;
+    let mut arena = self.first_arena;
+    while arena != self.current_arena {
+      arena  = unsafe { arena.as_ref_unchecked() }.next_arena;
+      index += 1;
     }
+    let offset = unsafe { self.next_node.offset_from(self.current_arena.as_mut_unchecked().first_node()) };
 
-    self.last_arena = arena;
-    self.arena_count += 1;
+    (index, offset as usize)
+  }
 
-    arena
+  /// Caps the number of arenas that a collection allocates to reach its slop factor, which for small live sets
+  /// would otherwise be up to `SMALL_MODEL_SLOP` times the live nodes. Arenas already allocated are kept, and
+  /// running out of nodes between collections still allocates past the cap.
+  pub fn set_max_arenas(&mut self, max_arenas: Option<u32>) {
+    self.max_arenas = max_arenas;
   }
 
-  /// Allocate a new `DagNode` when the current arena is (almost) full.
-  unsafe fn slow_new_dag_node(&mut self) -> *mut DagNode {
-    #[cfg(feature = "gc_debug")]
-    {
-      eprintln!("slow_new_dag_node()");
-      self.dump_memory_variables();
-    }
+  /// Stops collecting garbage once this allocator has run `limit` more collections, for profiling a fixed number of
+  /// collections. Collections are counted from this call, not from the start of the program, so `Some(0)` stops
+  /// collection at once. Later requests to collect are ignored, and the heap grows instead. `None` removes the limit,
+  /// which also resumes collection if the limit was reached.
+  pub fn set_gc_limit(&mut self, limit: Option<u64>) {
+    self.gc_limit         = limit;
+    self.gc_limit_count   = 0;
+    self.gc_limit_reached = limit == Some(0);
+  }
+
+  /// Whether the limit set by `set_gc_limit` has been reached, so that no more collections run.
+  #[inline(always)]
+  pub fn gc_limit_reached(&self) -> bool {
+    self.gc_limit_reached
+  }
+
+  /// Sets the number of arenas a collection always grows to, regardless of the live set, to avoid thrashing on
+  /// small heaps. The cap set by `set_max_arenas` takes precedence.
+  pub fn set_min_arenas(&mut self, min_arenas: u32) {
+    self.min_arenas = min_arenas;
+  }
+
+  /// Allocates, in one batch, enough arenas for the allocator to hold at least `node_count` nodes in total, rather
+  /// than growing one arena at a time from `slow_new_dag_node`. Does nothing if there is already room.
+  pub fn reserve_nodes(&mut self, node_count: usize) {
+    let wanted_arena_count = node_count.div_ceil(ARENA_SIZE) as u32;
+    if self.arena_count >= wanted_arena_count {
+      return;
+    }
+
+    let current_was_last = !self.current_arena.is_null() && self.current_arena == self.last_arena;
+    unsafe {
+      while self.arena_count < wanted_arena_count {
+        self.allocate_new_arena();
+      }
+
+      if self.current_arena.is_null() {
+        // Nothing allocated yet, so start at the first of the new arenas.
+        self.current_arena = self.first_arena;
+        let current_arena  = self.current_arena.as_mut_unchecked();
+        self.next_node     = current_arena.first_node();
+        self.end_pointer   = match current_arena.next_arena.is_null() {
+          true  => current_arena.node_at(ARENA_SIZE - RESERVE_SIZE),
+          false => current_arena.node_at(ARENA_SIZE),
+        };
+      } else if current_was_last {
+        // Only the last arena keeps a reserve, and the current arena no longer is the last.
+        self.end_pointer = self.current_arena.as_mut_unchecked().node_at(ARENA_SIZE);
+      }
+    }
+  }
+
+  /// Sets how the arenas are swept from the next collection on. See `SweepMode`.
+  pub fn set_sweep_mode(&mut self, sweep_mode: SweepMode) {
+    self.sweep_mode = sweep_mode;
+  }
+
+  /// Sets whether collections print a report of the arenas.
+  pub fn set_show_gc(&mut self, show_gc: bool) {
+    self.show_gc = show_gc;
+  }
+
+  /// Sets whether collections gather the statistics that only callers read: `GcStats::churned_nodes`,
+  /// `GcStats::bucket_utilization`, `live_count_history`, and the survivors listed by `last_live_nodes`. With this and
+  /// `show_gc` both off, these are skipped and read as zero or empty, while everything the collector itself depends
+  /// on is still computed. Builds with `gc_debug`, whose checks walk the survivors, list them regardless.
+  pub fn set_collect_stats(&mut self, collect_stats: bool) {
+    self.collect_stats = collect_stats;
+  }
+
+  /// Whether the current collection gathers statistics. See `set_collect_stats`.
+  #[inline(always)]
+  fn wants_stats(&self) -> bool {
+    self.collect_stats || self.show_gc
+  }
+
+  /// Sets the order in which collections destroy dead nodes from the next collection on. See `DestructionOrder`.
+  pub fn set_destruction_order(&mut self, destruction_order: DestructionOrder) {
+    self.destruction_order = destruction_order;
+  }
+
+  /// Sets whether relocating collections hash-cons the survivors, so that structurally equal survivors become a
+  /// single node and the heap after the collection shares as much as it can. This costs a pass over the live nodes
+  /// and a hash table as large as them. Survivors are equal if they have the same symbol, kind, flags, and user word
+  /// and their children are equal; symbols are compared by identity, so they must be interned.
+  pub fn set_hashcons_on_gc(&mut self, hashcons_on_gc: bool) {
+    self.hashcons_on_gc = hashcons_on_gc;
+  }
+
+  /// The number of survivors whose marks the lazy sweep has cleared while allocating. Stays the same while
+  /// allocating after an eager sweep.
+  pub fn lazily_swept_nodes(&self) -> u64 {
+    self.lazily_swept_nodes
+  }
+
+  /// For each count of survivors, how many free nodes the lazy sweep found right after skipping that many, since
+  /// the histogram was last reset. A node taken from the eager sweep's list skipped none. This measures how the cost
+  /// of sweeping is spread over allocations. Test only.
+  #[cfg(test)]
+  pub(crate) fn sweep_scan_histogram(&self) -> &[usize] {
+    &self.sweep_scan_histogram
+  }
+
+  #[cfg(test)]
+  pub(crate) fn reset_sweep_scan_histogram(&mut self) {
+    self.sweep_scan_histogram.clear();
+  }
+
+  #[cfg(test)]
+  fn record_sweep_scan(&mut self, skipped: u64) {
+    let skipped = skipped as usize;
+    if self.sweep_scan_histogram.len() <= skipped {
+      self.sweep_scan_histogram.resize(skipped + 1, 0);
+    }
+    self.sweep_scan_histogram[skipped] += 1;
+  }
+
+  /// Installs a hook that runs at the start of every collection. If it returns `false`, the collection is skipped
+  /// and `need_to_collect_garbage` is left as it was. An allocator that wanted to collect still does, so every
+  /// later call to `ok_to_collect_garbage` runs the hook again until it allows the collection. This lets a caller
+  /// put off a pause until a critical section is over. The hook runs with the allocator locked, so it must not
+  /// allocate nodes, and it should be cheap, as it may run at every safe point while it vetoes.
+  pub fn set_on_pre_collect(&mut self, hook: Option<PreCollectHook>) {
+    self.on_pre_collect = hook;
+  }
+
+  /// Installs a hook that runs each time an arena is added for new nodes, with the arena count including it, so
+  /// that a host can watch the heap grow as it happens. The arenas a relocating collection copies into are reported
+  /// too, counted along with the arenas they replace, which are only freed at the end of the collection. The hook
+  /// runs with the allocator locked, so it must not allocate nodes.
+  pub fn set_on_arena_allocated(&mut self, hook: Option<ArenaAllocatedHook>) {
+    self.on_arena_allocated = hook;
+  }
+
+  /// Installs a hook that every collection calls for each dead `Data` node before destroying it, in the order set by
+  /// `set_destruction_order`, so that a host can release what the node stands for. The node's children are still
+  /// intact, but with `DestructionOrder::Scan` they may already have been finalized, and in a relocating collection a
+  /// child that survived has already been moved, so only its symbol and kind are left at its old address. Set it before
+  /// allocating, as nodes that died before it was installed may be finalized late. The hook runs with the allocator
+  /// locked, so it must not allocate nodes.
+  pub fn set_on_finalize(&mut self, hook: Option<FinalizeHook>) {
+    self.on_finalize = hook;
+  }
+
+  /// Installs a scan that every collection calls during its mark phase. The nodes it returns survive along with
+  /// everything reachable from them, as if they were registered roots, so that a host can keep nodes in its own data
+  /// structures without a `RootContainer` for each. A relocating collection moves them like any survivor, and the
+  /// host finds the copies with `last_gc_forwarding`. The scan runs with the allocator locked, so it must not
+  /// allocate nodes.
+  pub fn set_external_root_scan(&mut self, scan: Option<ExternalRootScan>) {
+    self.external_roots = scan;
+  }
+
+  /// The nodes the external root scan returns, if one is installed, without nulls.
+  fn scan_external_roots(&self) -> Vec<DagNodePtr> {
+    match &self.external_roots {
+      Some(scan) => scan().into_iter().filter(|root| !root.is_null()).collect(),
+      None       => Vec::new()
+    }
+  }
+
+  /// Allocates a new `DagNode`
+  pub fn allocate_dag_node(&mut self) -> *mut DagNode {
+    let node = self.next_free_node();
+    increment_active_node_count();
+    node
+  }
+
+  /// Allocates a new `DagNode` unless that needs an arena that would take the arenas and bucket storage together
+  /// past the storage allocator's `max_total_bytes`.
+  pub fn try_allocate_dag_node(&mut self) -> Result<*mut DagNode, String> {
+    let node = self.find_free_node(true);
+    if node.is_null() {
+      return Err(format!(
+        "allocating a node needs a new arena, which would exceed the ceiling of {} bytes",
+        acquire_storage_allocator().max_total_bytes().unwrap_or_default()
+      ));
+    }
+    increment_active_node_count();
+    Ok(node)
+  }
+
+  /// The bytes held by this allocator's arenas.
+  #[inline(always)]
+  pub fn arena_bytes(&self) -> usize {
+    self.arena_count as usize * size_of::<Arena>()
+  }
+
+  /// Whether one more arena keeps the arenas and the global bucket storage within the ceiling, if there is one.
+  fn new_arena_fits_ceiling(&self) -> bool {
+    let storage_allocator = acquire_storage_allocator();
+    match storage_allocator.max_total_bytes() {
+      None                  => true,
+      Some(max_total_bytes) => {
+        self.arena_bytes() + size_of::<Arena>() + storage_allocator.total_bytes_allocated() <= max_total_bytes
+      }
+    }
+  }
+
+  /// Finds the next free node, sweeping as it goes. The node is not counted as active. Nodes listed by an eager
+  /// sweep are handed out first.
+  #[inline(always)]
+  pub(crate) fn next_free_node(&mut self) -> *mut DagNode {
+    self.find_free_node(false)
+  }
+
+  /// Clears the mark of a survivor the lazy sweep passes over, noting it for `rollback` if a checkpoint has been
+  /// taken since the last collection. Only the mark belongs to the collector. Flags like those of a frozen node
+  /// must survive the sweep.
+  #[inline(always)]
+  fn sweep_survivor(&mut self, node: &mut DagNode) {
+    node.flags.remove(DagNodeFlag::Marked);
+    self.lazily_swept_nodes += 1;
+    if let Some(swept) = self.swept_since_checkpoint.as_mut() {
+      swept.push(node);
+    }
+  }
+
+  /// Finds the next free node as `next_free_node` does. If `within_ceiling` is set and only a new arena that does
+  /// not fit under the ceiling could provide a node, returns null instead.
+  fn find_free_node(&mut self, within_ceiling: bool) -> *mut DagNode {
+    if let Some(&node) = self.free_nodes.get(self.next_free) {
+      self.next_free += 1;
+      // The last `RESERVE_SIZE` free nodes are a reserve, as at the end of the last arena when sweeping lazily, so
+      // that a collection can run before the free nodes run out and a new arena is allocated.
+      if self.free_nodes.len() - self.next_free < RESERVE_SIZE {
+        self.need_to_collect_garbage = true;
+      }
+      #[cfg(test)]
+      self.record_sweep_scan(0);
+      return node;
+    }
+    #[cfg(test)]
+    let swept_before = self.lazily_swept_nodes;
+
+    // ToDo: I think we can replace these pointers with indices into the current arena's data array.
+    //       Includes next_node, end_pointer, end_node.
+    let mut current_node = self.next_node;
+
+    unsafe{
+      loop {
+        if (current_node.is_null() && self.end_pointer.is_null()) || current_node == self.end_pointer {
+          // Arena is full. Allocate a new one.
+          current_node = self.slow_new_dag_node(within_ceiling);
+          if current_node.is_null() {
+            return current_node;
+          }
+          break;
+        }
+
+        { // Scope of `current_node_mut: &mut DagNode`
+          let current_node_mut = deref_node_mut(current_node);
+          if current_node_mut.simple_reuse() {
+            break;
+          }
+          if !current_node_mut.is_marked() {
+            // Not marked, but needs destruction because it's not simple reuse.
+            drop_in_place(current_node_mut);
+            break;
+          }
+          self.sweep_survivor(current_node_mut);
+        }
+
+        current_node = offset_node(self.current_arena, current_node, 1);
+      }
+
+      self.next_node = offset_node(self.current_arena, current_node, 1);
+    } // end of unsafe block
+
+    #[cfg(test)]
+    self.record_sweep_scan(self.lazily_swept_nodes - swept_before);
+
+    current_node
+  }
+
+
+  /// The number of node slots between the allocation cursor and the reserve of the last arena, which is where
+  /// the allocator asks for a collection. Slots still holding live nodes are skipped by allocation, so this is an
+  /// upper bound on the number of nodes that can be allocated. Returns 0 once a collection has been requested.
+  pub fn nodes_until_gc(&self) -> usize {
+    if self.need_to_collect_garbage {
+      return 0;
+    }
+    if self.current_arena.is_null() {
+      // The first arena is allocated on demand.
+      return ARENA_SIZE - RESERVE_SIZE;
+    }
+
+    unsafe {
+      // The last `RESERVE_SIZE` free nodes of an eager sweep are a reserve.
+      let free_nodes = (self.free_nodes.len() - self.next_free).saturating_sub(RESERVE_SIZE);
+      let mut slots  = free_nodes + self.end_pointer.offset_from(self.next_node) as usize;
+      let mut arena = self.current_arena.as_ref_unchecked().next_arena;
+
+      while !arena.is_null() {
+        let arena_ref = arena.as_ref_unchecked();
+        // The last arena in the linked list is given a reserve.
+        slots += match arena_ref.next_arena.is_null() {
+          true  => ARENA_SIZE - RESERVE_SIZE,
+          false => ARENA_SIZE
+        };
+        arena = arena_ref.next_arena;
+      }
+
+      slots
+    }
+  }
+
+  /// The nodes that survived the most recent collection, in arena order. Survivors cannot be reused before the next
+  /// collection, so these pointers stay valid until then. Empty if the collection did not gather statistics. See
+  /// `set_collect_stats`.
+  pub fn last_live_nodes(&self) -> &[DagNodePtr] {
+    &self.last_live_nodes
+  }
+
+  /// Where the most recent collection moved `old`, if it was a relocating collection and `old` survived it. The
+  /// forwarding of an address that a new arena has reused is stale, so it is ignored.
+  pub fn last_gc_forwarding(&self, old: DagNodePtr) -> Option<DagNodePtr> {
+    let new = self.last_forwarding.get(&old).copied()?;
+    match self.contains_node(old) {
+      true  => None,
+      false => Some(new),
+    }
+  }
+
+  /// Statistics about the most recent collection.
+  pub fn last_gc_stats(&self) -> GcStats {
+    self.last_gc_stats
+  }
+
+  /// The number of nodes that survived each of the last 32 collections, oldest first. A count that keeps rising from
+  /// one collection to the next while the program is in a steady state suggests a leak, such as a root never dropped.
+  pub fn live_count_history(&self) -> Vec<usize> {
+    self.live_count_history.iter().copied().collect()
+  }
+
+  /// Records the nodes marked by the mark phase, if statistics are on or `gc_debug` needs them. Must be called before
+  /// the arenas are touched again.
+  unsafe fn record_live_nodes(&mut self) {
+    self.last_live_nodes.clear();
+    if !self.wants_stats() && !cfg!(feature = "gc_debug") {
+      return;
+    }
+    let mut arena = self.first_arena;
+
+    while !arena.is_null() {
+      let arena_mut = arena.as_mut_unchecked();
+
+      for offset in 0..ARENA_SIZE {
+        let node = arena_mut.node_at(offset);
+        if deref_node(node).is_marked() {
+          self.last_live_nodes.push(node);
+        }
+      }
+
+      arena = arena_mut.next_arena;
+    }
+  }
+
+  /// Walks the arenas and groups the nodes in use by kind. Every slot before the allocation cursor has been handed
+  /// out (or skipped as live) since the last collection, so all of them are reported except the slots that were
+  /// never initialized. Past the cursor, only the survivors of the last collection, which are still marked, are
+  /// reported. Nodes allocated since the last collection are reported whether or not they are still reachable.
+  pub fn live_nodes_by_kind(&self) -> HashMap<DagNodeKind, Vec<DagNodePtr>> {
+    let mut groups: HashMap<DagNodeKind, Vec<DagNodePtr>> = HashMap::new();
+    self.for_each_node_in_use(|node| {
+      groups.entry(unsafe { deref_node(node) }.kind).or_default().push(node);
+    });
+
+    groups
+  }
+
+  /// Calls `f` on each node in use, by the criteria described for `live_nodes_by_kind`, in arena order.
+  fn for_each_node_in_use(&self, mut f: impl FnMut(DagNodePtr)) {
+    let mut arena         = self.first_arena;
+    let mut before_cursor = true;
+
+    while !arena.is_null() {
+      let arena_mut = unsafe { arena.as_mut_unchecked() };
+
+      for offset in 0..ARENA_SIZE {
+        let node = arena_mut.node_at(offset);
+        if node == self.next_node {
+          before_cursor = false;
+        }
+
+        let node_ref = unsafe { deref_node(node) };
+        let in_use   = match before_cursor {
+          true  => !node_ref.is_default(),
+          false => node_ref.is_marked()
+        };
+        if in_use {
+          f(node);
+        }
+      }
+
+      arena = arena_mut.next_arena;
+    }
+  }
+
+  /// Deep copies every arena, together with the bucket storage of `storage_allocator`, into a new heap that is
+  /// independent of this one. See `HeapFork`.
+  pub(crate) fn fork(&self, storage_allocator: &StorageAllocator) -> HeapFork {
+    let (storage_fork, bucket_map) = storage_allocator.fork();
+
+    // Copy the arenas, keeping their order.
+    let mut arenas     : HashMap<*mut Arena, *mut Arena> = HashMap::new();
+    let mut arena_map  : BTreeMap<usize, usize>          = BTreeMap::new();
+    let mut first_arena: *mut Arena                      = null_mut();
+    let mut last_arena : *mut Arena                      = null_mut();
+    let mut arena = self.first_arena;
+    while !arena.is_null() {
+      let arena_mut = unsafe { arena.as_mut_unchecked() };
+      let copy      = arena_mut.allocate_copy();
+      arenas.insert(arena, copy);
+      arena_map.insert(arena_mut.first_node() as usize, unsafe { (*copy).first_node() } as usize);
+
+      match unsafe { last_arena.as_mut() } {
+        None       => first_arena = copy,
+        Some(last) => last.next_arena = copy,
+      }
+      last_arena = copy;
+      arena      = arena_mut.next_arena;
+    }
+
+    let arena_of    = |arena: *mut Arena| arenas.get(&arena).copied().unwrap_or(null_mut());
+    let node_map    = |node: DagNodePtr| translate_node(&arena_map, node);
+    let storage_map = |ptr: *const Void| translate_storage(&bucket_map, ptr);
+
+    // Point every copied node at the copied heap.
+    self.for_each_node_in_use(|node| unsafe {
+      deref_node_mut(node_map(node)).remap(&node_map, &storage_map);
+    });
+
+    let node_allocator = NodeAllocator {
+      show_gc        : self.show_gc,
+      collect_stats  : self.collect_stats,
+      gc_limit       : self.gc_limit,
+      gc_limit_count  : self.gc_limit_count,
+      gc_limit_reached: self.gc_limit_reached,
+      min_arenas     : self.min_arenas,
+      max_arenas     : self.max_arenas,
+      on_pre_collect : None,
+      on_arena_allocated: None,
+      on_finalize    : None,
+      external_roots : None,
+      last_live_nodes: self.last_live_nodes.iter().map(|&node| node_map(node)).collect(),
+      last_gc_stats  : self.last_gc_stats,
+      live_count_history: self.live_count_history.clone(),
+      last_forwarding: self.last_forwarding.iter().map(|(&old, &new)| (old, node_map(new))).collect(),
+      sweep_mode     : self.sweep_mode,
+      destruction_order: self.destruction_order,
+      hashcons_on_gc : self.hashcons_on_gc,
+      free_nodes     : self.free_nodes.iter().map(|&node| node_map(node)).collect(),
+      next_free      : self.next_free,
+      lazily_swept_nodes: self.lazily_swept_nodes,
+      swept_since_checkpoint: self.swept_since_checkpoint
+                                  .as_ref()
+                                  .map(|swept| swept.iter().map(|&node| node_map(node)).collect()),
+      #[cfg(test)]
+      sweep_scan_histogram: self.sweep_scan_histogram.clone(),
+      arena_count    : self.arena_count,
+
+      current_arena_past_active_arena: self.current_arena_past_active_arena,
+      need_to_collect_garbage        : self.need_to_collect_garbage,
+
+      first_arena,
+      last_arena,
+      current_arena    : arena_of(self.current_arena),
+      next_node        : node_map(self.next_node),
+      end_pointer      : node_map(self.end_pointer),
+      last_active_arena: arena_of(self.last_active_arena),
+      last_active_node : node_map(self.last_active_node),
+    };
+
+    HeapFork {
+      node_allocator,
+      storage_allocator: storage_fork,
+      arena_map,
+      bucket_map,
+    }
+  }
+
+  /// Captures the current allocation cursors so that allocations made after this point can be discarded with
+  /// `NodeAllocator::rollback`.
+  pub fn checkpoint(&mut self) -> Checkpoint {
+    let swept_survivors = self.swept_since_checkpoint.get_or_insert_with(Vec::new).len();
+    Checkpoint {
+      current_arena                  : self.current_arena,
+      next_node                      : self.next_node,
+      end_pointer                    : self.end_pointer,
+      current_arena_past_active_arena: self.current_arena_past_active_arena,
+      need_to_collect_garbage        : self.need_to_collect_garbage,
+      next_free                      : self.next_free,
+      active_node_count              : active_node_count(),
+      storage_in_use                 : acquire_storage_allocator().storage_in_use(),
+      swept_survivors,
+    }
+  }
+
+  /// Restores the allocation cursors captured by `checkpoint`, discarding every node allocated since. The
+  /// discarded nodes are handed out again by subsequent allocations. Bucket storage allocated since the
+  /// checkpoint is no longer counted as in use, but its memory is only reclaimed by the next garbage collection.
+  /// Arenas allocated since the checkpoint are kept. Survivors the lazy sweep passed over since the checkpoint are
+  /// marked again, as the restored cursor will pass over them once more.
+  ///
+  /// # Safety
+  ///
+  /// No garbage collection may have occurred between `checkpoint` and `rollback`. A collection resets the cursors
+  /// and relocates bucket storage, after which the checkpoint describes a heap that no longer exists and rolling
+  /// back to it corrupts the allocator. Furthermore, no node allocated since the checkpoint may be reachable from
+  /// a root or from a node allocated before the checkpoint, as those nodes will be reused.
+  pub unsafe fn rollback(&mut self, checkpoint: Checkpoint) {
+    self.current_arena                   = checkpoint.current_arena;
+    self.next_node                       = checkpoint.next_node;
+    self.end_pointer                     = checkpoint.end_pointer;
+    self.current_arena_past_active_arena = checkpoint.current_arena_past_active_arena;
+    self.need_to_collect_garbage         = checkpoint.need_to_collect_garbage;
+    self.next_free                       = checkpoint.next_free;
+
+    if let Some(swept) = self.swept_since_checkpoint.as_mut() {
+      for node in swept.drain(checkpoint.swept_survivors..) {
+        deref_node_mut(node).flags.insert(DagNodeFlag::Marked);
+        self.lazily_swept_nodes -= 1;
+      }
+    }
+
+    ACTIVE_NODE_COUNT.store(checkpoint.active_node_count, Relaxed);
+    acquire_storage_allocator().restore_storage_in_use(checkpoint.storage_in_use);
+    // Nodes cached by threads may lie past the restored cursor.
+    ALLOCATION_EPOCH.fetch_add(1, Release);
+  }
+
+  /// Allocates a new arena, adding it to the linked list of arenas, and
+  /// returns (a pointer to) the new arena.
+  unsafe fn allocate_new_arena(&mut self) -> *mut Arena {
+    #[cfg(feature = "gc_debug")]
+    {
+      eprintln!("allocate_new_arena()");
+      self.dump_memory_variables();
+    }
+
+    let arena = Arena::allocate_new_arena();
+    match self.last_arena.as_mut() {
+      None => {
+        // Allocating the first arena
+        self.first_arena = arena;
+      }
+      Some(last_arena) => {
+        last_arena.next_arena = arena;
+      }
+    }
+
+    self.last_arena = arena;
+    self.arena_count += 1;
+    if let Some(hook) = self.on_arena_allocated.as_mut() {
+      hook(self.arena_count);
+    }
+
+    arena
+  }
+
+  /// Allocate a new `DagNode` when the current arena is (almost) full. If `within_ceiling` is set, returns null
+  /// rather than add an arena that does not fit under the ceiling.
+  unsafe fn slow_new_dag_node(&mut self, within_ceiling: bool) -> *mut DagNode {
+    #[cfg(feature = "gc_debug")]
+    {
+      eprintln!("slow_new_dag_node()");
+      self.dump_memory_variables();
+    }
 
     loop {
       if self.current_arena.is_null() {
+        if within_ceiling && !self.new_arena_fits_ceiling() {
+          return null_mut();
+        }
         // Allocate the first arena
         self.current_arena = self.allocate_new_arena();
         let arena          = self.current_arena.as_mut_unchecked();
         let first_node     = arena.first_node();
         // The last arena in the linked list is given a reserve.
-        self.end_pointer   = first_node.add(ARENA_SIZE - RESERVE_SIZE);
+        self.end_pointer   = arena.node_at(ARENA_SIZE - RESERVE_SIZE);
 
         // These two members are initialized on first call to `NodeAllocator::sweep_arenas()`.
         // self.last_active_arena = arena;
@@ -233,13 +1497,17 @@ impl NodeAllocator {
 
       if arena.is_null() {
         self.need_to_collect_garbage = true;
-        let end_node = current_arena.first_node().add(ARENA_SIZE);
+        let end_node = current_arena.node_at(ARENA_SIZE);
 
         if self.end_pointer != end_node {
-          // Use up the reserve
+          // Use up the reserve. The sweep below passes over the reserve like any other slots, so a survivor there is
+          // unmarked and skipped and garbage is destroyed before its slot is handed out.
           self.next_node   = self.end_pointer; // Next node is invalid where we are called.
           self.end_pointer = end_node;
         } else {
+          if within_ceiling && !self.new_arena_fits_ceiling() {
+            return null_mut();
+          }
           // Allocate a new arena
           if self.current_arena == self.last_active_arena {
             self.current_arena_past_active_arena = true;
@@ -248,7 +1516,7 @@ impl NodeAllocator {
           self.current_arena = self.allocate_new_arena();
           let arena          = self.current_arena.as_mut_unchecked();
           let first_node     = arena.first_node();
-          self.end_pointer   = first_node.add(ARENA_SIZE); // ToDo: Why no reserve here?
+          self.end_pointer   = arena.node_at(ARENA_SIZE); // ToDo: Why no reserve here?
 
           return first_node;
         }
@@ -266,10 +1534,10 @@ impl NodeAllocator {
         match current_arena.next_arena.is_null() {
           true => {
             // The last arena in the linked list is given a reserve.
-            self.end_pointer = self.next_node.add(ARENA_SIZE - RESERVE_SIZE);
+            self.end_pointer = current_arena.node_at(ARENA_SIZE - RESERVE_SIZE);
           }
           false => {
-            self.end_pointer = self.next_node.add(ARENA_SIZE);
+            self.end_pointer = current_arena.node_at(ARENA_SIZE);
           }
         }
       }
@@ -284,7 +1552,7 @@ impl NodeAllocator {
       let mut cursor = self.next_node;
       // Loop over all nodes from self.next_node to self.end_pointer
       while cursor != end_node {
-        let cursor_mut = cursor.as_mut_unchecked();
+        let cursor_mut = deref_node_mut(cursor);
 
         if cursor_mut.simple_reuse(){
           return cursor;
@@ -294,26 +1562,55 @@ impl NodeAllocator {
           return cursor;
         }
 
-        cursor_mut.flags.remove(DagNodeFlag::Marked);
+        self.sweep_survivor(cursor_mut);
 
-        cursor = cursor.add(1);
+        cursor = offset_node(self.current_arena, cursor, 1);
       } // end loop over all nodes
     } // end outermost loop
   }
 
-  unsafe fn collect_garbage(&mut self) {
-    static mut GC_COUNT: u64 = 0;
+  /// Runs the copying collection of bucket storage without touching the arenas, for when bucket storage is under
+  /// pressure but the arenas are not. The argument vectors of nodes reachable from the roots are copied into fresh
+  /// buckets and the old buckets are freed for reuse. Nodes are not marked, so the arenas, the lazy sweep, and
+  /// `active_node_count` are unaffected.
+  pub(crate) unsafe fn collect_storage_only(&mut self) {
+    acquire_storage_allocator()._prepare_to_mark();
 
-    if self.first_arena.is_null() {
-      return;
+    let mut visited: HashSet<DagNodePtr> = HashSet::new();
+    let mut stack  : Vec<DagNodePtr>                       = Vec::new();
+    for_each_root(|root| stack.push(root));
+    stack.extend(self.scan_external_roots());
+
+    while let Some(node) = stack.pop() {
+      if !visited.insert(node) {
+        continue;
+      }
+      let node_mut = deref_node_mut(node);
+      node_mut.relocate_arguments();
+      stack.extend(node_mut.iter_children());
     }
 
-    GC_COUNT += 1;
-    let gc_count = GC_COUNT; // To silence shared_mut_ref warning
-    if self.show_gc {
-      // We moved this up here so that it appears before the bucket storage statistics.
-      println!("Collection: {}", gc_count);
+    acquire_storage_allocator()._sweep_garbage();
+  }
+
+  /// Frees every arena without destroying its nodes, leaving `first_arena` dangling for the caller to replace.
+  unsafe fn free_arenas(&mut self) {
+    let mut arena = self.first_arena;
+    while !arena.is_null() {
+      let next_arena = (*arena).next_arena;
+      Arena::free(arena);
+      arena = next_arena;
     }
+  }
+
+  pub(crate) unsafe fn collect_garbage(&mut self) {
+    self.collect_garbage_from(&[]);
+  }
+
+  /// Collects garbage, keeping the nodes reachable from `extra_roots` as well as those reachable from the registered
+  /// roots. Returns the number of nodes reachable from `extra_roots`, or `None` if no collection ran.
+  unsafe fn collect_garbage_from(&mut self, extra_roots: &[DagNodePtr]) -> Option<usize> {
+    let gc_count = self.start_collection()?;
 
     self.sweep_arenas();
     #[cfg(feature = "gc_debug")]
@@ -323,22 +1620,116 @@ impl NodeAllocator {
 
     let old_active_node_count = active_node_count();
     ACTIVE_NODE_COUNT.store(0, Relaxed); // to be updated during mark phase.
+    #[cfg(feature = "gc_debug")]
+    clear_mark_record(&self.arena_ranges());
+    #[cfg(feature = "gc_safe_mark")]
+    set_mark_arenas(&self.arena_ranges());
 
     acquire_storage_allocator()._prepare_to_mark();
 
+    // Survivors of the previous collection cannot have been reused since, so any other survivor is new.
+    #[cfg(feature = "gc_debug")]
+    let previous_survivors: HashSet<DagNodePtr> = match self.wants_stats() {
+      true  => self.last_live_nodes.iter().copied().collect(),
+      false => HashSet::new(),
+    };
+
+    // The extra roots are marked first so that the count of nodes reachable from them is not affected by the
+    // registered roots. Marking is idempotent, so nodes reachable from both are counted once in the total.
+    for &root in extra_roots {
+      if !root.is_null() {
+        deref_node_mut(root).mark();
+      }
+    }
+    let reachable_from_extra_roots = active_node_count();
     mark_roots();
+    for root in self.scan_external_roots() {
+      deref_node_mut(root).mark();
+    }
+    self.record_live_nodes();
+    if self.on_finalize.is_some() || self.destruction_order == DestructionOrder::ParentsFirst {
+      // Before the storage sweep, while the argument vectors of the dead nodes are still intact.
+      self.destroy_dead_nodes();
+    }
 
-    acquire_storage_allocator()._sweep_garbage();
+    self.last_gc_stats = GcStats {
+      collection   : gc_count,
+      nodes_before : old_active_node_count,
+      live_nodes   : active_node_count(),
+      churned_nodes: 0,
+      bucket_utilization: 0.0,
+    };
+    #[cfg(feature = "gc_debug")]
+    if self.wants_stats() {
+      let allocated_since_last_gc = old_active_node_count.saturating_sub(previous_survivors.len());
+      let new_survivors = self.last_live_nodes
+                              .iter()
+                              .filter(|node| !previous_survivors.contains(node))
+                              .count();
+      self.last_gc_stats.churned_nodes = allocated_since_last_gc.saturating_sub(new_survivors);
+    }
 
-    // Garbage Collection for Arenas
-    let active_node_count = active_node_count(); // updated during mark phase
+    {
+      let mut storage_allocator = acquire_storage_allocator();
+      storage_allocator._sweep_garbage();
+      if self.wants_stats() {
+        self.last_gc_stats.bucket_utilization = storage_allocator.bucket_utilization();
+      }
+    }
+    #[cfg(feature = "gc_debug")]
+    {
+      self.check_node_vectors();
+      self.reconcile_storage();
+    }
 
-    let node_capacity = (self.arena_count as usize) * ARENA_SIZE;
+    self.finish_collection(old_active_node_count);
+
+    Some(reachable_from_extra_roots)
+  }
+
+  /// Decides whether a collection may run and, if so, counts it and returns its number. A collection does not run
+  /// before the first arena exists, when the pre-collect hook vetoes it, or once the collection limit is reached.
+  fn start_collection(&mut self) -> Option<u64> {
+    if self.first_arena.is_null() {
+      return None;
+    }
 
+    if let Some(hook) = self.on_pre_collect.as_mut() {
+      if !hook() {
+        return None;
+      }
+    }
+
+    if self.gc_limit_reached {
+      return None;
+    }
+
+    self.last_forwarding.clear();
+    let gc_count = GC_COUNT.fetch_add(1, Relaxed) + 1;
+    // Cached nodes are unreachable, so they are about to be treated as free.
+    ALLOCATION_EPOCH.fetch_add(1, Release);
+    // The collection invalidates every checkpoint.
+    self.swept_since_checkpoint = None;
     if self.show_gc {
-      // println!(
-      //   "Arenas: {}\tNodes: {} ({:.2} MB)\tCollected: {} ({:.2}) MB\tNow: {} ({:.2} MB)",
-      //   self.arena_count,
+      // We moved this up here so that it appears before the bucket storage statistics.
+      println!("Collection: {}", gc_count);
+    }
+
+    Some(gc_count)
+  }
+
+  /// Ends a collection once the survivors are marked and bucket storage is swept: reports the collection, grows the
+  /// arenas according to the slop factor, and resets the allocation cursor to the first node of the first arena.
+  unsafe fn finish_collection(&mut self, old_active_node_count: usize) {
+    // Garbage Collection for Arenas
+    let active_node_count = active_node_count(); // updated during mark phase
+
+    let node_capacity = (self.arena_count as usize) * ARENA_SIZE;
+
+    if self.show_gc {
+      // println!(
+      //   "Arenas: {}\tNodes: {} ({:.2} MB)\tCollected: {} ({:.2}) MB\tNow: {} ({:.2} MB)",
+      //   self.arena_count,
       //   node_capacity,
       //   ((node_capacity * size_of::<DagNode>()) as f64) / (1024.0 * 1024.0),
       //   old_active_node_count - active_node_count,
@@ -372,25 +1763,43 @@ impl NodeAllocator {
       );
     }
 
-    if GC_COUNT == self.early_quit{
-      std::process::exit(0);
+    if let Some(limit) = self.gc_limit {
+      self.gc_limit_count  += 1;
+      self.gc_limit_reached = self.gc_limit_count >= limit;
     }
 
+    if self.wants_stats() {
+      if self.live_count_history.len() == LIVE_COUNT_HISTORY {
+        self.live_count_history.pop_front();
+      }
+      self.live_count_history.push_back(active_node_count);
+    }
+
+    self.reset_after_collection(active_node_count);
+  }
+
+  /// Grows the arenas according to the slop factor for `active_node_count` survivors and resets the allocation
+  /// cursor to the first node of the first arena.
+  unsafe fn reset_after_collection(&mut self, active_node_count: usize) {
     // Calculate if we should allocate more arenas to avoid an early gc.
     // Compute slop factor
     // Case: ACTIVE_NODE_COUNT >= UPPER_BOUND
     let mut slop_factor: f64 = BIG_MODEL_SLOP;
-    if ACTIVE_NODE_COUNT.load(Relaxed) < LOWER_BOUND {
+    if active_node_count < LOWER_BOUND {
       // Case: ACTIVE_NODE_COUNT < LOWER_BOUND
       slop_factor = SMALL_MODEL_SLOP;
-    } else if ACTIVE_NODE_COUNT.load(Relaxed) < UPPER_BOUND {
+    } else if active_node_count < UPPER_BOUND {
       // Case: LOWER_BOUND <= ACTIVE_NODE_COUNT < UPPER_BOUND
       // Linearly interpolate between the two models.
       slop_factor += ((UPPER_BOUND - active_node_count as usize) as f64 * (SMALL_MODEL_SLOP - BIG_MODEL_SLOP)) / (UPPER_BOUND - LOWER_BOUND) as f64;
     }
 
     // Allocate new arenas so that we have capacity for at least slop_factor times the actually used nodes.
-    let ideal_arena_count = (active_node_count as f64 * slop_factor / (ARENA_SIZE as f64)).ceil() as u32;
+    let mut ideal_arena_count = (active_node_count as f64 * slop_factor / (ARENA_SIZE as f64)).ceil() as u32;
+    ideal_arena_count = max(ideal_arena_count, self.min_arenas);
+    if let Some(max_arenas) = self.max_arenas {
+      ideal_arena_count = min(ideal_arena_count, max_arenas);
+    }
 
     #[cfg(feature = "gc_debug")]
     println!("ideal_arena_count: {}", ideal_arena_count);
@@ -407,15 +1816,21 @@ impl NodeAllocator {
       match current_arena.next_arena.is_null() {
         true => {
           // The last arena in the linked list is given a reserve.
-          self.end_pointer = self.next_node.add(ARENA_SIZE - RESERVE_SIZE);
+          self.end_pointer = current_arena.node_at(ARENA_SIZE - RESERVE_SIZE);
         },
         false => {
-          self.end_pointer = self.next_node.add(ARENA_SIZE);
+          self.end_pointer = current_arena.node_at(ARENA_SIZE);
         }
       }
     }
     self.need_to_collect_garbage = false;
 
+    self.free_nodes.clear();
+    self.next_free = 0;
+    if self.sweep_mode == SweepMode::Eager {
+      self.sweep_eagerly();
+    }
+
     #[cfg(feature = "gc_debug")]
     {
       eprintln!("end of GC");
@@ -423,6 +1838,262 @@ impl NodeAllocator {
     }
   }
 
+  /// Destroys every dead node right after marking, in the order set by `set_destruction_order`, calling the finalizer
+  /// on the dead `Data` nodes first. Each one is then reset, so the sweep that follows finds it free and destroys
+  /// nothing twice. A dead node is an unmarked node with a symbol.
+  unsafe fn destroy_dead_nodes(&mut self) {
+    let dead = self.nodes_where(|node| !node.is_marked() && !node.is_default());
+    self.destroy_nodes(dead);
+  }
+
+  /// The nodes of every arena for which `predicate` holds, in arena order.
+  unsafe fn nodes_where(&self, predicate: impl Fn(&DagNode) -> bool) -> Vec<DagNodePtr> {
+    let mut nodes: Vec<DagNodePtr> = Vec::new();
+    let mut arena = self.first_arena;
+    while !arena.is_null() {
+      let arena_mut = arena.as_mut_unchecked();
+      for offset in 0..ARENA_SIZE {
+        let node = arena_mut.node_at(offset);
+        if predicate(deref_node(node)) {
+          nodes.push(node);
+        }
+      }
+      arena = arena_mut.next_arena;
+    }
+    nodes
+  }
+
+  /// Destroys the nodes of `dead` as `destroy_dead_nodes` does, finalizing the `Data` nodes among them, then resets
+  /// every one.
+  unsafe fn destroy_nodes(&mut self, mut dead: Vec<DagNodePtr>) {
+    if self.destruction_order == DestructionOrder::ParentsFirst {
+      dead = parents_first(dead);
+    }
+
+    for &node in &dead {
+      let node_mut = deref_node_mut(node);
+      if node_mut.kind == DagNodeKind::Data {
+        if let Some(hook) = self.on_finalize.as_mut() {
+          hook(node_mut);
+        }
+      }
+      if node_mut.needs_destruction() {
+        drop_in_place(node);
+      }
+    }
+    // Only reset once every node is destroyed, as a node of a cycle may be destroyed after its children.
+    for &node in &dead {
+      node.write(DagNode::default());
+    }
+  }
+
+  /// Sweeps every arena at once right after marking: survivors are unmarked and every other node is destroyed,
+  /// reset, and listed in `free_nodes`. The last `RESERVE_SIZE` free nodes are held back as a reserve: handing out
+  /// the first of them asks for a collection. The cursor is moved to the end of the last arena, so that only if the
+  /// reserve runs out too does allocation continue in a new arena, and the next collection has nothing left to sweep.
+  unsafe fn sweep_eagerly(&mut self) {
+    let mut arena = self.first_arena;
+    while !arena.is_null() {
+      let arena_mut = arena.as_mut_unchecked();
+
+      for offset in 0..ARENA_SIZE {
+        let node     = arena_mut.node_at(offset);
+        let node_mut = deref_node_mut(node);
+        if node_mut.is_marked() {
+          node_mut.flags.remove(DagNodeFlag::Marked);
+          continue;
+        }
+        if node_mut.needs_destruction() {
+          drop_in_place(node);
+        }
+        node.write(DagNode::default());
+        self.free_nodes.push(node);
+      }
+
+      arena = arena_mut.next_arena;
+    }
+
+    self.current_arena                   = self.last_arena;
+    self.next_node                       = self.last_arena.as_mut_unchecked().node_at(ARENA_SIZE);
+    self.end_pointer                     = self.next_node;
+    self.current_arena_past_active_arena = true;
+  }
+
+  /// Collects garbage in an allocator other than the global one, keeping the nodes of this allocator reachable from
+  /// `roots`, together with `storage_allocator`, which holds their argument vectors, as `DagNode::new_in` arranges.
+  /// The registered roots, which belong to the global heap, are ignored, as are nodes of other heaps, and neither
+  /// global allocator nor the global active node count is touched. Returns the number of survivors.
+  ///
+  /// # Safety
+  ///
+  /// `storage_allocator` must be the allocator that holds the argument vectors of this allocator's nodes, and no
+  /// pointer to a node not reachable from `roots` may be used afterward.
+  pub unsafe fn collect_isolated(&mut self, storage_allocator: &mut StorageAllocator, roots: &[DagNodePtr]) -> usize {
+    if self.first_arena.is_null() {
+      return 0;
+    }
+    self.sweep_arenas();
+    storage_allocator._prepare_to_mark();
+
+    let mut live_node_count = 0;
+    let mut stack           = roots.to_vec();
+    while let Some(node) = stack.pop() {
+      if node.is_null() || !self.contains_node(node) {
+        continue;
+      }
+      let node_mut = deref_node_mut(node);
+      if node_mut.is_marked() {
+        continue;
+      }
+      node_mut.flags.insert(DagNodeFlag::Marked);
+      node_mut.relocate_arguments_in(storage_allocator);
+      live_node_count += 1;
+      stack.extend(node_mut.iter_children());
+    }
+    storage_allocator._sweep_garbage();
+
+    self.reset_after_collection(live_node_count);
+    live_node_count
+  }
+
+  /// Collects garbage like `collect_garbage`, except that the survivors are copied into fresh arenas in depth first
+  /// order from the roots, parents before their children and children from left to right, so that the nodes of a
+  /// term are close together in the order a traversal visits them. The old arenas are freed. Registered and
+  /// permanent roots and the children of survivors are updated to the copies; every other pointer to a node,
+  /// including checkpoints and thread caches, is invalidated. Returns `None` if no collection ran.
+  pub(crate) unsafe fn collect_garbage_relocating(&mut self) -> Option<GcStats> {
+    let gc_count = self.start_collection()?;
+
+    let old_active_node_count = active_node_count();
+    acquire_storage_allocator()._prepare_to_mark();
+
+    // Copy phase. Each copied node is flagged `Copied` and its arguments, which the copy now holds, are replaced by
+    // a forwarding pointer to the copy.
+    let mut originals: Vec<DagNodePtr> = Vec::new();
+    let mut arenas   : Vec<*mut Arena> = Vec::new();
+    let mut stack    : Vec<DagNodePtr> = Vec::new();
+    for_each_root(|root| stack.push(root));
+    stack.extend(self.scan_external_roots());
+    stack.reverse();
+    // Only representatives are copied. Every other survivor is forwarded to its representative's copy afterward.
+    let representatives = match self.hashcons_on_gc {
+      true  => hash_cons_representatives(&stack),
+      false => HashMap::new()
+    };
+
+    while let Some(node) = stack.pop() {
+      let node = representatives.get(&node).copied().unwrap_or(node);
+      if let Some(copy) = deref_node(node).forwarding() {
+        // `Copied` only lasts for one pass, so a node flagged before the pass began would forward into freed memory.
+        debug_assert!(
+          arenas.iter().any(|&arena| (*arena).contains(copy)),
+          "node entered the copy pass already Copied"
+        );
+        continue;
+      }
+      if originals.len().is_multiple_of(ARENA_SIZE) {
+        let arena = Arena::allocate_new_arena();
+        if let Some(&last_arena) = arenas.last() {
+          (*last_arena).next_arena = arena;
+        }
+        arenas.push(arena);
+        if let Some(hook) = self.on_arena_allocated.as_mut() {
+          hook(self.arena_count + arenas.len() as u32);
+        }
+      }
+
+      let arena: *mut Arena = arenas[arenas.len() - 1];
+      let copy              = (*arena).node_at(originals.len() % ARENA_SIZE);
+      std::ptr::copy_nonoverlapping(node, copy, 1);
+      // Survivors stay marked until the lazy sweep passes them. `Copied` belongs to the original, which is about to be
+      // freed, and must not outlive the pass on the copy.
+      deref_node_mut(copy).flags.insert(DagNodeFlag::Marked);
+      deref_node_mut(copy).flags.remove(DagNodeFlag::Copied);
+      deref_node_mut(node).set_forwarding(copy);
+      originals.push(node);
+
+      stack.extend(deref_node(copy).iter_children().rev().filter(|child| !child.is_null()));
+    }
+
+    let mut merged: Vec<(DagNodePtr, DagNodePtr)> = Vec::new();
+    for (&node, &representative) in representatives.iter().filter(|(node, representative)| node != representative) {
+      let copy = deref_node(representative).forwarding().unwrap();
+      deref_node_mut(node).set_forwarding(copy);
+      merged.push((node, copy));
+    }
+
+    // Fix-up phase, in address order so that the argument vectors are laid out in the same order as the nodes.
+    let live_node_count = originals.len();
+    let copy_at         = |index: usize| {
+      let arena: *mut Arena = arenas[index / ARENA_SIZE];
+      (*arena).node_at(index % ARENA_SIZE)
+    };
+    let forward         = |node: DagNodePtr| match node.is_null() {
+      true  => node,
+      false => deref_node(node).forwarding().unwrap_or(node)
+    };
+    for index in 0..live_node_count {
+      deref_node_mut(copy_at(index)).relocate(forward);
+    }
+    forward_roots(forward);
+    // The originals keep `Copied`, as their arenas are freed below.
+    for (index, &original) in originals.iter().enumerate() {
+      self.last_forwarding.insert(original, copy_at(index));
+    }
+    for (node, copy) in merged {
+      self.last_forwarding.insert(node, copy);
+    }
+    ACTIVE_NODE_COUNT.store(live_node_count, Relaxed);
+    let last_copy = match live_node_count {
+      0 => null_mut(),
+      _ => copy_at(live_node_count - 1)
+    };
+
+    // The originals that were not copied are dead, and are destroyed and finalized like the dead of any collection.
+    // Marks left by the previous collection say nothing about this one, so only `Copied` sets the survivors apart.
+    let dead = self.nodes_where(|node| !node.is_copied() && !node.is_default());
+    self.destroy_nodes(dead);
+
+    // Only the copies are marked, so the sweep at the start of the next collection can stop at the last of them.
+    self.free_arenas();
+    if arenas.is_empty() {
+      arenas.push(Arena::allocate_new_arena());
+      if let Some(hook) = self.on_arena_allocated.as_mut() {
+        hook(1);
+      }
+    }
+    self.first_arena       = arenas[0];
+    self.last_arena        = arenas[arenas.len() - 1];
+    self.arena_count       = arenas.len() as u32;
+    self.last_active_arena = self.last_arena;
+    self.last_active_node  = last_copy;
+    self.record_live_nodes();
+
+    self.last_gc_stats = GcStats {
+      collection   : gc_count,
+      nodes_before : old_active_node_count,
+      live_nodes   : live_node_count,
+      churned_nodes: 0,
+      bucket_utilization: 0.0,
+    };
+    {
+      let mut storage_allocator = acquire_storage_allocator();
+      storage_allocator._sweep_garbage();
+      if self.wants_stats() {
+        self.last_gc_stats.bucket_utilization = storage_allocator.bucket_utilization();
+      }
+    }
+    #[cfg(feature = "gc_debug")]
+    {
+      self.check_node_vectors();
+      self.reconcile_storage();
+    }
+
+    self.finish_collection(old_active_node_count);
+
+    Some(self.last_gc_stats)
+  }
+
   /// Tidy up lazy sweep phase - clear marked flags and call dtors where necessary.
   unsafe fn sweep_arenas(&mut self) {
     #[cfg(feature = "gc_debug")]
@@ -432,8 +2103,7 @@ impl NodeAllocator {
     }
 
     let mut new_last_active_arena = self.current_arena;
-    // self.next_node never points to first node, so subtract 1.
-    let mut new_last_active_node  = self.next_node.sub(1);
+    let mut new_last_active_node  = self.last_allocated_node();
 
     // `NodeAllocator::current_arena_past_active_arena` is initialized to `true`, so this whole method
     // effectively just initializes `last_active_arena` and `last_active_node`.
@@ -443,10 +2113,10 @@ impl NodeAllocator {
       let mut arena_cursor: *mut Arena = self.current_arena;
 
       while arena_cursor != self.last_active_arena {
-        let end_node_ptr = arena_cursor.as_mut_unchecked().first_node().add(ARENA_SIZE);
+        let end_node_ptr = arena_cursor.as_mut_unchecked().node_at(ARENA_SIZE);
 
         while node_cursor_ptr != end_node_ptr {
-          let node_cursor_mut = node_cursor_ptr.as_mut_unchecked();
+          let node_cursor_mut = deref_node_mut(node_cursor_ptr);
 
           if node_cursor_mut.is_marked() {
             new_last_active_arena = arena_cursor;
@@ -460,7 +2130,7 @@ impl NodeAllocator {
             node_cursor_mut.flags = DagNodeFlags::empty();
           }
 
-          node_cursor_ptr = node_cursor_ptr.add(1);
+          node_cursor_ptr = offset_node(arena_cursor, node_cursor_ptr, 1);
         } // end loop over nodes
 
         arena_cursor    = arena_cursor.as_mut_unchecked().next_arena;
@@ -472,7 +2142,7 @@ impl NodeAllocator {
       let end_node_ptr = self.last_active_node;
 
       while node_cursor_ptr <= end_node_ptr {
-        let d_mut = node_cursor_ptr.as_mut_unchecked();
+        let d_mut = deref_node_mut(node_cursor_ptr);
 
         if d_mut.is_marked() {
           new_last_active_arena = arena_cursor;
@@ -486,7 +2156,7 @@ impl NodeAllocator {
           d_mut.flags = DagNodeFlags::empty();
         }
 
-        node_cursor_ptr = node_cursor_ptr.add(1);
+        node_cursor_ptr = offset_node(arena_cursor, node_cursor_ptr, 1);
       } // end loop overactive nodes
     }
 
@@ -494,6 +2164,27 @@ impl NodeAllocator {
     self.last_active_node  = new_last_active_node;
   }
 
+  /// The node just before `self.next_node`, that is, the last node handed out from the current arena. Between
+  /// allocations `next_node` never points to the first node of its arena, except right after `collect_garbage`
+  /// resets the cursor (or a rollback to such a state), in which case nothing has been handed out from the current
+  /// arena and null is returned. Null compares below every node, so it bounds an empty range in `sweep_arenas`.
+  unsafe fn last_allocated_node(&mut self) -> *mut DagNode {
+    let first_node = self.current_arena.as_mut_unchecked().first_node();
+    if self.next_node == first_node {
+      // Only a reset, or a rollback to one, leaves the cursor at an arena's first node, and both are in the first
+      // arena. Anywhere else, stepping back from `next_node` is expected to be safe.
+      debug_assert_eq!(
+        self.current_arena,
+        self.first_arena,
+        "next_node is the first node of an arena other than the first"
+      );
+      std::ptr::null_mut()
+    } else {
+      debug_assert!(self.current_arena.as_ref_unchecked().in_bounds(self.next_node));
+      self.next_node.sub(1)
+    }
+  }
+
   /// Verify that no `DagNode` objects within the arenas managed by the allocator are in a “marked” state.
   #[cfg(feature = "gc_debug")]
   unsafe fn check_invariant(&self) {
@@ -516,10 +2207,10 @@ impl NodeAllocator {
           };
 
       for node_idx in 0..bound {
-        if d.as_ref_unchecked().is_marked() {
+        if deref_node(d).is_marked() {
           eprintln!("check_invariant() : MARKED DagNode! arena = {} node = {}", arena_idx, node_idx);
         }
-        d = d.add(1);
+        d = offset_node(arena, d, 1);
       } // end loop over nodes
 
       if arena == self.current_arena { break; }
@@ -529,6 +2220,107 @@ impl NodeAllocator {
     } // end loop over arenas
   }
 
+  /// Removes `Marked` from every node before the allocation cursor, the nodes that `check_invariant` requires to be
+  /// unmarked, and returns how many were marked. This undoes a speculative mark by a test or by an external marker
+  /// that does not run a collection. Despite the name, not every arena is cleared: the nodes ahead of the cursor keep
+  /// their marks, as they are how the lazy sweep knows the survivors of the last collection, so a speculative mark of
+  /// a node ahead of the cursor lasts until the sweep reaches it.
+  pub fn clear_all_marks(&mut self) -> usize {
+    let mut cleared = 0;
+    let mut arena   = self.first_arena;
+
+    while !arena.is_null() {
+      let arena_mut = unsafe { arena.as_mut_unchecked() };
+      let bound     = match arena == self.current_arena {
+        true  => (self.next_node as usize - arena_mut.first_node() as usize) / size_of::<DagNode>(),
+        false => ARENA_SIZE
+      };
+
+      for offset in 0..bound {
+        let node_mut = unsafe { deref_node_mut(arena_mut.node_at(offset)) };
+        if node_mut.is_marked() {
+          node_mut.flags.remove(DagNodeFlag::Marked);
+          cleared += 1;
+        }
+      }
+
+      if arena == self.current_arena { break; }
+      arena = arena_mut.next_arena;
+    }
+
+    cleared
+  }
+
+  /// Whether `node` points to a node slot of one of this allocator's arenas.
+  pub fn contains_node(&self, node: *const DagNode) -> bool {
+    let mut arena = self.first_arena;
+
+    while !arena.is_null() {
+      let arena_ref = unsafe { arena.as_ref_unchecked() };
+      if arena_ref.contains(node) {
+        return true;
+      }
+      arena = arena_ref.next_arena;
+    }
+
+    false
+  }
+
+  /// Validates the argument vector of every survivor of the last collection: its length is at most its capacity,
+  /// the vector and its elements live in in use bucket storage, and every child is an arena node. Reports each
+  /// offending node and returns how many there were.
+  #[cfg(feature = "gc_debug")]
+  unsafe fn check_node_vectors(&self) -> usize {
+    let storage_allocator = acquire_storage_allocator();
+    let mut bad_nodes     = 0;
+
+    for &node in self.last_live_nodes.iter() {
+      let Some(node_vector) = deref_node(node).node_vector() else { continue; };
+
+      let problem = if node_vector.len() > node_vector.capacity() {
+        Some(format!("length {} exceeds capacity {}", node_vector.len(), node_vector.capacity()))
+      } else if !storage_allocator.contains(std::ptr::from_ref(node_vector).cast(), size_of_val(node_vector)) {
+        Some("NodeVector is not in bucket storage".to_string())
+      } else if node_vector.capacity() > 0
+          && !storage_allocator.contains(node_vector.data_ptr().cast(), node_vector.capacity_bytes())
+      {
+        Some("NodeVector data is not in bucket storage".to_string())
+      } else {
+        node_vector.as_slice()
+                   .iter()
+                   .position(|&child| !self.contains_node(child))
+                   .map(|index| format!("child {} is not an arena node", index))
+      };
+
+      if let Some(problem) = problem {
+        eprintln!("check_node_vectors() : DagNode {:p} ({}): {}", node, deref_node(node), problem);
+        bad_nodes += 1;
+      }
+    }
+
+    bad_nodes
+  }
+
+  /// Checks, with a debug assertion, that the bucket storage in use after a collection is exactly the storage of the
+  /// survivors' argument vectors, each a `NodeVector` and its data, since marking copies each live vector once and
+  /// nothing else. Returns the number of bytes.
+  #[cfg(feature = "gc_debug")]
+  unsafe fn reconcile_storage(&self) -> usize {
+    let live_vector_bytes = self.last_live_nodes
+                                .iter()
+                                .filter_map(|&node| deref_node(node).node_vector())
+                                .map(|node_vector| size_of_val(node_vector) + node_vector.capacity_bytes())
+                                .sum();
+    let storage_in_use    = acquire_storage_allocator().storage_in_use();
+    debug_assert_eq!(
+      storage_in_use,
+      live_vector_bytes,
+      "bucket storage in use does not match the argument vectors of the live nodes"
+    );
+
+    live_vector_bytes
+  }
+
   #[cfg(feature = "gc_debug")]
   unsafe fn check_arenas(&self) {
     let mut arena     = self.first_arena;
@@ -539,10 +2331,10 @@ impl NodeAllocator {
       let mut d     = arena_mut.first_node();
 
       for node_idx in 0..ARENA_SIZE {
-        if d.as_ref_unchecked().is_marked() {
+        if deref_node(d).is_marked() {
           eprintln!("check_arenas() : MARKED DagNode! arena = {} node = {}", arena_idx, node_idx);
         }
-        d = d.add(1);
+        d = offset_node(arena, d, 1);
       } // end loop over nodes
 
       if arena == self.current_arena { break; }
@@ -555,66 +2347,113 @@ impl NodeAllocator {
   /// Prints the state of the allocator.
   #[cfg(feature = "gc_debug")]
   pub fn dump_memory_variables(&self) {
+    let mut out = String::new();
+    self.write_memory_variables(&mut out);
+    eprint!("{}", out);
+  }
+
+  /// Renders the table printed by `dump_memory_variables` into `out`. With `gc_debug`, the table ends with the number
+  /// of free and in use slots of each arena. Free slots are those past the allocation cursor that are not marked,
+  /// which are the only slots that can be handed out before the next collection.
+  pub fn write_memory_variables(&self, out: &mut String) {
     let bucket_needs_collection = acquire_storage_allocator().want_to_collect_garbage();
 
     //────────
-    eprintln!("╭─────────────────────────────────────────────╮");
-    eprintln!("│{:<32} {:>12}│", "Variable", "Value");
-    eprintln!("├─────────────────────────────────────────────┤");
-    eprintln!("│{:<32} {:>12}│", "arena_count", self.arena_count);
-    eprintln!("│{:<32} {:>12}│", "active_node_count", ACTIVE_NODE_COUNT.load(Relaxed));
-    eprintln!("│{:<32} {:>12}│", "need_to_collect_garbage", self.need_to_collect_garbage);
-    eprintln!(
+    _ = writeln!(out, "╭─────────────────────────────────────────────╮");
+    _ = writeln!(out, "│{:<32} {:>12}│", "Variable", "Value");
+    _ = writeln!(out, "├─────────────────────────────────────────────┤");
+    _ = writeln!(out, "│{:<32} {:>12}│", "arena_count", self.arena_count);
+    _ = writeln!(out, "│{:<32} {:>12}│", "active_node_count", ACTIVE_NODE_COUNT.load(Relaxed));
+    _ = writeln!(out, "│{:<32} {:>12}│", "need_to_collect_garbage", self.need_to_collect_garbage);
+    _ = writeln!(
+      out,
       "│{:<32} {:>12}│",
       "need_to_collect_storage",
       bucket_needs_collection
     );
-    eprintln!(
+    _ = writeln!(
+      out,
       "│{:<32} {:>12}│",
       "current_arena_past_active_arena",
       self.current_arena_past_active_arena
     );
-    eprintln!(
+    _ = writeln!(
+      out,
       "│{:<32} {:>12}│",
       "need_to_collect_garbage",
       self.need_to_collect_garbage
     );
-    eprintln!(
+    _ = writeln!(
+      out,
       "│{:<32} {:>12p}│",
       "first_arena",
       self.first_arena
     );
-    eprintln!(
+    _ = writeln!(
+      out,
       "│{:<32} {:>12p}│",
       "last_arena",
       self.last_arena
     );
-    eprintln!(
+    _ = writeln!(
+      out,
       "│{:<32} {:>12p}│",
       "current_arena",
       self.current_arena
     );
-    eprintln!(
+    _ = writeln!(
+      out,
       "│{:<32} {:>12p}│",
       "next_node",
       self.next_node
     );
-    eprintln!(
+    _ = writeln!(
+      out,
       "│{:<32} {:>12p}│",
       "end_pointer",
       self.end_pointer
     );
-    eprintln!(
+    _ = writeln!(
+      out,
       "│{:<32} {:>12p}│",
       "last_active_arena",
       self.last_active_arena
     );
-    eprintln!(
+    _ = writeln!(
+      out,
       "│{:<32} {:>12p}│",
       "last_active_node",
       self.last_active_node
     );
-    eprintln!("╰─────────────────────────────────────────────╯");
+    #[cfg(feature = "gc_debug")]
+    {
+      _ = writeln!(out, "├─────────────────────────────────────────────┤");
+      let mut arena         = self.first_arena;
+      let mut arena_index   = 0;
+      let mut before_cursor = !self.next_node.is_null();
+      while !arena.is_null() {
+        let arena_mut = unsafe { arena.as_mut_unchecked() };
+        let mut free  = 0;
+        for offset in 0..ARENA_SIZE {
+          let node = arena_mut.node_at(offset);
+          if node == self.next_node {
+            before_cursor = false;
+          }
+          if !before_cursor && !unsafe { deref_node(node) }.is_marked() {
+            free += 1;
+          }
+        }
+        _ = writeln!(
+          out,
+          "│{:<32} {:>12}│",
+          format!("arena {} free/in use", arena_index),
+          format!("{}/{}", free, ARENA_SIZE - free)
+        );
+        arena = arena_mut.next_arena;
+        arena_index += 1;
+      }
+    }
+    _ = writeln!(out, "╰─────────────────────────────────────────────╯");
   }
 /*  pub fn dump_memory_variables(&self) {
     let bucket_needs_collection = acquire_storage_allocator().want_to_collect_garbage();
@@ -654,6 +2493,125 @@ impl NodeAllocator {
 
 
 
+impl Drop for NodeAllocator {
+  /// Frees the arenas of a fork's allocator, or of the global one when a test resets the heap.
+  fn drop(&mut self) {
+    unsafe { self.free_arenas(); }
+  }
+}
+
+#[cfg(feature = "gc_debug")]
+thread_local! {
+  /// For each arena of the allocator collecting on this thread, ordered by address: the address of its first node,
+  /// its node count, and a bitmap with a bit per node set once the current mark phase has counted that node. Only the
+  /// collecting thread marks, so it owns the bitmaps and `record_mark` takes no lock.
+  static MARKED_THIS_CYCLE: RefCell<Vec<(usize, usize, Vec<u64>)>> = const { RefCell::new(Vec::new()) };
+}
+/// The number of times a node was counted more than once by a mark phase.
+#[cfg(feature = "gc_debug")]
+static DOUBLE_MARK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Forgets which nodes the previous mark phase counted, and sizes the bitmaps for the arenas of `arena_ranges`.
+#[cfg(feature = "gc_debug")]
+fn clear_mark_record(arena_ranges: &[(*const DagNode, usize)]) {
+  MARKED_THIS_CYCLE.with_borrow_mut(|arenas| {
+    arenas.clear();
+    arenas.extend(
+      arena_ranges.iter().map(|&(first, count)| (first as usize, count, vec![0u64; count.div_ceil(u64::BITS as usize)]))
+    );
+    arenas.sort_unstable_by_key(|&(start, ..)| start);
+  });
+}
+
+/// Records that the mark phase counted `node` as active, reporting the node if it was already counted this cycle.
+/// Each node should transition from unmarked to marked exactly once per collection, so a repeat means
+/// `ACTIVE_NODE_COUNT` is being double counted. Nodes outside the arenas given to `clear_mark_record` are not tracked.
+#[cfg(feature = "gc_debug")]
+pub(crate) fn record_mark(node: &DagNode) {
+  let address = node as *const DagNode as usize;
+  let repeated = MARKED_THIS_CYCLE.with_borrow_mut(|arenas| {
+    let arena_index = arenas.partition_point(|&(start, ..)| start <= address);
+    if arena_index == 0 {
+      return false;
+    }
+    let (start, count, bitmap) = &mut arenas[arena_index - 1];
+    let index = (address - *start) / size_of::<DagNode>();
+    if index >= *count {
+      return false;
+    }
+    let (word, bit) = (index / u64::BITS as usize, 1u64 << (index % u64::BITS as usize));
+    let repeated = bitmap[word] & bit != 0;
+    bitmap[word] |= bit;
+    repeated
+  });
+
+  if repeated {
+    DOUBLE_MARK_COUNT.fetch_add(1, Relaxed);
+    eprintln!("record_mark() : DagNode {:p} ({}) marked twice in one collection", node, node);
+  }
+}
+
+/// The number of double marks detected since the program started.
+#[cfg(feature = "gc_debug")]
+pub fn double_mark_count() -> usize {
+  DOUBLE_MARK_COUNT.load(Relaxed)
+}
+
+/// The first address and length in bytes of each arena of the allocator collecting, against which the mark phase
+/// checks child pointers before following them.
+#[cfg(feature = "gc_safe_mark")]
+static MARK_ARENAS: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+/// The number of child pointers the mark phase refused to follow.
+#[cfg(feature = "gc_safe_mark")]
+static BAD_CHILD_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the arenas that `is_markable` accepts nodes from to those of `arena_ranges`.
+#[cfg(feature = "gc_safe_mark")]
+fn set_mark_arenas(arena_ranges: &[(*const DagNode, usize)]) {
+  *MARK_ARENAS.lock().unwrap() = arena_ranges.iter()
+                                             .map(|&(first, count)| (first as usize, count * size_of::<DagNode>()))
+                                             .collect();
+}
+
+/// Whether the mark phase may follow the non-null child pointer `node`, that is, whether it points to the start of a
+/// node in one of the collecting allocator's arenas. A pointer that does not is reported and counted.
+#[cfg(feature = "gc_safe_mark")]
+pub(crate) fn is_markable(node: DagNodePtr) -> bool {
+  let address  = node as usize;
+  let markable = address.is_multiple_of(align_of::<DagNode>())
+      && MARK_ARENAS.lock().unwrap().iter().any(|&(start, bytes)| {
+        address >= start && address - start < bytes && (address - start).is_multiple_of(size_of::<DagNode>())
+      });
+
+  if !markable {
+    BAD_CHILD_COUNT.fetch_add(1, Relaxed);
+    eprintln!("Bad node found: {:p} is not a node of any arena, skipping it.", node);
+  }
+  markable
+}
+
+/// The number of child pointers the mark phase has refused to follow since the program started.
+#[cfg(feature = "gc_safe_mark")]
+pub fn bad_child_count() -> usize {
+  BAD_CHILD_COUNT.load(Relaxed)
+}
+
+/// Offsets `node`, which lies in `arena`, by `count` nodes. With the `gc_bounds_check` feature, asserts that the
+/// result still lies within `arena` (or one past its last node).
+#[inline(always)]
+#[cfg_attr(not(feature = "gc_bounds_check"), allow(unused_variables))]
+unsafe fn offset_node(arena: *mut Arena, node: *mut DagNode, count: usize) -> *mut DagNode {
+  let result = node.add(count);
+  #[cfg(feature = "gc_bounds_check")]
+  assert!(
+    (*arena).in_bounds(result),
+    "node pointer {:p} outside of arena {:p}",
+    result,
+    arena
+  );
+  result
+}
+
 #[inline(always)]
 pub(crate) fn increment_active_node_count() {
   ACTIVE_NODE_COUNT.fetch_add(1, Relaxed);
@@ -664,17 +2622,55 @@ pub fn active_node_count() -> usize {
   ACTIVE_NODE_COUNT.load(Relaxed)
 }
 
+/// Serializes tests that use the global heap.
+#[cfg(test)]
+static GLOBAL_HEAP_TEST_LOCK: Mutex<()> = Mutex::new(());
+
+/// Reinitializes the global node and storage allocators and the active node count, giving the caller a fresh heap.
+/// The old allocators are dropped with the heap they hold, so nothing may keep a pointer into it.
+///
+/// Panics if any root is still registered, as it would refer to the old heap. Test only.
+#[cfg(test)]
+pub fn reset_global_allocator() {
+  assert_eq!(crate::dag_node::root_count(), 0, "cannot reset the allocator while roots are registered");
+  // Permanent roots point into the heap being thrown away.
+  crate::dag_node::root_container::clear_permanent_roots();
+
+  let mut allocator = acquire_node_allocator("reset_global_allocator");
+  *allocator = NodeAllocator::new();
+  crate::dag_node::allocator::storage_allocator::reset_global_storage_allocator();
+  ACTIVE_NODE_COUNT.store(0, Relaxed);
+  GC_COUNT.store(0, Relaxed);
+  SYNC_GC_COUNT.store(0, Relaxed);
+  #[cfg(feature = "gc_timing")]
+  MAX_ALLOC_STALL_NANOS.store(0, Relaxed);
+  // Nodes cached by threads belong to the old heap.
+  ALLOCATION_EPOCH.fetch_add(1, Release);
+}
+
+/// Gives a test exclusive use of a freshly reset global heap for as long as the returned guard is held. Every test
+/// that allocates nodes, registers roots, or collects garbage must hold it, since tests run concurrently. Test only.
+#[cfg(test)]
+pub(crate) fn lock_global_heap() -> MutexGuard<'static, ()> {
+  // A failed test poisons the lock, which must not fail every later test.
+  let guard = GLOBAL_HEAP_TEST_LOCK.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+  reset_global_allocator();
+  guard
+}
+
 
 #[cfg(test)]
 mod tests {
   use crate::abstractions::IString;
-  use crate::dag_node::{DagNode, DagNodeKind, DagNodePtr, RootContainer};
+  use crate::dag_node::{register_roots, DagNode, DagNodeKind, DagNodePtr, RootContainer};
   use crate::dag_node::allocator::*;
+  use crate::dag_node::allocator::node_allocator::{active_node_count, lock_global_heap, reset_global_allocator};
   use crate::symbol::Symbol;
-  use crate::util::{build_random_tree, print_tree};
+  use crate::util::{build_random_tree, make_arity_symbols, print_tree, TreeStyle};
 
   #[test]
   fn test_allocate_dag_node() {
+    let _heap = lock_global_heap();
     let node_ptr = allocate_dag_node();
     let node_mut = match unsafe { node_ptr.as_mut() } {
       None => {
@@ -689,12 +2685,8 @@ mod tests {
 
   #[test]
   fn test_dag_creation() {
-    let symbols = (0..=10)
-        .map(|x| {
-          let name = IString::from(format!("sym({})", x).as_str());
-          Symbol::new(name, x)
-        })
-        .collect::<Vec<_>>();
+    let _heap = lock_global_heap();
+    let symbols = make_arity_symbols(10, |arity| format!("sym({})", arity));
 
     let root = DagNode::new(&symbols[3]);
     let _root_container = RootContainer::new(root);
@@ -704,8 +2696,8 @@ mod tests {
     let max_width : usize = 3;
 
     // Recursively build the random tree
-    build_random_tree(&symbols, root, max_height, max_width, 0);
-    print_tree(root, String::new(), false);
+    build_random_tree(&symbols, root, max_height, max_width, 0).unwrap();
+    print_tree(root, String::new(), false, &TreeStyle::default());
     // println!("Symbols: {:?}", symbols);
     acquire_node_allocator("dump_memory_variables").dump_memory_variables()
   }
@@ -713,12 +2705,8 @@ mod tests {
 
   #[test]
   fn test_garbage_collection() {
-    let symbols = (0..=10)
-        .map(|x| {
-          let name = IString::from(format!("sym({})", x).as_str());
-          Symbol::new(name, x)
-        })
-        .collect::<Vec<_>>();
+    let _heap = lock_global_heap();
+    let symbols = make_arity_symbols(10, |arity| format!("sym({})", arity));
 
     for _ in 0..100 {
       let mut root_vec = Vec::with_capacity(10);
@@ -733,7 +2721,7 @@ mod tests {
         let max_width : usize = 4; // base
 
         // Recursively build the random tree
-        build_random_tree(&symbols, root, max_height, max_width, 0);
+        build_random_tree(&symbols, root, max_height, max_width, 0).unwrap();
       }
       { acquire_node_allocator("ok_to_collect_garbage").ok_to_collect_garbage(); }
 
@@ -743,8 +2731,1478 @@ mod tests {
   }
 
 
+  #[test]
+  fn test_reset_global_allocator() {
+    use crate::dag_node::allocator::arena::FREED_ARENAS;
+    let _heap = lock_global_heap();
+
+    for _ in 0..100 {
+      allocate_dag_node();
+    }
+    let arena_count = acquire_node_allocator("test_reset_global_allocator").arena_count as usize;
+    assert!(arena_count > 0);
+    assert!(active_node_count() > 0);
+
+    // The old heap's arenas are freed, not leaked.
+    let freed = FREED_ARENAS.with(|freed| freed.get());
+    reset_global_allocator();
+    assert_eq!(FREED_ARENAS.with(|freed| freed.get()), freed + arena_count);
+
+    let allocator = acquire_node_allocator("test_reset_global_allocator");
+    assert_eq!(allocator.arena_count, 0);
+    assert!(allocator.first_arena.is_null());
+    assert!(allocator.next_node.is_null());
+    assert_eq!(active_node_count(), 0);
+  }
+
+  #[test]
+  fn test_drop_frees_arenas() {
+    use crate::dag_node::allocator::arena::FREED_ARENAS;
+    use super::ARENA_SIZE;
+    let mut allocator = NodeAllocator::new();
+    for _ in 0..2 * ARENA_SIZE + 1 {
+      allocator.next_free_node();
+    }
+    assert_eq!(allocator.arena_count(), 3);
+
+    let freed = FREED_ARENAS.with(|freed| freed.get());
+    drop(allocator);
+    assert_eq!(FREED_ARENAS.with(|freed| freed.get()), freed + 3);
+  }
+
+  #[test]
+  fn test_checkpoint_rollback() {
+    let _heap = lock_global_heap();
+    let mut allocator = acquire_node_allocator("test_checkpoint_rollback");
+
+    for _ in 0..10 {
+      allocator.allocate_dag_node();
+    }
+
+    let checkpoint = allocator.checkpoint();
+    let first_discarded = allocator.allocate_dag_node();
+    for _ in 0..99 {
+      allocator.allocate_dag_node();
+    }
+    assert_ne!(allocator.checkpoint(), checkpoint);
+
+    unsafe { allocator.rollback(checkpoint); }
+    assert_eq!(allocator.checkpoint(), checkpoint);
+    assert_eq!(allocator.next_node, checkpoint.next_node);
+    assert_eq!(allocator.current_arena, checkpoint.current_arena);
+
+    // The first node allocated after the checkpoint is handed out again.
+    assert_eq!(allocator.allocate_dag_node(), first_discarded);
+  }
+
+  #[test]
+  fn test_rollback_keeps_swept_survivors() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // Survivors interleaved with garbage, so that allocating after the collection sweeps past some of them.
+    let mut roots = Vec::new();
+    for _ in 0..50 {
+      DagNode::new(&a);
+      roots.push(RootContainer::new(DagNode::with_args(&f, &[DagNode::new(&a)], DagNodeKind::default())));
+    }
+    mark_from(&[]);
+
+    let mut allocator = acquire_node_allocator("test_rollback_keeps_swept_survivors");
+    let checkpoint    = allocator.checkpoint();
+    let swept         = allocator.lazily_swept_nodes();
+    for _ in 0..40 {
+      allocator.allocate_dag_node();
+    }
+    assert!(allocator.lazily_swept_nodes() > swept);
+    unsafe { allocator.rollback(checkpoint); }
+    assert_eq!(allocator.lazily_swept_nodes(), swept);
+
+    // Allocating again must skip the survivors rather than hand them out.
+    let mut live = std::collections::HashSet::new();
+    for root in &roots {
+      live.insert(root.node());
+      live.extend(unsafe { &*root.node() }.iter_children().copied());
+    }
+    for _ in 0..200 {
+      assert!(!live.contains(&allocator.allocate_dag_node()));
+    }
+  }
+
+  #[test]
+  fn test_thread_cache_avoids_lock() {
+    let _heap = lock_global_heap();
+    use super::{LOCK_ACQUISITIONS, THREAD_CACHE_SIZE};
+    // Few enough to stay out of the reserve, where the cache takes nodes one at a time, even in the smaller arenas
+    // of `node_align_64`.
+    const ALLOCATIONS: usize = 3_000;
+
+    let locks_before = LOCK_ACQUISITIONS.with(|count| count.get());
+    for _ in 0..ALLOCATIONS {
+      assert!(!allocate_dag_node().is_null());
+    }
+    let locks = LOCK_ACQUISITIONS.with(|count| count.get()) - locks_before;
+
+    assert_eq!(locks, ALLOCATIONS.div_ceil(THREAD_CACHE_SIZE));
+  }
+
+  #[test]
+  fn test_thread_cache_leaves_reserve() {
+    use super::THREAD_NODE_CACHE;
+    let _heap = lock_global_heap();
+    let cached = || THREAD_NODE_CACHE.with(|cache| cache.borrow().len);
+    while !want_to_collect_garbage() || cached() > 0 {
+      allocate_dag_node();
+    }
+
+    // In the reserve, every allocation takes a single node from the allocator, so the cache is left empty.
+    let (arena, offset) = cursor_position();
+    for _ in 0..10 {
+      allocate_dag_node();
+      assert_eq!(cached(), 0);
+    }
+    assert_eq!(cursor_position(), (arena, offset + 10));
+  }
+
+  #[cfg(feature = "gc_bounds_check")]
+  #[test]
+  fn test_bounds_checked_allocation_spans_arenas() {
+    use super::{ARENA_SIZE, RESERVE_SIZE};
+    let _heap = lock_global_heap();
+
+    let mut allocator = acquire_node_allocator("test_bounds_checked_allocation_spans_arenas");
+    // Runs through the first arena, its reserve, and well into a second arena.
+    for _ in 0..(2 * ARENA_SIZE - RESERVE_SIZE) {
+      allocator.allocate_dag_node();
+    }
+    assert!(allocator.arena_count >= 2);
+  }
+
+  #[test]
+  fn test_reserve_is_swept() {
+    use super::{ARENA_SIZE, RESERVE_SIZE};
+
+    let _heap = lock_global_heap();
+    let a = Symbol::new(IString::from("a"), 0);
+    let f = Symbol::new(IString::from("f"), 3);
+    let mut allocator = NodeAllocator::new();
+    let mut storage   = StorageAllocator::new();
+
+    // Fill the first arena, reserve included, alternating leaves and nodes with argument vectors to destroy.
+    let leaf  = DagNode::new_in(&mut allocator, &mut storage, &a);
+    let nodes = (1..ARENA_SIZE).map(|i| match i % 2 {
+      0 => DagNode::new_in(&mut allocator, &mut storage, &a),
+      _ => DagNode::with_args_in(&mut allocator, &mut storage, &f, &[leaf, leaf, leaf], DagNodeKind::default()),
+    }).collect::<Vec<_>>();
+    assert!(allocator.want_to_collect_garbage());
+    let first_node = allocator.arena_ranges()[0].0;
+    assert_eq!(allocator.arena_count(), 1);
+    assert_eq!(nodes.last().copied(), Some(unsafe { first_node.add(ARENA_SIZE - 1) }.cast_mut()));
+
+    // Some leaves in the reserve survive; the rest of the reserve is garbage.
+    let reserve   = &nodes[ARENA_SIZE - RESERVE_SIZE - 1..];
+    let survivors = reserve.iter().copied().filter(|&node| unsafe { &*node }.is_leaf()).step_by(4).collect::<Vec<_>>();
+    assert!(!survivors.is_empty());
+    assert_eq!(unsafe { allocator.collect_isolated(&mut storage, &survivors) }, survivors.len());
+    assert_eq!(allocator.arena_count(), 1);
+
+    // Allocating every free slot reaches into the reserve without handing out a survivor or a marked node.
+    let allocated = (0..ARENA_SIZE - survivors.len()).map(|_| allocator.next_free_node()).collect::<Vec<_>>();
+    let handed_out_in_reserve = allocated.iter().filter(|node| reserve.contains(node)).count();
+    assert_eq!(handed_out_in_reserve, RESERVE_SIZE - survivors.len());
+    for &node in &allocated {
+      assert!(!survivors.contains(&node));
+      assert!(!unsafe { &*node }.is_marked());
+    }
+    assert!(survivors.iter().all(|&survivor| !unsafe { &*survivor }.is_marked()));
+    assert_eq!(allocator.arena_count(), 1);
+  }
+
+  #[test]
+  fn test_sweep_scan_histogram() {
+    let _heap = lock_global_heap();
+    let a = Symbol::new(IString::from("a"), 0);
+    let mut allocator = NodeAllocator::new();
+    let mut storage   = StorageAllocator::new();
+
+    // Survivors at slots 1, 2, and 5 of the first arena.
+    let nodes = (0..12).map(|_| DagNode::new_in(&mut allocator, &mut storage, &a)).collect::<Vec<_>>();
+    assert_eq!(allocator.sweep_scan_histogram(), [12]);
+    assert_eq!(unsafe { allocator.collect_isolated(&mut storage, &[nodes[1], nodes[2], nodes[5]]) }, 3);
+    allocator.reset_sweep_scan_histogram();
+
+    // Slot 0 is found at once, slot 3 after skipping two survivors, slot 4 at once, and slot 6 after skipping one.
+    let allocated = (0..6).map(|_| allocator.next_free_node()).collect::<Vec<_>>();
+    assert_eq!(allocated, [nodes[0], nodes[3], nodes[4], nodes[6], nodes[7], nodes[8]]);
+    assert_eq!(allocator.sweep_scan_histogram(), [4, 1, 1]);
+  }
+
+  #[test]
+  fn test_sweep_right_after_collection() {
+    let _heap = lock_global_heap();
+    let symbol = Symbol::new(IString::from("leaf"), 0);
+
+    let mut allocator = acquire_node_allocator("test_sweep_right_after_collection");
+    for _ in 0..10 {
+      allocator.allocate_dag_node();
+    }
+    unsafe { allocator.collect_garbage(); }
+
+    // The collection reset the cursor to the first node.
+    let first_node = unsafe { allocator.first_arena.as_mut_unchecked().first_node() };
+    assert_eq!(allocator.next_node, first_node);
+
+    // Collecting again sweeps from the reset cursor. Nothing was marked, so nothing is active.
+    unsafe { allocator.collect_garbage(); }
+    assert_eq!(allocator.next_node, first_node);
+    assert!(allocator.last_active_node.is_null());
+    assert_eq!(allocator.last_active_arena, allocator.first_arena);
+
+    // A rooted node survives back to back collections.
+    drop(allocator);
+    let root            = DagNode::new(&symbol);
+    let _root_container = RootContainer::new(root);
+    let mut allocator   = acquire_node_allocator("test_sweep_right_after_collection");
+    unsafe {
+      allocator.collect_garbage();
+      allocator.collect_garbage();
+    }
+    assert!(unsafe { &*root }.is_marked());
+    assert_eq!(allocator.last_active_node, root);
+  }
+
+  #[test]
+  fn test_remaining_capacity() {
+    use crate::dag_node::allocator::node_vector::NodeVector;
+    let _heap = lock_global_heap();
+
+    {
+      let mut allocator = acquire_node_allocator("test_remaining_capacity");
+      allocator.allocate_dag_node();
+      let before = allocator.nodes_until_gc();
+      for _ in 0..100 {
+        allocator.allocate_dag_node();
+      }
+      assert_eq!(before - allocator.nodes_until_gc(), 100);
+    }
+
+    let before = bytes_until_gc();
+    NodeVector::with_capacity(4);
+    assert_eq!(before - bytes_until_gc(), size_of::<NodeVector>() + 4 * size_of::<DagNodePtr>());
+  }
+
+  #[test]
+  fn test_live_nodes_by_kind() {
+    let _heap = lock_global_heap();
+    let variable = Symbol::new(IString::from("x"), 0);
+    let constant = Symbol::new(IString::from("c"), 0);
+
+    for _ in 0..3 {
+      DagNode::with_kind(&constant, DagNodeKind::ACU);
+    }
+    for _ in 0..2 {
+      DagNode::with_kind(&variable, DagNodeKind::Variable);
+    }
+    for _ in 0..4 {
+      DagNode::new(&constant);
+    }
+
+    let groups = live_nodes_by_kind();
+    assert_eq!(groups.len(), 3);
+    assert_eq!(groups[&DagNodeKind::ACU].len(), 3);
+    assert_eq!(groups[&DagNodeKind::Variable].len(), 2);
+    assert_eq!(groups[&DagNodeKind::Free].len(), 4);
+  }
+
+  #[cfg(feature = "gc_debug")]
+  #[test]
+  fn test_no_double_marks() {
+    use super::{clear_mark_record, double_mark_count, record_mark};
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // f(g(a), g(a)) with g(a) shared
+    let g_node = DagNode::with_args(&g, &[DagNode::new(&a)], DagNodeKind::default());
+    let f_node = DagNode::with_args(&f, &[g_node, g_node], DagNodeKind::default());
+    let _root  = RootContainer::new(f_node);
+
+    let double_marks = double_mark_count();
+    unsafe { acquire_node_allocator("test_no_double_marks").collect_garbage(); }
+    assert_eq!(double_mark_count(), double_marks);
+    assert_eq!(active_node_count(), 3);
+
+    // Counting the same node twice is reported.
+    clear_mark_record(&arena_ranges());
+    let node = unsafe { &*f_node };
+    record_mark(node);
+    record_mark(node);
+    assert_eq!(double_mark_count(), double_marks + 1);
+  }
+
+  #[test]
+  fn test_max_arenas() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // Enough live nodes that the slop factor alone would ask for five arenas.
+    let mut chain: DagNodePtr = DagNode::new(&a);
+    for _ in 0..3000 {
+      chain = DagNode::with_args(&f, &[chain], DagNodeKind::default());
+    }
+    let _root = RootContainer::new(chain);
+
+    set_max_arenas(Some(2));
+    for _ in 0..20 {
+      for _ in 0..2000 {
+        DagNode::new(&a);
+      }
+      unsafe { acquire_node_allocator("test_max_arenas").collect_garbage(); }
+      assert!(arena_count() <= 2);
+    }
+    assert_eq!(active_node_count(), 3001);
+
+    set_min_arenas(8);
+    set_max_arenas(None);
+    unsafe { acquire_node_allocator("test_max_arenas").collect_garbage(); }
+    assert_eq!(arena_count(), 8);
+  }
+
+  #[test]
+  fn test_external_roots_survive() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // Held only by the host, as an address, since node pointers are not `Send`.
+    let leaf = DagNode::new(&a);
+    let term = DagNode::with_args(&f, &[leaf, leaf], DagNodeKind::default());
+    let held = term as usize;
+    mark_external(move || vec![held as DagNodePtr]);
+    for _ in 0..100 {
+      DagNode::new(&a);
+    }
+
+    assert_eq!(mark_from(&[]), Some(0));
+    assert_eq!(active_node_count(), 2);
+    let fresh = (0..1000).map(|_| DagNode::new(&a)).collect::<Vec<_>>();
+    assert!(!fresh.contains(&term) && !fresh.contains(&leaf));
+    assert_eq!(unsafe { &*term }.iter_children().copied().collect::<Vec<_>>(), [leaf, leaf]);
+
+    // A relocating collection moves the external roots like any survivor.
+    collect_garbage_relocating().unwrap();
+    let moved = last_gc_forwarding(term).unwrap();
+    assert_eq!(unsafe { &*moved }.symbol().name, f.name);
+
+    clear_mark_external();
+    assert_eq!(mark_from(&[]), Some(0));
+    assert_eq!(active_node_count(), 0);
+  }
+
+  #[cfg(feature = "gc_timing")]
+  #[test]
+  fn test_max_alloc_stall() {
+    let _heap = lock_global_heap();
+    let a = Symbol::new(IString::from("a"), 0);
+    assert_eq!(max_alloc_stall(), std::time::Duration::ZERO);
+
+    // Churn until a call to `ok_to_collect_garbage` collects.
+    while gc_cycle_count() == 0 {
+      for _ in 0..1000 {
+        DagNode::new(&a);
+      }
+      ok_to_collect_garbage();
+    }
+    assert!(max_alloc_stall() > std::time::Duration::ZERO);
+  }
+
+  #[test]
+  fn test_collect_if_needed() {
+    let _heap = lock_global_heap();
+    let a = Symbol::new(IString::from("a"), 0);
+    DagNode::new(&a);
+    assert_eq!(collect_if_needed(), None);
+    assert_eq!(gc_cycle_count(), 0);
+
+    while !want_to_collect_garbage() {
+      DagNode::new(&a);
+    }
+    let stats = collect_if_needed().unwrap();
+    assert_eq!(stats.collection, 1);
+    assert_eq!(stats.live_nodes, 0);
+    assert!(!want_to_collect_garbage());
+    assert_eq!(collect_if_needed(), None);
+  }
+
+  #[test]
+  fn test_collection_without_stats() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 3);
+    let a = Symbol::new(IString::from("a"), 0);
+    set_show_gc(false);
+    set_collect_stats(false);
+
+    // A root over a shared leaf, and garbage with argument vectors of its own.
+    let leaf = DagNode::new(&a);
+    let root = RootContainer::new(DagNode::with_args(&f, &[leaf, leaf, leaf], DagNodeKind::default()));
+    let mut in_use = Vec::new();
+    for _ in 0..3 {
+      for _ in 0..1000 {
+        DagNode::with_args(&f, &[leaf, leaf, leaf], DagNodeKind::default());
+      }
+      assert_eq!(mark_from(&[]), Some(0));
+      assert_eq!(active_node_count(), 2);
+      assert_eq!(last_gc_stats().live_nodes, 2);
+      assert_eq!(last_gc_stats().bucket_utilization, 0.0);
+      in_use.push(in_use_bucket_bytes());
+    }
+
+    // The garbage and its vectors were reclaimed each time, and the survivors are intact.
+    assert!(in_use.iter().all(|&bytes| bytes == in_use[0]));
+    assert_eq!(arena_count(), 1);
+    assert!(live_count_history().is_empty());
+    let mut live_nodes = 0;
+    for_each_live_node(|_| live_nodes += 1);
+    assert_eq!(live_nodes, if cfg!(feature = "gc_debug") { 2 } else { 0 });
+    assert_eq!(unsafe { &*root.node() }.iter_children().copied().collect::<Vec<_>>(), [leaf, leaf, leaf]);
+
+    // Turning statistics back on gathers them again.
+    set_collect_stats(true);
+    mark_from(&[]);
+    assert_eq!(live_count_history(), [2]);
+    assert!(last_gc_stats().bucket_utilization > 0.0);
+  }
+
+  #[test]
+  fn test_live_count_history() {
+    let _heap = lock_global_heap();
+    let a = Symbol::new(IString::from("a"), 0);
+    assert!(live_count_history().is_empty());
+
+    // A steady live set among the garbage.
+    let steady = (0..100).map(|_| DagNode::new(&a)).collect::<Vec<_>>();
+    let _steady_roots = register_roots(&steady);
+    for _ in 0..5 {
+      for _ in 0..1000 {
+        DagNode::new(&a);
+      }
+      mark_from(&[]);
+    }
+    assert_eq!(live_count_history(), vec![100; 5]);
+
+    // A live set that grows with every collection, as a leak would.
+    let mut leaked = Vec::new();
+    for _ in 0..5 {
+      let nodes = (0..100).map(|_| DagNode::new(&a)).collect::<Vec<_>>();
+      leaked.extend(register_roots(&nodes));
+      mark_from(&[]);
+    }
+    let history = live_count_history();
+    assert_eq!(history.len(), 10);
+    assert!(history[5..].windows(2).all(|pair| pair[1] > pair[0]));
+    assert_eq!(history.last().copied(), Some(600));
+
+    for _ in 0..2 * super::LIVE_COUNT_HISTORY {
+      mark_from(&[]);
+    }
+    assert_eq!(live_count_history(), vec![600; super::LIVE_COUNT_HISTORY]);
+  }
+
+  #[test]
+  fn test_sync_gc_count() {
+    let _heap = lock_global_heap();
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // Collections a caller runs itself are not counted.
+    DagNode::new(&a);
+    assert_eq!(mark_from(&[]), Some(0));
+    assert_eq!(sync_gc_count(), 0);
+
+    // Churn until an allocation makes `ok_to_collect_garbage` collect.
+    while gc_cycle_count() < 2 {
+      DagNode::new(&a);
+      ok_to_collect_garbage();
+    }
+    assert_eq!(sync_gc_count(), 1);
+    ok_to_collect_garbage();
+    assert_eq!(sync_gc_count(), 1);
+
+    reset_sync_gc_count();
+    assert_eq!(sync_gc_count(), 0);
+    assert_eq!(gc_cycle_count(), 2);
+  }
+
+  #[test]
+  fn test_pre_collect_hook_cancels_collection() {
+    use std::sync::{atomic::{AtomicUsize, Ordering::Relaxed}, Arc};
+
+    let _heap = lock_global_heap();
+    let a = Symbol::new(IString::from("a"), 0);
+    for _ in 0..100 {
+      DagNode::new(&a);
+    }
+    let live = active_node_count();
+    assert_eq!(live, 100);
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let hook_calls = calls.clone();
+    set_on_pre_collect(Some(Box::new(move || hook_calls.fetch_add(1, Relaxed) > 0)));
+
+    unsafe { acquire_node_allocator("test_pre_collect_hook").collect_garbage(); }
+    assert_eq!(calls.load(Relaxed), 1);
+    assert_eq!(active_node_count(), live);
+    // The vetoed collection was not wanted, and the veto does not ask for one.
+    assert!(!want_to_collect_garbage());
+
+    unsafe { acquire_node_allocator("test_pre_collect_hook").collect_garbage(); }
+    assert_eq!(calls.load(Relaxed), 2);
+    assert_eq!(active_node_count(), 0);
+    assert!(!want_to_collect_garbage());
+  }
+
+  #[test]
+  fn test_parents_first_destruction() {
+    use std::sync::{Arc, Mutex};
+
+    let _heap = lock_global_heap();
+    let symbols = (0..3).map(|arity| Symbol::new(IString::from("d"), arity)).collect::<Vec<_>>();
+    // Each finalized node, with whether all of its children were still unfinalized and unreset at the time.
+    let finalized      = Arc::new(Mutex::new(Vec::<(usize, bool)>::new()));
+    let hook_finalized = finalized.clone();
+    set_on_finalize(Some(Box::new(move |node: &DagNode| {
+      let mut finalized  = hook_finalized.lock().unwrap();
+      let children_valid = node.iter_children().all(|&child| {
+        !unsafe { &*child }.symbol.is_null() && finalized.iter().all(|&(done, _)| done != child as usize)
+      });
+      finalized.push((node as *const DagNode as usize, children_valid));
+    })));
+
+    // Children are allocated first, so arena order would reach each child before its parents.
+    let build = || {
+      let leaf   = DagNode::with_kind(&symbols[0], DagNodeKind::Data);
+      let middle = DagNode::with_args(&symbols[1], &[leaf], DagNodeKind::Data);
+      DagNode::with_args(&symbols[2], &[middle, leaf], DagNodeKind::Data)
+    };
+    let (dead, live) = (build(), build());
+    let _root = RootContainer::new(live);
+
+    set_destruction_order(DestructionOrder::ParentsFirst);
+    mark_from(&[]);
+    let order = finalized.lock().unwrap().clone();
+    assert_eq!(order.len(), 3);
+    assert_eq!(order[0].0, dead as usize);
+    assert!(order.iter().all(|&(_, children_valid)| children_valid));
+    // The dead nodes were reset, so the next collection finalizes nothing again.
+    mark_from(&[]);
+    assert_eq!(finalized.lock().unwrap().len(), 3);
+
+    set_destruction_order(DestructionOrder::Scan);
+    finalized.lock().unwrap().clear();
+    build();
+    mark_from(&[]);
+    let order = finalized.lock().unwrap().clone();
+    assert_eq!(order.len(), 3);
+    assert!(!order.iter().all(|&(_, children_valid)| children_valid));
+    set_on_finalize(None);
+  }
+
+  #[test]
+  fn test_relocation_finalizes_dead_nodes() {
+    use std::sync::{Arc, Mutex};
+
+    let _heap = lock_global_heap();
+    let d = Symbol::new(IString::from("d"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+    let finalized      = Arc::new(Mutex::new(Vec::<usize>::new()));
+    let hook_finalized = finalized.clone();
+    set_on_finalize(Some(Box::new(move |node: &DagNode| {
+      hook_finalized.lock().unwrap().push(node as *const DagNode as usize);
+    })));
+
+    let leaf = DagNode::new(&a);
+    let dead = DagNode::with_args(&d, &[leaf], DagNodeKind::Data);
+    let live = DagNode::with_args(&d, &[leaf], DagNodeKind::Data);
+    let root = RootContainer::new(live);
+    // Marks left by an ordinary collection must not keep a node alive through the relocating one.
+    mark_from(&[dead]);
+    assert!(finalized.lock().unwrap().is_empty());
+
+    collect_garbage_relocating().unwrap();
+    assert_eq!(*finalized.lock().unwrap(), [dead as usize]);
+    assert_eq!(active_node_count(), 2);
+    // The copies are not finalized again.
+    drop(root);
+    mark_from(&[]);
+    assert_eq!(finalized.lock().unwrap().len(), 2);
+    set_on_finalize(None);
+  }
+
+  #[test]
+  fn test_on_arena_allocated() {
+    use std::sync::{Arc, Mutex};
+    use super::ARENA_SIZE;
+
+    let _heap = lock_global_heap();
+    let a = Symbol::new(IString::from("a"), 0);
+    let counts      = Arc::new(Mutex::new(Vec::new()));
+    let hook_counts = counts.clone();
+    set_on_arena_allocated(Some(Box::new(move |arena_count| hook_counts.lock().unwrap().push(arena_count))));
+
+    for _ in 0..3 * ARENA_SIZE {
+      DagNode::new(&a);
+    }
+    let seen = counts.lock().unwrap().clone();
+    assert!(seen.len() >= 3);
+    assert!(seen.windows(2).all(|pair| pair[1] == pair[0] + 1));
+    assert_eq!(seen.last().copied(), Some(arena_count()));
+
+    // A relocating collection reports its copy arenas on top of the arenas they replace.
+    let root   = RootContainer::new(DagNode::new(&a));
+    let before = arena_count();
+    counts.lock().unwrap().clear();
+    collect_garbage_relocating().unwrap();
+    assert_eq!(*counts.lock().unwrap(), [before + 1]);
+    assert_eq!(arena_count(), 1);
+    drop(root);
+
+    set_on_arena_allocated(None);
+    let seen = counts.lock().unwrap().len();
+    reserve_nodes(10 * ARENA_SIZE);
+    assert_eq!(counts.lock().unwrap().len(), seen);
+  }
+
+  #[test]
+  fn test_max_total_bytes() {
+    use super::{Arena, ARENA_SIZE};
+
+    let _heap = lock_global_heap();
+    let a = Symbol::new(IString::from("a"), 0);
+    let arena_bytes = size_of::<Arena>();
+    // Room for one ordinary bucket and two arenas.
+    set_max_total_bytes(Some(256 * 1024 + 2 * arena_bytes));
+    assert!(try_allocate_storage(64).is_ok());
+
+    let mut allocated = 0;
+    let error = loop {
+      match try_allocate_dag_node() {
+        Ok(node) => unsafe { node.write(DagNode::default()) },
+        Err(error) => break error,
+      }
+      allocated += 1;
+      assert!(allocated <= 2 * ARENA_SIZE, "the ceiling did not stop allocation");
+    };
+    assert!(error.contains("ceiling"));
+    assert_eq!(arena_count(), 2);
+    assert!(allocated > ARENA_SIZE);
+    assert!(want_to_collect_garbage());
+
+    // The bucket still has room, but a large object would be new storage.
+    assert!(try_allocate_storage(64).is_ok());
+    assert!(try_allocate_storage(64 * 1024).is_err());
+
+    // Only the `try_*` functions respect the ceiling.
+    DagNode::new(&a);
+    assert_eq!(arena_count(), 3);
+    set_max_total_bytes(None);
+    assert!(try_allocate_storage(64 * 1024).is_ok());
+  }
+
+  #[cfg(feature = "gc_debug")]
+  #[test]
+  fn test_clear_all_marks() {
+    use crate::dag_node::{deref_node_mut, DagNodeFlag};
+
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+    let leaf  = DagNode::new(&a);
+    let term  = DagNode::with_args(&f, &[leaf, leaf], DagNodeKind::default());
+    let _root = RootContainer::new(term);
+
+    // The survivors are marked until the lazy sweep reaches them, which clearing must not undo.
+    mark_from(&[]);
+    assert!(unsafe { &*term }.is_marked());
+    assert_eq!(clear_all_marks(), 0);
+    assert!(unsafe { &*term }.is_marked());
+
+    // Mark a fresh subgraph by hand.
+    let leaves = (0..10).map(|_| DagNode::new(&a)).collect::<Vec<_>>();
+    let parent = DagNode::with_args(&f, &leaves, DagNodeKind::default());
+    let marked = leaves.iter().copied().chain([parent]).collect::<Vec<_>>();
+    for &node in marked.iter() {
+      unsafe { deref_node_mut(node) }.flags.insert(DagNodeFlag::Marked);
+    }
+
+    assert_eq!(clear_all_marks(), marked.len());
+    assert!(marked.iter().all(|&node| !unsafe { &*node }.is_marked()));
+    assert!(!unsafe { &*term }.is_marked());
+    let node_allocator = acquire_node_allocator("test_clear_all_marks");
+    unsafe { node_allocator.check_invariant() };
+  }
+
+  #[test]
+  fn test_reserve_nodes() {
+    use super::ARENA_SIZE;
+    let _heap = lock_global_heap();
+    let a = Symbol::new(IString::from("a"), 0);
+    let node_count = 100_000;
+
+    reserve_nodes(node_count);
+    let reserved_arena_count = arena_count();
+    assert_eq!(reserved_arena_count as usize, node_count.div_ceil(ARENA_SIZE));
+
+    for _ in 0..node_count {
+      DagNode::new(&a);
+    }
+    assert_eq!(arena_count(), reserved_arena_count);
+    assert_eq!(active_node_count(), node_count);
+
+    // Reserving less than what is already there does nothing.
+    reserve_nodes(10);
+    assert_eq!(arena_count(), reserved_arena_count);
+  }
+
+  #[test]
+  fn test_for_each_live_node() {
+    use std::collections::HashSet;
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    let a1     = DagNode::new(&a);
+    let a2     = DagNode::new(&a);
+    let a3     = DagNode::new(&a);
+    let f_node = DagNode::with_args(&f, &[a1, a2], DagNodeKind::default());
+    let g_node = DagNode::with_args(&g, &[a3], DagNodeKind::default());
+    // Garbage
+    DagNode::with_args(&g, &[DagNode::new(&a)], DagNodeKind::default());
+
+    let _roots = register_roots(&[f_node, g_node]);
+    unsafe { acquire_node_allocator("test_for_each_live_node").collect_garbage(); }
+
+    let mut visited = HashSet::new();
+    for_each_live_node(|node| assert!(visited.insert(node)));
+    assert_eq!(visited, HashSet::from([a1, a2, a3, f_node, g_node]));
+  }
+
+  #[cfg(feature = "gc_debug")]
+  #[test]
+  fn test_churned_nodes() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+    let throwaway = 500;
+
+    let tree  = DagNode::with_args(&f, &[DagNode::new(&a), DagNode::new(&a)], DagNodeKind::default());
+    let _root = RootContainer::new(tree);
+    for _ in 0..throwaway {
+      DagNode::new(&a);
+    }
+
+    unsafe { acquire_node_allocator("test_churned_nodes").collect_garbage(); }
+    let stats = last_gc_stats();
+    assert_eq!(stats.nodes_before, throwaway + 3);
+    assert_eq!(stats.live_nodes, 3);
+    assert_eq!(stats.churned_nodes, throwaway);
+
+    // The survivors are not counted again.
+    for _ in 0..throwaway {
+      DagNode::new(&a);
+    }
+    unsafe { acquire_node_allocator("test_churned_nodes").collect_garbage(); }
+    let stats = last_gc_stats();
+    assert_eq!(stats.live_nodes, 3);
+    assert_eq!(stats.churned_nodes, throwaway);
+  }
+
+  #[test]
+  fn test_collect_storage_only() {
+    use super::acquire_storage_allocator;
+    let _heap = lock_global_heap();
+    // Arity 3, as binary nodes hold their children inline and own no bucket storage.
+    let f = Symbol::new(IString::from("f"), 3);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    let (a_node, b_node) = (DagNode::new(&a), DagNode::new(&b));
+    let kept  = DagNode::with_args(&f, &[a_node, b_node, a_node], DagNodeKind::default());
+    let _root = RootContainer::new(kept);
+    for _ in 0..1000 {
+      DagNode::with_args(&f, &[a_node, b_node, a_node], DagNodeKind::default());
+    }
+
+    let storage_before = acquire_storage_allocator().storage_in_use();
+    let nodes_before   = active_node_count();
+    collect_storage_only();
+    let storage_after = acquire_storage_allocator().storage_in_use();
+
+    assert!(storage_after < storage_before);
+    assert_eq!(storage_after, unsafe { &*kept }.owned_bytes());
+    assert_eq!(active_node_count(), nodes_before);
+
+    // Reusing the freed buckets must not overwrite the relocated vector.
+    for _ in 0..1000 {
+      DagNode::with_args(&f, &[b_node, a_node, b_node], DagNodeKind::default());
+    }
+    let children = unsafe { &*kept }.iter_children().copied().collect::<Vec<_>>();
+    assert_eq!(children, vec![a_node, b_node, a_node]);
+  }
+
+  #[cfg(feature = "gc_debug")]
+  #[test]
+  #[should_panic(expected = "is a tombstone")]
+  fn test_reclaimed_vector_is_a_tombstone() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 3);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // Not rooted, so the collection does not relocate its vector, but we keep using it anyway.
+    let args = vec![DagNode::new(&a), DagNode::new(&a), DagNode::new(&a)];
+    let unrooted = DagNode::with_args(&f, &args, DagNodeKind::default());
+    collect_storage_only();
+    unsafe { &*unrooted }.len();
+  }
+
+  #[cfg(feature = "gc_debug")]
+  #[test]
+  fn test_reconcile_storage() {
+    use crate::dag_node::allocator::node_vector::NodeVector;
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 1);
+    let g = Symbol::new(IString::from("g"), 3);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // `inner` holds its one child inline, so only the `g` nodes own bucket storage.
+    let leaf  = DagNode::new(&a);
+    let inner = DagNode::with_args(&f, &[leaf], DagNodeKind::default());
+    let outer = DagNode::with_args(&g, &[inner, leaf, inner], DagNodeKind::default());
+    let other = DagNode::with_args(&g, &[leaf, outer, leaf], DagNodeKind::default());
+    let _roots = register_roots(&[outer, other]);
+    for _ in 0..10 {
+      DagNode::with_args(&g, &[leaf, leaf, leaf], DagNodeKind::default());
+    }
+
+    // Collections reconcile bucket storage themselves; this checks that the sum is the one expected.
+    let header   = size_of::<NodeVector>();
+    let expected = 2 * header + (3 + 3) * size_of::<DagNodePtr>();
+    assert_eq!(mark_from(&[]), Some(0));
+    assert_eq!(unsafe { acquire_node_allocator("test_reconcile_storage").reconcile_storage() }, expected);
+    assert!(collect_garbage_relocating().is_some());
+    assert_eq!(unsafe { acquire_node_allocator("test_reconcile_storage").reconcile_storage() }, expected);
+  }
+
+  #[cfg(feature = "gc_debug")]
+  #[test]
+  fn test_memory_dump_counts_free_slots() {
+    use super::ARENA_SIZE;
+    let _heap = lock_global_heap();
+    let allocated = 100;
+
+    let mut allocator = acquire_node_allocator("test_memory_dump_counts_free_slots");
+    for _ in 0..allocated {
+      let node = allocator.allocate_dag_node();
+      unsafe { node.write(DagNode::default()); }
+    }
+    let mut dump = String::new();
+    allocator.write_memory_variables(&mut dump);
+
+    let counts = dump.lines()
+        .filter(|line| line.contains(" free/in use "))
+        .map(|line| {
+          let counts = line.trim_end_matches('│').split_whitespace().last().unwrap();
+          let (free, in_use) = counts.split_once('/').unwrap();
+          (free.parse::<usize>().unwrap(), in_use.parse::<usize>().unwrap())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(counts.len(), allocator.arena_count() as usize);
+    assert_eq!(counts, vec![(ARENA_SIZE - allocated, allocated)]);
+    assert!(dump.contains("active_node_count"));
+  }
+
+  #[cfg(feature = "gc_debug")]
+  #[test]
+  fn test_check_node_vectors() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 3);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    let leaves = (0..3).map(|_| DagNode::new(&a)).collect::<Vec<_>>();
+    let inner  = DagNode::with_args(&f, &leaves, DagNodeKind::default());
+    let outer  = DagNode::with_args(&f, &[inner, leaves[0], inner], DagNodeKind::default());
+    let _root  = RootContainer::new(outer);
+
+    let mut allocator = acquire_node_allocator("test_check_node_vectors");
+    unsafe {
+      allocator.collect_garbage();
+      assert_eq!(allocator.last_live_nodes().len(), 5);
+      assert_eq!(allocator.check_node_vectors(), 0);
+    }
+    assert!(!allocator.contains_node(std::ptr::null()));
+  }
+
+  #[test]
+  fn test_mark_from() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    let leaf_a = DagNode::new(&a);
+    let leaf_b = DagNode::new(&b);
+    let term   = DagNode::with_args(&f, &[leaf_a, leaf_b], DagNodeKind::default());
+    let _rooted_leaf = RootContainer::new(leaf_a);
+    let _rooted      = RootContainer::new(DagNode::new(&b));
+    // Reachable from nothing.
+    DagNode::new(&a);
+
+    // `leaf_a` is reachable twice from the explicit roots and is also registered.
+    assert_eq!(mark_from(&[term, leaf_a, std::ptr::null_mut()]), Some(3));
+    assert_eq!(last_gc_stats().live_nodes, 4);
+
+    // The explicit roots were not registered.
+    assert_eq!(mark_from(&[]), Some(0));
+    assert_eq!(last_gc_stats().live_nodes, 2);
+  }
+
+  #[test]
+  fn test_collect_garbage_relocating() {
+    use super::ARENA_SIZE;
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    // The spine `f(b, f(b, ... a))` is built bottom up with garbage in between, so it starts out in reverse order.
+    let depth = 50;
+    let mut term = DagNode::new(&a);
+    for _ in 0..depth {
+      DagNode::new(&a);
+      term = DagNode::with_args(&f, &[DagNode::new(&b), term], DagNodeKind::default());
+    }
+    let root = RootContainer::new(term);
+
+    let stats = collect_garbage_relocating().unwrap();
+    assert_eq!(stats.live_nodes, 2 * depth + 1);
+    assert_eq!(active_node_count(), 2 * depth + 1);
+    assert_ne!(root.node(), term);
+
+    // In depth first order every node directly follows the one visited before it.
+    let mut previous: Option<DagNodePtr> = None;
+    let mut spine    = root.node();
+    for level in 0..=depth {
+      let node = unsafe { &*spine };
+      if let Some(previous) = previous {
+        assert_eq!(spine, previous.wrapping_add(1));
+      }
+      if level == depth {
+        assert_eq!(node.symbol().name, a.name);
+        break;
+      }
+      assert_eq!(node.symbol().name, f.name);
+      let (&leaf, &next) = (node.iter_children().next().unwrap(), node.iter_children().nth(1).unwrap());
+      assert_eq!(leaf, spine.wrapping_add(1));
+      assert_eq!(unsafe { &*leaf }.symbol().name, b.name);
+      previous = Some(leaf);
+      spine    = next;
+    }
+
+    // The relocated heap keeps working.
+    for _ in 0..10 * ARENA_SIZE {
+      DagNode::new(&a);
+    }
+    assert_eq!(mark_from(&[]), Some(0));
+    assert_eq!(last_gc_stats().live_nodes, 2 * depth + 1);
+  }
+
+  #[test]
+  fn test_collect_and_compact() {
+    use crate::{dag_node::{root_count, TermTree}, util::walk};
+
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // A balanced term of `f`s over `g(a)` leaves, built with garbage between its nodes.
+    let mut level = (0..16).map(|_| {
+      DagNode::new(&a);
+      DagNode::with_args(&g, &[DagNode::new(&a)], DagNodeKind::default())
+    }).collect::<Vec<_>>();
+    while level.len() > 1 {
+      level = level.chunks(2).map(|pair| {
+        DagNode::new(&a);
+        DagNode::with_args(&f, pair, DagNodeKind::default())
+      }).collect();
+    }
+    let term     = level[0];
+    let expected = TermTree::from(unsafe { &*term });
+
+    let compacted = collect_and_compact(term);
+    assert_ne!(compacted, term);
+    assert_eq!(TermTree::from(unsafe { &*compacted }), expected);
+    assert_eq!(root_count(), 0);
+
+    // The term's nodes fill consecutive slots.
+    let mut nodes = Vec::new();
+    walk(compacted, usize::MAX, |node| nodes.push(node as usize)).unwrap();
+    nodes.sort_unstable();
+    assert_eq!(nodes.len(), 16 * 2 + 15);
+    assert_eq!(nodes.last().unwrap() - nodes[0], (nodes.len() - 1) * size_of::<DagNode>());
+    assert_eq!(active_node_count(), nodes.len());
+  }
+
+  #[test]
+  fn test_relocation_forwards_children() {
+    // Whether `node` lies in one of `ranges`, as returned by `arena_ranges`.
+    fn in_ranges(ranges: &[(*const DagNode, usize)], node: DagNodePtr) -> bool {
+      ranges.iter().any(|&(first, count)| {
+        (node as usize) >= first as usize && (node as usize) < first as usize + count * size_of::<DagNode>()
+      })
+    }
+
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 3);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // `f(g(a), a, a)`, with `a` shared three times.
+    let leaf    = DagNode::new(&a);
+    let wrapped = DagNode::with_args(&g, &[leaf], DagNodeKind::default());
+    let term    = DagNode::with_args(&f, &[wrapped, leaf, leaf], DagNodeKind::default());
+    let root    = RootContainer::new(term);
+    let old_ranges = arena_ranges();
+
+    collect_garbage_relocating().unwrap();
+    let new_ranges = arena_ranges();
+
+    let children = unsafe { &*root.node() }.iter_children().copied().collect::<Vec<_>>();
+    let leaf     = unsafe { &*children[0] }.iter_children().copied().next().unwrap();
+    assert_eq!(children[1..], [leaf, leaf]);
+    for node in [root.node(), children[0], leaf] {
+      assert!(in_ranges(&new_ranges, node));
+      assert!(!in_ranges(&old_ranges, node));
+      assert!(!unsafe { &*node }.is_copied());
+    }
+    assert_eq!(unsafe { &*leaf }.symbol().name, a.name);
+    assert_eq!(active_node_count(), 3);
+  }
+
+  #[test]
+  fn test_copied_flag_lasts_one_pass() {
+    use crate::dag_node::{deref_node_mut, DagNodeFlag};
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // `f(f(a, a), f(a, a))` with every subterm shared, so each pass meets already `Copied` nodes.
+    let leaf  = DagNode::new(&a);
+    let inner = DagNode::with_args(&f, &[leaf, leaf], DagNodeKind::default());
+    let root  = RootContainer::new(DagNode::with_args(&f, &[inner, inner], DagNodeKind::default()));
+    // A stray `Copied` on a node whose arguments are not a forwarding pointer is copied along with the node unless the
+    // pass clears it from the copy.
+    unsafe { deref_node_mut(inner) }.flags.insert(DagNodeFlag::Copied);
+
+    // A `Copied` left on a copy would make the next pass copy that node twice.
+    for _ in 0..2 {
+      assert_eq!(collect_garbage_relocating().unwrap().live_nodes, 3);
+      let term     = unsafe { &*root.node() };
+      let children = term.iter_children().copied().collect::<Vec<_>>();
+      let leaves   = unsafe { &*children[0] }.iter_children().copied().collect::<Vec<_>>();
+      assert_eq!(children, [children[0], children[0]]);
+      assert_eq!(leaves, [leaves[0], leaves[0]]);
+      for node in [root.node(), children[0], leaves[0]] {
+        assert!(!unsafe { &*node }.is_copied());
+      }
+      assert_eq!(unsafe { &*leaves[0] }.symbol().name, a.name);
+    }
+  }
+
+  #[test]
+  fn test_hashcons_on_gc() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    // Fifty unshared copies of `f(g(a), b)` and one of `f(a, b)`, whose `a` and `b` also merge with the others.
+    let build = || {
+      let wrapped = DagNode::with_args(&g, &[DagNode::new(&a)], DagNodeKind::default());
+      DagNode::with_args(&f, &[wrapped, DagNode::new(&b)], DagNodeKind::default())
+    };
+    let mut terms = (0..50).map(|_| build()).collect::<Vec<_>>();
+    terms.push(DagNode::with_args(&f, &[DagNode::new(&a), DagNode::new(&b)], DagNodeKind::default()));
+    let roots = register_roots(&terms);
+
+    // Without the option every survivor is kept.
+    assert_eq!(collect_garbage_relocating().unwrap().live_nodes, 50 * 4 + 3);
+
+    set_hashcons_on_gc(true);
+    let before = roots.iter().map(|root| root.node()).collect::<Vec<_>>();
+    assert_eq!(collect_garbage_relocating().unwrap().live_nodes, 5);
+    assert_eq!(active_node_count(), 5);
+    let shared = roots[0].node();
+    assert!(roots[..50].iter().all(|root| root.node() == shared));
+    assert_ne!(roots[50].node(), shared);
+    assert!(before.iter().all(|&node| last_gc_forwarding(node).is_some()));
+
+    // The merged term is intact and shares its leaves with the other.
+    let children = unsafe { &*shared }.iter_children().copied().collect::<Vec<_>>();
+    let other    = unsafe { &*roots[50].node() }.iter_children().copied().collect::<Vec<_>>();
+    assert_eq!(unsafe { &*children[0] }.symbol().name, g.name);
+    assert_eq!(unsafe { &*children[0] }.iter_children().next(), Some(&other[0]));
+    assert_eq!(children[1], other[1]);
+    assert_eq!(unsafe { &*other[1] }.symbol().name, b.name);
+  }
+
+  #[test]
+  fn test_last_gc_forwarding() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    let leaf  = DagNode::new(&a);
+    let term  = DagNode::with_args(&f, &[leaf], DagNodeKind::default());
+    let root  = RootContainer::new(term);
+    let dead  = DagNode::new(&a);
+    assert_eq!(last_gc_forwarding(leaf), None);
+
+    collect_garbage_relocating().unwrap();
+    let moved_leaf = last_gc_forwarding(leaf).unwrap();
+    assert_eq!(last_gc_forwarding(term), Some(root.node()));
+    assert_eq!(unsafe { &*root.node() }.iter_children().next(), Some(&moved_leaf));
+    assert_eq!(last_gc_forwarding(dead), None);
+
+    // An ordinary collection moves nothing and forgets the previous forwarding.
+    assert!(mark_from(&[]).is_some());
+    assert_eq!(last_gc_forwarding(leaf), None);
+
+    // An address that a new arena has reused is no longer forwarded.
+    let term = root.node();
+    collect_garbage_relocating().unwrap();
+    assert_eq!(last_gc_forwarding(term), Some(root.node()));
+    let mut allocator = acquire_node_allocator("test_last_gc_forwarding");
+    allocator.last_forwarding.insert(root.node(), term);
+    assert_eq!(allocator.last_gc_forwarding(root.node()), None);
+    assert_eq!(allocator.last_gc_forwarding(term), Some(root.node()));
+  }
+
+  #[test]
+  fn test_eager_sweep() {
+    use super::{RESERVE_SIZE, THREAD_CACHE_SIZE};
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // Survivors spread through the first arena, between garbage.
+    let mut survivors = Vec::new();
+    for _ in 0..100 {
+      let leaf = DagNode::new(&a);
+      survivors.push(DagNode::with_args(&f, &[leaf, leaf], DagNodeKind::default()));
+      DagNode::new(&a);
+    }
+    let _roots = register_roots(&survivors);
+
+    // The lazy sweep clears the marks of the survivors it passes.
+    assert_eq!(mark_from(&[]), Some(0));
+    for _ in 0..1000 {
+      DagNode::new(&a);
+    }
+    assert!(lazily_swept_nodes() > 0);
+
+    set_sweep_mode(SweepMode::Eager);
+    assert_eq!(mark_from(&[]), Some(0));
+    let swept = lazily_swept_nodes();
+    let free  = nodes_until_gc();
+    let nodes = (0..1000).map(|_| DagNode::new(&a)).collect::<Vec<_>>();
+    assert_eq!(lazily_swept_nodes(), swept);
+    // Threads take free nodes in batches.
+    assert!((free - 1000 - THREAD_CACHE_SIZE..=free - 1000).contains(&nodes_until_gc()));
+    // The garbage between the survivors is reused first, and no survivor is handed out.
+    assert!(nodes.iter().all(|node| !survivors.contains(node)));
+    assert!(survivors.iter().all(|&survivor| !unsafe { &*survivor }.is_marked()));
+    assert_eq!(mark_from(&[]), Some(0));
+    assert_eq!(last_gc_stats().live_nodes, 200);
+
+    // Handing out the reserve asks for a collection. Once the reserve runs out too, allocation continues past it.
+    let free   = nodes_until_gc();
+    let arenas = arena_count();
+    for _ in 0..free + THREAD_CACHE_SIZE {
+      DagNode::new(&a);
+    }
+    assert!(want_to_collect_garbage());
+    assert_eq!(arena_count(), arenas);
+    for _ in 0..RESERVE_SIZE + THREAD_CACHE_SIZE {
+      DagNode::new(&a);
+    }
+    assert_eq!(arena_count(), arenas + 1);
+    assert_eq!(lazily_swept_nodes(), swept);
+
+    // Allocating until a collection is wanted and then collecting, as a host does, reuses the same arenas.
+    assert_eq!(mark_from(&[]), Some(0));
+    let arenas = arena_count();
+    for _ in 0..30 {
+      while !want_to_collect_garbage() {
+        DagNode::new(&a);
+      }
+      assert!(collect_if_needed().is_some());
+      assert_eq!(arena_count(), arenas);
+    }
+    set_sweep_mode(SweepMode::Lazy);
+    assert_eq!(mark_from(&[]), Some(0));
+    assert_eq!(last_gc_stats().live_nodes, 200);
+  }
+
+  #[test]
+  fn test_large_vectors_are_kept_in_place() {
+    use super::acquire_storage_allocator;
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // A flattened associative term with more arguments than fit in a bucket allocation of ordinary size.
+    let leaf   = DagNode::new(&a);
+    let wide   = DagNode::with_args(&f, &[leaf; 5000], DagNodeKind::AU);
+    let data   = unsafe { &*wide }.node_vector().unwrap().data_ptr();
+    let root   = RootContainer::new(wide);
+
+    for _ in 0..3 {
+      assert_eq!(mark_from(&[]), Some(0));
+      let node_vector = unsafe { &*wide }.node_vector().unwrap();
+      assert_eq!(node_vector.data_ptr(), data);
+      assert_eq!(node_vector.len(), 5000);
+      assert!(node_vector.as_slice().iter().all(|&child| child == leaf));
+    }
+    assert!(acquire_storage_allocator().storage_in_use() >= 5000 * size_of::<DagNodePtr>());
+    let total = acquire_storage_allocator().total_bytes_allocated();
+
+    // Once unreachable, the large vector is freed.
+    drop(root);
+    assert_eq!(mark_from(&[]), Some(0));
+    assert_eq!(acquire_storage_allocator().total_bytes_allocated(), total - 5000 * size_of::<DagNodePtr>());
+    assert_eq!(acquire_storage_allocator().storage_in_use(), 0);
+  }
+
+  #[test]
+  fn test_live_bytes_by_kind() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // Two ACU nodes owning vectors, over three shared leaves.
+    let leaves = (0..3).map(|_| DagNode::with_kind(&a, DagNodeKind::Data)).collect::<Vec<_>>();
+    let inner  = DagNode::with_args(&f, &leaves, DagNodeKind::ACU);
+    let outer  = DagNode::with_args(&f, &[inner, leaves[0]], DagNodeKind::ACU);
+    let _root  = RootContainer::new(outer);
+    // Unreachable
+    DagNode::with_args(&f, &leaves, DagNodeKind::AU);
+
+    let bytes = live_bytes_by_kind();
+    assert_eq!(bytes.len(), 2);
+    assert_eq!(bytes[&DagNodeKind::Data], 3 * size_of::<DagNode>());
+    assert!(bytes[&DagNodeKind::ACU] >= 2 * size_of::<DagNode>() + 5 * size_of::<DagNodePtr>());
+    // Nodes padded by the `node_align_*` features can outweigh the vector.
+    if align_of::<DagNode>() == align_of::<usize>() {
+      assert!(bytes[&DagNodeKind::ACU] > bytes[&DagNodeKind::Data]);
+    }
+  }
+
+  #[test]
+  fn test_bucket_utilization() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 8);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // About 180 kB of live vectors, all in one bucket. The collection copies them into a second bucket, leaving the
+    // first one empty.
+    let leaf  = DagNode::new(&a);
+    let nodes = (0..2000).map(|_| DagNode::with_args(&f, &[leaf; 8], DagNodeKind::default()))
+                         .collect::<Vec<_>>();
+    let roots = nodes.iter().map(|&node| RootContainer::new(node)).collect::<Vec<_>>();
+    unsafe { acquire_node_allocator("test_bucket_utilization").collect_garbage(); }
+
+    let utilization = last_gc_stats().bucket_utilization;
+    let storage     = super::acquire_storage_allocator();
+    assert_eq!(utilization, storage.storage_in_use() as f64 / storage.total_bytes_allocated() as f64);
+    assert!(utilization > 0.25 && utilization < 0.5, "utilization {}", utilization);
+    drop(storage);
+    drop(roots);
+  }
+
+  #[test]
+  fn test_arena_ranges() {
+    use super::ARENA_SIZE;
+    let _heap = lock_global_heap();
+    let a = Symbol::new(IString::from("a"), 0);
+    assert!(arena_ranges().is_empty());
+
+    for _ in 0..3 * ARENA_SIZE {
+      DagNode::new(&a);
+    }
+    let mut ranges = arena_ranges();
+    assert_eq!(ranges.len() as u32, arena_count());
+    assert!(ranges.len() >= 3);
+    assert_eq!(ranges.iter().map(|&(_, count)| count).sum::<usize>(), arena_count() as usize * ARENA_SIZE);
+
+    ranges.sort_by_key(|&(first, _)| first);
+    for pair in ranges.windows(2) {
+      let (first, count) = pair[0];
+      assert!(first.wrapping_add(count) <= pair[1].0);
+    }
+  }
+
+  #[test]
+  fn test_cursor_position() {
+    let _heap = lock_global_heap();
+    assert_eq!(cursor_position(), (0, 0));
+
+    // Allocating from the allocator itself bypasses the thread cache, which takes nodes in batches.
+    let mut allocator = acquire_node_allocator("test_cursor_position");
+    allocator.allocate_dag_node();
+    assert_eq!(allocator.cursor_position(), (0, 1));
+    for _ in 0..100 {
+      allocator.allocate_dag_node();
+    }
+    assert_eq!(allocator.cursor_position(), (0, 101));
+  }
+
+  #[test]
+  fn test_gc_limit() {
+    let _heap = lock_global_heap();
+    let a = Symbol::new(IString::from("a"), 0);
+    let _root = RootContainer::new(DagNode::new(&a));
+
+    // Collections before the limit is set do not count toward it.
+    assert_eq!(mark_from(&[]), Some(0));
+    assert_eq!(gc_cycle_count(), 1);
+
+    set_gc_limit(Some(1));
+    assert!(!gc_limit_reached());
+    assert_eq!(mark_from(&[]), Some(0));
+    assert!(gc_limit_reached());
+
+    // The second collection is skipped.
+    assert_eq!(mark_from(&[]), None);
+    assert_eq!(gc_cycle_count(), 2);
+
+    set_gc_limit(None);
+    assert!(!gc_limit_reached());
+    assert_eq!(mark_from(&[]), Some(0));
+    assert_eq!(gc_cycle_count(), 3);
+
+    set_gc_limit(Some(0));
+    assert!(gc_limit_reached());
+    assert_eq!(mark_from(&[]), None);
+    set_gc_limit(None);
+  }
+
+  #[test]
+  fn test_gc_cycle_count() {
+    let _heap = lock_global_heap();
+    let a = Symbol::new(IString::from("a"), 0);
+    let _root = RootContainer::new(DagNode::new(&a));
+    assert_eq!(gc_cycle_count(), 0);
+
+    for _ in 0..3 {
+      unsafe { acquire_node_allocator("test_gc_cycle_count").collect_garbage(); }
+    }
+    assert_eq!(gc_cycle_count(), 3);
+    assert_eq!(last_gc_stats().collection, 3);
+  }
+
+  #[test]
+  fn test_fork_heap() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    let leaf_a = DagNode::new(&a);
+    let leaf_b = DagNode::new(&b);
+    let inner  = DagNode::with_args(&g, &[leaf_a], DagNodeKind::default());
+    let outer  = DagNode::with_args(&f, &[inner, leaf_b], DagNodeKind::default());
+    let _root  = RootContainer::new(outer);
+    unsafe { acquire_node_allocator("test_fork_heap").collect_garbage(); }
+
+    let fork = fork_heap();
+    assert_eq!(fork.arena_count(), arena_count());
+    assert!(fork.translate(std::ptr::null_mut()).is_null());
+
+    let forked_outer = fork.translate(outer);
+    assert!(fork.contains_node(forked_outer));
+    let original_children = unsafe { &*outer }.iter_children().copied().collect::<Vec<_>>();
+    let forked_children   = unsafe { &*forked_outer }.iter_children().copied().collect::<Vec<_>>();
+    assert_eq!(forked_children.len(), 2);
+    for (&original, &copy) in original_children.iter().zip(forked_children.iter()) {
+      assert_ne!(original, copy);
+      assert_eq!(copy, fork.translate(original));
+      assert!(std::ptr::eq(unsafe { &*copy }.symbol(), unsafe { &*original }.symbol()));
+    }
+    let forked_a = unsafe { &*forked_children[0] }.iter_children().next().copied().unwrap();
+    assert_eq!(forked_a, fork.translate(leaf_a));
+
+    // Mutate the fork.
+    unsafe { &mut *forked_outer }.kind = DagNodeKind::ACU;
+    unsafe { &mut *forked_a }.set_user_word(42).unwrap();
+
+    // The original is unchanged.
+    let outer_ref = unsafe { &*outer };
+    assert_eq!(outer_ref.kind, DagNodeKind::default());
+    assert_eq!(outer_ref.iter_children().copied().collect::<Vec<_>>(), original_children);
+    assert_eq!(unsafe { &*leaf_a }.user_word(), None);
+    assert_eq!(unsafe { &*inner }.iter_children().next(), Some(&leaf_a));
+  }
+
+  #[test]
+  fn test_fork_allocates_and_collects() {
+    use crate::dag_node::allocator::arena::FREED_ARENAS;
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 3);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    let global_leaf = DagNode::new(&a);
+    let _root       = RootContainer::new(global_leaf);
+    let global_nodes = active_node_count();
+
+    let mut fork = fork_heap();
+    let leaf     = fork.new_node(&a);
+    let term     = fork.with_args(&f, &[leaf, leaf, fork.translate(global_leaf)], DagNodeKind::default());
+    let term     = term.unwrap();
+    assert!(fork.contains_node(leaf));
+    assert!(fork.contains_node(term));
+    assert!(!acquire_node_allocator("test_fork_allocates_and_collects").contains_node(term));
+    assert_eq!(active_node_count(), global_nodes);
+
+    // A child from the global heap is refused.
+    assert!(fork.with_args(&f, &[leaf, global_leaf], DagNodeKind::default()).is_err());
+    let term_mut = unsafe { &mut *term };
+    term_mut.remove_last_child().unwrap();
+    term_mut.insert_child(global_leaf).unwrap();
+    assert!(fork.collect(&[term]).is_err());
+    term_mut.remove_last_child().unwrap();
+    term_mut.insert_child(leaf).unwrap();
+
+    // Only the term and its leaf survive, and their arguments are intact.
+    let storage_before = fork.storage_in_use();
+    assert_eq!(fork.collect(&[term]), Ok(2));
+    assert!(fork.storage_in_use() <= storage_before);
+    assert_eq!(unsafe { &*term }.iter_children().copied().collect::<Vec<_>>(), [leaf, leaf, leaf]);
+    assert_eq!(active_node_count(), global_nodes);
+
+    let arena_count = fork.arena_count() as usize;
+    let freed       = FREED_ARENAS.with(|freed| freed.get());
+    drop(fork);
+    assert_eq!(FREED_ARENAS.with(|freed| freed.get()), freed + arena_count);
+  }
+
   #[test]
   fn test_arena_exhaustion() {
+    let _heap = lock_global_heap();
     let mut symbol = Symbol::new(IString::from("mysymbol"), 1);
     let symbol_ptr = &mut symbol;
     let root: DagNodePtr = DagNode::new(symbol_ptr);
@@ -13,10 +13,12 @@ use std::cmp::min;
 
 use crate::{
   dag_node::{
-    allocator::storage_allocator::acquire_storage_allocator,
+    allocator::storage_allocator::{acquire_storage_allocator, StorageAllocator},
     node::DagNodePtr
   }
 };
+#[cfg(feature = "gc_debug")]
+use crate::dag_node::allocator::bucket::TOMBSTONE;
 
 
 pub type NodeVectorMutRef = &'static mut NodeVector;
@@ -37,20 +39,32 @@ impl NodeVector {
 
   // region Constructors
 
-  /// Creates a new empty vector with the given capacity.
+  /// Creates a new empty vector with the given capacity. A vector of capacity 0 is valid but can never hold an
+  /// element, and its (empty) data takes no bucket storage.
   pub fn with_capacity(capacity: usize) -> NodeVectorMutRef {
+    NodeVector::with_capacity_in(&mut acquire_storage_allocator(), capacity)
+  }
+
+  /// Like `with_capacity`, but takes the storage from `storage_allocator` instead of the global storage allocator,
+  /// mirroring `DagNode::new_in`. The vector's storage is not counted in the global allocator's `storage_in_use`.
+  pub fn with_capacity_in(storage_allocator: &mut StorageAllocator, capacity: usize) -> NodeVectorMutRef {
     unsafe {
       let node_vector_ptr: *mut NodeVector =
-          { acquire_storage_allocator().allocate_storage(size_of::<NodeVector>()) as *mut NodeVector };
+          storage_allocator.allocate_storage(size_of::<NodeVector>()) as *mut NodeVector;
       let node_vector: &mut NodeVector     = node_vector_ptr.as_mut_unchecked();
 
       // Initialize the NodeVector
       node_vector.length   = 0;
       node_vector.capacity = capacity;
 
+      if capacity == 0 {
+        node_vector.data = &mut [];
+        return node_vector;
+      }
+
       // Allocate the memory slice. Two separate allocations are needed to maintain alignment.
       let needed_memory    = capacity * size_of::<DagNodePtr>();
-      let data_ptr         = { acquire_storage_allocator().allocate_storage(needed_memory) as *mut DagNodePtr };
+      let data_ptr         = storage_allocator.allocate_storage(needed_memory) as *mut DagNodePtr;
       node_vector.data     = std::slice::from_raw_parts_mut(data_ptr, capacity);
 
       node_vector
@@ -60,18 +74,56 @@ impl NodeVector {
   /// Creates a new `NodeVector` from the given slice. The capacity of the
   /// new `NodeVector` is equal to its length.
   pub fn from_slice(vec: &[DagNodePtr]) -> NodeVectorMutRef {
-    let capacity = vec.len();
+    NodeVector::from_slice_with_capacity(vec, vec.len())
+  }
+
+  /// Creates a new `NodeVector` holding a copy of the given slice, with room for `capacity` nodes so that nodes can be
+  /// pushed up to that capacity without copying. Panics if `capacity` is less than the length of `vec`.
+  pub fn from_slice_with_capacity(vec: &[DagNodePtr], capacity: usize) -> NodeVectorMutRef {
+    assert!(capacity >= vec.len(), "capacity {} is less than the slice length {}", capacity, vec.len());
 
     let node_vector_mut: NodeVectorMutRef = NodeVector::with_capacity(capacity);
 
     // Copy contents of vec into node_vector.data
-    for (i, &item) in vec.iter().enumerate() {
-      node_vector_mut.data[i] = item;
+    node_vector_mut.data[..vec.len()].copy_from_slice(vec);
+    node_vector_mut.length = vec.len();
+
+    node_vector_mut
+  }
+
+  /// The vector a live vector becomes after a collection, which must be in its mark phase. The elements of a large
+  /// vector stay where they are, and only its header is copied; any other vector is copied with `shallow_copy`.
+  pub(crate) fn relocate(&self) -> NodeVectorMutRef {
+    let mut storage_allocator = acquire_storage_allocator();
+    if self.capacity == 0 || !storage_allocator.keep_large_object(self.data_ptr().cast()) {
+      drop(storage_allocator);
+      return self.shallow_copy();
+    }
+    self.relocate_large(&mut storage_allocator)
+  }
+
+  /// Like `relocate`, but for a vector whose storage came from `storage_allocator`, which must be in its mark phase.
+  pub(crate) fn relocate_in(&self, storage_allocator: &mut StorageAllocator) -> NodeVectorMutRef {
+    if self.capacity == 0 || !storage_allocator.keep_large_object(self.data_ptr().cast()) {
+      let copy = NodeVector::with_capacity_in(storage_allocator, self.capacity);
+      copy.data[..self.length].copy_from_slice(self.as_slice());
+      copy.length = self.length;
+      return copy;
     }
+    self.relocate_large(storage_allocator)
+  }
 
-    node_vector_mut.length = capacity;
+  /// Copies the header of a kept large vector into `storage_allocator`, leaving its elements in place.
+  fn relocate_large(&self, storage_allocator: &mut StorageAllocator) -> NodeVectorMutRef {
+    unsafe {
+      let header_ptr = storage_allocator.allocate_storage(size_of::<NodeVector>()) as *mut NodeVector;
+      let header     = header_ptr.as_mut_unchecked();
+      header.length   = self.length;
+      header.capacity = self.capacity;
+      header.data     = std::slice::from_raw_parts_mut(self.data_ptr().cast_mut(), self.capacity);
 
-    node_vector_mut
+      header
+    }
   }
 
   /// Creates an identical shallow copy, allocating new memory. The copy
@@ -105,6 +157,11 @@ impl NodeVector {
     }
   }
 
+  /// Makes a copy with capacity equal to its length, for reclaiming over-provisioned vectors.
+  pub fn shrink_to_fit(&self) -> NodeVectorMutRef {
+    self.copy_with_capacity(self.length)
+  }
+
   // endregion Constructors
 
   // Immutable iterator
@@ -112,6 +169,11 @@ impl NodeVector {
     self.data[..self.length].iter()
   }
 
+  /// Iterates over the elements for as long as `self` is borrowed, so unlike `iter` it needs no `'static` reference.
+  pub fn iter_borrowed<'a>(&'a self) -> std::slice::Iter<'a, DagNodePtr> {
+    self.data[..self.length].iter()
+  }
+
   // Mutable iterator
   pub fn iter_mut(&'static mut self) -> std::slice::IterMut<'static, DagNodePtr> {
     self.data[..self.length].iter_mut()
@@ -127,14 +189,62 @@ impl NodeVector {
 
   pub fn is_empty(&self) -> bool { self.len() == 0 }
 
-  /// Pushes the given node onto the (end) of the vector if there is enough capacity.
+  /// The address of the first element.
+  pub(crate) fn data_ptr(&self) -> *const DagNodePtr {
+    self.data.as_ptr()
+  }
+
+  /// Points this vector at `data`, which must hold a copy of its elements, and rewrites every element with
+  /// `node_map`. Used when copying a heap. A vector of capacity 0 has no data to move, so `data` is then ignored.
+  pub(crate) unsafe fn remap(&mut self, data: *mut DagNodePtr, node_map: &impl Fn(DagNodePtr) -> DagNodePtr) {
+    if self.capacity == 0 {
+      return;
+    }
+    self.data = std::slice::from_raw_parts_mut(data, self.capacity);
+    for node in self.data[..self.length].iter_mut() {
+      *node = node_map(*node);
+    }
+  }
+
+  /// Whether this vector's memory has been reclaimed by the bucket collector, which means whoever still refers to it
+  /// missed a relocation.
+  #[cfg(feature = "gc_debug")]
+  pub fn is_tombstone(&self) -> bool {
+    self.length == TOMBSTONE
+  }
+
+  /// Panics if this vector's memory has been reclaimed. See `is_tombstone`.
+  #[cfg(feature = "gc_debug")]
+  #[inline(always)]
+  pub fn assert_not_tombstone(&self) {
+    assert!(!self.is_tombstone(), "NodeVector {:p} is a tombstone: its storage was reclaimed", self);
+  }
+
+  /// The number of bytes of bucket storage backing the elements, which depends on capacity rather than length.
+  pub fn capacity_bytes(&self) -> usize {
+    self.capacity * size_of::<DagNodePtr>()
+  }
+
+  /// The initialized prefix `[0..len()]` as a slice.
+  pub fn as_slice(&self) -> &[DagNodePtr] {
+    &self.data[..self.length]
+  }
+
+  /// The initialized prefix `[0..len()]` as a mutable slice.
+  pub fn as_mut_slice(&mut self) -> &mut [DagNodePtr] {
+    &mut self.data[..self.length]
+  }
+
+  /// Pushes the given node onto the (end) of the vector if there is enough capacity. A vector never grows, as its
+  /// storage cannot be extended in place; when it is full, including when its capacity is 0, this returns an error,
+  /// and the caller can make a larger copy with `copy_with_capacity` or move the elements with `resize`.
   pub fn push(&mut self, node: DagNodePtr) -> Result<(), String> {
     #[cfg(feature = "gc_debug")]
-    if self.length >= self.capacity 
-        || self.data.len() != self.capacity
-    {
+    if self.data.len() != self.capacity {
       panic!("node_vec.len: {}, capacity: {}, data.len: {}", self.length, self.capacity, self.data.len());
-      // return Err(format!("node_vec.len: {}, capacity: {}, data.len: {}", self.length, self.capacity, self.data.len()));
+    }
+    if self.length >= self.capacity {
+      return Err(format!("NodeVector is full: len: {}, capacity: {}", self.length, self.capacity));
     }
 
     self.data[self.length] = node;
@@ -142,6 +252,31 @@ impl NodeVector {
     Ok(())
   }
 
+  /// Sets the length to `new_len`, truncating or filling the new slots with `fill`. Growing past the capacity moves
+  /// the elements to new storage with capacity `new_len`, leaving the old storage to the collector, so other
+  /// references to the old slice must not be kept. Fails only if `new_len` slots cannot be addressed.
+  pub fn resize(&mut self, new_len: usize, fill: DagNodePtr) -> Result<(), String> {
+    if new_len <= self.length {
+      self.length = new_len;
+      return Ok(());
+    }
+
+    if new_len > self.capacity {
+      let needed_memory = new_len.checked_mul(size_of::<DagNodePtr>())
+                                 .ok_or_else(|| format!("cannot resize a NodeVector to {} elements", new_len))?;
+      unsafe {
+        let data_ptr = { acquire_storage_allocator().allocate_storage(needed_memory) as *mut DagNodePtr };
+        std::ptr::copy_nonoverlapping(self.data.as_ptr(), data_ptr, self.length);
+        self.data = std::slice::from_raw_parts_mut(data_ptr, new_len);
+      }
+      self.capacity = new_len;
+    }
+
+    self.data[self.length..new_len].fill(fill);
+    self.length = new_len;
+    Ok(())
+  }
+
   pub fn pop(&mut self) -> Option<DagNodePtr> {
     if self.length == 0 {
       return None;
@@ -169,6 +304,16 @@ impl IndexMut<usize> for NodeVector {
   }
 }
 
+/// Two vectors are equal when they hold the same node pointers in the same order. This compares addresses, not the
+/// terms the nodes represent, and ignores capacity.
+impl PartialEq for NodeVector {
+  fn eq(&self, other: &Self) -> bool {
+    self.as_slice() == other.as_slice()
+  }
+}
+
+impl Eq for NodeVector {}
+
 impl<'a> IntoIterator for &'a NodeVector {
   type Item = &'a DagNodePtr;
   type IntoIter = std::slice::Iter<'a, DagNodePtr>;
@@ -186,3 +331,194 @@ impl<'a> IntoIterator for &'a mut NodeVector {
     self.data.iter_mut()
   }
 }
+
+
+#[cfg(test)]
+mod tests {
+  use std::ptr::null_mut;
+
+  use crate::dag_node::{
+    allocator::lock_global_heap,
+    DagNode,
+    DagNodePtr
+  };
+  use super::NodeVector;
+
+  #[test]
+  fn test_as_slice() {
+    let _heap = lock_global_heap();
+    let mut nodes: [DagNode; 3] = Default::default();
+    let node_vector = NodeVector::with_capacity(8);
+    node_vector.push(&mut nodes[0]).unwrap();
+    node_vector.push(&mut nodes[1]).unwrap();
+
+    assert_eq!(node_vector.as_slice().len(), node_vector.len());
+    assert_eq!(node_vector.as_mut_slice().len(), node_vector.len());
+
+    let replacement: DagNodePtr = &mut nodes[2];
+    node_vector.as_mut_slice()[1] = replacement;
+    assert_eq!(node_vector[1], replacement);
+
+    node_vector.as_mut_slice().fill(null_mut());
+    assert!(node_vector.as_slice().iter().all(|node| node.is_null()));
+  }
+
+  #[test]
+  fn test_eq_compares_pointers() {
+    let _heap = lock_global_heap();
+    let mut nodes: [DagNode; 2] = Default::default();
+    let first : DagNodePtr = &mut nodes[0];
+    let second: DagNodePtr = &mut nodes[1];
+
+    let original  = NodeVector::from_slice(&[first, second]);
+    let same      = NodeVector::with_capacity(8);
+    same.push(first).unwrap();
+    same.push(second).unwrap();
+    let reordered = NodeVector::from_slice(&[second, first]);
+
+    assert!(*original == *same);
+    assert!(*original != *reordered);
+    same.pop();
+    assert!(*original != *same);
+  }
+
+  #[test]
+  fn test_from_slice_with_capacity() {
+    let _heap = lock_global_heap();
+    let mut nodes: [DagNode; 8] = Default::default();
+    let pointers: Vec<DagNodePtr> = nodes.iter_mut().map(|node| node as DagNodePtr).collect();
+
+    let node_vector = NodeVector::from_slice_with_capacity(&pointers[..2], 8);
+    assert_eq!(node_vector.len(), 2);
+    assert_eq!(node_vector.capacity(), 8);
+    let data = node_vector.data_ptr();
+    for &node in &pointers[2..] {
+      node_vector.push(node).unwrap();
+    }
+    assert_eq!(node_vector.data_ptr(), data);
+    assert_eq!(node_vector.as_slice(), pointers.as_slice());
+    assert!(node_vector.push(pointers[0]).is_err());
+  }
+
+  #[test]
+  #[should_panic(expected = "is less than the slice length")]
+  fn test_from_slice_with_capacity_below_length() {
+    let _heap = lock_global_heap();
+    let mut node = DagNode::default();
+    let node: DagNodePtr = &mut node;
+    NodeVector::from_slice_with_capacity(&[node, node], 1);
+  }
+
+  #[test]
+  fn test_zero_capacity() {
+    let _heap = lock_global_heap();
+    let mut node = DagNode::default();
+
+    for node_vector in [NodeVector::with_capacity(0), NodeVector::from_slice(&[])] {
+      assert_eq!(node_vector.capacity(), 0);
+      assert!(node_vector.is_empty());
+      assert!(node_vector.push(&mut node).is_err());
+      assert_eq!(node_vector.len(), 0);
+      assert_eq!(node_vector.pop(), None);
+      assert_eq!(node_vector.iter().count(), 0);
+      assert_eq!(node_vector.as_slice(), &[]);
+      assert_eq!(node_vector.capacity_bytes(), 0);
+
+      // Growing means copying.
+      let grown = node_vector.copy_with_capacity(1);
+      grown.push(&mut node).unwrap();
+      assert_eq!(grown.len(), 1);
+      assert_eq!(node_vector.shallow_copy().capacity(), 0);
+    }
+  }
+
+  #[test]
+  fn test_shrink_to_fit() {
+    let _heap = lock_global_heap();
+    let mut nodes: [DagNode; 4] = Default::default();
+    let node_vector = NodeVector::with_capacity(16);
+    for node in nodes.iter_mut() {
+      node_vector.push(node).unwrap();
+    }
+    assert_eq!(node_vector.capacity_bytes(), 16 * size_of::<DagNodePtr>());
+
+    let shrunk = node_vector.shrink_to_fit();
+    assert_eq!(shrunk.capacity(), 4);
+    assert_eq!(shrunk.len(), 4);
+    assert_eq!(shrunk.capacity_bytes(), 4 * size_of::<DagNodePtr>());
+    assert_eq!(shrunk.as_slice(), node_vector.as_slice());
+  }
+
+  #[test]
+  fn test_iter_borrowed() {
+    // Sums the addresses through an ordinary borrow, which `iter` would reject for lacking `'static`.
+    fn address_sum(node_vector: &NodeVector) -> usize {
+      node_vector.iter_borrowed().map(|&node| node as usize).sum()
+    }
+
+    let _heap = lock_global_heap();
+    let mut nodes: [DagNode; 3] = Default::default();
+    let node_vector = NodeVector::with_capacity(4);
+    for node in nodes.iter_mut() {
+      node_vector.push(node).unwrap();
+    }
+
+    let expected = nodes.iter().map(|node| std::ptr::from_ref(node) as usize).sum::<usize>();
+    assert_eq!(address_sum(node_vector), expected);
+    assert!(node_vector.iter_borrowed().eq(node_vector.as_slice().iter()));
+    assert_eq!(NodeVector::with_capacity(0).iter_borrowed().count(), 0);
+  }
+
+  #[test]
+  fn test_with_capacity_in_local_allocator() {
+    use crate::dag_node::allocator::storage_allocator::{acquire_storage_allocator, StorageAllocator};
+
+    let _heap = lock_global_heap();
+    let mut nodes: [DagNode; 3] = Default::default();
+    let global_in_use = acquire_storage_allocator().storage_in_use();
+
+    let mut storage_allocator = StorageAllocator::new();
+    let node_vector = NodeVector::with_capacity_in(&mut storage_allocator, 4);
+    for node in nodes.iter_mut() {
+      node_vector.push(node).unwrap();
+    }
+    assert_eq!(node_vector.len(), 3);
+    assert_eq!(node_vector[2], std::ptr::from_mut(&mut nodes[2]));
+    assert!(storage_allocator.contains(node_vector.data_ptr().cast(), node_vector.capacity_bytes()));
+    assert_eq!(storage_allocator.storage_in_use(), size_of::<NodeVector>() + node_vector.capacity_bytes());
+    assert_eq!(acquire_storage_allocator().storage_in_use(), global_in_use);
+  }
+
+  #[test]
+  fn test_resize() {
+    let _heap = lock_global_heap();
+    let mut nodes: [DagNode; 3] = Default::default();
+    let [first, fill, other]: [DagNodePtr; 3] = nodes.each_mut().map(std::ptr::from_mut);
+
+    let node_vector = NodeVector::with_capacity(2);
+    node_vector.push(first).unwrap();
+    let old_data = node_vector.data_ptr();
+
+    // Growing past the capacity moves the elements.
+    node_vector.resize(5, fill).unwrap();
+    assert_eq!(node_vector.as_slice(), &[first, fill, fill, fill, fill]);
+    assert_eq!(node_vector.capacity(), 5);
+    assert_ne!(node_vector.data_ptr(), old_data);
+    assert!(node_vector.push(other).is_err());
+
+    node_vector.resize(2, null_mut()).unwrap();
+    assert_eq!(node_vector.as_slice(), &[first, fill]);
+    assert_eq!(node_vector.capacity(), 5);
+
+    // Growing within the capacity stays in place.
+    let data = node_vector.data_ptr();
+    node_vector.resize(4, other).unwrap();
+    assert_eq!(node_vector.as_slice(), &[first, fill, other, other]);
+    assert_eq!(node_vector.data_ptr(), data);
+
+    let empty = NodeVector::with_capacity(0);
+    empty.resize(3, fill).unwrap();
+    assert_eq!(empty.as_slice(), &[fill, fill, fill]);
+    assert!(empty.resize(usize::MAX, fill).is_err());
+  }
+}
@@ -8,6 +8,8 @@ The Bucket allocator manages memory by organizing it into buckets, each containi
 
 The garbage collection process in the bucket allocator follows a mark-and-sweep pattern with a copying strategy. During the mark phase, the allocator traverses the live data and copies it to available initially empty buckets (i.e. buckets which were empty prior to garbage collection). If the available buckets do not have enough space to accommodate the live objects, new buckets are allocated and added to the list. Once the objects are copied, the old memory locations are free to be collected in the sweep phase.
 
+Allocations of at least `LARGE_OBJECT_SIZE` bytes, such as the argument vector of a flattened associative operator with thousands of arguments, are not put in buckets. Each gets storage of its own that collections never copy: live large objects are kept where they are during the mark phase, and the rest are freed in the sweep phase.
+
 In the sweep phase, the allocator clears the old buckets, resetting their free space to the full bucket size. These buckets are then moved to the unused list and reset to an empty state, making them available for future allocations.
 
 Because live objects are relocated during garbage collection to previously empty buckets, there is no fragmentation after garbage collection. What's more, copying occurs in depth-first order on the graph nodes, improving locality for certain access patterns.
@@ -16,6 +18,7 @@ Because live objects are relocated during garbage collection to previously empty
 
 use std::{
   cmp::max,
+  collections::BTreeMap,
   sync::{Mutex, MutexGuard},
   ptr::NonNull
 };
@@ -24,7 +27,7 @@ use once_cell::sync::Lazy;
 
 use crate::{
   dag_node::{
-    allocator::bucket::Bucket,
+    allocator::{acquire_lock, bucket::Bucket, node_allocator::acquire_node_allocator},
     Void
   }
 };
@@ -32,22 +35,115 @@ use crate::{
 
 const BUCKET_MULTIPLIER    : usize = 8;              // To determine bucket size for huge allocations
 const MIN_BUCKET_SIZE      : usize = 256 * 1024 - 8; // Bucket size for normal allocations
+const LARGE_OBJECT_SIZE    : usize = 32 * 1024;      // Allocations at least this large get storage of their own
 const INITIAL_TARGET       : usize = 220 * 1024;     // Just under 8/9 of MIN_BUCKET_SIZE
-const TARGET_MULTIPLIER    : usize = 8;
+const TARGET_MULTIPLIER    : usize = 8;              // Default multiple of the live storage a collection targets
+const LOW_USAGE_DIVISOR    : usize = 2;              // Usage is low when the target it needs is at most this fraction
+const LOW_USAGE_COLLECTIONS: u32   = 3;              // Consecutive low usage collections before the target decays
 
 static GLOBAL_STORAGE_ALLOCATOR: Lazy<Mutex<StorageAllocator>> = Lazy::new(|| {
   Mutex::new(StorageAllocator::new())
 });
 
 
+/// Acquire the global storage allocator according to the `LockPolicy`.
 pub fn acquire_storage_allocator()  -> MutexGuard<'static, StorageAllocator> {
-  GLOBAL_STORAGE_ALLOCATOR.lock().unwrap()
+  acquire_lock(&GLOBAL_STORAGE_ALLOCATOR, "storage allocator", "acquire_storage_allocator")
+}
+
+/// Sets the most bytes the global allocators' arenas and bucket storage may hold together, enforced by
+/// `try_allocate_dag_node` and `try_allocate_storage`. The other allocation functions still grow the heap past it.
+pub fn set_max_total_bytes(max_total_bytes: Option<usize>) {
+  acquire_storage_allocator().set_max_total_bytes(max_total_bytes);
+}
+
+/// Allocates bucket storage like `StorageAllocator::allocate_storage`, but fails instead of growing the arenas and
+/// bucket storage together past the ceiling set by `set_max_total_bytes`.
+pub fn try_allocate_storage(bytes_needed: usize) -> Result<*mut Void, String> {
+  // Read before taking the storage allocator, as the node allocator must never be acquired while it is held.
+  let arena_bytes = acquire_node_allocator("try_allocate_storage").arena_bytes();
+  acquire_storage_allocator().try_allocate_storage(bytes_needed, arena_bytes)
+}
+
+/// The number of bytes of bucket storage that can be allocated before the global storage allocator asks for a
+/// collection.
+pub fn bytes_until_gc() -> usize {
+  acquire_storage_allocator().bytes_until_gc()
+}
+
+/// The number of allocations the global storage allocator served from a bucket already in use. See
+/// `StorageAllocator::bucket_fast_hits`.
+pub fn bucket_fast_hits() -> usize {
+  acquire_storage_allocator().bucket_fast_hits()
+}
+
+/// The number of allocations for which the global storage allocator had to find another bucket. See
+/// `StorageAllocator::bucket_slow_allocs`.
+pub fn bucket_slow_allocs() -> usize {
+  acquire_storage_allocator().bucket_slow_allocs()
+}
+
+/// Sets the amount of bucket storage the global storage allocator hands out before it first asks for a collection.
+/// See `StorageAllocator::set_initial_target`.
+pub fn set_initial_storage_target(target: usize) -> Result<(), String> {
+  acquire_storage_allocator().set_initial_target(target)
+}
+
+/// Sets the multiple of the live storage that the global storage allocator targets. See
+/// `StorageAllocator::set_target_multiplier`.
+pub fn set_storage_target_multiplier(multiplier: usize) -> Result<(), String> {
+  acquire_storage_allocator().set_target_multiplier(multiplier)
+}
+
+/// The size and fill of every bucket of the global storage allocator. See `StorageAllocator::bucket_report`.
+pub fn bucket_report() -> Vec<BucketInfo> {
+  acquire_storage_allocator().bucket_report()
+}
+
+/// The combined size of the global storage allocator's unused buckets. See `StorageAllocator::unused_bucket_bytes`.
+pub fn unused_bucket_bytes() -> usize {
+  acquire_storage_allocator().unused_bucket_bytes()
+}
+
+/// The combined size of the global storage allocator's buckets in use. See `StorageAllocator::in_use_bucket_bytes`.
+pub fn in_use_bucket_bytes() -> usize {
+  acquire_storage_allocator().in_use_bucket_bytes()
+}
+
+/// Replaces the global storage allocator with a fresh one, dropping the old one. Test only.
+#[cfg(test)]
+pub(crate) fn reset_global_storage_allocator() {
+  *acquire_storage_allocator() = StorageAllocator::new();
+}
+
+#[cfg(test)]
+thread_local! {
+  /// Number of buckets this thread has freed.
+  static FREED_BUCKETS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// The size and fill of one bucket, as listed by `StorageAllocator::bucket_report`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct BucketInfo {
+  /// The size of the bucket.
+  pub nr_bytes   : usize,
+  /// The bytes not yet allocated from the bucket.
+  pub bytes_free : usize,
+  /// The fraction of the bucket that is allocated.
+  pub utilization: f64,
+  /// Whether the bucket is in use, as opposed to on the unused list, whose buckets are entirely free.
+  pub in_use     : bool,
+}
+
+/// The storage of one allocation of at least `LARGE_OBJECT_SIZE` bytes, which collections keep in place.
+struct LargeObject {
+  data: Box<[usize]>,
+  kept: bool, // Found live during the current mark phase
 }
 
 pub struct StorageAllocator {
   // General settings
   show_gc   : bool, // Do we report GC stats to user
-  early_quit: u64,  // Do we quit early for profiling purposes
 
   need_to_collect_garbage: bool,
 
@@ -55,31 +151,54 @@ pub struct StorageAllocator {
   bucket_count  : u32,    // Total number of buckets
   bucket_list   : Option<NonNull<Bucket>>, // Linked list of "in use" buckets
   unused_list   : Option<NonNull<Bucket>>, // Linked list of unused buckets
+  old_bucket_list: Option<NonNull<Bucket>>, // Buckets that were in use when the mark phase started
+  large_objects : BTreeMap<usize, LargeObject>, // Large objects by start address
   storage_in_use: usize,  // Amount of bucket storage in use (bytes)
   total_bytes_allocated: usize,  // Total amount of bucket storage (bytes)
   old_storage_in_use   : usize, // A temporary to remember storage use prior to GC.
   target        : usize,  // Amount to use before GC (bytes)
+  low_usage_collections: u32, // Consecutive collections after which storage use was well below the target
+  target_multiplier    : usize, // Multiple of the live storage that a collection sets the target to
+
+  max_total_bytes: Option<usize>, // Ceiling on arenas and buckets together for the `try_*` allocations
+
+  // Statistics
+  bucket_fast_hits  : usize, // Allocations served from a bucket already in use
+  bucket_slow_allocs: usize, // Allocations that had to take an unused bucket or create a new one
 }
 
 // Access is hidden behind a mutex.
 unsafe impl Send for StorageAllocator {}
 // unsafe impl Sync for Allocator {}
 
+impl Default for StorageAllocator {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 impl StorageAllocator {
   pub fn new() -> Self {
     StorageAllocator {
       show_gc       : true,
-      early_quit    : 0,
 
       need_to_collect_garbage: false,
 
       bucket_count: 0,
       bucket_list   : None,
       unused_list   : None,
+      old_bucket_list: None,
+      large_objects : BTreeMap::new(),
       storage_in_use: 0,
       total_bytes_allocated: 0,
       old_storage_in_use   : 0,
       target        : INITIAL_TARGET,
+      low_usage_collections: 0,
+      target_multiplier    : TARGET_MULTIPLIER,
+      max_total_bytes: None,
+
+      bucket_fast_hits  : 0,
+      bucket_slow_allocs: 0,
     }
   }
 
@@ -89,6 +208,264 @@ impl StorageAllocator {
     self.need_to_collect_garbage
   }
 
+  /// Amount of bucket storage in use (bytes).
+  #[inline(always)]
+  pub fn storage_in_use(&self) -> usize {
+    self.storage_in_use
+  }
+
+  /// The number of bytes that can be allocated before the allocator asks for a collection.
+  #[inline(always)]
+  pub fn bytes_until_gc(&self) -> usize {
+    self.target.saturating_sub(self.storage_in_use)
+  }
+
+  /// Total amount of bucket storage, in use or not, including large objects (bytes).
+  #[inline(always)]
+  pub fn total_bytes_allocated(&self) -> usize {
+    self.total_bytes_allocated
+  }
+
+  /// The fraction of all bucket storage that is in use, 0 if there are no buckets. Right after a collection this
+  /// measures how well bucket sizing fits the live data, the copying leaving no fragmentation within buckets.
+  pub fn bucket_utilization(&self) -> f64 {
+    match self.total_bytes_allocated {
+      0     => 0.0,
+      total => self.storage_in_use as f64 / total as f64,
+    }
+  }
+
+  /// Lists the in use buckets, newest first, followed by the unused buckets. Mostly empty buckets right after a
+  /// collection mean the buckets are larger than the live data needs. Large objects are not in buckets and are not
+  /// listed.
+  pub fn bucket_report(&self) -> Vec<BucketInfo> {
+    let mut report = Vec::with_capacity(self.bucket_count as usize);
+    for (list, in_use) in [(self.bucket_list, true), (self.unused_list, false)] {
+      let mut maybe_bucket = list;
+      while let Some(bucket) = maybe_bucket {
+        let bucket   = unsafe { bucket.as_ref() };
+        let nr_bytes = bucket.data.len();
+        report.push(BucketInfo {
+          nr_bytes,
+          bytes_free : bucket.bytes_free,
+          utilization: (nr_bytes - bucket.bytes_free) as f64 / nr_bytes as f64,
+          in_use,
+        });
+        maybe_bucket = bucket.next_bucket;
+      }
+    }
+
+    report
+  }
+
+  /// The combined size of the buckets on the unused list, which hold no live data. Right after a collection this is
+  /// the part of `total_bytes_allocated` sitting idle until allocation needs it again.
+  pub fn unused_bucket_bytes(&self) -> usize {
+    Self::list_bytes(self.unused_list)
+  }
+
+  /// The combined size of the buckets in use, allocated from or not. Large objects are not counted.
+  pub fn in_use_bucket_bytes(&self) -> usize {
+    Self::list_bytes(self.bucket_list)
+  }
+
+  /// The combined size of the buckets on the list starting at `list`.
+  fn list_bytes(list: Option<NonNull<Bucket>>) -> usize {
+    let mut bytes        = 0;
+    let mut maybe_bucket = list;
+    while let Some(bucket) = maybe_bucket {
+      let bucket   = unsafe { bucket.as_ref() };
+      bytes       += bucket.data.len();
+      maybe_bucket = bucket.next_bucket;
+    }
+
+    bytes
+  }
+
+  /// The number of allocations served from a bucket already in use.
+  #[inline(always)]
+  pub fn bucket_fast_hits(&self) -> usize {
+    self.bucket_fast_hits
+  }
+
+  /// The number of allocations that found no room in the buckets in use and had to take an unused bucket or create a
+  /// new one. If this is a large fraction of `bucket_fast_hits`, buckets are too small for the allocation pattern.
+  #[inline(always)]
+  pub fn bucket_slow_allocs(&self) -> usize {
+    self.bucket_slow_allocs
+  }
+
+  /// Sets the number of bytes of storage in use that triggers the first collection, for programs that expect heavy
+  /// bucket use and would otherwise pay for an early, mostly empty collection. Later targets are computed from the
+  /// live storage as usual. The target may not be below the default of just under 8/9 of `MIN_BUCKET_SIZE`.
+  pub fn set_initial_target(&mut self, target: usize) -> Result<(), String> {
+    if target < INITIAL_TARGET {
+      return Err(format!(
+        "initial storage target {} is below the minimum of {} (8/9 of the minimum bucket size of {})",
+        target,
+        INITIAL_TARGET,
+        MIN_BUCKET_SIZE
+      ));
+    }
+
+    self.target                  = target;
+    self.need_to_collect_garbage = self.storage_in_use > self.target;
+    Ok(())
+  }
+
+  /// The multiple of the live storage that each collection sets the target to.
+  #[inline(always)]
+  pub fn target_multiplier(&self) -> usize {
+    self.target_multiplier
+  }
+
+  /// Sets the multiple of the live storage that each collection sets the target to, 8 by default. A larger multiplier
+  /// means fewer collections of bucket storage and more memory, and a multiplier of 1 asks for a collection as soon as
+  /// anything is allocated past the live storage. Takes effect at the next collection.
+  pub fn set_target_multiplier(&mut self, multiplier: usize) -> Result<(), String> {
+    if multiplier == 0 {
+      return Err("the storage target multiplier must be at least 1".to_string());
+    }
+
+    self.target_multiplier = multiplier;
+    Ok(())
+  }
+
+  /// Makes an independent copy of this allocator and every bucket. The copied storage still holds pointers into the
+  /// original heap. Also returns a map from the start address of each original bucket to its length and the start
+  /// address of its copy, for translating pointers.
+  pub(crate) fn fork(&self) -> (StorageAllocator, BTreeMap<usize, (usize, usize)>) {
+    let mut bucket_map = BTreeMap::new();
+    let mut copy_list  = |list: Option<NonNull<Bucket>>| -> Option<NonNull<Bucket>> {
+      let mut head: Option<NonNull<Bucket>> = None;
+      let mut tail: Option<NonNull<Bucket>> = None;
+      let mut maybe_bucket = list;
+
+      while let Some(bucket) = maybe_bucket {
+        let bucket     = unsafe { bucket.as_ref() };
+        let copy       = bucket.copy();
+        bucket_map.insert(bucket.data.as_ptr() as usize, (bucket.data.len(), copy.data.as_ptr() as usize));
+        let copy       = unsafe { NonNull::new_unchecked(Box::into_raw(Box::new(copy))) };
+        match tail {
+          None           => head = Some(copy),
+          Some(mut tail) => unsafe { tail.as_mut().next_bucket = Some(copy) },
+        }
+        tail         = Some(copy);
+        maybe_bucket = bucket.next_bucket;
+      }
+
+      head
+    };
+
+    let bucket_list = copy_list(self.bucket_list);
+    let unused_list = copy_list(self.unused_list);
+    let mut large_objects = BTreeMap::new();
+    for large_object in self.large_objects.values() {
+      let data = large_object.data.clone();
+      bucket_map.insert(
+        large_object.data.as_ptr() as usize,
+        (size_of_val(&*large_object.data), data.as_ptr() as usize)
+      );
+      large_objects.insert(data.as_ptr() as usize, LargeObject { data, kept: large_object.kept });
+    }
+    let fork = StorageAllocator {
+      show_gc              : self.show_gc,
+      need_to_collect_garbage: self.need_to_collect_garbage,
+      bucket_count         : self.bucket_count,
+      bucket_list,
+      unused_list,
+      old_bucket_list      : None,
+      large_objects,
+      storage_in_use       : self.storage_in_use,
+      total_bytes_allocated: self.total_bytes_allocated,
+      old_storage_in_use   : self.old_storage_in_use,
+      target               : self.target,
+      low_usage_collections: self.low_usage_collections,
+      target_multiplier    : self.target_multiplier,
+      max_total_bytes      : self.max_total_bytes,
+      bucket_fast_hits     : self.bucket_fast_hits,
+      bucket_slow_allocs   : self.bucket_slow_allocs,
+    };
+
+    (fork, bucket_map)
+  }
+
+  /// Whether `bytes` bytes starting at `ptr` lie inside storage allocated from an in use bucket or a large object.
+  pub fn contains(&self, ptr: *const Void, bytes: usize) -> bool {
+    let large_object = self.large_objects.range(..=ptr as usize).next_back();
+    if let Some((&start, large_object)) = large_object {
+      if (ptr as usize).saturating_add(bytes) <= start + size_of_val(&*large_object.data) {
+        return true;
+      }
+    }
+
+    let mut maybe_bucket = self.bucket_list;
+
+    while let Some(bucket) = maybe_bucket {
+      let bucket = unsafe { bucket.as_ref() };
+      if bucket.contains(ptr, bytes) {
+        return true;
+      }
+      maybe_bucket = bucket.next_bucket;
+    }
+
+    false
+  }
+
+  /// Restores the storage accounting to a previously observed value. Used by `NodeAllocator::rollback`.
+  /// The bucket memory itself is not reclaimed until the next garbage collection.
+  pub(crate) fn restore_storage_in_use(&mut self, storage_in_use: usize) {
+    self.storage_in_use          = storage_in_use;
+    self.need_to_collect_garbage = self.storage_in_use > self.target;
+  }
+
+  /// The ceiling on the bytes held by node arenas and bucket storage together, if any.
+  #[inline(always)]
+  pub fn max_total_bytes(&self) -> Option<usize> {
+    self.max_total_bytes
+  }
+
+  pub fn set_max_total_bytes(&mut self, max_total_bytes: Option<usize>) {
+    self.max_total_bytes = max_total_bytes;
+  }
+
+  /// Allocates like `allocate_storage`, unless the storage it would add, together with the storage already held and
+  /// `arena_bytes` of node arenas, exceeds `max_total_bytes`.
+  pub fn try_allocate_storage(&mut self, bytes_needed: usize, arena_bytes: usize) -> Result<*mut Void, String> {
+    if let Some(max_total_bytes) = self.max_total_bytes {
+      let total_bytes = arena_bytes + self.total_bytes_allocated + self.growth_needed(bytes_needed);
+      if total_bytes > max_total_bytes {
+        return Err(format!(
+          "allocating {} bytes would bring the heap to {} bytes, over the ceiling of {}",
+          bytes_needed,
+          total_bytes,
+          max_total_bytes
+        ));
+      }
+    }
+
+    Ok(self.allocate_storage(bytes_needed))
+  }
+
+  /// The bytes of new storage that `allocate_storage(bytes_needed)` would create, 0 if a bucket has room.
+  fn growth_needed(&self, bytes_needed: usize) -> usize {
+    if bytes_needed >= LARGE_OBJECT_SIZE {
+      return bytes_needed;
+    }
+    for list in [self.bucket_list, self.unused_list] {
+      let mut maybe_bucket = list;
+      while let Some(bucket) = maybe_bucket {
+        let bucket = unsafe { bucket.as_ref() };
+        if bucket.has_room(bytes_needed) {
+          return 0;
+        }
+        maybe_bucket = bucket.next_bucket;
+      }
+    }
+
+    max(BUCKET_MULTIPLIER * bytes_needed, MIN_BUCKET_SIZE)
+  }
+
   /// Allocates the given number of bytes using bucket storage.
   pub fn allocate_storage(&mut self, bytes_needed: usize) -> *mut Void {
     assert_eq!(bytes_needed % size_of::<usize>(), 0, "only whole machine words can be allocated");
@@ -98,12 +475,18 @@ impl StorageAllocator {
       self.need_to_collect_garbage = true;
     }
 
+    if bytes_needed >= LARGE_OBJECT_SIZE {
+      return self.allocate_large_object(bytes_needed);
+    }
+
     let mut b = self.bucket_list;
 
     while let Some(mut bucket) = b {
       let bucket = unsafe{ bucket.as_mut() };
 
-      if bucket.bytes_free >= bytes_needed {
+      // The alignment padding must fit too, or the bump would run past the end of the bucket.
+      if bucket.has_room(bytes_needed) {
+        self.bucket_fast_hits += 1;
         return bucket.allocate(bytes_needed);
       }
 
@@ -111,9 +494,36 @@ impl StorageAllocator {
     }
 
     // No space in any bucket, so we need to allocate a new one.
+    self.bucket_slow_allocs += 1;
     unsafe{ self.slow_allocate_storage(bytes_needed) }
   }
 
+  /// Allocates storage of its own for a large object. The caller has already counted it as in use.
+  fn allocate_large_object(&mut self, bytes_needed: usize) -> *mut Void {
+    let mut data = vec![0usize; bytes_needed / size_of::<usize>()].into_boxed_slice();
+    let start    = data.as_mut_ptr().cast::<Void>();
+    self.total_bytes_allocated += bytes_needed;
+    self.large_objects.insert(start as usize, LargeObject { data, kept: false });
+
+    start
+  }
+
+  /// During the mark phase, keeps the large object starting at `ptr` in place instead of copying it, counting it as
+  /// in use. Returns whether `ptr` is the start of a large object. A large object is only counted once however often
+  /// it is kept.
+  pub(crate) fn keep_large_object(&mut self, ptr: *const Void) -> bool {
+    match self.large_objects.get_mut(&(ptr as usize)) {
+      None => false,
+      Some(large_object) => {
+        if !large_object.kept {
+          large_object.kept    = true;
+          self.storage_in_use += size_of_val(&*large_object.data);
+        }
+        true
+      }
+    }
+  }
+
   /// Allocates the given number of bytes by creating more bucket storage.
   unsafe fn slow_allocate_storage(&mut self, bytes_needed: usize) -> *mut u8 {
     #[cfg(feature = "gc_debug")]
@@ -127,7 +537,7 @@ impl StorageAllocator {
     while let Some(mut bucket) = maybe_bucket {
       let bucket_mut = bucket.as_mut();
       
-      if bucket_mut.bytes_free >= bytes_needed {
+      if bucket_mut.has_room(bytes_needed) {
         // Move bucket from unused list to in use list
         
         if let Some(mut prev_bucket) = prev_bucket {
@@ -165,28 +575,79 @@ impl StorageAllocator {
     t
   }
 
-  /// Prepare bucket storage for mark phase of GC
+  /// Prepare bucket storage for mark phase of GC. Live storage is copied into the formerly unused buckets while
+  /// marking, so those become the in use list, and the old in use buckets are set aside until the sweep.
   pub(crate) fn _prepare_to_mark(&mut self) {
     self.old_storage_in_use = self.storage_in_use;
+    self.old_bucket_list    = self.bucket_list;
     self.bucket_list        = self.unused_list;
     self.unused_list        = None;
     self.storage_in_use     = 0;
+    for large_object in self.large_objects.values_mut() {
+      large_object.kept = false;
+    }
 
     self.need_to_collect_garbage = false;
   }
 
+  /// Grows the target to `target_multiplier` times the live storage. If instead the live storage has needed at most
+  /// `1/LOW_USAGE_DIVISOR` of the target for `LOW_USAGE_COLLECTIONS` consecutive collections, as after a spike, then
+  /// at each collection the target decays halfway toward what the live storage needs, but never below
+  /// `INITIAL_TARGET`.
+  fn update_target(&mut self) {
+    let needed_target = max(INITIAL_TARGET, self.target_multiplier.saturating_mul(self.storage_in_use));
+
+    if needed_target >= self.target {
+      self.target                = needed_target;
+      self.low_usage_collections = 0;
+      return;
+    }
+
+    if needed_target.saturating_mul(LOW_USAGE_DIVISOR) <= self.target {
+      self.low_usage_collections += 1;
+    } else if self.low_usage_collections < LOW_USAGE_COLLECTIONS {
+      self.low_usage_collections = 0;
+    }
+    // Once decay has begun, it continues until the target reaches what the live storage needs.
+    if self.low_usage_collections >= LOW_USAGE_COLLECTIONS {
+      self.target = self.target.midpoint(needed_target);
+    }
+  }
+
+  /// Frees every bucket of `list`.
+  unsafe fn free_bucket_list(list: Option<NonNull<Bucket>>) {
+    let mut maybe_bucket = list;
+    while let Some(bucket) = maybe_bucket {
+      let bucket   = Box::from_raw(bucket.as_ptr());
+      maybe_bucket = bucket.next_bucket;
+      #[cfg(test)]
+      FREED_BUCKETS.with(|freed| freed.set(freed.get() + 1));
+    }
+  }
+
   /// Garbage Collection for Buckets, called after mark completes
   pub(crate) unsafe fn _sweep_garbage(&mut self) {
-    let mut maybe_bucket = self.bucket_list;
+    let mut maybe_bucket = self.old_bucket_list.take();
 
-    // Reset all formerly active buckets
-    self.unused_list = maybe_bucket;
+    // Reset all formerly active buckets, which now hold only garbage, and move them to the unused list.
     while let Some(mut bucket) = maybe_bucket {
       let bucket_mut = bucket.as_mut();
       bucket_mut.reset();
-      maybe_bucket = bucket_mut.next_bucket;
+      maybe_bucket           = bucket_mut.next_bucket;
+      bucket_mut.next_bucket = self.unused_list;
+      self.unused_list       = Some(bucket);
     }
-    self.target = max(self.target, TARGET_MULTIPLIER*self.storage_in_use);
+
+    // Free the large objects that were not kept.
+    let mut freed_bytes = 0;
+    self.large_objects.retain(|_, large_object| {
+      if !large_object.kept {
+        freed_bytes += size_of_val(&*large_object.data);
+      }
+      large_object.kept
+    });
+    self.total_bytes_allocated -= freed_bytes;
+    self.update_target();
 
     if self.show_gc {
       println!(
@@ -219,3 +680,237 @@ impl StorageAllocator {
 
 }
 
+impl Drop for StorageAllocator {
+  /// Frees the buckets and large objects of a fork's allocator, or of the global one when a test resets the heap.
+  fn drop(&mut self) {
+    unsafe {
+      StorageAllocator::free_bucket_list(self.bucket_list.take());
+      StorageAllocator::free_bucket_list(self.unused_list.take());
+      StorageAllocator::free_bucket_list(self.old_bucket_list.take());
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use crate::dag_node::allocator::lock_global_heap;
+  use crate::dag_node::allocator::node_vector::NodeVector;
+  use super::{
+    acquire_storage_allocator,
+    bucket_fast_hits,
+    bucket_report,
+    BucketInfo,
+    bucket_slow_allocs,
+    in_use_bucket_bytes,
+    set_initial_storage_target,
+    set_storage_target_multiplier,
+    unused_bucket_bytes,
+    StorageAllocator,
+    INITIAL_TARGET,
+    LOW_USAGE_COLLECTIONS,
+    TARGET_MULTIPLIER
+  };
+
+  /// Runs the bucket side of a collection in which `live_bytes` bytes survive.
+  fn collect_with_live_bytes(storage_allocator: &mut StorageAllocator, live_bytes: usize) {
+    storage_allocator._prepare_to_mark();
+    storage_allocator.allocate_storage(live_bytes);
+    unsafe { storage_allocator._sweep_garbage(); }
+  }
+
+  #[test]
+  fn test_bucket_counters() {
+    let _heap = lock_global_heap();
+    for _ in 0..1000 {
+      NodeVector::with_capacity(4);
+    }
+
+    // Almost every allocation bumps the pointer in the newest bucket.
+    let (fast_hits, slow_allocs) = (bucket_fast_hits(), bucket_slow_allocs());
+    assert_eq!(fast_hits + slow_allocs, 2000);
+    assert!(slow_allocs >= 1);
+    assert!(fast_hits > 100 * slow_allocs);
+  }
+
+  #[test]
+  fn test_target_decays_after_spike() {
+    let mut storage_allocator = StorageAllocator::new();
+    storage_allocator.show_gc = false;
+
+    let spike = 1024 * 1024;
+    collect_with_live_bytes(&mut storage_allocator, spike);
+    let spike_target = storage_allocator.target;
+    assert_eq!(spike_target, TARGET_MULTIPLIER * spike);
+
+    // A brief quiet period does not shrink the target.
+    for _ in 1..LOW_USAGE_COLLECTIONS {
+      collect_with_live_bytes(&mut storage_allocator, 1024);
+      assert_eq!(storage_allocator.target, spike_target);
+    }
+    collect_with_live_bytes(&mut storage_allocator, 1024);
+    assert!(storage_allocator.target < spike_target);
+
+    for _ in 0..32 {
+      collect_with_live_bytes(&mut storage_allocator, 1024);
+    }
+    assert_eq!(storage_allocator.target, INITIAL_TARGET);
+
+    // The next collection is requested long before the spike's target is reached.
+    storage_allocator.allocate_storage(2 * INITIAL_TARGET);
+    assert!(storage_allocator.storage_in_use() < spike_target);
+    assert!(storage_allocator.want_to_collect_garbage());
+  }
+
+  #[test]
+  fn test_target_multiplier() {
+    // Counts the collections while allocating `total` bytes of garbage beside `live` bytes that always survive.
+    let collections_with = |multiplier: usize| {
+      let mut storage_allocator = StorageAllocator::new();
+      storage_allocator.show_gc = false;
+      storage_allocator.set_target_multiplier(multiplier).unwrap();
+
+      let (live, total) = (256 * 1024, 16 * 1024 * 1024);
+      let mut collections = 0;
+      for _ in 0..total / 1024 {
+        storage_allocator.allocate_storage(1024);
+        if storage_allocator.want_to_collect_garbage() {
+          collect_with_live_bytes(&mut storage_allocator, live);
+          collections += 1;
+          assert_eq!(storage_allocator.target, multiplier * live);
+        }
+      }
+      collections
+    };
+
+    let (slow, default) = (collections_with(2), collections_with(TARGET_MULTIPLIER));
+    assert!(slow > 2 * default, "{} collections with multiplier 2, {} by default", slow, default);
+
+    // A huge multiplier saturates the target instead of overflowing.
+    let mut storage_allocator = StorageAllocator::new();
+    storage_allocator.show_gc = false;
+    storage_allocator.set_target_multiplier(usize::MAX).unwrap();
+    collect_with_live_bytes(&mut storage_allocator, 1024);
+    assert_eq!(storage_allocator.target, usize::MAX);
+
+    let _heap = lock_global_heap();
+    assert!(set_storage_target_multiplier(0).is_err());
+    assert_eq!(acquire_storage_allocator().target_multiplier(), TARGET_MULTIPLIER);
+    set_storage_target_multiplier(3).unwrap();
+    assert_eq!(acquire_storage_allocator().target_multiplier(), 3);
+    set_storage_target_multiplier(TARGET_MULTIPLIER).unwrap();
+  }
+
+  #[test]
+  fn test_initial_target() {
+    let _heap = lock_global_heap();
+    assert!(set_initial_storage_target(INITIAL_TARGET - 8).is_err());
+
+    let target = 4 * 1024 * 1024;
+    set_initial_storage_target(target).unwrap();
+
+    let mut storage_allocator = acquire_storage_allocator();
+    while storage_allocator.storage_in_use() + 1024 <= target {
+      storage_allocator.allocate_storage(1024);
+      assert!(!storage_allocator.want_to_collect_garbage());
+    }
+    assert!(storage_allocator.storage_in_use() > INITIAL_TARGET);
+
+    storage_allocator.allocate_storage(1024);
+    assert!(storage_allocator.want_to_collect_garbage());
+  }
+
+  #[test]
+  fn test_bucket_report() {
+    let mut storage_allocator = StorageAllocator::new();
+    storage_allocator.show_gc = false;
+    assert!(storage_allocator.bucket_report().is_empty());
+
+    // The collection moves the live bytes to a new bucket and empties the old one.
+    storage_allocator.allocate_storage(4096);
+    collect_with_live_bytes(&mut storage_allocator, 1024);
+    storage_allocator.allocate_storage(512);
+
+    let report = storage_allocator.bucket_report();
+    assert_eq!(report.len(), storage_allocator.bucket_count as usize);
+    let (in_use, unused): (Vec<&BucketInfo>, Vec<_>) = report.iter().partition(|info| info.in_use);
+    assert_eq!(in_use.len(), 1);
+    assert!(in_use[0].utilization > 0.0);
+    assert_eq!(in_use[0].nr_bytes - in_use[0].bytes_free, storage_allocator.storage_in_use());
+    assert!(!unused.is_empty());
+    for info in unused {
+      assert_eq!(info.bytes_free, info.nr_bytes);
+      assert_eq!(info.utilization, 0.0);
+    }
+
+    let _heap = lock_global_heap();
+    NodeVector::with_capacity(4);
+    assert!(bucket_report().iter().any(|info| info.in_use && info.utilization > 0.0));
+  }
+
+  #[test]
+  fn test_unused_bucket_bytes() {
+    let mut storage_allocator = StorageAllocator::new();
+    storage_allocator.show_gc = false;
+    assert_eq!(storage_allocator.unused_bucket_bytes(), 0);
+
+    storage_allocator.allocate_storage(4096);
+    assert_eq!(storage_allocator.unused_bucket_bytes(), 0);
+    assert_eq!(storage_allocator.in_use_bucket_bytes(), storage_allocator.total_bytes_allocated());
+
+    // The collection copies the live bytes to a new bucket and moves the old one to the unused list.
+    collect_with_live_bytes(&mut storage_allocator, 1024);
+    assert!(storage_allocator.unused_bucket_bytes() > 0);
+    assert!(storage_allocator.in_use_bucket_bytes() >= storage_allocator.storage_in_use());
+    assert_eq!(
+      storage_allocator.unused_bucket_bytes() + storage_allocator.in_use_bucket_bytes(),
+      storage_allocator.total_bytes_allocated()
+    );
+
+    let _heap = lock_global_heap();
+    NodeVector::with_capacity(4);
+    assert_eq!(unused_bucket_bytes(), 0);
+    assert!(in_use_bucket_bytes() > 0);
+  }
+
+  #[test]
+  fn test_drop_frees_buckets() {
+    use super::FREED_BUCKETS;
+    let mut storage_allocator = StorageAllocator::new();
+    storage_allocator.show_gc = false;
+    storage_allocator.allocate_storage(4096);
+    storage_allocator.allocate_storage(super::LARGE_OBJECT_SIZE);
+    // The collection leaves one bucket in use and the old one on the unused list.
+    collect_with_live_bytes(&mut storage_allocator, 1024);
+    assert_eq!(storage_allocator.bucket_count, 2);
+
+    let freed = FREED_BUCKETS.with(|freed| freed.get());
+    drop(storage_allocator);
+    assert_eq!(FREED_BUCKETS.with(|freed| freed.get()), freed + 2);
+  }
+
+  #[test]
+  fn test_allocation_needs_room_for_padding() {
+    let mut storage_allocator = StorageAllocator::new();
+    storage_allocator.show_gc = false;
+    storage_allocator.allocate_storage(8);
+
+    // Leave the bucket room for the payload but not for the padding that realigns `next_free` after it.
+    let mut crafted = storage_allocator.bucket_list.unwrap();
+    let bucket      = unsafe { crafted.as_mut() };
+    bucket.next_free  = bucket.next_free.wrapping_add(1);
+    bucket.bytes_free = 8;
+    assert!(!bucket.has_room(8));
+
+    let slow_allocs = storage_allocator.bucket_slow_allocs;
+    let allocation  = storage_allocator.allocate_storage(8);
+    assert_eq!(storage_allocator.bucket_slow_allocs, slow_allocs + 1);
+    assert_ne!(storage_allocator.bucket_list, Some(crafted));
+    assert_eq!(allocation.align_offset(8), 0);
+
+    let bucket = unsafe { crafted.as_ref() };
+    assert_eq!(bucket.bytes_free, 8);
+    assert!(!bucket.contains(allocation, 8));
+    assert_eq!(storage_allocator.storage_in_use(), 16);
+  }
+}
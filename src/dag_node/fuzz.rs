@@ -0,0 +1,254 @@
+/*!
+
+A single entry point for structured fuzzing of the allocator and DAG operations. A fuzzer generates a sequence of
+`Op`s, `apply` runs it against the global heap, and `verify_heap` then checks what survived. Every `Op` is valid
+whatever its fields hold: node indices are taken modulo the number of nodes in the handle table, and operations
+that cannot apply, like removing a child from a leaf, do nothing.
+
+Compiled only with `--cfg fuzzing`, which `cargo fuzz` sets, and in tests. Under `--cfg fuzzing`, `Op` derives
+`arbitrary::Arbitrary`, so a fuzz target can take a `Vec<Op>` as its input and pass it to `apply`.
+
+*/
+
+use std::collections::HashSet;
+
+use once_cell::sync::Lazy;
+
+use crate::{
+  abstractions::IString,
+  dag_node::{
+    allocator::{acquire_node_allocator, mark_from},
+    deref_node,
+    deref_node_mut,
+    root_container::for_each_root,
+    DagNode,
+    DagNodePtr,
+    RootContainer,
+    RootHandle
+  },
+  symbol::Symbol
+};
+
+/// The symbols of the nodes `apply` allocates, one for each arity, as a node's arity must match its number of
+/// children. They are static so that nodes left in the heap never outlive them.
+static FUZZ_SYMBOLS: Lazy<Vec<Symbol>> =
+  Lazy::new(|| (0..=u8::MAX).map(|arity| Symbol::new(IString::from("fuzz"), arity)).collect());
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(fuzzing, derive(arbitrary::Arbitrary))]
+pub enum Op {
+  /// Allocates a node without children and adds it to the handle table.
+  Allocate,
+  /// Replaces the node `parent` with a node holding its children followed by the node `child`.
+  InsertChild { parent: usize, child: usize },
+  /// Replaces the node `parent` with a node holding its children but the last.
+  RemoveChild { parent: usize },
+  /// Registers the node as a root.
+  Root(usize),
+  /// Drops the node's root, if it has one.
+  Unroot(usize),
+  /// Runs a full collection, after which the handle table keeps only the nodes reachable from its roots.
+  Collect,
+}
+
+struct Handle {
+  node: DagNodePtr,
+  root: Option<RootHandle>,
+}
+
+impl Handle {
+  /// Points the handle at a new node holding `args`, moving its root, if any, to the new node. A node's symbol never
+  /// changes, so a node that gains or loses a child is replaced by a node of the symbol with the new arity, and any
+  /// node that has the old node as a child keeps it.
+  fn replace_node(&mut self, args: &[DagNodePtr]) -> Result<(), String> {
+    let node = DagNode::new(&FUZZ_SYMBOLS[args.len()]);
+    unsafe { deref_node_mut(node) }.insert_children(args.iter().copied())?;
+    self.node = node;
+    if self.root.is_some() {
+      self.root = Some(RootContainer::new(node));
+    }
+    Ok(())
+  }
+}
+
+/// Runs `ops` against the global heap, then verifies the heap. Roots taken by `Op::Root` are dropped on return.
+pub fn apply(ops: &[Op]) -> Result<(), String> {
+  let mut handles: Vec<Handle> = Vec::new();
+
+  for &op in ops {
+    match op {
+      Op::Allocate => {
+        handles.push(Handle { node: DagNode::new(&FUZZ_SYMBOLS[0]), root: None });
+      }
+
+      Op::InsertChild { parent, child } if !handles.is_empty() => {
+        let child    = handles[child % handles.len()].node;
+        let length   = handles.len();
+        let handle   = &mut handles[parent % length];
+        let mut args = unsafe { deref_node(handle.node) }.iter_children().copied().collect::<Vec<_>>();
+        // There is no symbol of greater arity.
+        if args.len() < u8::MAX as usize {
+          args.push(child);
+          handle.replace_node(&args)?;
+        }
+      }
+
+      Op::RemoveChild { parent } if !handles.is_empty() => {
+        let length = handles.len();
+        let handle = &mut handles[parent % length];
+        let args   = unsafe { deref_node(handle.node) }.iter_children().copied().collect::<Vec<_>>();
+        if let Some((_, rest)) = args.split_last() {
+          handle.replace_node(rest)?;
+        }
+      }
+
+      Op::Root(index) if !handles.is_empty() => {
+        let length = handles.len();
+        let handle = &mut handles[index % length];
+        if handle.root.is_none() {
+          handle.root = Some(RootContainer::new(handle.node));
+        }
+      }
+
+      Op::Unroot(index) if !handles.is_empty() => {
+        let length = handles.len();
+        handles[index % length].root = None;
+      }
+
+      Op::Collect => {
+        // Nodes not reachable from a root are freed, so their handles must go first.
+        let reachable = reachable_from(handles.iter().filter(|handle| handle.root.is_some()).map(|h| h.node));
+        handles.retain(|handle| reachable.contains(&handle.node));
+        mark_from(&[]);
+      }
+
+      _ => {}
+    }
+  }
+
+  verify_heap()
+}
+
+/// Checks every node reachable from the registered roots: it must be a node of the global allocator that no
+/// relocating collection left flagged `Copied`, it must hold as many arguments as its symbol declares, and its
+/// argument vector, if it has one, must be no longer than its capacity.
+pub fn verify_heap() -> Result<(), String> {
+  let mut roots = Vec::new();
+  for_each_root(|node| roots.push(node));
+
+  let node_allocator = acquire_node_allocator("verify_heap");
+  for node in reachable_from(roots.into_iter()) {
+    if !node_allocator.contains_node(node) {
+      return Err(format!("reachable node {:p} is not an arena node", node));
+    }
+    let node_ref = unsafe { deref_node(node) };
+    if node_ref.is_copied() {
+      return Err(format!("reachable node {:p} is still flagged as copied", node));
+    }
+    if !node_ref.has_consistent_arity() {
+      return Err(format!(
+        "node {:p} has {} children but its symbol declares {}",
+        node,
+        node_ref.arg_count(),
+        node_ref.declared_arity()
+      ));
+    }
+    if let Some(node_vector) = node_ref.node_vector() {
+      if node_vector.len() > node_vector.capacity() {
+        return Err(format!(
+          "node {:p} has {} children but capacity for {}",
+          node,
+          node_vector.len(),
+          node_vector.capacity()
+        ));
+      }
+    }
+  }
+
+  Ok(())
+}
+
+/// The nodes reachable from `roots`, including the roots.
+fn reachable_from(roots: impl Iterator<Item = DagNodePtr>) -> HashSet<DagNodePtr> {
+  let mut reachable              = HashSet::new();
+  let mut stack: Vec<DagNodePtr> = roots.collect();
+
+  while let Some(node) = stack.pop() {
+    if reachable.insert(node) {
+      stack.extend(unsafe { deref_node(node) }.iter_children());
+    }
+  }
+
+  reachable
+}
+
+
+#[cfg(test)]
+mod tests {
+  use crate::dag_node::{allocator::lock_global_heap, root_count};
+  use super::{apply, Op};
+
+  #[test]
+  fn test_apply_keeps_heap_consistent() {
+    let _heap = lock_global_heap();
+    let ops = [
+      Op::Allocate,
+      Op::Allocate,
+      Op::Allocate,
+      Op::InsertChild { parent: 0, child: 1 },
+      Op::InsertChild { parent: 0, child: 2 },
+      Op::InsertChild { parent: 0, child: 1 },
+      Op::Root(0),
+      Op::Collect,
+      // Everything is reachable from node 0. Once node 0 loses its root, only node 4 survives.
+      Op::Allocate,
+      Op::Allocate,
+      Op::InsertChild { parent: 1, child: 3 },
+      Op::RemoveChild { parent: 0 },
+      Op::RemoveChild { parent: 4 },
+      Op::Root(4),
+      Op::Unroot(0),
+      Op::Collect,
+      // Node 1 gets node 0 as its child, and is rooted twice through the same handle.
+      Op::Allocate,
+      Op::InsertChild { parent: 0, child: 7 },
+      Op::InsertChild { parent: 7, child: 0 },
+      Op::Root(7),
+      Op::Root(7),
+      Op::Collect,
+      Op::Unroot(usize::MAX),
+      Op::Collect,
+    ];
+    assert_eq!(apply(&ops), Ok(()));
+    assert_eq!(root_count(), 0);
+
+    assert_eq!(apply(&[Op::RemoveChild { parent: 3 }, Op::Root(1), Op::Collect]), Ok(()));
+
+    // Removing the second of two children leaves a unary node.
+    let ops = [
+      Op::Allocate,
+      Op::Allocate,
+      Op::InsertChild { parent: 0, child: 1 },
+      Op::InsertChild { parent: 0, child: 1 },
+      Op::RemoveChild { parent: 0 },
+      Op::Root(0),
+      Op::Collect,
+    ];
+    assert_eq!(apply(&ops), Ok(()));
+  }
+
+  #[cfg(fuzzing)]
+  #[test]
+  fn test_apply_arbitrary_ops() {
+    use arbitrary::{Arbitrary, Unstructured};
+
+    let _heap = lock_global_heap();
+    // Scrambled bytes standing in for a fuzzer's input.
+    let bytes     = (0..4096u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect::<Vec<_>>();
+    let mut input = Unstructured::new(&bytes);
+    let ops       = (0..256).map(|_| Op::arbitrary(&mut input)).collect::<Result<Vec<_>, _>>().unwrap();
+    assert!(ops.contains(&Op::Collect));
+    assert_eq!(apply(&ops), Ok(()));
+    assert_eq!(root_count(), 0);
+  }
+}
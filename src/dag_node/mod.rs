@@ -9,7 +9,7 @@ The following compares Maude's `DagNode` to our implementation here.
 
 |                | Maude                                        | mod2lib                     |
 |:---------------|:---------------------------------------------|:----------------------------|
-| size           | Fixed 3 word size (or 6 words?)              | Fixed size struct (3 words) |
+| size           | Fixed 3 word size (or 6 words?)              | Fixed size struct (4 words) |
 | tag            | implicit via vtable pointer                  | enum variant                |
 | flags          | `MemoryInfo` in first word                   | `BitFlags` field            |
 | shared impl    | base class impl                              | enum impl                   |
@@ -20,39 +20,91 @@ The following compares Maude's `DagNode` to our implementation here.
 
 
 mod flags;
+#[cfg(any(fuzzing, test))]
+pub mod fuzz;
 mod node;
 mod root_container;
+mod serialize;
+mod term_tree;
+mod zipper;
 pub mod allocator;
 
 pub use node::*;
 pub use flags::*;
-#[allow(unused_imports)]
-pub use root_container::RootContainer;
+pub use serialize::{read_dag, write_dag_streaming};
+pub use term_tree::{OwnedTerm, TermTree};
+pub use zipper::Zipper;
+pub use root_container::{
+  RootContainer,
+  RootHandle,
+  PermanentRoot,
+  make_permanent,
+  permanent_root_count,
+  register_roots,
+  root_count
+};
+#[cfg(feature = "gc_debug")]
+pub use root_container::validate_roots;
 
 /// A `*mut Void` is a pointer to a `u8`
 pub type Void = u8;
 
+/// The numeric values are part of any serialized format, so they must never change. New variants get new values.
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Default)]
+#[repr(u8)]
 pub enum DagNodeKind {
   #[default]
-  Free = 0,
-  ACU,
-  AU,
-  CUI,
-  Variable,
-  NA,
-  Data,
+  Free     = 0,
+  ACU      = 1,
+  AU       = 2,
+  CUI      = 3,
+  Variable = 4,
+  NA       = 5,
+  Data     = 6,
   // Integer,
   // Float
 }
 
+impl DagNodeKind {
+  /// Every variant, in order of numeric value.
+  pub const ALL: [DagNodeKind; 7] = [
+    DagNodeKind::Free,
+    DagNodeKind::ACU,
+    DagNodeKind::AU,
+    DagNodeKind::CUI,
+    DagNodeKind::Variable,
+    DagNodeKind::NA,
+    DagNodeKind::Data,
+  ];
+
+  /// The stable numeric value of this kind.
+  #[inline(always)]
+  pub fn as_u8(self) -> u8 {
+    self as u8
+  }
+
+  /// The kind with the given numeric value, if there is one.
+  pub fn from_u8(value: u8) -> Option<DagNodeKind> {
+    match value {
+      0 => Some(DagNodeKind::Free),
+      1 => Some(DagNodeKind::ACU),
+      2 => Some(DagNodeKind::AU),
+      3 => Some(DagNodeKind::CUI),
+      4 => Some(DagNodeKind::Variable),
+      5 => Some(DagNodeKind::NA),
+      6 => Some(DagNodeKind::Data),
+      _ => None
+    }
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use crate::{
     dag_node::{
       DagNodeKind,
       flags::DagNodeFlags,
-      node::{DagNode, DagNodeArgument}
+      node::{ArgumentTag, ArgumentWords, DagNode, DAG_NODE_BUDGET, DAG_NODE_WORDS}
     },
     symbol::SymbolPtr
   };
@@ -60,10 +112,39 @@ mod tests {
   #[test]
   fn size_of_dag_node() {
     println!("size of SymbolPtr: {}", size_of::<SymbolPtr>());
-    println!("size of DagNodeArgument: {}", size_of::<DagNodeArgument>());
+    println!("size of ArgumentWords: {}", size_of::<ArgumentWords>());
     println!("size of DagNodeKind: {}", size_of::<DagNodeKind>());
     println!("size of DagNodeFlags: {}", size_of::<DagNodeFlags>());
     println!("size of DagNode: {}", size_of::<DagNode>());
-    assert_eq!(size_of::<DagNode>(), 4 * size_of::<usize>());
+    assert!(
+      size_of::<DagNode>() <= DAG_NODE_BUDGET,
+      "DagNode is {} bytes, over its budget of {} ({} words)",
+      size_of::<DagNode>(),
+      DAG_NODE_BUDGET,
+      DAG_NODE_WORDS
+    );
+    // Each part is no larger than its share of the budget. The arguments' tag shares the last word with `kind` and
+    // `flags`, so that the two children of a `Pair` take no more than two words.
+    assert!(size_of::<SymbolPtr>() <= size_of::<usize>());
+    assert!(size_of::<ArgumentWords>() <= 2 * size_of::<usize>());
+    assert!(size_of::<ArgumentTag>() + size_of::<DagNodeKind>() + size_of::<DagNodeFlags>() <= size_of::<usize>());
+  }
+
+  #[test]
+  fn dag_node_kind_encoding_is_stable() {
+    // Changing any of these breaks serialized data.
+    assert_eq!(DagNodeKind::Free.as_u8(), 0);
+    assert_eq!(DagNodeKind::ACU.as_u8(), 1);
+    assert_eq!(DagNodeKind::AU.as_u8(), 2);
+    assert_eq!(DagNodeKind::CUI.as_u8(), 3);
+    assert_eq!(DagNodeKind::Variable.as_u8(), 4);
+    assert_eq!(DagNodeKind::NA.as_u8(), 5);
+    assert_eq!(DagNodeKind::Data.as_u8(), 6);
+
+    for kind in DagNodeKind::ALL {
+      assert_eq!(DagNodeKind::from_u8(kind.as_u8()), Some(kind));
+    }
+    assert_eq!(DagNodeKind::from_u8(DagNodeKind::ALL.len() as u8), None);
+    assert_eq!(DagNodeKind::from_u8(u8::MAX), None);
   }
 }
@@ -1,16 +1,28 @@
 /*!
 
 `DagNode` is the building block for the Directed Acyclic Graph and is what makes the engine fast. `DagNode`s are small
-(24 bytes == 3 machine words) and garbage collected.
+(`DAG_NODE_WORDS` == 4 machine words, 32 bytes on 64 bit targets) and garbage collected.
 
 */
 
 use std::{
-  cmp::max,
+  cmp::{max, Ordering},
+  collections::{HashMap, HashSet},
   fmt::{Display, Formatter},
+  hash::{DefaultHasher, Hash, Hasher},
+  iter::Enumerate,
   marker::PhantomPinned,
-  ptr::null_mut
+  ptr::null_mut,
+  slice::Iter
 };
+#[cfg(feature = "symbol_stats")]
+use std::sync::Mutex;
+
+#[cfg(feature = "symbol_stats")]
+use once_cell::sync::Lazy;
+#[cfg(feature = "merkle_digest")]
+use sha2::{Digest, Sha256};
+
 use crate::{
   dag_node::{
     flags::{
@@ -18,9 +30,15 @@ use crate::{
       DagNodeFlags
     },
     DagNodeKind,
+    RootContainer,
+    RootHandle,
+    Void,
+    term_tree::{OwnedTerm, TermTree},
     allocator::{
       allocate_dag_node,
       increment_active_node_count,
+      NodeAllocator,
+      StorageAllocator,
       node_vector::{
         NodeVector,
         NodeVectorMutRef
@@ -28,28 +46,171 @@ use crate::{
     }
   },
   symbol::{
+    DisplayOrPlaceholder,
     Symbol,
     SymbolPtr
   },
 };
 
+/// The most machine words a `DagNode` may take: one for the symbol, two for the arguments (room for the two children
+/// of a `Pair`), and one for `kind`, `flags`, and the arguments' tag. `ARENA_SIZE` is derived from the actual node
+/// size, so a smaller node fills arenas with more nodes. The `node_align_*` features pad nodes further, to a multiple
+/// of their alignment. A field that pushes a node past this budget fails to compile.
+pub(crate) const DAG_NODE_WORDS: usize = 4;
+
+/// The bytes a `DagNode` may take: `DAG_NODE_WORDS` words, padded to the alignment the `node_align_*` features set.
+pub(crate) const DAG_NODE_BUDGET: usize = (DAG_NODE_WORDS * size_of::<usize>()).next_multiple_of(align_of::<DagNode>());
+
+const _: () = assert!(
+  size_of::<DagNode>() <= DAG_NODE_BUDGET,
+  "DagNode has outgrown its size budget of DAG_NODE_WORDS words"
+);
+
 /// Public interface uses `Pin`. We need to be able to have multiple references,
 /// and we occasionally need to mutate the node, so we use `*mut DagNode`
 /// instead of `&mut DagNode` or `&DagNode`.
 // ToDo: Should this be `NonNull<*mut DagNode>`?
 pub type DagNodePtr = *mut DagNode;
+/// A node's parent and its index among the parent's children, or `None` for the root of a traversal.
+pub type ParentLink = Option<(DagNodePtr, usize)>;
+
+/// The number of nodes `DagNode::with_kind` and `DagNode::with_args` have allocated, keyed by symbol address, as
+/// `SymbolPtr` is not `Send`.
+#[cfg(feature = "symbol_stats")]
+static SYMBOL_ALLOCATIONS: Lazy<Mutex<HashMap<usize, usize>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[cfg(feature = "symbol_stats")]
+fn count_allocation(symbol: SymbolPtr) {
+  let mut allocations = SYMBOL_ALLOCATIONS.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+  *allocations.entry(symbol as usize).or_default() += 1;
+}
+
+/// The number of nodes allocated for each symbol by `DagNode::with_kind` and `DagNode::with_args`, and so by every
+/// constructor built on them, since the counts were last reset. Only recorded with the `symbol_stats` feature.
+#[cfg(feature = "symbol_stats")]
+pub fn allocations_by_symbol() -> HashMap<SymbolPtr, usize> {
+  SYMBOL_ALLOCATIONS.lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner)
+                    .iter()
+                    .map(|(&symbol, &count)| (symbol as SymbolPtr, count))
+                    .collect()
+}
+
+/// Sets every count returned by `allocations_by_symbol` back to zero.
+#[cfg(feature = "symbol_stats")]
+pub fn reset_allocations_by_symbol() {
+  SYMBOL_ALLOCATIONS.lock().unwrap_or_else(std::sync::PoisonError::into_inner).clear();
+}
+
+/// Dereferences a node pointer, asserting under `debug_assertions` that it is not null.
+///
+/// # Safety
+///
+/// `node` must be non-null and point to a `DagNode` valid for `'a`. Debug builds catch a null `node`, but release
+/// builds do not.
+#[inline(always)]
+pub(crate) unsafe fn deref_node<'a>(node: *const DagNode) -> &'a DagNode {
+  debug_assert!(!node.is_null(), "dereferenced a null DagNode pointer");
+  &*node
+}
+
+/// Mutable version of `deref_node`.
+///
+/// # Safety
+///
+/// See `deref_node`. The caller must also ensure the returned reference is not aliased.
+#[inline(always)]
+pub(crate) unsafe fn deref_node_mut<'a>(node: DagNodePtr) -> &'a mut DagNode {
+  debug_assert!(!node.is_null(), "dereferenced a null DagNode pointer");
+  &mut *node
+}
 
+/// A deterministic total order on terms: by top symbol (`Symbol::compare`), then by `DagNode::structural_hash`, then
+/// by number of arguments, then by the arguments from left to right. Terms compare equal exactly when they are
+/// structurally equal, up to symbols with the same name and arity.
+pub fn canonical_compare(a: DagNodePtr, b: DagNodePtr) -> Ordering {
+  canonical_compare_memoized(a, b, &mut HashMap::new())
+}
+
+/// `canonical_compare` taking the structural hashes from `hashes` and adding those it computes, so that sorting
+/// hashes each node once. Each pair of nodes is compared once, so shared subterms are not compared again.
+fn canonical_compare_memoized(a: DagNodePtr, b: DagNodePtr, hashes: &mut HashMap<*const DagNode, u64>) -> Ordering {
+  let mut compared: HashSet<(DagNodePtr, DagNodePtr)> = HashSet::new();
+  let mut pairs   : Vec<(DagNodePtr, DagNodePtr)>     = vec![(a, b)];
+
+  while let Some((a, b)) = pairs.pop() {
+    // A pair compared before compared equal, or the comparison would have ended there.
+    if a == b || !compared.insert((a, b)) {
+      continue;
+    }
+    let (a, b) = unsafe { (deref_node(a), deref_node(b)) };
+
+    let ordering = a.symbol().compare(b.symbol())
+                    .then_with(|| a.structural_hash_memoized(hashes).cmp(&b.structural_hash_memoized(hashes)))
+                    .then_with(|| a.len().cmp(&b.len()));
+    if ordering.is_ne() {
+      return ordering;
+    }
+    // Pushed in reverse so that the arguments are compared from left to right.
+    pairs.extend(a.iter_children().copied().zip(b.iter_children().copied()).rev());
+  }
+
+  Ordering::Equal
+}
+
+/// The most children a node holds without a `NodeVector`.
+const INLINE_CHILDREN: usize = 2;
+
+/// A node's arguments, as `DagNode::args` reads them and `DagNode::set_args` stores them.
 #[derive(Default)]
 pub enum DagNodeArgument{
   #[default]
   None,
   Single(DagNodePtr),
-  Many(NodeVectorMutRef)
+  /// Exactly two children, held in the node so that binary operators need no bucket storage.
+  Pair([DagNodePtr; 2]),
+  Many(NodeVectorMutRef),
+  /// A node without arguments holding a user supplied word in place of the arguments.
+  UserWord(usize),
+}
+
+/// The variant of `DagNodeArgument` a node holds. It is stored beside `kind` and `flags` rather than in an enum with
+/// the arguments, whose tag would take a third word, so that the two children of a `Pair` fit in the two words of
+/// `ArgumentWords` and the node in `DAG_NODE_WORDS`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[repr(u8)]
+pub(crate) enum ArgumentTag {
+  #[default]
+  None,
+  Single,
+  Pair,
+  Many,
+  UserWord,
+}
+
+/// The payload of a node's arguments, read as the node's `ArgumentTag` says.
+#[derive(Copy, Clone)]
+pub(crate) union ArgumentWords {
+  single   : DagNodePtr,
+  pair     : [DagNodePtr; 2],
+  many     : *mut NodeVector,
+  user_word: usize,
+}
+
+impl Default for ArgumentWords {
+  fn default() -> Self {
+    ArgumentWords { pair: [null_mut(); 2] }
+  }
 }
 
+// Padding each node to the alignment keeps every node of an arena aligned, not only the first.
+#[cfg_attr(feature = "node_align_16", repr(align(16)))]
+#[cfg_attr(feature = "node_align_32", repr(align(32)))]
+#[cfg_attr(feature = "node_align_64", repr(align(64)))]
 pub struct DagNode {
   pub(crate) symbol: SymbolPtr,
-  args:      DagNodeArgument,
+  args:      ArgumentWords,
+  args_tag:  ArgumentTag,
   pub kind:  DagNodeKind,
   pub flags: DagNodeFlags,
 
@@ -65,48 +226,133 @@ impl DagNode {
   }
 
   pub fn with_kind(symbol: SymbolPtr, kind: DagNodeKind) -> DagNodePtr {
-    let node: DagNodePtr = allocate_dag_node();
-    let node_mut         = unsafe { &mut *node };
+    #[cfg(feature = "symbol_stats")]
+    count_allocation(symbol);
+    DagNode::init_with_kind(allocate_dag_node(), symbol, kind, NodeVector::with_capacity)
+  }
+
+  pub fn with_args(symbol: SymbolPtr, args: &[DagNodePtr], kind: DagNodeKind) -> DagNodePtr {
+    #[cfg(feature = "symbol_stats")]
+    count_allocation(symbol);
+    DagNode::init_with_args(allocate_dag_node(), symbol, args, kind, NodeVector::with_capacity)
+  }
+
+  /// Like `new`, but also registers the node as a root, so it survives collection until the handle is dropped.
+  pub fn new_rooted(symbol: SymbolPtr) -> (DagNodePtr, RootHandle) {
+    let node = DagNode::new(symbol);
+    (node, RootContainer::new(node))
+  }
+
+  /// Like `with_args`, but also registers the node as a root. See `DagNode::new_rooted`.
+  pub fn with_args_rooted(
+    symbol: SymbolPtr,
+    args  : &[DagNodePtr],
+    kind  : DagNodeKind
+  ) -> (DagNodePtr, RootHandle) {
+    let node = DagNode::with_args(symbol, args, kind);
+    (node, RootContainer::new(node))
+  }
+
+  /// Makes a node with this node's symbol and kind but the arguments `args`, as when rebuilding a term around new
+  /// children. The new node is unrooted.
+  pub fn with_same_head(&self, args: &[DagNodePtr]) -> DagNodePtr {
+    DagNode::with_args(self.symbol, args, self.kind)
+  }
+
+  /// Like `new`, but takes the node from `allocator` and its argument vector from `storage_allocator` instead of the
+  /// global allocators, so that tests can build terms in an isolated heap and collect it with
+  /// `NodeAllocator::collect_isolated`. The node is not counted in `active_node_count`.
+  pub fn new_in(
+    allocator        : &mut NodeAllocator,
+    storage_allocator: &mut StorageAllocator,
+    symbol           : SymbolPtr
+  ) -> DagNodePtr {
+    DagNode::init_with_kind(
+      allocator.next_free_node(),
+      symbol,
+      DagNodeKind::default(),
+      |capacity| NodeVector::with_capacity_in(storage_allocator, capacity)
+    )
+  }
 
-    let arity = unsafe{ &*symbol }.arity as usize;
+  /// Like `with_args`, but takes the node from `allocator` and its argument vector from `storage_allocator`. See
+  /// `DagNode::new_in`.
+  pub fn with_args_in(
+    allocator        : &mut NodeAllocator,
+    storage_allocator: &mut StorageAllocator,
+    symbol           : SymbolPtr,
+    args             : &[DagNodePtr],
+    kind             : DagNodeKind
+  ) -> DagNodePtr {
+    DagNode::init_with_args(
+      allocator.next_free_node(),
+      symbol,
+      args,
+      kind,
+      |capacity| NodeVector::with_capacity_in(storage_allocator, capacity)
+    )
+  }
+
+  /// Initializes `node`, taking an argument vector, if the symbol's arity needs one, from `with_capacity`. A symbol of
+  /// arity 2 or less needs none, as `insert_child` holds up to two children in the node.
+  fn init_with_kind(
+    node         : DagNodePtr,
+    symbol       : SymbolPtr,
+    kind         : DagNodeKind,
+    with_capacity: impl FnOnce(usize) -> NodeVectorMutRef
+  ) -> DagNodePtr {
+    let node_mut = unsafe { deref_node_mut(node) };
+
+    let arity = unsafe{ &*symbol }.arity() as usize;
 
     node_mut.kind   = kind;
     node_mut.flags  = DagNodeFlags::empty();
     node_mut.symbol = symbol;
-    node_mut.args   = if arity > 1 {
-      DagNodeArgument::Many(NodeVector::with_capacity(arity))
-    } else {
-      DagNodeArgument::None
-    };
+    node_mut.set_args(
+      match arity > INLINE_CHILDREN {
+        true  => DagNodeArgument::Many(with_capacity(arity)),
+        false => DagNodeArgument::None
+      }
+    );
     node
   }
 
-  pub fn with_args(symbol: SymbolPtr, args: &mut Vec<DagNodePtr>, kind: DagNodeKind) -> DagNodePtr {
+  /// Initializes `node` with `args`, taking an argument vector, if one is needed, from `with_capacity`.
+  fn init_with_args(
+    node         : DagNodePtr,
+    symbol       : SymbolPtr,
+    args         : &[DagNodePtr],
+    kind         : DagNodeKind,
+    with_capacity: impl FnOnce(usize) -> NodeVectorMutRef
+  ) -> DagNodePtr {
     assert!(!symbol.is_null());
-    let node: DagNodePtr = { allocate_dag_node() };
-    let node_mut         = unsafe { &mut *node };
+    let node_mut = unsafe { deref_node_mut(node) };
 
     node_mut.kind   = kind;
     node_mut.flags  = DagNodeFlags::empty();
     node_mut.symbol = symbol;
 
-    let arity = unsafe{ &*symbol }.arity as usize;
+    let arity = unsafe{ &*symbol }.arity() as usize;
 
-    if arity > 1 || args.len() > 1 {
-      let capacity = max(arity, args.len());
-      let node_vector = NodeVector::with_capacity(capacity);
+    if arity > INLINE_CHILDREN || args.len() > INLINE_CHILDREN {
+      let capacity    = max(arity, args.len());
+      let node_vector = with_capacity(capacity);
 
       for node in args.iter().cloned() {
         _  = node_vector.push(node);
       }
 
-      node_mut.args = DagNodeArgument::Many(node_vector);
+      node_mut.set_args(DagNodeArgument::Many(node_vector));
+    }
+    else {
+      node_mut.set_args(
+        match args.len() {
+          2 => DagNodeArgument::Pair([args[0], args[1]]),
+          1 => DagNodeArgument::Single(args[0]),
+          _ => DagNodeArgument::None
+        }
+      );
     }
-    else if args.len() == 1 {
-      node_mut.args = DagNodeArgument::Single(args[0]);
-    } else {
-      node_mut.args = DagNodeArgument::None;
-    };
 
     node
   }
@@ -115,33 +361,109 @@ impl DagNode {
 
   // region Accessors
 
+  /// The node's arguments.
+  #[inline(always)]
+  pub(crate) fn args(&self) -> DagNodeArgument {
+    unsafe {
+      match self.args_tag {
+        ArgumentTag::None     => DagNodeArgument::None,
+        ArgumentTag::Single   => DagNodeArgument::Single(self.args.single),
+        ArgumentTag::Pair     => DagNodeArgument::Pair(self.args.pair),
+        ArgumentTag::Many     => DagNodeArgument::Many(&mut *self.args.many),
+        ArgumentTag::UserWord => DagNodeArgument::UserWord(self.args.user_word),
+      }
+    }
+  }
+
+  /// Replaces the node's arguments with `args`.
+  #[inline(always)]
+  pub(crate) fn set_args(&mut self, args: DagNodeArgument) {
+    (self.args_tag, self.args) = match args {
+      DagNodeArgument::None              => (ArgumentTag::None, ArgumentWords::default()),
+      DagNodeArgument::Single(child)     => (ArgumentTag::Single, ArgumentWords { single: child }),
+      DagNodeArgument::Pair(pair)        => (ArgumentTag::Pair, ArgumentWords { pair }),
+      DagNodeArgument::Many(node_vector) => (ArgumentTag::Many, ArgumentWords { many: node_vector }),
+      DagNodeArgument::UserWord(word)    => (ArgumentTag::UserWord, ArgumentWords { user_word: word }),
+    };
+  }
+
+  /// The children held in the node itself rather than in a `NodeVector`, which are none if it has a vector.
+  #[inline(always)]
+  fn inline_children(&mut self) -> &mut [DagNodePtr] {
+    unsafe {
+      match self.args_tag {
+        ArgumentTag::Single => std::slice::from_mut(&mut self.args.single),
+        ArgumentTag::Pair   => &mut self.args.pair,
+        _                   => &mut []
+      }
+    }
+  }
+
+  /// Iterates over the children the node actually holds. A node holding more children inline than its symbol's
+  /// arity, or a `NodeVector` for a symbol of arity below 2, as `with_args` can build, fails an assertion in debug
+  /// builds only. A node still being given its children by `insert_child` may hold fewer than the arity.
   pub fn iter_children(&self) -> std::slice::Iter<'static, DagNodePtr> {
     let arity = self.arity();
-    match &self.args {
-      DagNodeArgument::None => {
-        assert_eq!(arity, 0);
-        [].iter()
-      }
-      DagNodeArgument::Single(node) => {
-        assert_eq!(arity, 1);
+    match self.args() {
+      DagNodeArgument::None
+      | DagNodeArgument::UserWord(_) => [].iter(),
+      DagNodeArgument::Single(_) => {
+        debug_assert!(arity >= 1, "a node of arity {} holds 1 child", arity);
         // Make a fat pointer to the single node and return an iterator to it. This allows `self` to
         // escape the method. Of course, `self` actually points to a `DagNode` that is valid for the
         // lifetime of the program, so even in the event of the GC equivalent of a dangling pointer
         // or use after free, this will be safe. (Strictly speaking, it's probably UB.)
-        let v = unsafe { std::slice::from_raw_parts(node, 1) };
+        let v = unsafe { std::slice::from_raw_parts(&self.args.single, 1) };
+        v.iter()
+      }
+      DagNodeArgument::Pair(_) => {
+        debug_assert!(arity > 1, "a node of arity {} holds 2 children", arity);
+        // Escapes `self` like the `Single(..)` branch.
+        let v = unsafe { std::slice::from_raw_parts(self.args.pair.as_ptr(), 2) };
         v.iter()
       }
       DagNodeArgument::Many(node_vector) => {
-        assert!(arity>1);
-        // We need to allow `self` to escape the method, same as `Single(..)` branch.
-        let node_vector_ptr: *const NodeVector = *node_vector;
-        unsafe{ &*node_vector_ptr }.iter()
+        debug_assert!(arity > 1, "a node of arity {} holds {} children", arity, node_vector.len());
+        #[cfg(feature = "gc_debug")]
+        node_vector.assert_not_tombstone();
+        // The vector lives in bucket storage, so it too can escape the method.
+        let node_vector: &'static NodeVector = node_vector;
+        node_vector.iter()
       }
     }
   }
 
+  /// Iterates over copies of this node's child pointers. Unlike `iter_children`, the iterator borrows `self`, so no
+  /// reference into the node or its `NodeVector` can outlive it. Prefer this unless the iterator must escape.
+  pub fn children_borrowed(&self) -> impl ExactSizeIterator<Item = DagNodePtr> + '_ {
+    let children: &[DagNodePtr] = match self.args() {
+      DagNodeArgument::None
+      | DagNodeArgument::UserWord(_) => &[],
+      DagNodeArgument::Single(_)     => unsafe { std::slice::from_ref(&self.args.single) },
+      DagNodeArgument::Pair(_)       => unsafe { &self.args.pair },
+      DagNodeArgument::Many(node_vector) => {
+        #[cfg(feature = "gc_debug")]
+        node_vector.assert_not_tombstone();
+        let node_vector: &'static NodeVector = node_vector;
+        node_vector.as_slice()
+      }
+    };
+    children.iter().copied()
+  }
+
+  /// Iterates over this node's children together with their positions.
+  pub fn enumerate_children(&self) -> impl Iterator<Item = (usize, DagNodePtr)> {
+    self.iter_children().copied().enumerate()
+  }
+
+  /// The first child satisfying `pred`, with its position.
+  pub fn find_child(&self, pred: impl Fn(DagNodePtr) -> bool) -> Option<(usize, DagNodePtr)> {
+    self.enumerate_children().find(|&(_, child)| pred(child))
+  }
+
   #[inline(always)]
   pub fn symbol(&self) -> &Symbol {
+    debug_assert!(!self.symbol.is_null(), "DagNode has a null symbol");
     unsafe {
       &*self.symbol
     }
@@ -149,123 +471,2154 @@ impl DagNode {
 
   #[inline(always)]
   pub fn arity(&self) -> u8 {
-    self.symbol().arity
+    self.symbol().arity()
   }
 
   #[inline(always)]
   pub fn len(&self) -> usize {
-    match &self.args {
-      DagNodeArgument::None      => 0,
+    match self.args() {
+      DagNodeArgument::None
+      | DagNodeArgument::UserWord(_) => 0,
       DagNodeArgument::Single(_) => 1,
-      DagNodeArgument::Many(v)   => v.len()
+      DagNodeArgument::Pair(_)   => 2,
+      DagNodeArgument::Many(v)   => {
+        #[cfg(feature = "gc_debug")]
+        v.assert_not_tombstone();
+        v.len()
+      }
     }
   }
 
-  pub fn insert_child(&mut self, new_child: DagNodePtr) -> Result<(), String>{
-    match self.args {
+  /// Whether the node holds no arguments.
+  #[inline(always)]
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
 
-      DagNodeArgument::None => {
-        self.args = DagNodeArgument::Single(new_child);
-        Ok(())
+  /// The number of arguments the node actually holds, the same as `len`.
+  #[inline(always)]
+  pub fn arg_count(&self) -> usize {
+    self.len()
+  }
+
+  /// The number of arguments the node's symbol declares, which `with_args` does not enforce.
+  #[inline(always)]
+  pub fn declared_arity(&self) -> usize {
+    self.arity() as usize
+  }
+
+  /// Whether the node holds as many arguments as its symbol declares, or more if it is a flattened `AU` or `ACU`
+  /// node, as made by `flatten_au`.
+  pub fn has_consistent_arity(&self) -> bool {
+    let (arg_count, declared_arity) = (self.arg_count(), self.declared_arity());
+    arg_count == declared_arity || (self.is_variadic() && arg_count > declared_arity)
+  }
+  /// Whether the node may hold more arguments than its symbol declares, as a flattened `AU` or `ACU` node may.
+  #[inline(always)]
+  fn is_variadic(&self) -> bool {
+    matches!(self.kind, DagNodeKind::AU | DagNodeKind::ACU) && self.declared_arity() >= 2
+  }
+
+
+  /// Whether this node has no argument storage at all. Unlike `arity() == 0` or `len() == 0`, this looks at the
+  /// shape of `args`: a node whose vector has room for arguments it has not been given yet is not a leaf, while a
+  /// fresh node of a unary or binary symbol, which will hold its arguments inline, is.
+  #[inline(always)]
+  pub fn is_leaf(&self) -> bool {
+    matches!(self.args_tag, ArgumentTag::None | ArgumentTag::UserWord)
+  }
+
+  /// Checks this node's children against `others` using the supplied equality, short-circuiting on a length
+  /// difference or on the first mismatch.
+  pub fn children_match(&self, others: &[DagNodePtr], eq: impl Fn(DagNodePtr, DagNodePtr) -> bool) -> bool {
+    self.len() == others.len()
+        && self.iter_children().zip(others.iter()).all(|(&child, &other)| eq(child, other))
+  }
+
+  /// Compares this term with `other` down to `depth` levels, where this node is level 1, treating anything deeper as
+  /// equal. A `false` result proves the terms differ, so this is a cheap filter before full structural equality.
+  pub fn eq_to_depth(&self, other: &DagNode, depth: usize) -> bool {
+    if depth == 0 || std::ptr::eq(self, other) {
+      return true;
+    }
+
+    self.symbol().same_as(other.symbol())
+        && self.children_match(
+          other.iter_children().as_slice(),
+          |child, other_child| unsafe { deref_node(child).eq_to_depth(deref_node(other_child), depth - 1) }
+        )
+  }
+
+  /// Whether this term and `other` are the same term, with symbols compared by `Symbol::same_as`, so that terms
+  /// built from separately made symbols of the same name and arity are equal. Each pair of nodes is compared once,
+  /// so shared subterms are not compared again.
+  pub fn structural_eq(&self, other: &DagNode) -> bool {
+    let mut compared: HashSet<(*const DagNode, *const DagNode)> = HashSet::new();
+    let mut pairs   : Vec<(*const DagNode, *const DagNode)>     = vec![(self, other)];
+
+    while let Some((node, other_node)) = pairs.pop() {
+      // A pair compared before was equal, or the comparison would have ended there.
+      if node == other_node || !compared.insert((node, other_node)) {
+        continue;
       }
+      let (node, other_node) = unsafe { (deref_node(node), deref_node(other_node)) };
 
-      DagNodeArgument::Single(first_child) => {
-        let vec   = NodeVector::from_slice(&[first_child, new_child]);
-        self.args = DagNodeArgument::Many(vec);
-        Ok(())
+      if !node.symbol().same_as(other_node.symbol()) || node.len() != other_node.len() {
+        return false;
       }
+      pairs.extend(
+        node.iter_children()
+            .zip(other_node.iter_children())
+            .map(|(&child, &other_child)| (child.cast_const(), other_child.cast_const()))
+      );
+    }
 
-      DagNodeArgument::Many(ref mut vec) => {
-        vec.push(new_child)
+    true
+  }
+
+  /// Groups this node's children by their top symbol, in the order they occur. Symbols are compared by identity,
+  /// so the symbols must be interned.
+  pub fn children_by_symbol(&self) -> HashMap<SymbolPtr, Vec<DagNodePtr>> {
+    let mut groups: HashMap<SymbolPtr, Vec<DagNodePtr>> = HashMap::new();
+
+    for &child in self.iter_children() {
+      let symbol = unsafe { (*child).symbol };
+      groups.entry(symbol).or_default().push(child);
+    }
+
+    groups
+  }
+
+  /// Computes the immediate dominator of every node reachable from this one: the closest node through which every
+  /// path from this node passes. A node whose immediate dominator is its only parent is used by nothing else in the
+  /// term and can be mutated in place. This node is mapped to itself. Uses the iterative algorithm of Cooper, Harvey,
+  /// and Kennedy over the reverse postorder.
+  pub fn dominators(&self) -> HashMap<DagNodePtr, DagNodePtr> {
+    let root: DagNodePtr = std::ptr::from_ref(self).cast_mut();
+
+    // Number the nodes in postorder, recording the parents of each.
+    let mut postorder: Vec<DagNodePtr>                      = Vec::new();
+    let mut numbers  : HashMap<DagNodePtr, usize>           = HashMap::new();
+    let mut parents  : HashMap<DagNodePtr, Vec<DagNodePtr>> = HashMap::new();
+    let mut visited  : HashSet<DagNodePtr>                  = HashSet::from([root]);
+    let mut path     : Vec<(DagNodePtr, usize)>             = vec![(root, 0)];
+
+    while let Some((node, next_child)) = path.last_mut() {
+      let node_ref = unsafe { deref_node(*node) };
+      match node_ref.iter_children().nth(*next_child) {
+        Some(&child) => {
+          *next_child += 1;
+          parents.entry(child).or_default().push(*node);
+          if visited.insert(child) {
+            path.push((child, 0));
+          }
+        }
+        None => {
+          numbers.insert(*node, postorder.len());
+          postorder.push(*node);
+          path.pop();
+        }
+      }
+    }
+
+    // `idom[n]` is the current estimate of the immediate dominator of the node numbered `n`.
+    let root_number = postorder.len() - 1;
+    let mut idom: Vec<Option<usize>> = vec![None; postorder.len()];
+    idom[root_number] = Some(root_number);
+
+    let intersect = |idom: &[Option<usize>], mut first: usize, mut second: usize| {
+      while first != second {
+        while first < second {
+          first = idom[first].unwrap();
+        }
+        while second < first {
+          second = idom[second].unwrap();
+        }
       }
+      first
+    };
 
+    let mut changed = true;
+    while changed {
+      changed = false;
+      for number in (0..root_number).rev() {
+        let mut new_idom: Option<usize> = None;
+        for parent in &parents[&postorder[number]] {
+          let parent = numbers[parent];
+          if idom[parent].is_none() {
+            continue;
+          }
+          new_idom = Some(match new_idom {
+            None           => parent,
+            Some(new_idom) => intersect(&idom, parent, new_idom),
+          });
+        }
+        if new_idom != idom[number] {
+          idom[number] = new_idom;
+          changed      = true;
+        }
+      }
     }
+
+    postorder.iter()
+             .zip(idom)
+             .map(|(&node, dominator)| (node, postorder[dominator.unwrap()]))
+             .collect()
   }
 
-  // endregion
+  /// Counts the references to every node reachable from this one from the other nodes reachable from it. This node
+  /// has in-degree 0, and a node with in-degree 1 is uniquely owned within the term. A parent using a child twice,
+  /// as in `f(a, a)`, counts twice.
+  pub fn in_degrees(&self) -> HashMap<DagNodePtr, usize> {
+    let root: DagNodePtr = std::ptr::from_ref(self).cast_mut();
+    let mut in_degrees: HashMap<DagNodePtr, usize> = HashMap::from([(root, 0)]);
+    let mut stack     : Vec<DagNodePtr>            = vec![root];
 
-  // region GC related methods
-  #[inline(always)]
-  pub fn is_marked(&self) -> bool {
-    self.flags.contains(DagNodeFlag::Marked)
+    while let Some(node) = stack.pop() {
+      for &child in unsafe { deref_node(node) }.iter_children() {
+        let in_degree = in_degrees.entry(child).or_insert(0);
+        *in_degree += 1;
+        // Visit each node's children only once.
+        if *in_degree == 1 {
+          stack.push(child);
+        }
+      }
+    }
+
+    in_degrees
   }
 
-  #[inline(always)]
-  pub fn needs_destruction(&self) -> bool {
-    // self.flags.contains(DagNodeFlag::NeedsDestruction)
-    match self.args {
-      DagNodeArgument::None
-      | DagNodeArgument::Single(_) => false,
-      DagNodeArgument::Many(_) => true,
+  /// The number of nodes in the longest chain of this term in which each node has exactly one child, the child
+  /// being the next node of the chain. Such chains are what make recursive walks like `mark` and `print_tree` deep, so
+  /// a large result is a reason to prefer the iterative paths. Shared subterms are measured once.
+  pub fn max_chain_length(&self) -> usize {
+    let root: DagNodePtr = std::ptr::from_ref(self).cast_mut();
+    // The length of the chain starting at each node measured so far.
+    let mut lengths: HashMap<DagNodePtr, usize> = HashMap::new();
+    let mut stack  : Vec<DagNodePtr>            = vec![root];
+    let mut longest = 0;
+
+    while let Some(node) = stack.pop() {
+      if lengths.contains_key(&node) {
+        continue;
+      }
+
+      // Follow the chain down to its end or to a node already measured, then measure the chain on the way back up.
+      let mut chain: Vec<DagNodePtr> = Vec::new();
+      let mut next = node;
+      let mut length = loop {
+        if let Some(&length) = lengths.get(&next) {
+          break length;
+        }
+        let next_ref = unsafe { deref_node(next) };
+        if next_ref.len() != 1 {
+          lengths.insert(next, 0);
+          stack.extend(next_ref.iter_children());
+          break 0;
+        }
+        chain.push(next);
+        next = *next_ref.iter_children().next().unwrap();
+      };
+      for &link in chain.iter().rev() {
+        length += 1;
+        lengths.insert(link, length);
+      }
+      longest = max(longest, length);
     }
+
+    longest
   }
 
-  #[inline(always)]
-  pub fn simple_reuse(&self) -> bool {
-    !self.flags.contains(DagNodeFlag::Marked) && !self.needs_destruction()
+  /// Lists the subterms of this term in post-order, children left to right before their parent, each with its
+  /// parent and its index among the parent's children. This node comes last with no parent. A shared subterm is
+  /// listed once per occurrence, so the list is as long as the term is as a tree.
+  pub fn post_order_with_parents(&self) -> Vec<(DagNodePtr, ParentLink)> {
+    let root: DagNodePtr = std::ptr::from_ref(self).cast_mut();
+    let mut order: Vec<(DagNodePtr, ParentLink)>        = Vec::new();
+    // Each entry is a node, its parent link, and the number of its children already listed.
+    let mut stack: Vec<(DagNodePtr, ParentLink, usize)> = vec![(root, None, 0)];
+
+    while let Some((node, _, next_child)) = stack.last_mut() {
+      let node = *node;
+      if let Some(&child) = unsafe { deref_node(node) }.iter_children().nth(*next_child) {
+        let index = *next_child;
+        *next_child += 1;
+        stack.push((child, Some((node, index)), 0));
+      } else {
+        let (node, parent, _) = stack.pop().unwrap();
+        order.push((node, parent));
+      }
+    }
+
+    order
   }
 
-  #[inline(always)]
-  pub fn mark(&'static mut self) {
-    if self.flags.contains(DagNodeFlag::Marked) {
-      return;
+  /// Lists each distinct variable of this term, in the order of first occurrence from left to right, with the
+  /// position of every occurrence as the path of child indices from this node. A variable is identified by its
+  /// symbol, compared by address, so distinct symbols that share a name are distinct variables. As in
+  /// `post_order_with_parents`, a shared subterm contributes one set of positions per occurrence.
+  pub fn variables(&self) -> Vec<(SymbolPtr, Vec<Vec<usize>>)> {
+    let mut variables: Vec<(SymbolPtr, Vec<Vec<usize>>)> = Vec::new();
+    // The index of each variable in `variables`.
+    let mut indices  : HashMap<SymbolPtr, usize>         = HashMap::new();
+    let mut stack    : Vec<(&DagNode, Vec<usize>)>       = vec![(self, Vec::new())];
+
+    while let Some((node, path)) = stack.pop() {
+      if node.symbol().is_variable() {
+        let index = *indices.entry(node.symbol).or_insert_with(|| {
+          variables.push((node.symbol, Vec::new()));
+          variables.len() - 1
+        });
+        variables[index].1.push(path);
+        continue;
+      }
+      // Pushed in reverse so that children are visited left to right.
+      for (index, &child) in node.iter_children().enumerate().rev() {
+        let mut child_path = path.clone();
+        child_path.push(index);
+        stack.push((unsafe { deref_node(child) }, child_path));
+      }
     }
 
-    increment_active_node_count();
-    self.flags.insert(DagNodeFlag::Marked);
-    
-    let arity = self.arity();
-    
-    match &mut self.args {
+    variables
+  }
 
-      DagNodeArgument::None => { /* pass */ }
+  /// The leaves of this term, the nodes whose symbols have arity 0, from left to right. With `distinct`, a shared
+  /// leaf is listed once, at its first occurrence, and a shared subterm is visited once; otherwise every leaf is
+  /// listed at every occurrence, which takes time proportional to the size of the term as a tree.
+  pub fn leaves(&self, distinct: bool) -> Vec<DagNodePtr> {
+    let mut leaves: Vec<DagNodePtr> = Vec::new();
+    let mut seen  : HashSet<DagNodePtr> = HashSet::new();
+    let mut stack : Vec<&DagNode> = vec![self];
 
-      DagNodeArgument::Single(node) => {
-        if let Some(node) = unsafe { node.as_mut() } {
-          node.mark();
+    while let Some(node) = stack.pop() {
+      let this: DagNodePtr = std::ptr::from_ref(node).cast_mut();
+      if distinct && !seen.insert(this) {
+        continue;
+      }
+      if node.symbol().arity() == 0 {
+        leaves.push(this);
+        continue;
+      }
+      // Pushed in reverse so that children are visited left to right.
+      stack.extend(node.iter_children().rev().map(|&child| unsafe { deref_node(child) }));
+    }
+
+    leaves
+  }
+
+  /// Whether every variable of this term occurs exactly once, so that a matcher need not check that the occurrences
+  /// of a variable match equal subterms. A variable occurring twice through sharing is not linear either.
+  pub fn is_linear(&self) -> bool {
+    self.variables().iter().all(|(_, positions)| positions.len() == 1)
+  }
+
+  /// An owned copy of this term that holds no pointers into the heap, so that it can be sent to another thread and
+  /// rebuilt there with `OwnedTerm::into_dag`. Shared subterms are copied once per occurrence, so a term with heavy
+  /// sharing should go through `TermTree::from_dag`, which bounds the size of the tree, instead.
+  pub fn to_owned_tree(&self) -> OwnedTerm {
+    TermTree::from(self)
+  }
+
+  /// Builds a term in which every occurrence of `target`, compared by address, is replaced by `replacement`. Only the
+  /// nodes on a path from this node to an occurrence are copied, each once, so everything else, including sharing
+  /// among the copies, is kept. Occurrences inside a frozen subterm, other than the frozen subterm itself, are left
+  /// alone. Returns this node itself if `target` does not occur. The new nodes are unrooted.
+  pub fn replace_subterm(&self, target: DagNodePtr, replacement: DagNodePtr) -> DagNodePtr {
+    let mut copies: HashMap<DagNodePtr, DagNodePtr> = HashMap::from([(target, replacement)]);
+
+    for node in self.postorder(|node| !std::ptr::eq(node, target) && !node.is_frozen()) {
+      let node_ref = unsafe { deref_node(node) };
+      if node == target {
+        continue;
+      }
+      if node_ref.is_frozen() {
+        copies.insert(node, node);
+        continue;
+      }
+
+      let args = node_ref.iter_children().map(|child| copies[child]).collect::<Vec<_>>();
+      let copy = match node_ref.iter_children().as_slice() == args.as_slice() {
+        true  => node,
+        false => node_ref.with_same_head(&args),
+      };
+      copies.insert(node, copy);
+    }
+
+    copies[&std::ptr::from_ref(self).cast_mut()]
+  }
+
+  /// Copies every node of the term once, so that sharing in the original is sharing in the copy and the copy has as
+  /// many nodes as the original. Fails once more than `max_nodes` nodes have been made. The new nodes are unrooted.
+  pub fn deep_copy(&self, max_nodes: usize) -> Result<DagNodePtr, String> {
+    self.copy_memoized(&HashMap::new(), true, max_nodes)
+        .map_err(|()| format!("copy exceeds {} nodes", max_nodes))
+  }
+
+  /// Builds the term in which every variable bound in `bindings` is replaced by its binding. As in
+  /// `replace_subterm`, only the nodes above a bound variable are copied, each once, and the bindings are shared
+  /// rather than copied, so a variable occurring many times costs no more than once. Fails once more than
+  /// `max_nodes` nodes have been made. The new nodes are unrooted.
+  pub fn instantiate(&self, bindings: &HashMap<SymbolPtr, DagNodePtr>, max_nodes: usize) -> Result<DagNodePtr, String> {
+    self.copy_memoized(bindings, false, max_nodes)
+        .map_err(|()| format!("instance exceeds {} nodes", max_nodes))
+  }
+
+  /// Copies the nodes above a variable bound in `bindings`, or every node if `copy_all` is set, each once, failing
+  /// once more than `max_nodes` nodes have been made.
+  fn copy_memoized(
+    &self,
+    bindings : &HashMap<SymbolPtr, DagNodePtr>,
+    copy_all : bool,
+    max_nodes: usize
+  ) -> Result<DagNodePtr, ()> {
+    let is_bound = |node: &DagNode| node.symbol().is_variable() && bindings.contains_key(&node.symbol);
+    let mut copies   : HashMap<DagNodePtr, DagNodePtr> = HashMap::new();
+    let mut remaining: usize                           = max_nodes;
+
+    for node in self.postorder(|node| !is_bound(node)) {
+      let node_ref = unsafe { deref_node(node) };
+      if is_bound(node_ref) {
+        copies.insert(node, bindings[&node_ref.symbol]);
+        continue;
+      }
+
+      let args = node_ref.iter_children().map(|child| copies[child]).collect::<Vec<_>>();
+      let copy = match !copy_all && node_ref.iter_children().as_slice() == args.as_slice() {
+        true  => node,
+        false => {
+          remaining = remaining.checked_sub(1).ok_or(())?;
+          node_ref.with_same_head(&args)
         }
+      };
+      copies.insert(node, copy);
+    }
+
+    Ok(copies[&std::ptr::from_ref(self).cast_mut()])
+  }
+
+  /// The nodes reachable from this one, each once and after all of its children, with children taken from left to
+  /// right. The children of a node for which `descend` is false are left out unless reachable another way. The term
+  /// is walked with an explicit stack, so that deep terms cannot overflow the call stack.
+  fn postorder(&self, mut descend: impl FnMut(&DagNode) -> bool) -> Vec<DagNodePtr> {
+    let mut enter = |node: DagNodePtr| {
+      let node_ref = unsafe { deref_node(node) };
+      match descend(node_ref) {
+        true  => (node, node_ref.iter_children()),
+        false => (node, [].iter()),
       }
+    };
 
-      DagNodeArgument::Many(ref mut node_vec) => {
-        
+    let root: DagNodePtr = std::ptr::from_ref(self).cast_mut();
+    let mut postorder: Vec<DagNodePtr>                                           = Vec::new();
+    let mut visited  : HashSet<DagNodePtr>                                       = HashSet::from([root]);
+    let mut path     : Vec<(DagNodePtr, std::slice::Iter<'static, DagNodePtr>)> = vec![enter(root)];
 
-        for node in node_vec.iter() {
-          if let Some(node) = unsafe { node.as_mut() } {
-            node.mark();
-          } else {
-            eprintln!("Bad node found.")
+    while let Some((node, children)) = path.last_mut() {
+      match children.next() {
+        Some(&child) => {
+          if visited.insert(child) {
+            path.push(enter(child));
           }
         }
-
-        if node_vec.capacity() != arity as usize || node_vec.len() > node_vec.capacity() {
-          panic!("Node vector capacity mismatch.")
+        None => {
+          postorder.push(*node);
+          path.pop();
         }
-        
-        // Reallocate
-        *node_vec = node_vec.shallow_copy();
+      }
+    }
+
+    postorder
+  }
+
+  /// A hash of the term's structure, its symbols' names and arities and the shape of its arguments, that does not
+  /// depend on node addresses or sharing. Structurally equal terms have equal hashes.
+  pub fn structural_hash(&self) -> u64 {
+    self.structural_hash_memoized(&mut HashMap::new())
+  }
+
+  fn structural_hash_memoized(&self, hashes: &mut HashMap<*const DagNode, u64>) -> u64 {
+    for node in self.postorder(|node| !hashes.contains_key(&std::ptr::from_ref(node))) {
+      let node = unsafe { deref_node(node) };
+      if hashes.contains_key(&std::ptr::from_ref(node)) {
+        continue;
       }
 
+      let mut hasher = DefaultHasher::new();
+      node.symbol().name.hash(&mut hasher);
+      node.symbol().arity().hash(&mut hasher);
+      node.len().hash(&mut hasher);
+      for &child in node.iter_children() {
+        hashes[&child.cast_const()].hash(&mut hasher);
+      }
+      hashes.insert(node, hasher.finish());
     }
 
+    hashes[&std::ptr::from_ref(self)]
   }
-  //endregion
 
-}
+  /// A SHA-256 digest of the term's structure, over the same data as `structural_hash`. Unlike that hash, it is the
+  /// same in every process and build, so it can serve as a persistent key for the term.
+  #[cfg(feature = "merkle_digest")]
+  pub fn merkle_digest(&self) -> [u8; 32] {
+    let mut digests: HashMap<DagNodePtr, [u8; 32]> = HashMap::new();
 
-impl Display for DagNode {
-  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-    write!(f, "node<{}>", self.symbol())
+    for node in self.postorder(|_| true) {
+      let node_ref = unsafe { deref_node(node) };
+
+      // Every field has a fixed width or a length prefix, so distinct terms never feed the same bytes to the hasher.
+      let name       = node_ref.symbol().name;
+      let mut hasher = Sha256::new();
+      hasher.update((name.len() as u64).to_le_bytes());
+      hasher.update(name.as_bytes());
+      hasher.update([node_ref.symbol().arity()]);
+      hasher.update((node_ref.len() as u64).to_le_bytes());
+      for child in node_ref.iter_children() {
+        hasher.update(digests[child]);
+      }
+      digests.insert(node, hasher.finalize().into());
+    }
+
+    digests[&std::ptr::from_ref(self).cast_mut()]
   }
-}
 
-impl Default for DagNode {
-  fn default() -> Self {
-    DagNode{
-      symbol: null_mut(),
-      args: DagNodeArgument::None,
-      kind: Default::default(),
-      flags: Default::default(),
-      _pin: Default::default(),
+  /// Builds a term in which the arguments of every `ACU` node are sorted by `canonical_compare`, so that terms equal
+  /// modulo commutativity get the same argument order. Nested applications of an associative symbol are not
+  /// flattened. Nodes that are already in canonical form are shared with this term, which is returned itself if it is
+  /// in canonical form throughout. The new nodes are unrooted.
+  pub fn normalize_acu(&self) -> DagNodePtr {
+    let mut normal_forms: HashMap<DagNodePtr, DagNodePtr> = HashMap::new();
+    let mut hashes      : HashMap<*const DagNode, u64>    = HashMap::new();
+
+    for node in self.postorder(|_| true) {
+      let node_ref = unsafe { deref_node(node) };
+      let mut args = node_ref.iter_children().map(|child| normal_forms[child]).collect::<Vec<_>>();
+      if node_ref.kind == DagNodeKind::ACU {
+        args.sort_by(|&a, &b| canonical_compare_memoized(a, b, &mut hashes));
+      }
+      let normal_form = match node_ref.iter_children().as_slice() == args.as_slice() {
+        true  => node,
+        false => node_ref.with_same_head(&args),
+      };
+      normal_forms.insert(node, normal_form);
+    }
+
+    normal_forms[&std::ptr::from_ref(self).cast_mut()]
+  }
+
+  /// Builds a term in which arguments of every `AU` or `ACU` node that are applications of the same symbol with the
+  /// same kind are replaced by their own arguments, so `f(f(a, b), c)` becomes `f(a, b, c)`. Fails if a flattened
+  /// node would get more than `max_args` arguments, which guards against pathological inputs producing enormous
+  /// argument lists. Unchanged nodes are shared with this term. The new nodes are unrooted.
+  pub fn flatten_au(&self, max_args: usize) -> Result<DagNodePtr, String> {
+    let mut flattened_map: HashMap<DagNodePtr, DagNodePtr> = HashMap::new();
+
+    for node in self.postorder(|_| true) {
+      let node_ref    = unsafe { deref_node(node) };
+      let associative = matches!(node_ref.kind, DagNodeKind::AU | DagNodeKind::ACU);
+      let mut args    = Vec::with_capacity(node_ref.len());
+      for child in node_ref.iter_children() {
+        let child     = flattened_map[child];
+        let child_ref = unsafe { deref_node(child) };
+        match associative && child_ref.symbol == node_ref.symbol && child_ref.kind == node_ref.kind {
+          true  => args.extend(child_ref.iter_children()),
+          false => args.push(child),
+        }
+        if args.len() > max_args {
+          return Err(format!(
+            "flattening {} would give more than the maximum of {} arguments",
+            node_ref,
+            max_args
+          ));
+        }
+      }
+
+      let flattened = match node_ref.iter_children().as_slice() == args.as_slice() {
+        true  => node,
+        false => node_ref.with_same_head(&args),
+      };
+      flattened_map.insert(node, flattened);
     }
+
+    Ok(flattened_map[&std::ptr::from_ref(self).cast_mut()])
+  }
+
+  /// Stores a user supplied word (a sort id, a source position, ...) in the otherwise unused argument slot of a
+  /// node without children. It is unavailable for nodes with arguments, and inserting a child discards it.
+  pub fn set_user_word(&mut self, word: usize) -> Result<(), String> {
+    match self.args() {
+      DagNodeArgument::None
+      | DagNodeArgument::UserWord(_) => {
+        self.set_args(DagNodeArgument::UserWord(word));
+        Ok(())
+      }
+      _ => Err(format!("cannot store a user word in node {}, which has arguments", self))
+    }
+  }
+
+  /// The word stored with `set_user_word`, if any.
+  #[inline(always)]
+  pub fn user_word(&self) -> Option<usize> {
+    match self.args() {
+      DagNodeArgument::UserWord(word) => Some(word),
+      _ => None
+    }
+  }
+
+  /// Displays the whole term inline, e.g. `f(g(a), b)`, eliding subterms nested deeper than `max_depth` (the root
+  /// is at depth 0) as `...`. A compound subterm occurring more than once is labeled at its first occurrence and
+  /// referenced by label afterward, e.g. `f(#1=g(a), #1)`, so shared subterms are printed only once.
+  pub fn display_term(&self, max_depth: usize) -> TermDisplay<'_> {
+    TermDisplay {
+      node: self,
+      max_depth
+    }
+  }
+
+  /// Appends `new_child`. The first two children are held in the node itself, and a third is refused unless the node
+  /// was made with a `NodeVector`, as `new` does for symbols of arity greater than 2.
+  pub fn insert_child(&mut self, new_child: DagNodePtr) -> Result<(), String>{
+    if self.is_frozen() {
+      return Err(format!("cannot insert a child into node {}, which is frozen", self));
+    }
+    match self.args() {
+
+      DagNodeArgument::None
+      | DagNodeArgument::UserWord(_) => {
+        self.set_args(DagNodeArgument::Single(new_child));
+        Ok(())
+      }
+
+      DagNodeArgument::Single(first_child) => {
+        self.set_args(DagNodeArgument::Pair([first_child, new_child]));
+        Ok(())
+      }
+
+      DagNodeArgument::Pair(_) => {
+        Err(format!("cannot insert a third child into node {}, which holds two inline", self))
+      }
+
+      DagNodeArgument::Many(vec) => {
+        vec.push(new_child)
+      }
+
+    }
+  }
+
+  /// Appends every node of `children` in order. Fails if the node would hold more children than its symbol's arity,
+  /// unless it is a flattened `AU` or `ACU` node, which takes any number. An iterator whose size hint already
+  /// exceeds the arity is refused before anything is inserted; one that outruns its hint is refused at the first
+  /// child past the arity, leaving the children before it inserted.
+  ///
+  /// Unlike `insert_child`, this grows the node as needed: once the node has more children than it holds inline,
+  /// they move to a `NodeVector` with room for the lower bound of the iterator's size hint, so an iterator that
+  /// reports its length exactly costs one allocation. One that outruns its hint makes the vector double, leaving the
+  /// old storage to the collector.
+  pub fn insert_children(&mut self, children: impl IntoIterator<Item = DagNodePtr>) -> Result<(), String> {
+    if self.is_frozen() {
+      return Err(format!("cannot insert children into node {}, which is frozen", self));
+    }
+    let mut children = children.into_iter();
+    let arity        = self.declared_arity();
+    let variadic     = self.is_variadic();
+
+    if !variadic && self.len() + children.size_hint().0 > arity {
+      return Err(format!(
+        "cannot insert at least {} children into node {} of arity {}, which holds {}",
+        children.size_hint().0,
+        self,
+        arity,
+        self.len()
+      ));
+    }
+
+    while let Some(child) = children.next() {
+      if !variadic && self.len() == arity {
+        return Err(format!("cannot insert a child into node {}, which already holds its arity of {}", self, arity));
+      }
+      let wanted = self.len() + 1 + children.size_hint().0;
+      match self.args() {
+        DagNodeArgument::Many(vec) if vec.len() == vec.capacity() => {
+          self.set_args(DagNodeArgument::Many(vec.copy_with_capacity(max(wanted, 2 * vec.capacity()))));
+        }
+        DagNodeArgument::Many(_) => {}
+        // Room inline.
+        _ if wanted <= INLINE_CHILDREN => {}
+        _ => {
+          let existing = self.children_borrowed().collect::<Vec<_>>();
+          self.set_args(DagNodeArgument::Many(NodeVector::from_slice_with_capacity(&existing, wanted)));
+        }
+      }
+      self.insert_child(child)?;
+    }
+
+    Ok(())
+  }
+
+  /// Removes and returns the last child, undoing `insert_child`: a node left with one child holds it inline.
+  pub fn remove_last_child(&mut self) -> Result<DagNodePtr, String> {
+    if self.is_frozen() {
+      return Err(format!("cannot remove a child from node {}, which is frozen", self));
+    }
+    let arity   = self.arity();
+    let removed = match self.args() {
+      DagNodeArgument::Single(child) => {
+        self.set_args(DagNodeArgument::None);
+        Some(child)
+      }
+      DagNodeArgument::Pair([first, second]) => {
+        self.set_args(DagNodeArgument::Single(first));
+        Some(second)
+      }
+      DagNodeArgument::Many(vec) => {
+        let removed = vec.pop();
+        // The collector expects a vector with room for the symbol's arity, so the node keeps its vector unless the
+        // symbol takes at most one argument.
+        if vec.len() == 1 && arity <= 1 {
+          self.set_args(DagNodeArgument::Single(vec[0]));
+        }
+        removed
+      }
+      DagNodeArgument::None
+      | DagNodeArgument::UserWord(_) => None,
+    };
+    removed.ok_or_else(|| format!("cannot remove a child from node {}, which has none", self))
+  }
+
+  /// Swaps the children at positions `i` and `j` in place. Only nodes with at least two children can be reordered
+  /// this way.
+  pub fn swap_children(&mut self, i: usize, j: usize) -> Result<(), String> {
+    if self.is_frozen() {
+      return Err(format!("cannot swap children of node {}, which is frozen", self));
+    }
+    let children: &mut [DagNodePtr] = match self.args() {
+      DagNodeArgument::Pair(_)  => self.inline_children(),
+      DagNodeArgument::Many(vec) => vec.as_mut_slice(),
+      _ => return Err(format!("cannot swap children of node {}, which has fewer than two argument slots", self))
+    };
+    if i >= children.len() || j >= children.len() {
+      return Err(format!("cannot swap children {} and {} of a node with {} children", i, j, children.len()));
+    }
+    children.swap(i, j);
+    Ok(())
+  }
+
+  /// Makes the subgraph rooted at this node immutable by flagging every node in it `Unrewritable` and `Unstackable`,
+  /// as Maude does for terms that rules must leave alone. Frozen nodes refuse in place changes, and
+  /// `replace_subterm` does not look inside them, so a frozen term can be shared freely. Nodes are never unfrozen.
+  pub fn freeze(&mut self) {
+    let mut stack: Vec<DagNodePtr> = vec![self];
+    while let Some(node) = stack.pop() {
+      let node_mut = unsafe { deref_node_mut(node) };
+      // Everything below a frozen node is frozen already.
+      if node_mut.is_frozen() {
+        continue;
+      }
+      node_mut.flags.insert(DagNodeFlag::Unrewritable | DagNodeFlag::Unstackable);
+      stack.extend(node_mut.iter_children());
+    }
+  }
+
+  /// Whether this node is part of a frozen subgraph. See `DagNode::freeze`.
+  #[inline(always)]
+  pub fn is_frozen(&self) -> bool {
+    self.flags.contains(DagNodeFlag::Unrewritable | DagNodeFlag::Unstackable)
+  }
+
+  // endregion
+
+  // region GC related methods
+
+  /// The number of bytes of bucket storage owned by the subgraph rooted at this node, counting each `NodeVector`
+  /// once no matter how many times its node is shared.
+  pub fn owned_bytes(&self) -> usize {
+    let mut visited: HashSet<*const DagNode> = HashSet::new();
+    let mut stack  : Vec<&DagNode>           = vec![self];
+    let mut bytes  : usize                   = 0;
+
+    while let Some(node) = stack.pop() {
+      if !visited.insert(node) {
+        continue;
+      }
+      if let DagNodeArgument::Many(node_vector) = node.args() {
+        bytes += size_of::<NodeVector>() + node_vector.capacity_bytes();
+      }
+      stack.extend(node.iter_children().map(|&child| unsafe { deref_node(child) }));
+    }
+
+    bytes
+  }
+
+  /// The vector holding this node's arguments, if it has more than one.
+  pub(crate) fn node_vector(&self) -> Option<&NodeVector> {
+    match self.args() {
+      DagNodeArgument::Many(node_vector) => Some(node_vector),
+      _ => None
+    }
+  }
+
+  /// Rewrites the pointers of a node copied from another heap: children with `node_map`, and the argument vector
+  /// with `storage_map`, which must translate addresses of the original bucket storage into its copy.
+  ///
+  /// # Safety
+  ///
+  /// The argument vector must not have been remapped already, and `storage_map` must return copies of it.
+  pub(crate) unsafe fn remap(
+    &mut self,
+    node_map   : &impl Fn(DagNodePtr) -> DagNodePtr,
+    storage_map: &impl Fn(*const Void) -> *mut Void,
+  ) {
+    match self.args() {
+      DagNodeArgument::None
+      | DagNodeArgument::UserWord(_) => { /* pass */ }
+
+      DagNodeArgument::Single(_)
+      | DagNodeArgument::Pair(_) => {
+        self.inline_children().iter_mut().for_each(|child| *child = node_map(*child));
+      }
+
+      DagNodeArgument::Many(node_vector) => {
+        let copy: *mut NodeVector = storage_map(std::ptr::from_ref(node_vector).cast()).cast();
+        let data                  = storage_map(node_vector.data_ptr().cast()).cast();
+        let copy                  = &mut *copy;
+        copy.remap(data, node_map);
+        self.set_args(DagNodeArgument::Many(copy));
+      }
+    }
+  }
+
+  /// Copies this node's argument vector, if it has one, into fresh bucket storage, as `NodeVector::relocate`
+  /// describes. Used by collections that relocate bucket storage without marking nodes.
+  pub(crate) fn relocate_arguments(&mut self) {
+    if let DagNodeArgument::Many(node_vector) = self.args() {
+      self.set_args(DagNodeArgument::Many(node_vector.relocate()));
+    }
+  }
+
+  /// Like `relocate_arguments`, but for a node of an isolated heap whose arguments are stored in `storage_allocator`.
+  pub(crate) fn relocate_arguments_in(&mut self, storage_allocator: &mut StorageAllocator) {
+    if let DagNodeArgument::Many(node_vector) = self.args() {
+      self.set_args(DagNodeArgument::Many(node_vector.relocate_in(storage_allocator)));
+    }
+  }
+
+  /// Copies this node's argument vector, if it has one, into fresh bucket storage with `NodeVector::relocate` and
+  /// replaces each child with `forward(child)`. Used by the relocating collector once every survivor has been copied.
+  pub(crate) fn relocate(&mut self, forward: impl Fn(DagNodePtr) -> DagNodePtr) {
+    match self.args() {
+      DagNodeArgument::None
+      | DagNodeArgument::UserWord(_) => { /* pass */ }
+
+      DagNodeArgument::Single(_)
+      | DagNodeArgument::Pair(_) => {
+        self.inline_children().iter_mut().for_each(|child| *child = forward(*child));
+      }
+
+      DagNodeArgument::Many(node_vector) => {
+        let copy = node_vector.relocate();
+        for child in copy.as_mut_slice() {
+          *child = forward(*child);
+        }
+        self.set_args(DagNodeArgument::Many(copy));
+      }
+    }
+  }
+
+  /// Whether the relocating collector has copied this node in the current collection, in which case its arguments
+  /// have been replaced by a pointer to the copy. See `DagNode::forwarding`.
+  #[inline(always)]
+  pub fn is_copied(&self) -> bool {
+    self.flags.contains(DagNodeFlag::Copied)
+  }
+
+  /// Flags this node as `Copied` and stores a pointer to `copy` in place of its arguments, which must have been
+  /// copied to `copy` already.
+  pub(crate) fn set_forwarding(&mut self, copy: DagNodePtr) {
+    debug_assert!(self.forwarding().is_none(), "node copied twice in one pass");
+    self.set_args(DagNodeArgument::UserWord(copy as usize));
+    self.flags.insert(DagNodeFlag::Copied);
+  }
+
+  /// Where the relocating collector copied this node, if it is `Copied`.
+  #[inline(always)]
+  pub(crate) fn forwarding(&self) -> Option<DagNodePtr> {
+    match (self.args(), self.is_copied()) {
+      (DagNodeArgument::UserWord(copy), true) => Some(copy as DagNodePtr),
+      _ => None
+    }
+  }
+
+  /// Whether this node is in the state of `DagNode::default()`, as an arena slot is before its first use and after
+  /// the collector destroys its node. Every constructor sets a symbol, so this is the case exactly when the symbol is
+  /// null.
+  #[inline(always)]
+  pub fn is_default(&self) -> bool {
+    self.symbol.is_null()
+  }
+
+  #[inline(always)]
+  pub fn is_marked(&self) -> bool {
+    self.flags.contains(DagNodeFlag::Marked)
+  }
+
+  #[inline(always)]
+  pub fn needs_destruction(&self) -> bool {
+    // self.flags.contains(DagNodeFlag::NeedsDestruction)
+    self.args_tag == ArgumentTag::Many
+  }
+
+  #[inline(always)]
+  pub fn simple_reuse(&self) -> bool {
+    !self.flags.contains(DagNodeFlag::Marked) && !self.needs_destruction()
+  }
+
+  /// Marks the child `node`, returning whether it did. A null child is skipped, and with the `gc_safe_mark` feature
+  /// so is a pointer to anything but a node of the collecting allocator's arenas.
+  #[inline(always)]
+  fn mark_child(node: DagNodePtr) -> bool {
+    if node.is_null() {
+      return false;
+    }
+    #[cfg(feature = "gc_safe_mark")]
+    if !crate::dag_node::allocator::is_markable(node) {
+      return false;
+    }
+    unsafe { deref_node_mut(node) }.mark();
+    true
+  }
+
+  #[inline(always)]
+  pub fn mark(&'static mut self) {
+    if self.flags.contains(DagNodeFlag::Marked) {
+      return;
+    }
+
+    increment_active_node_count();
+    #[cfg(feature = "gc_debug")]
+    crate::dag_node::allocator::record_mark(self);
+    self.flags.insert(DagNodeFlag::Marked);
+    
+    let arity = self.arity();
+    
+    match self.args() {
+
+      DagNodeArgument::None
+      | DagNodeArgument::UserWord(_) => { /* pass */ }
+
+      DagNodeArgument::Single(node) => {
+        DagNode::mark_child(node);
+      }
+
+      DagNodeArgument::Pair([first, second]) => {
+        DagNode::mark_child(first);
+        DagNode::mark_child(second);
+      }
+
+      DagNodeArgument::Many(node_vec) => {
+        for &node in node_vec.iter() {
+          if !DagNode::mark_child(node) && node.is_null() {
+            eprintln!("Bad node found.")
+          }
+        }
+
+        // A flattened node can have more arguments than its symbol's arity, but never fewer slots.
+        if node_vec.capacity() < arity as usize || node_vec.len() > node_vec.capacity() {
+          panic!("Node vector capacity mismatch.")
+        }
+        
+        // Reallocate
+        self.set_args(DagNodeArgument::Many(node_vec.relocate()));
+      }
+
+    }
+
+  }
+  //endregion
+
+}
+
+impl Display for DagNode {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    // Nodes in the free space of an arena have no symbol, and formatting one by accident must not crash.
+    write!(f, "node<{}>", self.symbol.display_or_placeholder())
+  }
+}
+
+/// Renders a term inline. See `DagNode::display_term`.
+pub struct TermDisplay<'a> {
+  node     : &'a DagNode,
+  max_depth: usize,
+}
+
+impl TermDisplay<'_> {
+  /// Counts the occurrences of each compound node that will be rendered, not descending into repeats. The term is
+  /// walked with an explicit stack in the order it is written, so that deep terms cannot overflow the call stack.
+  fn count_occurrences(&self) -> HashMap<*const DagNode, usize> {
+    let mut counts: HashMap<*const DagNode, usize> = HashMap::new();
+    let mut stack : Vec<(&DagNode, usize)>         = vec![(self.node, 0)];
+
+    while let Some((node, depth)) = stack.pop() {
+      if depth > self.max_depth || node.is_leaf() {
+        continue;
+      }
+
+      let count = counts.entry(node).or_insert(0);
+      *count += 1;
+      if *count == 1 {
+        // Pushed in reverse so that children are visited left to right.
+        stack.extend(node.iter_children().rev().map(|&child| (unsafe { deref_node(child) }, depth + 1)));
+      }
+    }
+
+    counts
+  }
+}
+
+impl Display for TermDisplay<'_> {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let counts = self.count_occurrences();
+
+    let mut labels: HashMap<*const DagNode, usize> = HashMap::new();
+    // The compound nodes being written, with their depths and the children not yet written. The term is walked with
+    // an explicit stack, so that deep terms cannot overflow the call stack.
+    let mut path  : Vec<(usize, Enumerate<Iter<'static, DagNodePtr>>)> = Vec::new();
+    let mut next  : Option<(&DagNode, usize)>      = Some((self.node, 0));
+
+    loop {
+      if let Some((node, depth)) = next.take() {
+        if depth > self.max_depth {
+          write!(f, "...")?;
+        } else if node.is_leaf() {
+          write!(f, "{}", node.symbol())?;
+        } else if let Some(label) = labels.get(&(node as *const DagNode)) {
+          write!(f, "#{}", label)?;
+        } else {
+          if counts.get(&(node as *const DagNode)).is_some_and(|&count| count > 1) {
+            let label = labels.len() + 1;
+            labels.insert(node, label);
+            write!(f, "#{}=", label)?;
+          }
+          write!(f, "{}(", node.symbol())?;
+          path.push((depth, node.iter_children().enumerate()));
+        }
+      }
+
+      let Some((depth, children)) = path.last_mut() else {
+        break;
+      };
+      match children.next() {
+        Some((i, &child)) => {
+          if i > 0 {
+            write!(f, ", ")?;
+          }
+          next = Some((unsafe { deref_node(child) }, *depth + 1));
+        }
+        None => {
+          path.pop();
+          write!(f, ")")?;
+        }
+      }
+    }
+
+    Ok(())
+  }
+}
+
+impl Default for DagNode {
+  fn default() -> Self {
+    DagNode{
+      symbol: null_mut(),
+      args: Default::default(),
+      args_tag: Default::default(),
+      kind: Default::default(),
+      flags: Default::default(),
+      _pin: Default::default(),
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use std::{cmp::Ordering, collections::HashMap};
+
+  use crate::{
+    abstractions::IString,
+    dag_node::allocator::{
+      active_node_count,
+      in_use_bucket_bytes,
+      lazily_swept_nodes,
+      lock_global_heap,
+      mark_from,
+      NodeAllocator,
+      StorageAllocator
+    },
+    dag_node::{canonical_compare, RootContainer, deref_node, deref_node_mut, DagNode, DagNodeKind, DagNodePtr},
+    symbol::{Symbol, SymbolPtr, SymbolType}
+  };
+
+  #[test]
+  fn test_children_match() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    let a_node = DagNode::new(&a);
+    let b_node = DagNode::new(&b);
+    let node   = DagNode::with_args(&f, &[a_node, b_node], DagNodeKind::default());
+    let node   = unsafe { &*node };
+
+    let same_symbol = |x: DagNodePtr, y: DagNodePtr| unsafe { (*x).symbol == (*y).symbol };
+
+    // Matching
+    assert!(node.children_match(&[a_node, b_node], same_symbol));
+    assert!(node.children_match(&[DagNode::new(&a), DagNode::new(&b)], same_symbol));
+    // Length mismatch
+    assert!(!node.children_match(&[a_node], same_symbol));
+    assert!(!node.children_match(&[a_node, b_node, b_node], same_symbol));
+    // Element mismatch
+    assert!(!node.children_match(&[b_node, a_node], same_symbol));
+    assert!(!node.children_match(&[a_node, b_node], |x, y| x != y));
+  }
+
+  #[test]
+  fn test_dominators() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let h = Symbol::new(IString::from("h"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    // The diamond f(g(s), h(s)) with s = f(a, b)
+    let leaf_a = DagNode::new(&a);
+    let leaf_b = DagNode::new(&b);
+    let shared = DagNode::with_args(&f, &[leaf_a, leaf_b], DagNodeKind::default());
+    let left   = DagNode::with_args(&g, &[shared], DagNodeKind::default());
+    let right  = DagNode::with_args(&h, &[shared], DagNodeKind::default());
+    let root   = DagNode::with_args(&f, &[left, right], DagNodeKind::default());
+
+    let dominators = unsafe { deref_node(root) }.dominators();
+    assert_eq!(dominators.len(), 6);
+    assert_eq!(dominators[&root], root);
+    assert_eq!(dominators[&left], root);
+    assert_eq!(dominators[&right], root);
+    assert_eq!(dominators[&shared], root);
+    // Below the shared node, everything is used only through it.
+    assert_eq!(dominators[&leaf_a], shared);
+    assert_eq!(dominators[&leaf_b], shared);
+
+    // Within the left branch alone, the shared node is used only by `left`.
+    let dominators = unsafe { deref_node(left) }.dominators();
+    assert_eq!(dominators.len(), 4);
+    assert_eq!(dominators[&shared], left);
+  }
+
+  #[test]
+  fn test_in_degrees() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // The diamond f(g(s), g(s)) with two distinct g nodes sharing s = g(a)
+    let leaf   = DagNode::new(&a);
+    let shared = DagNode::with_args(&g, &[leaf], DagNodeKind::default());
+    let left   = DagNode::with_args(&g, &[shared], DagNodeKind::default());
+    let right  = DagNode::with_args(&g, &[shared], DagNodeKind::default());
+    let root   = DagNode::with_args(&f, &[left, right], DagNodeKind::default());
+
+    let in_degrees = unsafe { deref_node(root) }.in_degrees();
+    assert_eq!(in_degrees.len(), 5);
+    assert_eq!(in_degrees[&root], 0);
+    assert_eq!(in_degrees[&left], 1);
+    assert_eq!(in_degrees[&right], 1);
+    assert_eq!(in_degrees[&shared], 2);
+    assert_eq!(in_degrees[&leaf], 1);
+
+    // A repeated argument counts once per occurrence.
+    let pair = DagNode::with_args(&f, &[leaf, leaf], DagNodeKind::default());
+    assert_eq!(unsafe { deref_node(pair) }.in_degrees()[&leaf], 2);
+  }
+
+  #[test]
+  fn test_max_chain_length() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    let mut chain = DagNode::new(&a);
+    assert_eq!(unsafe { deref_node(chain) }.max_chain_length(), 0);
+    for _ in 0..1000 {
+      chain = DagNode::with_args(&g, &[chain], DagNodeKind::default());
+    }
+    assert_eq!(unsafe { deref_node(chain) }.max_chain_length(), 1000);
+
+    // f(g(g(a)), chain), with the longest chain below a node with two children.
+    let short = DagNode::with_args(&g, &[DagNode::new(&a)], DagNodeKind::default());
+    let short = DagNode::with_args(&g, &[short], DagNodeKind::default());
+    let term  = DagNode::with_args(&f, &[short, chain], DagNodeKind::default());
+    assert_eq!(unsafe { deref_node(term) }.max_chain_length(), 1000);
+    // A chain that runs into a part already measured.
+    let longer = DagNode::with_args(&g, &[chain], DagNodeKind::default());
+    let term   = DagNode::with_args(&f, &[longer, chain], DagNodeKind::default());
+    assert_eq!(unsafe { deref_node(term) }.max_chain_length(), 1001);
+  }
+
+  #[test]
+  fn test_post_order_with_parents() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    // f(g(a), b)
+    let a_node = DagNode::new(&a);
+    let g_node = DagNode::with_args(&g, &[a_node], DagNodeKind::default());
+    let b_node = DagNode::new(&b);
+    let f_node = DagNode::with_args(&f, &[g_node, b_node], DagNodeKind::default());
+
+    let order = unsafe { deref_node(f_node) }.post_order_with_parents();
+    assert_eq!(order, vec![
+      (a_node, Some((g_node, 0))),
+      (g_node, Some((f_node, 0))),
+      (b_node, Some((f_node, 1))),
+      (f_node, None),
+    ]);
+
+    // A shared child is listed at each of its positions.
+    let h_node = DagNode::with_args(&f, &[b_node, b_node], DagNodeKind::default());
+    let order  = unsafe { deref_node(h_node) }.post_order_with_parents();
+    assert_eq!(order, vec![(b_node, Some((h_node, 0))), (b_node, Some((h_node, 1))), (h_node, None)]);
+  }
+
+  #[test]
+  fn test_replace_subterm() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    // f(g(a), f(g(a), b)) with g(a) shared
+    let leaf_a = DagNode::new(&a);
+    let leaf_b = DagNode::new(&b);
+    let shared = DagNode::with_args(&g, &[leaf_a], DagNodeKind::default());
+    let right  = DagNode::with_args(&f, &[shared, leaf_b], DagNodeKind::default());
+    let root   = DagNode::with_args(&f, &[shared, right], DagNodeKind::ACU);
+    let root_ref = unsafe { deref_node(root) };
+
+    let replacement = DagNode::new(&b);
+    let result      = unsafe { deref_node(root_ref.replace_subterm(leaf_a, replacement)) };
+    assert_ne!(std::ptr::from_ref(result), root.cast_const());
+    assert_eq!(result.kind, DagNodeKind::ACU);
+
+    // Both references to the shared node see the replacement, through a single copy.
+    let children       = result.iter_children().copied().collect::<Vec<_>>();
+    let right_children  = unsafe { deref_node(children[1]) }.iter_children().copied().collect::<Vec<_>>();
+    assert_eq!(children[0], right_children[0]);
+    assert_ne!(children[0], shared);
+    assert_eq!(unsafe { deref_node(children[0]) }.iter_children().next(), Some(&replacement));
+    // Untouched subterms are shared with the original.
+    assert_eq!(right_children[1], leaf_b);
+
+    // The original is unchanged.
+    assert_eq!(unsafe { deref_node(shared) }.iter_children().next(), Some(&leaf_a));
+    assert_eq!(root_ref.replace_subterm(DagNode::new(&a), replacement), root);
+    assert_eq!(root_ref.replace_subterm(root, replacement), replacement);
+  }
+
+  #[test]
+  fn test_with_same_head() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    let (a_node, b_node) = (DagNode::new(&a), DagNode::new(&b));
+    let node    = unsafe { deref_node(DagNode::with_args(&f, &[a_node, b_node], DagNodeKind::ACU)) };
+    let sibling = unsafe { deref_node(node.with_same_head(&[b_node, b_node])) };
+    assert_eq!(sibling.symbol, node.symbol);
+    assert_eq!(sibling.kind, DagNodeKind::ACU);
+    assert_eq!(sibling.iter_children().copied().collect::<Vec<_>>(), vec![b_node, b_node]);
+    assert_eq!(node.iter_children().copied().collect::<Vec<_>>(), vec![a_node, b_node]);
+  }
+
+  #[cfg(feature = "symbol_stats")]
+  #[test]
+  fn test_allocations_by_symbol() {
+    use std::ptr::from_ref;
+    use crate::dag_node::{allocations_by_symbol, reset_allocations_by_symbol};
+
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+    reset_allocations_by_symbol();
+
+    let leaves = (0..5).map(|_| DagNode::new(&a)).collect::<Vec<_>>();
+    for pair in leaves.chunks(2) {
+      DagNode::with_args(&f, pair, DagNodeKind::default());
+    }
+    DagNode::new_rooted(&a);
+
+    let allocations = allocations_by_symbol();
+    assert_eq!(allocations.len(), 2);
+    assert_eq!(allocations[&from_ref(&a)], 6);
+    assert_eq!(allocations[&from_ref(&f)], 3);
+
+    reset_allocations_by_symbol();
+    assert!(allocations_by_symbol().is_empty());
+  }
+
+  #[test]
+  fn test_is_default() {
+    let _heap = lock_global_heap();
+    let a = Symbol::new(IString::from("a"), 0);
+
+    assert!(DagNode::default().is_default());
+    let node = DagNode::new(&a);
+    assert!(!unsafe { deref_node(node) }.is_default());
+    unsafe { node.write(DagNode::default()) };
+    assert!(unsafe { deref_node(node) }.is_default());
+  }
+
+  #[test]
+  fn test_leaves() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    // f(a, g(b, a)) with both occurrences of `a` the same node.
+    let a_node = DagNode::new(&a);
+    let b_node = DagNode::new(&b);
+    let g_node = DagNode::with_args(&g, &[b_node, a_node], DagNodeKind::default());
+    let term   = unsafe { deref_node(DagNode::with_args(&f, &[a_node, g_node], DagNodeKind::default())) };
+    assert_eq!(term.leaves(false), vec![a_node, b_node, a_node]);
+    assert_eq!(term.leaves(true), vec![a_node, b_node]);
+    assert_eq!(unsafe { deref_node(a_node) }.leaves(false), vec![a_node]);
+
+    // `f(s, s)` nested 64 deep has 2^64 leaf occurrences, but the distinct leaves are found visiting each node once.
+    let mut nested = a_node;
+    for _ in 0..64 {
+      nested = DagNode::with_args(&f, &[nested, nested], DagNodeKind::default());
+    }
+    assert_eq!(unsafe { deref_node(nested) }.leaves(true), vec![a_node]);
+  }
+
+  #[test]
+  fn test_variables() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+    let mut x = Symbol::new(IString::from("x"), 0);
+    let mut y = Symbol::new(IString::from("y"), 0);
+    x.symbol_type = SymbolType::Variable;
+    y.symbol_type = SymbolType::Variable;
+
+    // f(x, g(x))
+    let x_node = DagNode::new(&x);
+    let g_node = DagNode::with_args(&g, &[x_node], DagNodeKind::default());
+    let term   = unsafe { deref_node(DagNode::with_args(&f, &[x_node, g_node], DagNodeKind::default())) };
+    let (x_ptr, y_ptr): (SymbolPtr, SymbolPtr) = (&x, &y);
+    assert_eq!(term.variables(), vec![(x_ptr, vec![vec![0], vec![1, 0]])]);
+
+    // f(g(y), x), with variables listed by first occurrence.
+    let y_node = DagNode::new(&y);
+    let g_node = DagNode::with_args(&g, &[y_node], DagNodeKind::default());
+    let term   = unsafe { deref_node(DagNode::with_args(&f, &[g_node, x_node], DagNodeKind::default())) };
+    assert_eq!(term.variables(), vec![(y_ptr, vec![vec![0, 0]]), (x_ptr, vec![vec![1]])]);
+
+    // A different symbol with the same name and arity is a different variable.
+    let mut other_x = Symbol::new(IString::from("x"), 0);
+    other_x.symbol_type = SymbolType::Variable;
+    let other_x_ptr: SymbolPtr = &other_x;
+    let term = DagNode::with_args(&f, &[x_node, DagNode::new(&other_x)], DagNodeKind::default());
+    assert_eq!(unsafe { deref_node(term) }.variables(), vec![(x_ptr, vec![vec![0]]), (other_x_ptr, vec![vec![1]])]);
+
+    let ground = DagNode::with_args(&g, &[DagNode::new(&a)], DagNodeKind::default());
+    assert!(unsafe { deref_node(ground) }.variables().is_empty());
+  }
+
+  #[test]
+  fn test_is_linear() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let mut x = Symbol::new(IString::from("x"), 0);
+    let mut y = Symbol::new(IString::from("y"), 0);
+    x.symbol_type = SymbolType::Variable;
+    y.symbol_type = SymbolType::Variable;
+
+    let (x_node, y_node) = (DagNode::new(&x), DagNode::new(&y));
+    let linear     = DagNode::with_args(&f, &[x_node, y_node], DagNodeKind::default());
+    let non_linear = DagNode::with_args(&f, &[x_node, DagNode::new(&x)], DagNodeKind::default());
+    assert!(unsafe { deref_node(linear) }.is_linear());
+    assert!(!unsafe { deref_node(non_linear) }.is_linear());
+    assert!(unsafe { deref_node(x_node) }.is_linear());
+
+    // Two variables with the same name and arity but different symbols do not make a term non-linear.
+    let mut other_x = Symbol::new(IString::from("x"), 0);
+    other_x.symbol_type = SymbolType::Variable;
+    let distinct = DagNode::with_args(&f, &[x_node, DagNode::new(&other_x)], DagNodeKind::default());
+    assert!(unsafe { deref_node(distinct) }.is_linear());
+  }
+
+  #[test]
+  fn test_instantiate_keeps_sharing() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+    let mut x = Symbol::new(IString::from("X"), 0);
+    x.symbol_type = SymbolType::Variable;
+
+    // The binding `f(s, s)` nested 40 deep has 41 nodes but would be a tree of 2^41 - 1 if sharing were lost.
+    let depth = 40;
+    let mut binding = DagNode::new(&a);
+    for _ in 0..depth {
+      binding = DagNode::with_args(&f, &[binding, binding], DagNodeKind::default());
+    }
+    let variable = DagNode::new(&x);
+    let pattern  = unsafe { deref_node(DagNode::with_args(&f, &[variable, variable], DagNodeKind::default())) };
+
+    // Only the head is new; both occurrences of `X` share the binding.
+    let bindings = HashMap::from([(&x as SymbolPtr, binding)]);
+    assert!(pattern.instantiate(&bindings, 0).is_err());
+    let instance = unsafe { deref_node(pattern.instantiate(&bindings, 1).unwrap()) };
+    assert_eq!(instance.iter_children().copied().collect::<Vec<_>>(), [binding, binding]);
+    assert_eq!(pattern.instantiate(&HashMap::new(), 0), Ok(std::ptr::from_ref(pattern).cast_mut()));
+
+    // A deep copy makes one node per node of the DAG.
+    let error = instance.deep_copy(depth + 1).unwrap_err();
+    assert_eq!(error, format!("copy exceeds {} nodes", depth + 1));
+    let copy     = unsafe { deref_node(instance.deep_copy(depth + 2).unwrap()) };
+    let children = copy.iter_children().copied().collect::<Vec<_>>();
+    assert_eq!(children[0], children[1]);
+    assert_ne!(children[0], binding);
+    assert_eq!(unsafe { deref_node(children[0]) }.structural_hash(), unsafe { deref_node(binding) }.structural_hash());
+  }
+
+  #[test]
+  fn test_freeze_survives_collection() {
+    let _heap = lock_global_heap();
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    let frozen = DagNode::with_args(&g, &[DagNode::new(&a)], DagNodeKind::default());
+    let _root  = RootContainer::new(frozen);
+    unsafe { deref_node_mut(frozen) }.freeze();
+
+    // The lazy sweep passes over the survivors while finding room for the new nodes.
+    let swept = lazily_swept_nodes();
+    mark_from(&[]);
+    for _ in 0..100 {
+      DagNode::new(&a);
+    }
+    assert!(lazily_swept_nodes() > swept);
+    assert!(unsafe { deref_node(frozen) }.is_frozen());
+    assert!(!unsafe { deref_node(frozen) }.is_marked());
+  }
+
+  #[test]
+  fn test_freeze() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    // f(g(a), f(a, b)) with a shared between the frozen g(a) and the rest
+    let leaf_a = DagNode::new(&a);
+    let leaf_b = DagNode::new(&b);
+    let frozen = DagNode::with_args(&g, &[leaf_a], DagNodeKind::default());
+    let right  = DagNode::with_args(&f, &[leaf_a, leaf_b], DagNodeKind::default());
+    let root   = unsafe { deref_node(DagNode::with_args(&f, &[frozen, right], DagNodeKind::default())) };
+    unsafe { deref_node_mut(frozen) }.freeze();
+    assert!(unsafe { deref_node(frozen) }.is_frozen() && unsafe { deref_node(leaf_a) }.is_frozen());
+    assert!(!root.is_frozen() && !unsafe { deref_node(right) }.is_frozen());
+
+    // The occurrence of `a` under `g` is untouched; the one outside is replaced.
+    let replacement = DagNode::new(&b);
+    let result      = unsafe { deref_node(root.replace_subterm(leaf_a, replacement)) };
+    let children    = result.iter_children().copied().collect::<Vec<_>>();
+    assert_eq!(children[0], frozen);
+    assert_eq!(unsafe { deref_node(children[1]) }.iter_children().next(), Some(&replacement));
+    // The frozen subterm itself can still be replaced as a whole.
+    let result = unsafe { deref_node(root.replace_subterm(frozen, replacement)) };
+    assert_eq!(result.iter_children().copied().collect::<Vec<_>>(), [replacement, right]);
+
+    assert!(unsafe { deref_node_mut(frozen) }.insert_child(leaf_b).is_err());
+    assert!(unsafe { deref_node_mut(leaf_a) }.insert_child(leaf_b).is_err());
+    assert!(unsafe { deref_node_mut(right) }.swap_children(0, 1).is_ok());
+    let frozen_pair = DagNode::with_args(&f, &[leaf_a, leaf_b], DagNodeKind::default());
+    unsafe { deref_node_mut(frozen_pair) }.freeze();
+    assert!(unsafe { deref_node_mut(frozen_pair) }.swap_children(0, 1).is_err());
+  }
+
+  #[cfg(feature = "gc_safe_mark")]
+  #[test]
+  fn test_safe_mark_skips_bad_children() {
+    use crate::dag_node::{allocator::bad_child_count, DagNodeArgument};
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 3);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    let leaf       = DagNode::new(&a);
+    let outside    = Box::into_raw(Box::new(DagNode::default()));
+    let misaligned = leaf.cast::<u8>().wrapping_add(1).cast::<DagNode>();
+    let f_node     = DagNode::with_args(&f, &[leaf, leaf, leaf], DagNodeKind::default());
+    let g_node     = DagNode::with_args(&g, &[leaf], DagNodeKind::default());
+    let _f_root    = RootContainer::new(f_node);
+    let _g_root    = RootContainer::new(g_node);
+
+    // Corrupt both argument representations, leaving one good child in `f`.
+    unsafe {
+      let DagNodeArgument::Many(node_vector) = (*f_node).args() else { panic!("f should hold a node vector") };
+      node_vector[1] = outside;
+      node_vector[2] = misaligned;
+      (*g_node).set_args(DagNodeArgument::Single(outside));
+    }
+
+    let bad_children = bad_child_count();
+    assert_eq!(mark_from(&[]), Some(0));
+    assert_eq!(bad_child_count(), bad_children + 3);
+    assert_eq!(active_node_count(), 3);
+    assert!(!unsafe { &*outside }.is_marked());
+
+    drop(unsafe { Box::from_raw(outside) });
+  }
+
+  #[cfg(feature = "merkle_digest")]
+  #[test]
+  fn test_merkle_digest() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    // `f(a, f(a, right))` with the `a` leaves shared.
+    let term = |right: &Symbol| {
+      let shared = DagNode::new(&a);
+      let inner  = DagNode::with_args(&f, &[shared, DagNode::new(right)], DagNodeKind::default());
+      DagNode::with_args(&f, &[shared, inner], DagNodeKind::default())
+    };
+    let (first, second, changed) = unsafe { (deref_node(term(&a)), deref_node(term(&a)), deref_node(term(&b))) };
+    assert_eq!(first.merkle_digest(), second.merkle_digest());
+    assert_ne!(first.merkle_digest(), changed.merkle_digest());
+  }
+
+  #[test]
+  fn test_normalize_acu() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    let g_of  = |leaf: &Symbol| DagNode::with_args(&g, &[DagNode::new(leaf)], DagNodeKind::default());
+    let first  = DagNode::with_args(&f, &[g_of(&b), g_of(&a)], DagNodeKind::ACU);
+    let second = DagNode::with_args(&f, &[g_of(&a), g_of(&b)], DagNodeKind::ACU);
+    let (first, second) = unsafe { (deref_node(first), deref_node(second)) };
+    assert_eq!(first.structural_hash(), first.structural_hash());
+    assert_ne!(first.structural_hash(), second.structural_hash());
+
+    // Both input orders give the same argument order.
+    let leaves = |term: DagNodePtr| {
+      unsafe { deref_node(term) }
+          .iter_children()
+          .map(|&child| unsafe { deref_node(*deref_node(child).iter_children().next().unwrap()) }.symbol().name)
+          .collect::<Vec<_>>()
+    };
+    let first_normal  = first.normalize_acu();
+    let second_normal = second.normalize_acu();
+    assert_eq!(leaves(first_normal), leaves(second_normal));
+    assert_eq!(canonical_compare(first_normal, second_normal), Ordering::Equal);
+    assert_eq!(
+      unsafe { deref_node(first_normal) }.structural_hash(),
+      unsafe { deref_node(second_normal) }.structural_hash()
+    );
+
+    // Normalizing is stable, and non-ACU nodes keep their order.
+    assert_eq!(unsafe { deref_node(first_normal) }.normalize_acu(), first_normal);
+    let free = DagNode::with_args(&f, &[g_of(&b), g_of(&a)], DagNodeKind::default());
+    assert_eq!(unsafe { deref_node(free) }.normalize_acu(), free);
+  }
+
+  #[test]
+  fn test_deep_and_shared_terms() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // `g` nested far deeper than a recursive walk could go on a test thread's stack.
+    let deep = |leaf: DagNodePtr| {
+      (0..50_000).fold(leaf, |term, _| DagNode::with_args(&g, &[term], DagNodeKind::default()))
+    };
+    let leaf            = DagNode::new(&a);
+    let (first, second) = (deep(leaf), deep(DagNode::new(&a)));
+    assert_eq!(unsafe { deref_node(first) }.structural_hash(), unsafe { deref_node(second) }.structural_hash());
+    assert_eq!(canonical_compare(first, second), Ordering::Equal);
+    assert_eq!(unsafe { deref_node(first) }.normalize_acu(), first);
+
+    let replaced = unsafe { deref_node(first) }.replace_subterm(leaf, DagNode::new(&a));
+    assert_ne!(replaced, first);
+    assert_eq!(canonical_compare(replaced, second), Ordering::Equal);
+    assert_eq!(unsafe { deref_node(first) }.flatten_au(1), Ok(first));
+    let copy = unsafe { deref_node(first) }.deep_copy(50_001).unwrap();
+    assert_eq!(canonical_compare(copy, second), Ordering::Equal);
+    assert!(unsafe { deref_node(copy) }.structural_eq(unsafe { deref_node(second) }));
+    #[cfg(feature = "merkle_digest")]
+    assert_eq!(unsafe { deref_node(copy) }.merkle_digest(), unsafe { deref_node(second) }.merkle_digest());
+
+    // Two copies of `f(s, s)` nested 64 deep compare equal visiting each pair of nodes once.
+    let shared = || {
+      (0..64).fold(DagNode::new(&a), |term, _| DagNode::with_args(&f, &[term, term], DagNodeKind::ACU))
+    };
+    let (first, second) = (shared(), shared());
+    assert_eq!(canonical_compare(first, second), Ordering::Equal);
+    assert!(unsafe { deref_node(first) }.structural_eq(unsafe { deref_node(second) }));
+    assert_eq!(unsafe { deref_node(first) }.normalize_acu(), first);
+    assert!(unsafe { deref_node(first) }.flatten_au(1000).is_err());
+    assert!(unsafe { deref_node(first) }.deep_copy(65).is_ok());
+    assert!(unsafe { deref_node(first) }.deep_copy(64).is_err());
+  }
+
+  #[test]
+  fn test_new_in_local_allocator() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 3);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+    let _global_root   = RootContainer::new(DagNode::new(&a));
+    let global_nodes   = active_node_count();
+    let global_storage = in_use_bucket_bytes();
+
+    let mut allocator = NodeAllocator::new();
+    let mut storage   = StorageAllocator::new();
+    let leaf_a  = DagNode::new_in(&mut allocator, &mut storage, &a);
+    let leaf_b  = DagNode::new_in(&mut allocator, &mut storage, &b);
+    let args    = &[leaf_a, leaf_b, leaf_a];
+    let term    = DagNode::with_args_in(&mut allocator, &mut storage, &f, args, DagNodeKind::default());
+    let garbage = DagNode::with_args_in(&mut allocator, &mut storage, &f, args, DagNodeKind::default());
+    assert!(allocator.contains_node(term));
+    assert_eq!(active_node_count(), global_nodes);
+    // The argument vectors come from the local storage allocator.
+    assert_eq!(in_use_bucket_bytes(), global_storage);
+    let vectors_in_use = storage.storage_in_use();
+    assert!(vectors_in_use > 0);
+
+    // Only the local term survives; the global root is not consulted. Its vector is copied, and the garbage's is freed.
+    assert_eq!(unsafe { allocator.collect_isolated(&mut storage, &[term]) }, 3);
+    assert_eq!(active_node_count(), global_nodes);
+    assert_eq!(storage.storage_in_use(), vectors_in_use / 2);
+    assert_eq!(in_use_bucket_bytes(), global_storage);
+    let term_ref = unsafe { deref_node(term) };
+    assert_eq!(term_ref.iter_children().copied().collect::<Vec<_>>(), [leaf_a, leaf_b, leaf_a]);
+    assert_eq!(term_ref.symbol().name, f.name);
+
+    // New nodes skip the survivors and reuse the garbage.
+    let reused = (0..4).map(|_| DagNode::new_in(&mut allocator, &mut storage, &b)).collect::<Vec<_>>();
+    assert!(reused.contains(&garbage));
+    assert!(!reused.iter().any(|node| [leaf_a, leaf_b, term].contains(node)));
+  }
+
+  #[test]
+  fn test_rooted_constructors() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    let (leaf, leaf_root) = DagNode::new_rooted(&a);
+    let (term, term_root) = DagNode::with_args_rooted(&f, &[leaf, DagNode::new(&a)], DagNodeKind::ACU);
+    assert_eq!(leaf_root.node(), leaf);
+    assert_eq!(term_root.node(), term);
+    for _ in 0..100 {
+      DagNode::new(&f);
+    }
+
+    assert!(mark_from(&[]).is_some());
+    let fresh = (0..1000).map(|_| DagNode::new(&a)).collect::<Vec<_>>();
+    assert!(!fresh.contains(&leaf) && !fresh.contains(&term));
+
+    let term_ref = unsafe { deref_node(term) };
+    assert_eq!(term_ref.kind, DagNodeKind::ACU);
+    assert_eq!(term_ref.symbol().name, f.name);
+    assert_eq!(term_ref.iter_children().next(), Some(&leaf));
+    assert_eq!(unsafe { deref_node(leaf) }.symbol().name, a.name);
+  }
+
+  #[test]
+  fn test_children_borrowed() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 3);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    let leaf   = DagNode::new(&a);
+    let many   = DagNode::with_args(&f, &[leaf, DagNode::new(&a), leaf], DagNodeKind::default());
+    let single = DagNode::with_args(&g, &[many], DagNodeKind::default());
+
+    for node in [leaf, many, single] {
+      let node = unsafe { deref_node(node) };
+      assert_eq!(node.children_borrowed().collect::<Vec<_>>(), node.iter_children().copied().collect::<Vec<_>>());
+    }
+    let many = unsafe { deref_node(many) };
+    assert_eq!(many.children_borrowed().len(), 3);
+  }
+
+  #[test]
+  fn test_flatten_au() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // f(f(f(...f(a, a)..., a), a), a) nested 10 deep, with a non-associative g(a, a) at the bottom.
+    let leaf     = DagNode::new(&a);
+    let bottom   = DagNode::with_args(&g, &[leaf, leaf], DagNodeKind::default());
+    let mut term = bottom;
+    for _ in 0..10 {
+      term = DagNode::with_args(&f, &[term, leaf], DagNodeKind::AU);
+    }
+    let term = unsafe { deref_node(term) };
+
+    let flattened = unsafe { deref_node(term.flatten_au(11).unwrap()) };
+    assert_eq!(flattened.len(), 11);
+    assert_eq!(flattened.iter_children().next(), Some(&bottom));
+    assert!(flattened.iter_children().skip(1).all(|&child| child == leaf));
+
+    // The guard triggers before the argument list gets too long.
+    let error = term.flatten_au(5).unwrap_err();
+    assert!(error.contains("maximum of 5 arguments"), "{}", error);
+
+    // Other kinds are not flattened.
+    let free = DagNode::with_args(&f, &[bottom, leaf], DagNodeKind::default());
+    assert_eq!(unsafe { deref_node(free) }.flatten_au(2), Ok(free));
+  }
+
+  #[test]
+  #[cfg_attr(debug_assertions, should_panic(expected = "a node of arity 1 holds 2 children"))]
+  fn test_iter_children_arity_mismatch() {
+    let _heap = lock_global_heap();
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // Without debug assertions, the children held are iterated whatever the arity says.
+    let children = vec![DagNode::new(&a), DagNode::new(&a)];
+    let node     = DagNode::with_args(&g, &children, DagNodeKind::default());
+    assert_eq!(unsafe { deref_node(node) }.iter_children().copied().collect::<Vec<_>>(), children);
+    let leaf     = DagNode::with_args(&a, &[children[0]], DagNodeKind::default());
+    assert_eq!(unsafe { deref_node(leaf) }.iter_children().count(), 1);
+  }
+
+  #[test]
+  fn test_display_null_symbol() {
+    let node = DagNode::default();
+    assert_eq!(node.to_string(), "node<<null-symbol>>");
+
+    let a = Symbol::new(IString::from("a"), 0);
+    let node = DagNode { symbol: &a, ..DagNode::default() };
+    assert_eq!(node.to_string(), "node<a>");
+  }
+
+  #[test]
+  fn test_has_consistent_arity() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    let leaves     = (0..3).map(|_| DagNode::new(&a)).collect::<Vec<_>>();
+    let consistent = unsafe { &*DagNode::with_args(&f, &leaves[..2], DagNodeKind::default()) };
+    assert_eq!((consistent.arg_count(), consistent.declared_arity()), (2, 2));
+    assert!(consistent.has_consistent_arity());
+    assert!(unsafe { &*leaves[0] }.has_consistent_arity());
+
+    // `with_args` takes the arguments it is given, whatever the symbol says.
+    let short = unsafe { &*DagNode::with_args(&f, &leaves[..1], DagNodeKind::default()) };
+    assert_eq!((short.arg_count(), short.declared_arity()), (1, 2));
+    assert!(!short.has_consistent_arity());
+    let long = unsafe { &*DagNode::with_args(&f, &leaves, DagNodeKind::default()) };
+    assert!(!long.has_consistent_arity());
+    // Only flattening explains extra arguments.
+    let flattened = unsafe { &*DagNode::with_args(&f, &leaves, DagNodeKind::AU) };
+    assert!(flattened.has_consistent_arity());
+  }
+
+  #[test]
+  fn test_swap_children() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 3);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+    let c = Symbol::new(IString::from("c"), 0);
+
+    let children = vec![DagNode::new(&a), DagNode::new(&b), DagNode::new(&c)];
+    let node     = unsafe { deref_node_mut(DagNode::with_args(&f, &children, DagNodeKind::default())) };
+    node.swap_children(0, 2).unwrap();
+    assert_eq!(node.iter_children().copied().collect::<Vec<_>>(), vec![children[2], children[1], children[0]]);
+
+    assert!(node.swap_children(1, 3).is_err());
+    let single = unsafe { deref_node_mut(DagNode::with_args(&g, &[children[0]], DagNodeKind::default())) };
+    assert!(single.swap_children(0, 0).is_err());
+  }
+
+  #[test]
+  fn test_remove_last_child_keeps_vector() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 3);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    let leaves = vec![DagNode::new(&a), DagNode::new(&a), DagNode::new(&a)];
+    let node   = unsafe { deref_node_mut(DagNode::with_args(&f, &leaves, DagNodeKind::default())) };
+    assert_eq!(node.remove_last_child(), Ok(leaves[2]));
+    assert_eq!(node.remove_last_child(), Ok(leaves[1]));
+    // The node keeps its vector, which still has room for all three children.
+    assert_eq!(node.node_vector().unwrap().capacity(), 3);
+    node.insert_child(leaves[1]).unwrap();
+
+    let _root = RootContainer::new(node);
+    mark_from(&[]);
+    assert_eq!(node.iter_children().copied().collect::<Vec<_>>(), leaves[..2]);
+    assert_eq!(active_node_count(), 3);
+  }
+
+  #[test]
+  fn test_insert_children() {
+    use crate::dag_node::allocator::{bucket_fast_hits, bucket_slow_allocs};
+
+    let _heap = lock_global_heap();
+    let f    = Symbol::new(IString::from("f"), 10);
+    let g    = Symbol::new(IString::from("g"), 2);
+    let h    = Symbol::new(IString::from("h"), 1);
+    let plus = Symbol::new(IString::from("+"), 2);
+    let a    = Symbol::new(IString::from("a"), 0);
+    let storage_allocations = || bucket_fast_hits() + bucket_slow_allocs();
+
+    let leaves = (0..10).map(|_| DagNode::new(&a)).collect::<Vec<_>>();
+
+    // A fresh node of arity 10 takes one vector, which is a header and its elements, and filling it takes no more.
+    let before = storage_allocations();
+    let node   = unsafe { deref_node_mut(DagNode::new(&f)) };
+    node.insert_children(leaves.iter().copied()).unwrap();
+    assert_eq!(storage_allocations() - before, 2);
+    assert_eq!(node.iter_children().copied().collect::<Vec<_>>(), leaves);
+    assert_eq!(node.node_vector().unwrap().capacity(), 10);
+    assert!(node.has_consistent_arity());
+    // A full node takes no more.
+    assert!(node.insert_children([leaves[0]]).is_err());
+    assert_eq!(node.len(), 10);
+
+    // A flattened node takes any number, growing once for an exact size hint. An iterator without an exact size hint
+    // still gets all its children in.
+    let flat   = unsafe { deref_node_mut(DagNode::with_kind(&plus, DagNodeKind::AU)) };
+    let before = storage_allocations();
+    flat.insert_children(leaves.iter().copied()).unwrap();
+    assert_eq!(storage_allocations() - before, 2);
+    flat.insert_children(leaves.iter().copied().filter(|_| true)).unwrap();
+    assert_eq!(flat.len(), 20);
+    assert!(flat.has_consistent_arity());
+
+    // Two children stay inline in a `Pair`.
+    let pair = unsafe { deref_node_mut(DagNode::new(&g)) };
+    pair.insert_children(leaves[..2].iter().copied()).unwrap();
+    assert!(pair.node_vector().is_none());
+    assert!(pair.insert_children([leaves[0]]).is_err());
+
+    // Too many children are refused before any is inserted if the size hint says so, and at the first extra child if
+    // it does not.
+    let single = unsafe { deref_node_mut(DagNode::new(&h)) };
+    assert!(single.insert_children(leaves[..2].iter().copied()).is_err());
+    assert_eq!(single.len(), 0);
+    assert!(single.insert_children(leaves[..2].iter().copied().filter(|_| true)).is_err());
+    assert_eq!(single.len(), 1);
+
+    let frozen = unsafe { deref_node_mut(DagNode::new(&g)) };
+    frozen.freeze();
+    assert!(frozen.insert_children([leaves[0]]).is_err());
+    assert_eq!(frozen.len(), 0);
+  }
+
+  #[test]
+  fn test_inserted_pair_needs_no_storage() {
+    use crate::dag_node::allocator::acquire_storage_allocator;
+
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    // A binary node built a child at a time holds both children inline too.
+    let (a_node, b_node) = (DagNode::new(&a), DagNode::new(&b));
+    let pair = unsafe { deref_node_mut(DagNode::new(&f)) };
+    assert!(pair.is_leaf());
+    pair.insert_child(a_node).unwrap();
+    pair.insert_child(b_node).unwrap();
+    assert_eq!(acquire_storage_allocator().storage_in_use(), 0);
+    assert_eq!(pair.iter_children().copied().collect::<Vec<_>>(), vec![a_node, b_node]);
+    assert!(pair.node_vector().is_none());
+    assert!(pair.insert_child(a_node).is_err());
+  }
+
+  #[test]
+  fn test_pair_needs_no_storage() {
+    use crate::dag_node::allocator::bucket_report;
+
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    let (a_node, b_node) = (DagNode::new(&a), DagNode::new(&b));
+    let pair = unsafe { deref_node_mut(DagNode::with_args(&f, &[a_node, b_node], DagNodeKind::default())) };
+    // No bucket has been created, so no bucket storage is in use.
+    assert!(bucket_report().is_empty());
+    assert_eq!(pair.len(), 2);
+    assert_eq!(pair.iter_children().copied().collect::<Vec<_>>(), vec![a_node, b_node]);
+    assert!(!pair.needs_destruction());
+    assert!(pair.node_vector().is_none());
+
+    pair.swap_children(0, 1).unwrap();
+    assert_eq!(pair.children_borrowed().collect::<Vec<_>>(), vec![b_node, a_node]);
+    assert!(pair.insert_child(a_node).is_err());
+    assert_eq!(pair.remove_last_child(), Ok(a_node));
+    pair.insert_child(b_node).unwrap();
+    assert_eq!(pair.iter_children().copied().collect::<Vec<_>>(), vec![b_node, b_node]);
+    assert!(bucket_report().is_empty());
+
+    // Both children survive a collection through the pair.
+    let _root = RootContainer::new(pair);
+    mark_from(&[]);
+    assert_eq!(active_node_count(), 2);
+  }
+
+  #[test]
+  fn test_owned_bytes() {
+    use crate::dag_node::allocator::node_vector::NodeVector;
+
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 3);
+    let g = Symbol::new(IString::from("g"), 2);
+    let h = Symbol::new(IString::from("h"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // f(g(a, a), h(g(a, a)), a) where g(a, a) is shared
+    let a_node = DagNode::new(&a);
+    let g_node = DagNode::with_args(&g, &[a_node, a_node], DagNodeKind::default());
+    let h_node = DagNode::with_args(&h, &[g_node], DagNodeKind::default());
+    let f_node = DagNode::with_args(&f, &[g_node, h_node, a_node], DagNodeKind::default());
+
+    // `g` holds its two children inline, so only `f` needs a `NodeVector`.
+    let expected = size_of::<NodeVector>() + 3 * size_of::<DagNodePtr>();
+    assert_eq!(unsafe { &*f_node }.owned_bytes(), expected);
+    assert_eq!(unsafe { &*h_node }.owned_bytes(), 0);
+    assert_eq!(unsafe { &*a_node }.owned_bytes(), 0);
+  }
+
+  #[test]
+  fn test_user_word() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    let leaf = unsafe { &mut *DagNode::new(&a) };
+    assert_eq!(leaf.user_word(), None);
+    leaf.set_user_word(42).expect("leaf nodes can store a user word");
+    assert_eq!(leaf.user_word(), Some(42));
+    assert_eq!(leaf.len(), 0);
+    assert_eq!(leaf.iter_children().count(), 0);
+
+    let parent = unsafe { &mut *DagNode::with_args(&f, &[leaf], DagNodeKind::default()) };
+    assert!(parent.set_user_word(7).is_err());
+    assert_eq!(parent.user_word(), None);
+  }
+
+  #[cfg(debug_assertions)]
+  #[test]
+  #[should_panic(expected = "dereferenced a null DagNode pointer")]
+  fn test_deref_null_node() {
+    let node = unsafe { deref_node(std::ptr::null()) };
+    println!("{}", node.is_marked());
+  }
+
+  #[test]
+  fn test_eq_to_depth() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    // f(g(a), a) and f(g(b), a) agree on levels 1 and 2
+    let left_g  = DagNode::with_args(&g, &[DagNode::new(&a)], DagNodeKind::default());
+    let left    = DagNode::with_args(&f, &[left_g, DagNode::new(&a)], DagNodeKind::default());
+    let right_g = DagNode::with_args(&g, &[DagNode::new(&b)], DagNodeKind::default());
+    let right   = DagNode::with_args(&f, &[right_g, DagNode::new(&a)], DagNodeKind::default());
+    let (left, right) = unsafe { (&*left, &*right) };
+
+    assert!(left.eq_to_depth(right, 0));
+    assert!(left.eq_to_depth(right, 2));
+    assert!(!left.eq_to_depth(right, 3));
+    assert!(left.eq_to_depth(left, 10));
+    assert!(!left.eq_to_depth(unsafe { &*left_g }, 1));
+  }
+
+  #[test]
+  fn test_structural_eq_with_twin_symbols() {
+    let _heap = lock_global_heap();
+    let f      = Symbol::new(IString::from("f"), 2);
+    let a      = Symbol::new(IString::from("a"), 0);
+    let f_twin = Symbol::new(IString::from("f"), 2);
+    let a_twin = Symbol::new(IString::from("a"), 0);
+    let g      = Symbol::new(IString::from("f"), 1);
+    assert!(f.same_as(&f_twin) && !f.same_as(&g));
+
+    // f(f(a, a), f(a, a)), shared on one side and not on the other.
+    let shared = DagNode::with_args(&f, &[DagNode::new(&a), DagNode::new(&a)], DagNodeKind::default());
+    let left   = DagNode::with_args(&f, &[shared, shared], DagNodeKind::default());
+    let twin_child = || {
+      let args = vec![DagNode::new(&a_twin), DagNode::new(&a_twin)];
+      DagNode::with_args(&f_twin, &args, DagNodeKind::default())
+    };
+    let right  = DagNode::with_args(&f_twin, &[twin_child(), twin_child()], DagNodeKind::default());
+    let (left, right) = unsafe { (&*left, &*right) };
+
+    assert!(left.structural_eq(right));
+    assert!(right.structural_eq(left));
+    assert!(left.eq_to_depth(right, 3));
+    assert_eq!(canonical_compare(std::ptr::from_ref(left).cast_mut(), std::ptr::from_ref(right).cast_mut()),
+               Ordering::Equal);
+
+    let other = DagNode::with_args(&g, &[shared], DagNodeKind::default());
+    let other = DagNode::with_args(&f_twin, &[shared, other], DagNodeKind::default());
+    assert!(!left.structural_eq(unsafe { &*other }));
+  }
+
+  #[test]
+  fn test_enumerate_children() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 3);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    let children = (0..3).map(|_| DagNode::new(&a)).collect::<Vec<_>>();
+    let many     = unsafe { &*DagNode::with_args(&f, &children, DagNodeKind::default()) };
+    let expected = children.iter().copied().enumerate().collect::<Vec<_>>();
+    assert_eq!(many.enumerate_children().collect::<Vec<_>>(), expected);
+
+    let single = unsafe { &*DagNode::with_args(&g, &[children[0]], DagNodeKind::default()) };
+    assert_eq!(single.enumerate_children().collect::<Vec<_>>(), vec![(0, children[0])]);
+    assert_eq!(unsafe { &*children[0] }.enumerate_children().count(), 0);
+  }
+
+  #[test]
+  fn test_is_leaf() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    let leaf = DagNode::new(&a);
+    assert!(unsafe { &*leaf }.is_leaf());
+    unsafe { &mut *leaf }.set_user_word(3).unwrap();
+    assert!(unsafe { &*leaf }.is_leaf());
+
+    let single = DagNode::with_args(&g, &[leaf], DagNodeKind::default());
+    assert!(!unsafe { &*single }.is_leaf());
+
+    let many = DagNode::with_args(&f, &[leaf, single], DagNodeKind::default());
+    assert!(!unsafe { &*many }.is_leaf());
+
+    // Room for arguments makes a node compound even before it has any.
+    let h     = Symbol::new(IString::from("h"), 3);
+    let empty = unsafe { &*DagNode::new(&h) };
+    assert_eq!(empty.len(), 0);
+    assert!(!empty.is_leaf());
+    // A binary node holds its arguments inline, so it has no room for them until it is given them.
+    assert!(unsafe { &*DagNode::new(&f) }.is_leaf());
+  }
+
+  #[test]
+  fn test_display_term() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // g(g(g(g(g(a))))), which has depth 5
+    let mut chain = DagNode::new(&a);
+    for _ in 0..5 {
+      chain = DagNode::with_args(&g, &[chain], DagNodeKind::default());
+    }
+    let chain = unsafe { &*chain };
+    assert_eq!(chain.display_term(2).to_string(), "g(g(g(...)))");
+    assert_eq!(chain.display_term(5).to_string(), "g(g(g(g(g(a)))))");
+
+    // Shared subterms are printed once.
+    let g_node = DagNode::with_args(&g, &[DagNode::new(&a)], DagNodeKind::default());
+    let f_node = unsafe { &*DagNode::with_args(&f, &[g_node, g_node], DagNodeKind::default()) };
+    assert_eq!(f_node.display_term(usize::MAX).to_string(), "f(#1=g(a), #1)");
+  }
+
+  #[test]
+  fn test_display_deep_term() {
+    let _heap = lock_global_heap();
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // A chain too deep to display recursively.
+    let depth = 100_000;
+    let chain = (0..depth).fold(DagNode::new(&a), |term, _| DagNode::with_args(&g, &[term], DagNodeKind::default()));
+    let chain = unsafe { &*chain };
+    let text  = chain.display_term(usize::MAX).to_string();
+    assert_eq!(text, format!("{}a{}", "g(".repeat(depth), ")".repeat(depth)));
+    assert_eq!(chain.display_term(1).to_string(), "g(g(...))");
+  }
+
+  #[test]
+  fn test_children_by_symbol() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 3);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+    let x = Symbol::new(IString::from("x"), 0);
+
+    // f(a, g(x), a)
+    let first_a  = DagNode::new(&a);
+    let second_a = DagNode::new(&a);
+    let g_node   = DagNode::with_args(&g, &[DagNode::new(&x)], DagNodeKind::default());
+    let f_node   = DagNode::with_args(&f, &[first_a, g_node, second_a], DagNodeKind::default());
+
+    let groups = unsafe { &*f_node }.children_by_symbol();
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[&(&a as SymbolPtr)], vec![first_a, second_a]);
+    assert_eq!(groups[&(&g as SymbolPtr)], vec![g_node]);
+  }
+
+  #[test]
+  fn test_find_child() {
+    fn has_symbol(symbol: &Symbol) -> impl Fn(DagNodePtr) -> bool + '_ {
+      move |child| std::ptr::eq(unsafe { (*child).symbol }, symbol)
+    }
+
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 4);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    // f(a, b, a, b)
+    let children = [&a, &b, &a, &b].map(|symbol| DagNode::new(symbol));
+    let f_node   = unsafe { &*DagNode::with_args(&f, &children, DagNodeKind::default()) };
+
+    assert_eq!(f_node.find_child(has_symbol(&b)), Some((1, children[1])));
+    assert_eq!(f_node.find_child(has_symbol(&a)), Some((0, children[0])));
+    assert_eq!(f_node.find_child(has_symbol(&f)), None);
+    assert_eq!(unsafe { &*children[0] }.find_child(|_| true), None);
   }
 }
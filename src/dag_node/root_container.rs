@@ -15,19 +15,24 @@ use std::{
   },
   sync::MutexGuard
 };
-use crate::dag_node::node::DagNode;
+use crate::dag_node::{allocator::acquire_lock, node::DagNode};
 
 static LIST_HEAD: Mutex<AtomicPtr<RootContainer>> = Mutex::new(AtomicPtr::new(std::ptr::null_mut()));
+static PERMANENT_ROOTS: Mutex<Vec<PermanentRoot>> = Mutex::new(Vec::new());
 
+/// Acquire the root list according to the `LockPolicy`.
 pub fn acquire_root_list() -> MutexGuard<'static, AtomicPtr<RootContainer>> {
-  match LIST_HEAD.try_lock() {
-    Ok(lock) => { lock }
-    Err(_) => {
-      panic!("Deadlocked acquiring root list.")
-    }
-  }
+  acquire_lock(&LIST_HEAD, "root list", "acquire_root_list")
+}
+
+/// Acquire the permanent roots according to the `LockPolicy`.
+fn acquire_permanent_roots(caller: &str) -> MutexGuard<'static, Vec<PermanentRoot>> {
+  acquire_lock(&PERMANENT_ROOTS, "permanent roots", caller)
 }
 
+/// An owning handle to a registered root. The root is unregistered when the handle is dropped.
+pub type RootHandle = Box<RootContainer>;
+
 pub struct RootContainer {
   next: Option<NonNull<RootContainer>>,
   prev: Option<NonNull<RootContainer>>,
@@ -53,6 +58,11 @@ impl RootContainer {
     container
   }
 
+  /// The rooted node, or null if the container holds none.
+  pub fn node(&self) -> *mut DagNode {
+    self.node.map_or(std::ptr::null_mut(), NonNull::as_ptr)
+  }
+
   pub fn mark(&mut self) {
     unsafe {
       if let Some(mut node) = self.node {
@@ -63,6 +73,11 @@ impl RootContainer {
 
   pub fn link(&mut self){
     let list_head  = acquire_root_list();
+    self.link_locked(&list_head);
+  }
+
+  /// Links this container at the head of the list. The caller must hold the root list lock.
+  fn link_locked(&mut self, list_head: &AtomicPtr<RootContainer>){
     self.prev = None;
     self.next = unsafe { NonNull::new(*list_head.as_ptr()) };
 
@@ -104,7 +119,132 @@ impl Drop for RootContainer {
   }
 }
 
-/// Marks all roots in the linked list of `RootContainer`s.
+/// A root that is marked on every collection and never unregistered, for constants and other terms that live as
+/// long as the program. Cheaper than a `RootContainer`, since it is never linked into or unlinked from the root list.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PermanentRoot {
+  node: NonNull<DagNode>
+}
+
+unsafe impl Send for PermanentRoot {}
+
+impl PermanentRoot {
+  pub fn node(&self) -> *mut DagNode {
+    self.node.as_ptr()
+  }
+}
+
+/// Makes `node` a permanent root, so that it and everything reachable from it survive every collection.
+pub fn make_permanent(node: *mut DagNode) -> PermanentRoot {
+  let root = PermanentRoot { node: NonNull::new(node).expect("cannot make a null node permanent") };
+  acquire_permanent_roots("make_permanent").push(root);
+  root
+}
+
+/// The number of permanent roots.
+pub fn permanent_root_count() -> usize {
+  acquire_permanent_roots("permanent_root_count").len()
+}
+
+/// Forgets every permanent root. Used when the heap they point into is thrown away. Test only.
+#[cfg(test)]
+pub(crate) fn clear_permanent_roots() {
+  match PERMANENT_ROOTS.lock() {
+    Ok(mut roots) => roots.clear(),
+    Err(poisoned) => poisoned.into_inner().clear(),
+  }
+}
+
+/// Registers every node in `nodes` as a root, acquiring the root list lock only once. Each node is unregistered
+/// when its handle is dropped.
+pub fn register_roots(nodes: &[*mut DagNode]) -> Vec<RootHandle> {
+  let mut handles: Vec<RootHandle> = nodes.iter()
+      .map(|&node| {
+        assert!(!node.is_null());
+        Box::new(RootContainer {
+          next: None,
+          prev: None,
+          node: NonNull::new(node)
+        })
+      })
+      .collect();
+
+  let list_head = acquire_root_list();
+  for handle in handles.iter_mut() {
+    handle.link_locked(&list_head);
+  }
+
+  handles
+}
+
+/// The number of roots currently registered.
+pub fn root_count() -> usize {
+  let list_head = acquire_root_list();
+  let mut root  = NonNull::new(list_head.load(Ordering::Relaxed));
+  let mut count = 0;
+
+  while let Some(root_ptr) = root {
+    count += 1;
+    root = unsafe { root_ptr.as_ref() }.next;
+  }
+
+  count
+}
+
+/// Calls `f` on the node of every registered root, including permanent roots.
+pub(crate) fn for_each_root(mut f: impl FnMut(*mut DagNode)) {
+  let list_head = acquire_root_list();
+  let mut root  = NonNull::new(list_head.load(Ordering::Relaxed));
+
+  while let Some(root_ptr) = root {
+    let root_ref = unsafe { root_ptr.as_ref() };
+    if let Some(node) = root_ref.node {
+      f(node.as_ptr());
+    }
+    root = root_ref.next;
+  }
+
+  for root in acquire_permanent_roots("for_each_root").iter() {
+    f(root.node());
+  }
+}
+
+/// Replaces the node of every registered root, including permanent roots, with `forward(node)`. Used by the
+/// relocating collector once every survivor has been copied.
+pub(crate) fn forward_roots(forward: impl Fn(*mut DagNode) -> *mut DagNode) {
+  let list_head = acquire_root_list();
+  let mut root  = NonNull::new(list_head.load(Ordering::Relaxed));
+
+  while let Some(mut root_ptr) = root {
+    let root_mut = unsafe { root_ptr.as_mut() };
+    root_mut.node = root_mut.node.and_then(|node| NonNull::new(forward(node.as_ptr())));
+    root = root_mut.next;
+  }
+
+  for root in acquire_permanent_roots("forward_roots").iter_mut() {
+    root.node = NonNull::new(forward(root.node())).expect("a permanent root was forwarded to null");
+  }
+}
+
+/// Checks that every registered root, permanent ones included, holds a node in one of the global allocator's arenas,
+/// naming the first root that does not. A root left pointing at freed memory would otherwise be marked as if it were
+/// a node.
+#[cfg(feature = "gc_debug")]
+pub fn validate_roots() -> Result<(), String> {
+  let mut roots = Vec::new();
+  for_each_root(|node| roots.push(node));
+
+  let node_allocator = crate::dag_node::allocator::acquire_node_allocator("validate_roots");
+  for (index, node) in roots.into_iter().enumerate() {
+    if !node_allocator.contains_node(node) {
+      return Err(format!("root {} holds {:p}, which is not a node of any arena", index, node));
+    }
+  }
+
+  Ok(())
+}
+
+/// Marks all roots in the linked list of `RootContainer`s, then all permanent roots.
 pub fn mark_roots() {
   let list_head = acquire_root_list();
   let mut root = unsafe {
@@ -123,4 +263,107 @@ pub fn mark_roots() {
       }
     }
   }
+
+  for root in acquire_permanent_roots("mark_roots").iter() {
+    unsafe { &mut *root.node() }.mark();
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use std::{ptr::NonNull, sync::atomic::Ordering};
+
+  use crate::{
+    abstractions::IString,
+    dag_node::allocator::{acquire_node_allocator, active_node_count, lock_global_heap},
+    dag_node::{
+      DagNode,
+      DagNodeKind,
+      DagNodePtr,
+      RootContainer,
+      root_container::{acquire_root_list, make_permanent, permanent_root_count, register_roots, root_count}
+    },
+    symbol::Symbol
+  };
+
+  /// Counts the registered roots holding one of `nodes`.
+  fn count_roots_of(nodes: &[DagNodePtr]) -> usize {
+    let list_head = acquire_root_list();
+    let mut root  = NonNull::new(list_head.load(Ordering::Relaxed));
+    let mut count = 0;
+
+    while let Some(root_ptr) = root {
+      let root_ref = unsafe { root_ptr.as_ref() };
+      if root_ref.node.is_some_and(|node| nodes.contains(&node.as_ptr())) {
+        count += 1;
+      }
+      root = root_ref.next;
+    }
+
+    count
+  }
+
+  #[test]
+  fn test_register_roots() {
+    let _heap = lock_global_heap();
+    let symbol = Symbol::new(IString::from("r"), 0);
+    let nodes  = (0..5).map(|_| DagNode::new(&symbol)).collect::<Vec<_>>();
+
+    let handles = register_roots(&nodes);
+    assert_eq!(handles.len(), 5);
+    assert_eq!(count_roots_of(&nodes), 5);
+    assert_eq!(root_count(), 5);
+
+    drop(handles);
+    assert_eq!(count_roots_of(&nodes), 0);
+  }
+
+  #[cfg(feature = "gc_debug")]
+  #[test]
+  fn test_validate_roots() {
+    use crate::dag_node::root_container::validate_roots;
+
+    let _heap = lock_global_heap();
+    let symbol = Symbol::new(IString::from("r"), 0);
+    let nodes  = (0..5).map(|_| DagNode::new(&symbol)).collect::<Vec<_>>();
+    let _roots = register_roots(&nodes);
+    let _permanent = make_permanent(nodes[0]);
+    assert_eq!(validate_roots(), Ok(()));
+
+    // A node outside the arenas, as a root left dangling would hold.
+    let mut outside = DagNode::default();
+    let wild = RootContainer::new(&mut outside);
+    let error = validate_roots().unwrap_err();
+    assert!(error.contains(&format!("{:p}", &outside as *const DagNode)));
+
+    drop(wild);
+    assert_eq!(validate_roots(), Ok(()));
+  }
+
+  #[test]
+  fn test_permanent_root_survives_collections() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    let constant = DagNode::with_args(&f, &[DagNode::new(&a)], DagNodeKind::default());
+    let root     = make_permanent(constant);
+    assert_eq!(root.node(), constant);
+    assert_eq!(permanent_root_count(), 1);
+    {
+      let _temporary = RootContainer::new(DagNode::new(&a));
+    }
+    assert_eq!(root_count(), 0);
+
+    for _ in 0..5 {
+      for _ in 0..1000 {
+        DagNode::new(&a);
+      }
+      unsafe { acquire_node_allocator("test_permanent_root").collect_garbage(); }
+      assert_eq!(active_node_count(), 2);
+      assert!(unsafe { &*constant }.is_marked());
+      assert_eq!(unsafe { &*constant }.symbol().name, f.name);
+    }
+  }
 }
@@ -0,0 +1,266 @@
+/*!
+
+A line based text format for DAGs that preserves sharing. Each line describes one node,
+
+```text
+<kind> <child count> <child indices...> <symbol arity> "<symbol name>"
+```
+
+where a node's index is the number of lines before it. Nodes are written children first, so every index refers to
+an earlier line, and the root is the last line. The symbol name is quoted and runs to the end of the line. Inside the
+quotes, `\"`, `\\`, `\n`, and `\r` stand for a quote, a backslash, a newline, and a carriage return, so that any name
+can be read back.
+
+`write_dag_streaming` writes each node as soon as its children have been written, keeping only the map from visited
+nodes to their indices and the path to the current node, never a list of all nodes.
+
+*/
+
+use std::{
+  collections::HashMap,
+  io::{BufRead, Write},
+  slice::Iter
+};
+
+use crate::{
+  abstractions::IString,
+  dag_node::{
+    deref_node,
+    DagNode,
+    DagNodeKind,
+    DagNodePtr
+  },
+  symbol::SymbolTable
+};
+
+
+/// Writes the DAG under `root` to `out`, each shared node once. Returns the number of nodes written.
+pub fn write_dag_streaming(root: DagNodePtr, out: &mut impl Write) -> std::io::Result<usize> {
+  let mut indices: HashMap<DagNodePtr, usize> = HashMap::new();
+  let enter = |node: DagNodePtr| (node, unsafe { deref_node(node) }.iter_children());
+  // Each entry is a node and its children not yet visited.
+  let mut path: Vec<(DagNodePtr, Iter<'static, DagNodePtr>)> = vec![enter(root)];
+
+  while let Some((node, children)) = path.last_mut() {
+    if let Some(&child) = children.next() {
+      if !indices.contains_key(&child) {
+        path.push(enter(child));
+      }
+      continue;
+    }
+
+    let node     = *node;
+    let node_ref = unsafe { deref_node(node) };
+    path.pop();
+    // A child is only pushed if it has not been written, and it is written before its parent is, so a node can only
+    // be on the path twice if it is its own descendant.
+    debug_assert!(!indices.contains_key(&node), "the DAG under {:p} has a cycle", root);
+
+    write!(out, "{} {}", node_ref.kind.as_u8(), node_ref.len())?;
+    for child in node_ref.iter_children() {
+      write!(out, " {}", indices[child])?;
+    }
+    let symbol = node_ref.symbol();
+    write!(out, " {} ", symbol.arity())?;
+    write_name(out, &symbol.name)?;
+    writeln!(out)?;
+
+    indices.insert(node, indices.len());
+  }
+
+  Ok(indices.len())
+}
+
+/// Reads a DAG written by `write_dag_streaming`, interning its symbols in `symbols`, and returns its root. A node
+/// must have as many children as its symbol's arity, or, if it is an `ACU` or `AU` node of a symbol of arity at
+/// least 2, at least two. The collector must not run while reading, as the nodes read so far are unrooted.
+pub fn read_dag(input: impl BufRead, symbols: &mut SymbolTable) -> Result<DagNodePtr, String> {
+  let mut nodes: Vec<DagNodePtr> = Vec::new();
+
+  for (line_number, line) in input.lines().enumerate() {
+    let line  = line.map_err(|error| error.to_string())?;
+    let error = |message: &str| format!("line {}: {}", line_number + 1, message);
+
+    let mut fields = line.splitn(3, ' ');
+    let mut number = || -> Result<usize, String> {
+      fields.next()
+            .and_then(|field| field.parse().ok())
+            .ok_or_else(|| error("expected a number"))
+    };
+
+    let kind = u8::try_from(number()?).ok()
+                                     .and_then(DagNodeKind::from_u8)
+                                     .ok_or_else(|| error("invalid node kind"))?;
+    let child_count = number()?;
+
+    // The rest holds the child indices, the arity, and the quoted symbol name, which may contain spaces.
+    let rest = fields.next().ok_or_else(|| error("missing symbol"))?;
+    // Each child takes a field, as do the arity and the name, so a larger count cannot be honest.
+    if child_count > rest.split(' ').count().saturating_sub(2) {
+      return Err(error("more children than fields"));
+    }
+    let mut fields = rest.splitn(child_count + 2, ' ');
+    let mut args   = Vec::with_capacity(child_count);
+    for _ in 0..child_count {
+      let child = fields.next()
+                        .and_then(|field| field.parse::<usize>().ok())
+                        .and_then(|index| nodes.get(index).copied())
+                        .ok_or_else(|| error("invalid child index"))?;
+      args.push(child);
+    }
+    let arity = fields.next()
+                      .and_then(|field| field.parse::<u8>().ok())
+                      .ok_or_else(|| error("invalid symbol arity"))?;
+    let name  = fields.next()
+                      .and_then(read_name)
+                      .ok_or_else(|| error("missing or badly quoted symbol name"))?;
+    // Flattening leaves an associative node of a binary symbol with any number of children from two up.
+    let flattened = matches!(kind, DagNodeKind::ACU | DagNodeKind::AU) && arity >= 2 && child_count >= 2;
+    if child_count != arity as usize && !flattened {
+      return Err(error(&format!("{} children for a symbol of arity {}", child_count, arity)));
+    }
+
+    let symbol = symbols.intern(IString::from(name.as_str()), arity);
+    nodes.push(DagNode::with_args(symbol, &args, kind));
+  }
+
+  nodes.last().copied().ok_or_else(|| "empty input".to_string())
+}
+
+/// Writes `name` in quotes, escaping quotes, backslashes, and line breaks.
+fn write_name(out: &mut impl Write, name: &str) -> std::io::Result<()> {
+  write!(out, "\"")?;
+  for c in name.chars() {
+    match c {
+      '"'  => write!(out, "\\\"")?,
+      '\\' => write!(out, "\\\\")?,
+      '\n' => write!(out, "\\n")?,
+      '\r' => write!(out, "\\r")?,
+      c    => write!(out, "{}", c)?,
+    }
+  }
+  write!(out, "\"")
+}
+
+/// The name `write_name` wrote as `field`, or `None` if `field` is not a quoted, correctly escaped name.
+fn read_name(field: &str) -> Option<String> {
+  let quoted    = field.strip_prefix('"')?.strip_suffix('"')?;
+  let mut name  = String::with_capacity(quoted.len());
+  let mut chars = quoted.chars();
+
+  while let Some(c) = chars.next() {
+    match c {
+      '\\' => name.push(match chars.next()? {
+        '"'  => '"',
+        '\\' => '\\',
+        'n'  => '\n',
+        'r'  => '\r',
+        _    => return None,
+      }),
+      // A quote must be escaped inside the name.
+      '"'  => return None,
+      c    => name.push(c),
+    }
+  }
+
+  Some(name)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use crate::{
+    abstractions::IString,
+    dag_node::{
+      allocator::lock_global_heap,
+      deref_node,
+      DagNode,
+      DagNodeKind,
+      DagNodePtr
+    },
+    symbol::SymbolTable
+  };
+  use super::{read_dag, write_dag_streaming};
+
+  #[test]
+  fn test_streaming_round_trip() {
+    let _heap = lock_global_heap();
+    let mut symbols = SymbolTable::new();
+    let f = symbols.intern(IString::from("f"), 2);
+    let g = symbols.intern(IString::from("long name"), 1);
+    let a = symbols.intern(IString::from("a"), 0);
+
+    // Each level is `f(previous, g(previous))`, so the tree has exponentially many nodes but the DAG only 3 per level.
+    let levels = 4000;
+    let mut term: DagNodePtr = DagNode::new(a);
+    for _ in 0..levels {
+      let wrapped = DagNode::with_args(g, &[term], DagNodeKind::default());
+      term = DagNode::with_args(f, &[term, wrapped], DagNodeKind::ACU);
+    }
+
+    let mut written = Vec::new();
+    let count = write_dag_streaming(term, &mut written).unwrap();
+    assert_eq!(count, 1 + 2 * levels);
+    assert_eq!(written.iter().filter(|&&byte| byte == b'\n').count(), count);
+
+    let mut read_symbols = SymbolTable::new();
+    let copy = read_dag(written.as_slice(), &mut read_symbols).unwrap();
+    assert_ne!(copy, term);
+    assert_eq!(read_symbols.len(), 3);
+    let copy_ref = unsafe { deref_node(copy) };
+    assert_eq!(copy_ref.kind, DagNodeKind::ACU);
+    assert_eq!(copy_ref.symbol().name, IString::from("f"));
+
+    // Writing the copy gives the same nodes in the same order.
+    let mut rewritten = Vec::new();
+    assert_eq!(write_dag_streaming(copy, &mut rewritten).unwrap(), count);
+    assert_eq!(rewritten, written);
+
+    assert!(read_dag(r#"0 1 7 1 "g""#.as_bytes(), &mut read_symbols).is_err());
+    // The child count must match the arity, except for flattened associative nodes.
+    assert!(read_dag("0 0 0 \"a\"\n0 1 0 2 \"f\"\n".as_bytes(), &mut read_symbols).is_err());
+    assert!(read_dag("0 0 0 \"a\"\n0 2 0 0 1 \"g\"\n".as_bytes(), &mut read_symbols).is_err());
+    assert!(read_dag(r#"0 0 1 "g""#.as_bytes(), &mut read_symbols).is_err());
+    assert!(read_dag("0 0 0 \"a\"\n0 3 0 0 0 2 \"f\"\n".as_bytes(), &mut read_symbols).is_err());
+    let flattened = read_dag("0 0 0 \"a\"\n1 3 0 0 0 2 \"f\"\n".as_bytes(), &mut read_symbols).unwrap();
+    assert_eq!(unsafe { deref_node(flattened) }.len(), 3);
+    assert!(read_dag("".as_bytes(), &mut read_symbols).is_err());
+
+    // A child count the line cannot hold is refused before anything is allocated for it.
+    assert!(read_dag(r#"0 18446744073709551615 0 "a""#.as_bytes(), &mut read_symbols).is_err());
+    assert!(read_dag(r#"0 1000000000000 0 "a""#.as_bytes(), &mut read_symbols).is_err());
+    assert!(read_dag("0 0 0 \"a\"\n0 2 0 2 \"f\"\n".as_bytes(), &mut read_symbols).is_err());
+    assert!(read_dag("0 0 0 \"a\"\n0 2 0 0\n".as_bytes(), &mut read_symbols).is_err());
+    assert!(read_dag("0 0 0 \"a\"\n0 2 0 0 2\n".as_bytes(), &mut read_symbols).is_err());
+
+    // Names must be quoted, with every quote inside escaped.
+    assert!(read_dag("0 0 0 a\n".as_bytes(), &mut read_symbols).is_err());
+    assert!(read_dag(r#"0 0 0 "a"#.as_bytes(), &mut read_symbols).is_err());
+    assert!(read_dag(r#"0 0 0 "a"b""#.as_bytes(), &mut read_symbols).is_err());
+    assert!(read_dag(r#"0 0 0 "a\""#.as_bytes(), &mut read_symbols).is_err());
+    assert!(read_dag(r#"0 0 0 "a\t""#.as_bytes(), &mut read_symbols).is_err());
+  }
+
+  #[test]
+  fn test_symbol_names_round_trip() {
+    let _heap = lock_global_heap();
+    let mut symbols = SymbolTable::new();
+    // Quotes, backslashes, line breaks, spaces, and digits, which would otherwise be taken for other fields.
+    let names  = ["say \"hi\"", "back\\slash", "two\nlines\r", " padded ", "", "0 1 2"];
+    let f      = symbols.intern(IString::from(names[0]), names.len() as u8 - 1);
+    let leaves = names[1..].iter()
+                           .map(|&name| DagNode::new(symbols.intern(IString::from(name), 0)))
+                           .collect::<Vec<_>>();
+    let term   = DagNode::with_args(f, &leaves, DagNodeKind::default());
+
+    let mut written = Vec::new();
+    assert_eq!(write_dag_streaming(term, &mut written).unwrap(), names.len());
+    assert_eq!(written.iter().filter(|&&byte| byte == b'\n').count(), names.len());
+
+    let mut read_symbols = SymbolTable::new();
+    let copy     = unsafe { deref_node(read_dag(written.as_slice(), &mut read_symbols).unwrap()) };
+    let children = copy.iter_children().map(|&child| unsafe { deref_node(child) }.symbol().name);
+    assert_eq!(copy.symbol().name, IString::from(names[0]));
+    assert!(children.eq(names[1..].iter().map(|&name| IString::from(name))));
+  }
+}
@@ -0,0 +1,253 @@
+/*!
+
+An owned snapshot of a term. A `TermTree` holds no pointers into the garbage collected heap, so it can outlive any
+collection, be sent between threads, and be turned back into a DAG later with `TermTree::to_dag`.
+
+`DagNode::to_owned_tree` and `TermTree::into_dag` are the conversions at a thread boundary, under the name
+`OwnedTerm`: one thread takes a snapshot of a term, sends it, and another rebuilds the term in the heap.
+
+Converting a `DagNode` expands shared subterms, so a term with heavy sharing can produce a tree exponentially larger
+than the DAG it came from. `TermTree::from_dag` stops once the tree would exceed a budget of nodes.
+
+Conversions, `node_count`, and dropping a tree walk it with explicit stacks, so a deep term cannot overflow the call
+stack. The derived `Clone`, `PartialEq`, `Hash`, and `Debug` still recurse.
+
+*/
+
+use crate::{
+  abstractions::IString,
+  dag_node::{
+    deref_node,
+    DagNode,
+    DagNodeKind,
+    DagNodePtr
+  },
+  symbol::SymbolTable
+};
+
+
+/// A `TermTree` taken to move a term to another thread. See `DagNode::to_owned_tree`.
+pub type OwnedTerm = TermTree;
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct TermTree {
+  pub symbol_name: String,
+  pub arity      : u8,
+  pub kind       : DagNodeKind,
+  pub children   : Vec<TermTree>,
+}
+
+impl TermTree {
+  /// Takes a snapshot of the term at `node`, failing once the tree would have more than `max_nodes` nodes, as
+  /// expanding shared subterms can make the tree exponentially larger than the DAG.
+  pub fn from_dag(node: &DagNode, max_nodes: usize) -> Result<TermTree, String> {
+    TermTree::expand(node, max_nodes).ok_or_else(|| format!("tree exceeds {} nodes", max_nodes))
+  }
+
+  /// The number of nodes in the tree.
+  pub fn node_count(&self) -> usize {
+    let mut count                 = 0;
+    let mut stack: Vec<&TermTree> = vec![self];
+    while let Some(tree) = stack.pop() {
+      count += 1;
+      stack.extend(tree.children.iter());
+    }
+    count
+  }
+
+  /// Builds a fresh DAG for this tree, looking up each symbol by name and arity in `symbols`. The result shares
+  /// no subterms. Fails if a symbol is missing from `symbols`.
+  pub fn to_dag(&self, symbols: &SymbolTable) -> Result<DagNodePtr, String> {
+    // Each entry is a tree whose node is being built, with the nodes of the children converted so far.
+    let mut path: Vec<(&TermTree, Vec<DagNodePtr>)> = vec![(self, Vec::with_capacity(self.children.len()))];
+    loop {
+      let (tree, args) = path.last_mut().unwrap();
+      if let Some(child) = tree.children.get(args.len()) {
+        path.push((child, Vec::with_capacity(child.children.len())));
+        continue;
+      }
+
+      let (tree, args) = path.pop().unwrap();
+      let symbol = symbols
+          .get(IString::from(tree.symbol_name.as_str()), tree.arity)
+          .ok_or_else(|| format!("no symbol {}/{} in symbol table", tree.symbol_name, tree.arity))?;
+      let node   = DagNode::with_args(symbol, &args, tree.kind);
+      match path.last_mut() {
+        Some((_, parent_args)) => parent_args.push(node),
+        None                   => return Ok(node),
+      }
+    }
+  }
+
+  /// Like `to_dag`, consuming the tree, as on the receiving side of a thread boundary.
+  pub fn into_dag(self, symbols: &SymbolTable) -> Result<DagNodePtr, String> {
+    self.to_dag(symbols)
+  }
+
+  /// Builds the tree for `node`, or `None` once it would have more than `max_nodes` nodes.
+  fn expand(node: &DagNode, max_nodes: usize) -> Option<TermTree> {
+    if max_nodes == 0 {
+      return None;
+    }
+    let without_children = |node: &DagNode| TermTree {
+      symbol_name: node.symbol().name.to_string(),
+      arity      : node.arity(),
+      kind       : node.kind,
+      children   : Vec::with_capacity(node.len()),
+    };
+
+    // Each entry is a node whose tree is being built, with its children not yet converted.
+    let mut path: Vec<(TermTree, std::slice::Iter<'static, DagNodePtr>)> =
+        vec![(without_children(node), node.iter_children())];
+    let mut count = 1;
+    loop {
+      let (_, children) = path.last_mut().unwrap();
+      match children.next() {
+        Some(&child) => {
+          count += 1;
+          if count > max_nodes {
+            return None;
+          }
+          let child = unsafe { deref_node(child) };
+          path.push((without_children(child), child.iter_children()));
+        }
+        None => {
+          let (tree, _) = path.pop().unwrap();
+          match path.last_mut() {
+            Some((parent, _)) => parent.children.push(tree),
+            None              => return Some(tree),
+          }
+        }
+      }
+    }
+  }
+}
+
+// An `OwnedTerm` must stay free of heap pointers to cross threads.
+const _: fn() = || {
+  fn assert_send_sync<T: Send + Sync>() {}
+  assert_send_sync::<OwnedTerm>();
+};
+
+impl Drop for TermTree {
+  /// Moves every descendant into one list before dropping it, so that each is dropped without children and a deep
+  /// tree cannot overflow the call stack.
+  fn drop(&mut self) {
+    let mut descendants = std::mem::take(&mut self.children);
+    while let Some(mut tree) = descendants.pop() {
+      descendants.append(&mut tree.children);
+    }
+  }
+}
+
+impl From<&DagNode> for TermTree {
+  /// Takes a snapshot of the term without a budget. See `TermTree::from_dag`.
+  fn from(node: &DagNode) -> Self {
+    TermTree::expand(node, usize::MAX).expect("a tree of more than usize::MAX nodes")
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+
+  use crate::{
+    abstractions::IString,
+    dag_node::{
+      allocator::lock_global_heap,
+      deref_node,
+      DagNode,
+      DagNodeKind,
+      TermTree
+    },
+    symbol::SymbolTable
+  };
+
+  #[test]
+  fn test_term_tree_round_trip() {
+    let _heap = lock_global_heap();
+    let mut symbols = SymbolTable::new();
+    let f = symbols.intern(IString::from("f"), 2);
+    let g = symbols.intern(IString::from("g"), 1);
+    let a = symbols.intern(IString::from("a"), 0);
+
+    // f(g(a), g(a)) with g(a) shared
+    let g_node = DagNode::with_args(g, &[DagNode::new(a)], DagNodeKind::default());
+    let f_node = DagNode::with_args(f, &[g_node, g_node], DagNodeKind::ACU);
+
+    let tree = TermTree::from(unsafe { &*f_node });
+    assert_eq!(tree.symbol_name, "f");
+    assert_eq!(tree.arity, 2);
+    assert_eq!(tree.kind, DagNodeKind::ACU);
+    assert_eq!(tree.node_count(), 5);
+    assert_eq!(tree.children[0], tree.children[1]);
+    assert_eq!(tree.children[0].symbol_name, "g");
+    assert_eq!(tree.children[0].children[0].symbol_name, "a");
+    assert!(tree.children[0].children[0].children.is_empty());
+
+    let copy = tree.to_dag(&symbols).unwrap();
+    assert_eq!(TermTree::from(unsafe { &*copy }), tree);
+    let copy = unsafe { &*copy };
+    assert_ne!(copy.iter_children().next(), copy.iter_children().nth(1));
+
+    assert!(tree.to_dag(&SymbolTable::new()).is_err());
+  }
+
+  #[test]
+  fn test_deep_and_shared_trees() {
+    let _heap = lock_global_heap();
+    let mut symbols = SymbolTable::new();
+    let f = symbols.intern(IString::from("f"), 2);
+    let g = symbols.intern(IString::from("g"), 1);
+    let a = symbols.intern(IString::from("a"), 0);
+
+    // A chain too deep to convert, count, or drop recursively.
+    let depth = 500_000;
+    let mut chain = DagNode::new(a);
+    for _ in 0..depth {
+      chain = DagNode::with_args(g, &[chain], DagNodeKind::default());
+    }
+    let tree = TermTree::from_dag(unsafe { &*chain }, depth + 1).unwrap();
+    assert_eq!(tree.node_count(), depth + 1);
+    let copy = tree.to_dag(&symbols).unwrap();
+    assert_ne!(copy, chain);
+    assert_eq!(unsafe { &*copy }.symbol().name, IString::from("g"));
+    drop(tree);
+    assert!(TermTree::from_dag(unsafe { &*chain }, depth).is_err());
+
+    // Sixty four levels of f(s, s) make a tree of 2^65 - 1 nodes from a DAG of 65.
+    let mut shared = DagNode::new(a);
+    for _ in 0..64 {
+      shared = DagNode::with_args(f, &[shared, shared], DagNodeKind::default());
+    }
+    assert!(TermTree::from_dag(unsafe { &*shared }, 1000).is_err());
+    assert!(TermTree::from_dag(unsafe { &*shared }, 0).is_err());
+  }
+
+  #[test]
+  fn test_owned_term_crosses_threads() {
+    let _heap = lock_global_heap();
+    let mut symbols = SymbolTable::new();
+    let f = symbols.intern(IString::from("f"), 2);
+    let a = symbols.intern(IString::from("a"), 0);
+    let b = symbols.intern(IString::from("b"), 0);
+
+    let term  = DagNode::with_args(f, &[DagNode::new(a), DagNode::new(b)], DagNodeKind::default());
+    let owned = unsafe { deref_node(term) }.to_owned_tree();
+    let expected = owned.clone();
+
+    // The receiving thread has its own symbol table and rebuilds the term in the heap.
+    let rebuilt = thread::spawn(move || {
+      let mut symbols = SymbolTable::new();
+      for (name, arity) in [("f", 2), ("a", 0), ("b", 0)] {
+        symbols.intern(IString::from(name), arity);
+      }
+      let copy = owned.into_dag(&symbols).unwrap();
+      unsafe { deref_node(copy) }.to_owned_tree()
+    }).join().unwrap();
+
+    assert_eq!(rebuilt, expected);
+    assert_eq!(rebuilt.children[1].symbol_name, "b");
+  }
+}
@@ -0,0 +1,139 @@
+/*!
+
+A `Zipper` edits a term one position at a time. It keeps a focus, the subterm being looked at, and the path of
+parents from the root down to it. Moving down and back up without a change leaves the term as it was, while moving
+up past a replaced subterm builds a new parent around it, so `rebuild` returns a term sharing every subterm that
+was not on a path to a change. This is the step by step counterpart to `DagNode::replace_subterm`.
+
+The nodes built along the way are unrooted, so the collector must not run while a zipper holds them.
+
+*/
+
+use crate::dag_node::{
+  deref_node,
+  DagNode,
+  DagNodePtr
+};
+
+
+/// A parent of the focus, with the focus's position among its children and the children as they are now.
+struct Crumb {
+  parent  : DagNodePtr,
+  index   : usize,
+  children: Vec<DagNodePtr>,
+}
+
+pub struct Zipper {
+  focus: DagNodePtr,
+  path : Vec<Crumb>,
+}
+
+impl Zipper {
+  /// A zipper focused on `root`.
+  pub fn from_root(root: DagNodePtr) -> Self {
+    Zipper { focus: root, path: Vec::new() }
+  }
+
+  /// The subterm in focus.
+  #[inline(always)]
+  pub fn focus(&self) -> DagNodePtr {
+    self.focus
+  }
+
+  /// The number of steps from the root down to the focus.
+  #[inline(always)]
+  pub fn depth(&self) -> usize {
+    self.path.len()
+  }
+
+  /// Moves the focus to its child at `index`.
+  pub fn down(&mut self, index: usize) -> Result<(), String> {
+    let focus_ref = unsafe { deref_node(self.focus) };
+    let children  = focus_ref.iter_children().copied().collect::<Vec<_>>();
+    let child     = *children.get(index).ok_or_else(|| {
+      format!("cannot move to child {} of a node with {} children", index, children.len())
+    })?;
+
+    self.path.push(Crumb { parent: self.focus, index, children });
+    self.focus = child;
+    Ok(())
+  }
+
+  /// Moves the focus to its parent. If the focus was replaced, the parent is a new node with the replacement in
+  /// place of the old child.
+  pub fn up(&mut self) -> Result<(), String> {
+    let Crumb { parent, index, mut children } = self.path.pop().ok_or_else(|| "the focus is the root".to_string())?;
+    if children[index] == self.focus {
+      self.focus = parent;
+      return Ok(());
+    }
+
+    children[index] = self.focus;
+    let parent_ref  = unsafe { deref_node(parent) };
+    self.focus      = DagNode::with_args(parent_ref.symbol, &children, parent_ref.kind);
+    Ok(())
+  }
+
+  /// Replaces the subterm in focus with `node`.
+  #[inline(always)]
+  pub fn replace(&mut self, node: DagNodePtr) {
+    self.focus = node;
+  }
+
+  /// Moves up to the root and returns the edited term, which is the original root if nothing was replaced.
+  pub fn rebuild(mut self) -> DagNodePtr {
+    while self.up().is_ok() {}
+    self.focus
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use crate::{
+    abstractions::IString,
+    dag_node::{allocator::lock_global_heap, deref_node, DagNode, DagNodeKind},
+    symbol::Symbol
+  };
+  use super::Zipper;
+
+  #[test]
+  fn test_zipper_replace() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+    let c = Symbol::new(IString::from("c"), 0);
+
+    // f(g(a), b)
+    let a_node = DagNode::new(&a);
+    let g_node = DagNode::with_args(&g, &[a_node], DagNodeKind::default());
+    let b_node = DagNode::new(&b);
+    let f_node = DagNode::with_args(&f, &[g_node, b_node], DagNodeKind::default());
+
+    let mut zipper = Zipper::from_root(f_node);
+    zipper.down(0).unwrap();
+    zipper.down(0).unwrap();
+    assert_eq!(zipper.focus(), a_node);
+    assert_eq!(zipper.depth(), 2);
+    assert!(zipper.down(0).is_err());
+    zipper.replace(DagNode::new(&c));
+
+    let edited     = zipper.rebuild();
+    let edited_ref = unsafe { deref_node(edited) };
+    assert_eq!(edited_ref.display_term(usize::MAX).to_string(), "f(g(c), b)");
+    assert_ne!(edited, f_node);
+    // The untouched child is shared, and the original term is unchanged.
+    assert_eq!(edited_ref.iter_children().nth(1), Some(&b_node));
+    assert_eq!(unsafe { deref_node(f_node) }.display_term(usize::MAX).to_string(), "f(g(a), b)");
+
+    // Moving without replacing anything gives back the original term.
+    let mut zipper = Zipper::from_root(f_node);
+    zipper.down(1).unwrap();
+    zipper.up().unwrap();
+    assert!(zipper.up().is_err());
+    zipper.down(0).unwrap();
+    assert_eq!(zipper.rebuild(), f_node);
+  }
+}
@@ -7,14 +7,117 @@ mod abstractions;
 mod dag_node;
 mod util;
 
+pub use abstractions::IString;
+pub use symbol::{
+  Symbol,
+  SymbolAttribute,
+  SymbolAttributes,
+  SymbolError,
+  SymbolPtr,
+  SymbolTable,
+  SymbolType
+};
+pub use dag_node::{
+  allocator,
+  canonical_compare,
+  make_permanent,
+  permanent_root_count,
+  read_dag,
+  register_roots,
+  root_count,
+  write_dag_streaming,
+  DagNode,
+  DagNodeArgument,
+  DagNodeFlag,
+  DagNodeFlags,
+  DagNodeKind,
+  DagNodePtr,
+  OwnedTerm,
+  PermanentRoot,
+  RootContainer,
+  RootHandle,
+  TermDisplay,
+  TermTree,
+  Zipper
+};
+#[cfg(feature = "gc_debug")]
+pub use dag_node::validate_roots;
+#[cfg(feature = "symbol_stats")]
+pub use dag_node::{allocations_by_symbol, reset_allocations_by_symbol};
+#[cfg(fuzzing)]
+pub use dag_node::fuzz;
+
 
 #[cfg(test)]
 mod tests {
   use crate::{
-    symbol::Symbol,
+    symbol::{Symbol, SymbolAttribute, SymbolError, SymbolTable, SymbolType},
     abstractions::IString,
   };
 
+  #[test]
+  fn test_binds_tighter_than() {
+    let times = Symbol::new(IString::from("*"), 2).with_precedence(31);
+    let plus  = Symbol::new(IString::from("+"), 2).with_precedence(33);
+    let minus = Symbol::new(IString::from("-"), 2).with_precedence(33);
+    let f     = Symbol::new(IString::from("f"), 1);
+
+    assert!(times.binds_tighter_than(&plus));
+    assert!(!plus.binds_tighter_than(&times));
+    assert!(f.binds_tighter_than(&plus));
+    assert_eq!(f.precedence(), 0);
+
+    // Equal precedence
+    assert!(!plus.binds_tighter_than(&minus));
+    assert!(!minus.binds_tighter_than(&plus));
+    assert!(!minus.binds_tighter_than(&minus));
+
+    let mut associative_plus = plus.clone();
+    associative_plus.attributes.insert(SymbolAttribute::Associative);
+    assert!(associative_plus.binds_tighter_than(&associative_plus));
+    assert!(!associative_plus.binds_tighter_than(&minus));
+  }
+
+  #[test]
+  fn test_validate_symbol() {
+    let mut plus = Symbol::new(IString::from("+"), 2);
+    plus.attributes.insert(SymbolAttribute::Associative);
+    plus.attributes.insert(SymbolAttribute::Commutative);
+    plus.attributes.insert(SymbolAttribute::LeftIdentity);
+    assert_eq!(plus.validate(), Ok(()));
+
+    let mut constant = Symbol::new(IString::from("zero"), 0);
+    constant.attributes = plus.attributes;
+    let error = constant.validate().unwrap_err();
+    assert!(matches!(error, SymbolError::AxiomNeedsTwoArguments { arity: 0, axiom: "associative", .. }));
+    assert_eq!(error.to_string(), "symbol zero/0 is associative, which requires at least two arguments");
+
+    let mut variable = Symbol::new(IString::from("X"), 1);
+    variable.symbol_type = SymbolType::Variable;
+    assert!(matches!(variable.validate(), Err(SymbolError::NotNullary { arity: 1, .. })));
+
+    let mut symbol_table = SymbolTable::new();
+    assert!(symbol_table.register(plus.clone()).is_ok());
+    assert!(matches!(symbol_table.register(plus), Err(SymbolError::AlreadyRegistered { .. })));
+    assert!(symbol_table.register(constant).is_err());
+    assert_eq!(symbol_table.len(), 1);
+  }
+
+  #[test]
+  fn test_arity_fixed_at_construction() {
+    // The field is private, so `symbol.arity = 3` does not compile. Nothing public that changes a symbol changes it.
+    let mut symbol = Symbol::new(IString::from("f"), 2).with_precedence(10);
+    symbol.attributes.insert(SymbolAttribute::Commutative);
+    symbol.symbol_type = SymbolType::Data;
+    assert!(symbol.validate().is_err());
+    assert_eq!(symbol.arity(), 2);
+
+    let mut symbol_table = SymbolTable::new();
+    let interned = symbol_table.intern(IString::from("f"), 2);
+    assert_eq!(unsafe { &*interned }.arity(), 2);
+    assert_eq!(symbol.clone().arity(), 2);
+  }
+
   #[test]
   fn test_symbols(){
     let symbols = (0..=10)
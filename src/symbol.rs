@@ -10,7 +10,10 @@ the symbol `Integer` for example.
 
 */
 
-use std::fmt::{Debug, Display};
+use std::{
+  collections::HashMap,
+  fmt::{Debug, Display}
+};
 
 use enumflags2::{bitflags, make_bitflags, BitFlags};
 
@@ -23,9 +26,12 @@ pub type SymbolPtr = *const Symbol;
 pub struct Symbol {
   pub name       : IString,
 
-  pub arity      : u8,
+  // Private so that it cannot change while nodes using the symbol exist, as their argument counts depend on it.
+  arity          : u8,
   pub attributes : SymbolAttributes,
   pub symbol_type: SymbolType,
+  // As in Maude, lower values bind tighter. Only meaningful with the `Precedence` attribute.
+  pub precedence : u32,
 
   // ToDo: Can the `IString` value be used as the `hash_value`?
   // Unique integer for comparing symbols, also called order.
@@ -35,18 +41,97 @@ pub struct Symbol {
 }
 
 impl Symbol {
+  /// Makes a new symbol, distinct from every other even if it has the same name and arity. Structural comparisons
+  /// accept such a twin through `same_as`, but identity based code like `DagNode::children_by_symbol` does not, so
+  /// terms meant to be compared should get their symbols from one `SymbolTable`.
   pub fn new(name: IString, arity: u8) -> Symbol {
     let mut symbol = Symbol{
       name,
       arity,
       attributes: SymbolAttributes::default(),
       symbol_type: SymbolType::default(),
+      precedence: 0,
       hash_value: 0,
     };
     symbol.compute_hash();
     symbol
   }
 
+  /// Whether `other` is this symbol or one with the same name and arity, as made by a separate `Symbol::new`.
+  #[inline(always)]
+  pub fn same_as(&self, other: &Symbol) -> bool {
+    std::ptr::eq(self, other) || (self.name == other.name && self.arity == other.arity)
+  }
+
+  /// The number of arguments the symbol takes, fixed when the symbol is constructed.
+  #[inline(always)]
+  pub fn arity(&self) -> u8 {
+    self.arity
+  }
+
+
+  /// Gives the symbol a precedence, setting its `Precedence` attribute.
+  pub fn with_precedence(mut self, precedence: u32) -> Symbol {
+    self.precedence = precedence;
+    self.attributes.insert(SymbolAttribute::Precedence);
+    self
+  }
+
+  /// The symbol's precedence. Symbols without the `Precedence` attribute are applied prefix, as in `f(x, y)`, and
+  /// so bind as tightly as possible.
+  #[inline(always)]
+  pub fn precedence(&self) -> u32 {
+    match self.attributes.contains(SymbolAttribute::Precedence) {
+      true  => self.precedence,
+      false => 0
+    }
+  }
+
+  /// Whether a term headed by `self` can appear as an argument of `other` without parentheses. Lower precedence
+  /// binds tighter. At equal precedence only an associative symbol binds tighter than itself, since
+  /// `(a + b) + c` and `a + (b + c)` are then the same term.
+  pub fn binds_tighter_than(&self, other: &Symbol) -> bool {
+    match self.precedence().cmp(&other.precedence()) {
+      std::cmp::Ordering::Less    => true,
+      std::cmp::Ordering::Greater => false,
+      std::cmp::Ordering::Equal   => {
+        self.name == other.name
+            && self.arity == other.arity
+            && self.attributes.contains(SymbolAttribute::Associative)
+      }
+    }
+  }
+
+  /// Checks that the symbol's arity agrees with its theory axioms and type: the equational axioms only apply to
+  /// operators taking at least two arguments, and variables and data constants take none.
+  pub fn validate(&self) -> Result<(), SymbolError> {
+    if matches!(self.symbol_type, SymbolType::Variable | SymbolType::Data) && self.arity != 0 {
+      return Err(SymbolError::NotNullary {
+        name       : self.name,
+        arity      : self.arity,
+        symbol_type: self.symbol_type,
+      });
+    }
+
+    if self.arity < 2 {
+      let axioms = [
+        (SymbolAttribute::Associative,   "associative"),
+        (SymbolAttribute::Commutative,   "commutative"),
+        (SymbolAttribute::LeftIdentity,  "left identity"),
+        (SymbolAttribute::RightIdentity, "right identity"),
+        (SymbolAttribute::Idempotent,    "idempotent"),
+      ];
+      if let Some((_, axiom)) = axioms.iter().find(|(attribute, _)| self.attributes.contains(*attribute)) {
+        return Err(SymbolError::AxiomNeedsTwoArguments {
+          name : self.name,
+          arity: self.arity,
+          axiom,
+        });
+      }
+    }
+
+    Ok(())
+  }
 
   #[inline(always)]
   pub fn is_variable(&self) -> bool {
@@ -91,6 +176,106 @@ impl Debug for Symbol {
   }
 }
 
+/// Formats a `SymbolPtr` without dereferencing it when it is null, as it is in the unused nodes filling an arena.
+pub trait DisplayOrPlaceholder {
+  /// Renders the symbol as its `Display` does, or `<null-symbol>` if the pointer is null.
+  fn display_or_placeholder(self) -> SymbolPtrDisplay;
+}
+
+impl DisplayOrPlaceholder for SymbolPtr {
+  #[inline(always)]
+  fn display_or_placeholder(self) -> SymbolPtrDisplay {
+    SymbolPtrDisplay(self)
+  }
+}
+
+/// Renders a possibly null `SymbolPtr`. See `DisplayOrPlaceholder`.
+pub struct SymbolPtrDisplay(SymbolPtr);
+
+impl Display for SymbolPtrDisplay {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match unsafe { self.0.as_ref() } {
+      Some(symbol) => Display::fmt(symbol, f),
+      None         => write!(f, "<null-symbol>"),
+    }
+  }
+}
+
+/// Owns symbols at stable addresses so that nodes can point to them, and finds them by name and arity.
+#[derive(Default)]
+pub struct SymbolTable {
+  symbols: HashMap<(IString, u8), Box<Symbol>>,
+}
+
+impl SymbolTable {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Returns the symbol with the given name and arity, creating it if it does not yet exist.
+  pub fn intern(&mut self, name: IString, arity: u8) -> SymbolPtr {
+    let symbol = self.symbols
+                     .entry((name, arity))
+                     .or_insert_with(|| Box::new(Symbol::new(name, arity)));
+    symbol.as_ref()
+  }
+
+  /// Adds `symbol` to the table after checking it with `Symbol::validate`. Unlike `intern`, it is an error if a
+  /// symbol with the same name and arity already exists.
+  pub fn register(&mut self, symbol: Symbol) -> Result<SymbolPtr, SymbolError> {
+    symbol.validate()?;
+
+    let key = (symbol.name, symbol.arity);
+    if self.symbols.contains_key(&key) {
+      return Err(SymbolError::AlreadyRegistered { name: symbol.name, arity: symbol.arity });
+    }
+    let symbol = self.symbols.entry(key).or_insert(Box::new(symbol));
+    Ok(symbol.as_ref())
+  }
+
+  /// Looks up the symbol with the given name and arity.
+  pub fn get(&self, name: IString, arity: u8) -> Option<SymbolPtr> {
+    self.symbols.get(&(name, arity)).map(|symbol| symbol.as_ref() as SymbolPtr)
+  }
+
+  pub fn len(&self) -> usize {
+    self.symbols.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.symbols.is_empty()
+  }
+}
+
+/// An inconsistency found by `Symbol::validate` or `SymbolTable::register`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SymbolError {
+  /// An equational axiom was given to a symbol with fewer than two arguments.
+  AxiomNeedsTwoArguments { name: IString, arity: u8, axiom: &'static str },
+  /// A variable or data symbol was given arguments.
+  NotNullary { name: IString, arity: u8, symbol_type: SymbolType },
+  /// A symbol with this name and arity is already in the table.
+  AlreadyRegistered { name: IString, arity: u8 },
+}
+
+impl Display for SymbolError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      SymbolError::AxiomNeedsTwoArguments { name, arity, axiom } => {
+        write!(f, "symbol {}/{} is {}, which requires at least two arguments", name, arity, axiom)
+      }
+      SymbolError::NotNullary { name, arity, symbol_type } => {
+        write!(f, "symbol {}/{} is of type {:?}, which takes no arguments", name, arity, symbol_type)
+      }
+      SymbolError::AlreadyRegistered { name, arity } => {
+        write!(f, "symbol {}/{} is already registered", name, arity)
+      }
+    }
+  }
+}
+
+impl std::error::Error for SymbolError {}
+
 #[derive(Copy, Clone, Eq, PartialEq, Default, Debug, Hash)]
 pub enum SymbolType {
   #[default]
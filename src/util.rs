@@ -3,23 +3,37 @@ Utilities to build and print random trees
 
 */
 
+use std::{
+  collections::HashMap,
+  fmt::{Display, Formatter},
+  iter::Enumerate,
+  slice::Iter
+};
+
 use rand::Rng;
 
 use crate::{
+  abstractions::IString,
   dag_node::{
+    deref_node,
+    deref_node_mut,
     DagNode,
+    DagNodeKind,
     DagNodePtr,
   },
-  symbol::Symbol
+  symbol::{Symbol, SymbolAttribute, SymbolPtr, SymbolTable}
 };
 
 /*
-Recursively builds a random tree of `DagNode`s with a given height and arity rules.
+Recursively builds a random tree of `DagNode`s with a given height and arity rules, giving `parent`, which must have
+no children yet, as many children as its symbol's arity. Returns the number of nodes created, or an error if
+`symbols` does not hold a symbol of arity `i` at each index `i` up to `max_width`.
 
-Because this function holds on to iterators of `NodeVec`s, the GC cannot run during
-the building of the tree. Run the GC before or after. 
+Each subtree is built bottom up, so that every node is created with all of its children and its argument vector is
+sized for them, whatever its symbol's arity. The new nodes are unrooted until the whole tree hangs from `parent`, so
+the GC cannot run during the building of the tree. Run the GC before or after.
 
- - `symbols`: List of `Symbol` objects of each arity from 0 to `max_width`.
+ - `symbols`: List of `Symbol` objects; a node given `i` children by the arity rules gets `symbols[i]`.
  - `parent`: Pointer to the current parent node.
  - `max_height`: Maximum allowed height for the tree.
 */
@@ -29,84 +43,718 @@ pub fn build_random_tree(
   max_height: usize,
   max_width : usize,
   min_width : usize,
-) {
-  if max_height == 0 {
-    return; // Reached the maximum depth
-  }
-  
+) -> Result<usize, String> {
   // idiot-proof
   let min_width = std::cmp::min(max_width, min_width);
   let max_width = std::cmp::max(max_width, min_width);
-  
-  let mut rng   = rand::thread_rng();
-
-  // Get the parent node's arity from its symbol
-  let parent_arity = unsafe { (*parent).arity() };
-
-  // For each child based on the parent's arity, create a new node
-  for i in 0..parent_arity as usize {
-    // Determine the arity of the child node
-    let child_arity = if max_height == 1 {
-      0 // Leaf nodes must have arity 0
-    } else {
-      rng.gen_range(min_width..=max_width) // Random arity between min_width and max_width
-    };
+  check_arity_symbols(symbols, max_width)?;
 
-    // Create the child node with the symbol corresponding to its arity
-    let child_symbol = &symbols[child_arity];
-    let child_node   = DagNode::new(child_symbol);
+  let parent_mut = unsafe{ deref_node_mut(parent) };
+  // A parent whose symbol takes no arguments gets no children, whatever the height.
+  if max_height == 0 || parent_mut.arity() == 0 {
+    return Ok(0);
+  }
 
-    // Insert the child into the parent node
-    let parent_mut = unsafe{ parent.as_mut_unchecked() };
-    if let Err(msg) = parent_mut.insert_child(child_node) {
-      eprintln!("Failed to insert child: level = {} child = {} parent_arity = {}\n\t::{}", max_height, i, parent_arity, msg);
-    };
+  let mut rng     = rand::rng();
+  let mut created = 0;
+
+  assert_eq!(parent_mut.len(), 0, "build_random_tree() : the parent already has children");
+  let children = (0..parent_mut.arity())
+      .map(|_| build_random_subtree(symbols, max_height, max_width, min_width, &mut rng, &mut created))
+      .collect::<Vec<_>>();
+
+  // The parent's argument vector was sized by its symbol's arity, which is exactly the number of children.
+  for child in children {
+    parent_mut.insert_child(child).expect("build_random_tree() : the parent has no room for its children");
+  }
+
+  Ok(created)
+}
+
+/// Checks that `symbols[i]` has arity `i` for every `i` up to `max_arity`, as `build_random_tree` requires, so that a
+/// node given `i` children by the arity rules gets a symbol declaring `i` arguments.
+pub fn check_arity_symbols(symbols: &[Symbol], max_arity: usize) -> Result<(), String> {
+  if symbols.len() <= max_arity {
+    return Err(format!("expected a symbol for every arity up to {}, found only {} symbols", max_arity, symbols.len()));
+  }
+  match (0..=max_arity).find(|&arity| symbols[arity].arity() as usize != arity) {
+    Some(index) => Err(format!(
+      "symbols[{}] is {} of arity {}, expected arity {}",
+      index,
+      symbols[index].name,
+      symbols[index].arity(),
+      index
+    )),
+    None        => Ok(()),
+  }
+}
+
+/// Makes one symbol of each arity from 0 to `max_arity`, in the layout `build_random_tree` expects, naming the symbol
+/// of arity `i` `name_fn(i)`.
+pub fn make_arity_symbols(max_arity: usize, name_fn: impl Fn(usize) -> String) -> Vec<Symbol> {
+  assert!(max_arity <= u8::MAX as usize, "make_arity_symbols() : arity {} does not fit in a u8", max_arity);
+  (0..=max_arity).map(|arity| Symbol::new(IString::from(name_fn(arity).as_str()), arity as u8))
+                 .collect()
+}
+
+/// Builds a random subtree of height at most `height` with all of its children, counting its nodes in `created`.
+fn build_random_subtree(
+  symbols  : &[Symbol],
+  height   : usize,
+  max_width: usize,
+  min_width: usize,
+  rng      : &mut impl Rng,
+  created  : &mut usize,
+) -> DagNodePtr {
+  // The node is built after its children, so whether it is a leaf is decided by the height.
+  let width = if height == 1 {
+    0 // Leaf nodes must have arity 0
+  } else {
+    rng.random_range(min_width..=max_width) // Random arity between min_width and max_width
+  };
+
+  let symbol   = &symbols[width];
+  let args = (0..symbol.arity())
+      .map(|_| build_random_subtree(symbols, height - 1, max_width, min_width, rng, created))
+      .collect::<Vec<_>>();
+  *created += 1;
+
+  DagNode::with_args(symbol, &args, DagNodeKind::default())
+}
+
+/// Walks the term rooted at `root` in pre-order, calling `visit` on every occurrence of every subterm, so shared
+/// subterms are visited once per occurrence. Returns the number of nodes visited, or an error once more than
+/// `max_visits` nodes have been visited, which guards against cycles and wild pointers in a corrupt graph.
+pub fn walk(root: DagNodePtr, max_visits: usize, mut visit: impl FnMut(DagNodePtr)) -> Result<usize, String> {
+  let mut stack : Vec<DagNodePtr> = vec![root];
+  let mut visits: usize           = 0;
+
+  while let Some(node) = stack.pop() {
+    if visits == max_visits {
+      return Err(format!("walk() : visited more than {} nodes; the graph may be cyclic or corrupt", max_visits));
+    }
+    visits += 1;
+    visit(node);
+
+    // Push in reverse so that children are visited left to right.
+    stack.extend(unsafe { deref_node(node) }.iter_children().rev());
+  }
+
+  Ok(visits)
+}
+
+/// A description of a term for `build_tree`, e.g. `TreeSpec::new(f, vec![TreeSpec::leaf(a)])` for `f(a)`.
+#[derive(Clone, Debug)]
+pub struct TreeSpec {
+  pub symbol  : SymbolPtr,
+  pub children: Vec<TreeSpec>,
+}
+
+impl TreeSpec {
+  pub fn new(symbol: SymbolPtr, children: Vec<TreeSpec>) -> Self {
+    TreeSpec { symbol, children }
+  }
+
+  pub fn leaf(symbol: SymbolPtr) -> Self {
+    TreeSpec { symbol, children: Vec::new() }
+  }
+}
+
+/// Allocates the term described by `spec`, children first. No subterms are shared.
+pub fn build_tree(spec: &TreeSpec) -> DagNodePtr {
+  let args = spec.children.iter().map(build_tree).collect::<Vec<_>>();
+  DagNode::with_args(spec.symbol, &args, DagNodeKind::default())
+}
+
+/// The glyphs used to draw the branches of a tree by `print_tree`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TreeStyle {
+  /// Connects the last child to its parent.
+  pub tail    : &'static str,
+  /// Connects every other child to its parent.
+  pub branch  : &'static str,
+  /// Continues a parent's branch past descendants of a child that is not the last.
+  pub vertical: &'static str,
+  /// Indents descendants of a last child. Should be as wide as `vertical`.
+  pub indent  : &'static str,
+}
+
+impl TreeStyle {
+  /// Unicode box-drawing glyphs.
+  pub const fn unicode() -> Self {
+    TreeStyle {
+      tail    : "╰──",
+      branch  : "├──",
+      vertical: "│   ",
+      indent  : "    ",
+    }
+  }
 
-    // Recursively build the subtree for the child
-    build_random_tree(symbols, child_node, max_height - 1, max_width, min_width);
+  /// Plain ASCII glyphs for terminals without box-drawing support.
+  pub const fn ascii() -> Self {
+    TreeStyle {
+      tail    : "`--",
+      branch  : "+--",
+      vertical: "|   ",
+      indent  : "    ",
+    }
   }
 }
 
-/// Recursively prints a tree structure using ASCII box-drawing symbols.
+impl Default for TreeStyle {
+  fn default() -> Self {
+    TreeStyle::unicode()
+  }
+}
+
+/// Recursively prints a tree structure using the glyphs of `style`.
 ///
 /// - `node`: The current node to print.
 /// - `prefix`: The string prefix to apply to the current node's line.
 /// - `is_tail`: Whether the current node is the last child of its parent.
-pub fn print_tree(node: DagNodePtr, prefix: String, is_tail: bool) {
+/// - `style`: The glyphs used to draw branches.
+pub fn print_tree(node: DagNodePtr, prefix: String, is_tail: bool, style: &TreeStyle) {
+  let mut out = String::new();
+  write_tree(&mut out, node, prefix, is_tail, style);
+  print!("{}", out);
+}
+
+/// Renders a tree structure into `out`, one node per line. See `print_tree`.
+pub fn write_tree(out: &mut String, node: DagNodePtr, prefix: String, is_tail: bool, style: &TreeStyle) {
   assert_ne!(node, std::ptr::null_mut());
   let is_head = prefix.is_empty();
 
-  let node: &DagNode = unsafe{ &*node };
+  let node: &DagNode = unsafe{ deref_node(node) };
 
   // Print the current node
   let new_prefix = if is_head {
     ""
   }else {
-    if is_tail { "╰──" } else { "├──" }
+    if is_tail { style.tail } else { style.branch }
   };
-  println!(
-    "{}{}{}",
+  out.push_str(&format!(
+    "{}{}{}\n",
     prefix,
     new_prefix,
     node
-  );
+  ));
+
+  if node.is_leaf() {
+    return;
+  }
 
   // Determine the new prefix for children
   let new_prefix = if is_tail {
-    format!("{}    ", prefix)
+    format!("{}{}", prefix, style.indent)
   } else if is_head {
     format!(" ")
   }
   else {
-    format!("{}│   ", prefix)
+    format!("{}{}", prefix, style.vertical)
   };
 
   // Print each child
-  for (i, &child_ptr) in node.iter_children().enumerate() {
-    print_tree(
+  for (i, child_ptr) in node.enumerate_children() {
+    write_tree(
+      out,
       child_ptr,
       new_prefix.clone(),
       i == node.len() - 1, // Is this the last child?
+      style,
     );
   }
 }
+
+/// An error found by `parse_infix`, at a byte offset into the input.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseError {
+  pub offset : usize,
+  pub message: String,
+}
+
+impl Display for ParseError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "offset {}: {}", self.offset, self.message)
+  }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Token<'a> {
+  Name(&'a str),
+  Operator(&'a str),
+  Open,
+  Close,
+  Comma,
+  End,
+}
+
+/// A Pratt parser over the tokens of an infix expression. See `parse_infix`.
+struct InfixParser<'a> {
+  input  : &'a str,
+  offset : usize, // Start of the next token
+  symbols: &'a SymbolTable,
+}
+
+impl<'a> InfixParser<'a> {
+  fn error<T>(&self, offset: usize, message: String) -> Result<T, ParseError> {
+    Err(ParseError { offset, message })
+  }
+
+  /// The next token and the offsets of its start and end, without consuming it.
+  fn peek(&self) -> (Token<'a>, usize, usize) {
+    let rest  = &self.input[self.offset..];
+    let start = self.offset + (rest.len() - rest.trim_start().len());
+    let rest  = &self.input[start..];
+
+    // A name or an operator is the longest run of its kind of character.
+    let is_name     = |c: char| c.is_alphanumeric() || c == '_';
+    let is_operator = |c: char| !is_name(c) && !c.is_whitespace() && !"(),".contains(c);
+    let run_length  = |in_run: &dyn Fn(char) -> bool| rest.find(|c: char| !in_run(c)).unwrap_or(rest.len());
+
+    let (token, length) = match rest.chars().next() {
+      None                  => (Token::End, 0),
+      Some('(')             => (Token::Open, 1),
+      Some(')')             => (Token::Close, 1),
+      Some(',')             => (Token::Comma, 1),
+      Some(c) if is_name(c) => {
+        let length = run_length(&is_name);
+        (Token::Name(&rest[..length]), length)
+      }
+      Some(_)               => {
+        let length = run_length(&is_operator);
+        (Token::Operator(&rest[..length]), length)
+      }
+    };
+    (token, start, start + length)
+  }
+
+  fn next(&mut self) -> (Token<'a>, usize) {
+    let (token, start, end) = self.peek();
+    self.offset = end;
+    (token, start)
+  }
+
+  fn expect(&mut self, expected: Token<'a>, what: &str) -> Result<(), ParseError> {
+    match self.next() {
+      (token, _) if token == expected => Ok(()),
+      (_, offset)                     => self.error(offset, format!("expected {}", what)),
+    }
+  }
+
+  /// The infix operator named `name`: a binary symbol with a precedence.
+  fn operator(&self, name: &str) -> Option<&'a Symbol> {
+    let symbol = unsafe { &*self.symbols.get(IString::from(name), 2)? };
+    symbol.attributes.contains(SymbolAttribute::Precedence).then_some(symbol)
+  }
+
+  /// Parses an expression whose operators all bind tighter than `parent`, the operator it is the right argument of.
+  fn parse_expression(&mut self, parent: Option<&Symbol>) -> Result<DagNodePtr, ParseError> {
+    let mut left    = self.parse_primary()?;
+    let mut left_op = None::<&Symbol>; // The head of `left` if it is an unparenthesized infix term
+
+    while let (Token::Operator(name), offset, _) = self.peek() {
+      let Some(operator) = self.operator(name) else {
+        return self.error(offset, format!("unknown infix operator {}", name));
+      };
+      if parent.is_some_and(|parent| !operator.binds_tighter_than(parent)) {
+        break;
+      }
+      if left_op.is_some_and(|left_op| !left_op.binds_tighter_than(operator)) {
+        return self.error(offset, format!("{} needs parentheses to group with its left argument", name));
+      }
+      self.next();
+
+      let right = self.parse_expression(Some(operator))?;
+      left      = DagNode::with_args(operator, &[left, right], DagNodeKind::default());
+      left_op   = Some(operator);
+    }
+
+    Ok(left)
+  }
+
+  /// Parses a constant, an application `f(a, b)`, or a parenthesized expression.
+  fn parse_primary(&mut self) -> Result<DagNodePtr, ParseError> {
+    match self.next() {
+      (Token::Open, _) => {
+        let expression = self.parse_expression(None)?;
+        self.expect(Token::Close, "`)`")?;
+        Ok(expression)
+      }
+
+      (Token::Name(name), offset) => {
+        let mut args = Vec::new();
+        if self.peek().0 == Token::Open {
+          self.next();
+          loop {
+            args.push(self.parse_expression(None)?);
+            match self.next() {
+              (Token::Comma, _)  => continue,
+              (Token::Close, _)  => break,
+              (_, offset)        => return self.error(offset, "expected `,` or `)`".to_string()),
+            }
+          }
+        }
+
+        let symbol = u8::try_from(args.len()).ok()
+                                             .and_then(|arity| self.symbols.get(IString::from(name), arity));
+        match symbol {
+          Some(symbol) => Ok(DagNode::with_args(symbol, &args, DagNodeKind::default())),
+          None         => self.error(offset, format!("unknown symbol {}/{}", name, args.len())),
+        }
+      }
+
+      (Token::End, offset) => self.error(offset, "unexpected end of input".to_string()),
+      (_, offset)          => self.error(offset, "expected a term".to_string()),
+    }
+  }
+}
+
+/// Parses an expression like `f(a) + b * c` into a term over the symbols in `symbols`. Binary symbols with the
+/// `Precedence` attribute are infix operators; the rest are applied prefix, as in `f(a, b)`. An operator's arguments
+/// must bind tighter than it, as decided by `Symbol::binds_tighter_than`, so that `a + b * c` is `a + (b * c)`, a
+/// chain of an associative operator groups to the right, and any other chain needs parentheses. The collector must
+/// not run while parsing, as the nodes built so far are unrooted.
+pub fn parse_infix(input: &str, symbols: &SymbolTable) -> Result<DagNodePtr, ParseError> {
+  let mut parser = InfixParser { input, offset: 0, symbols };
+  let term       = parser.parse_expression(None)?;
+
+  match parser.next() {
+    (Token::End, _) => Ok(term),
+    (_, offset)     => parser.error(offset, "expected an infix operator or the end of input".to_string()),
+  }
+}
+
+/// Prints a term in the syntax read by `parse_infix`, with only the parentheses it needs. Shared subterms are
+/// printed once per occurrence.
+pub fn to_infix(node: DagNodePtr) -> String {
+  fn is_infix(node: &DagNode) -> bool {
+    let symbol = node.symbol();
+    node.len() == 2 && symbol.arity() == 2 && symbol.attributes.contains(SymbolAttribute::Precedence)
+  }
+
+  fn write_infix(out: &mut String, node: &DagNode) {
+    let children = node.iter_children().map(|&child| unsafe { deref_node(child) }).collect::<Vec<_>>();
+    if is_infix(node) {
+      for (i, child) in children.into_iter().enumerate() {
+        if i == 1 {
+          out.push_str(&format!(" {} ", node.symbol()));
+        }
+        let needs_parentheses = is_infix(child) && !child.symbol().binds_tighter_than(node.symbol());
+        if needs_parentheses { out.push('('); }
+        write_infix(out, child);
+        if needs_parentheses { out.push(')'); }
+      }
+      return;
+    }
+
+    out.push_str(&node.symbol().to_string());
+    if !children.is_empty() {
+      out.push('(');
+      for (i, child) in children.into_iter().enumerate() {
+        if i > 0 {
+          out.push_str(", ");
+        }
+        write_infix(out, child);
+      }
+      out.push(')');
+    }
+  }
+
+  let mut out = String::new();
+  write_infix(&mut out, unsafe { deref_node(node) });
+  out
+}
+
+/// Prints a term as `f(a, g(b))`, binding each compound subterm with more than one occurrence to a name in a
+/// preamble, one binding per line as in `#1 = g(a)`, and printing the name in its place. Every distinct node is
+/// expanded once, so the output is linear in the size of the DAG rather than of the tree. A binding comes after the
+/// bindings it refers to, and the term itself is the last line. Leaves are printed wherever they occur.
+pub fn to_sexpr_shared(root: DagNodePtr) -> String {
+  let in_degrees = unsafe { deref_node(root) }.in_degrees();
+  let is_shared  = |node: DagNodePtr| in_degrees.get(&node).is_some_and(|&in_degree| in_degree > 1);
+
+  let mut labels  : HashMap<DagNodePtr, usize> = HashMap::new();
+  let mut preamble: String                     = String::new();
+  // The term, then the expression of each shared node being printed, innermost last.
+  let mut outs    : Vec<String>                = vec![String::new()];
+  // The compound nodes being printed, with the children not yet printed. The term is walked with an explicit stack,
+  // so that deep terms cannot overflow the call stack.
+  let mut path    : Vec<(DagNodePtr, Enumerate<Iter<'static, DagNodePtr>>)> = Vec::new();
+  let mut next    : Option<DagNodePtr>         = Some(root);
+
+  loop {
+    if let Some(node) = next.take() {
+      let node_ref = unsafe { deref_node(node) };
+      if let Some(label) = labels.get(&node) {
+        outs.last_mut().unwrap().push_str(&format!("#{}", label));
+      } else if node_ref.is_leaf() {
+        outs.last_mut().unwrap().push_str(&node_ref.symbol().to_string());
+      } else {
+        if is_shared(node) {
+          outs.push(String::new());
+        }
+        outs.last_mut().unwrap().push_str(&format!("{}(", node_ref.symbol()));
+        path.push((node, node_ref.iter_children().enumerate()));
+      }
+    }
+
+    let Some((node, children)) = path.last_mut() else {
+      break;
+    };
+    match children.next() {
+      Some((i, &child)) => {
+        if i > 0 {
+          outs.last_mut().unwrap().push_str(", ");
+        }
+        next = Some(child);
+      }
+      None => {
+        let node = *node;
+        path.pop();
+        outs.last_mut().unwrap().push(')');
+        if is_shared(node) {
+          let expression = outs.pop().unwrap();
+          let label      = labels.len() + 1;
+          labels.insert(node, label);
+          preamble.push_str(&format!("#{} = {}\n", label, expression));
+          outs.last_mut().unwrap().push_str(&format!("#{}", label));
+        }
+      }
+    }
+  }
+
+  preamble + &outs.pop().unwrap()
+}
+
+
+#[cfg(test)]
+mod tests {
+  use crate::{
+    abstractions::IString,
+    dag_node::{allocator::lock_global_heap, DagNode, DagNodeKind},
+    dag_node::deref_node,
+    symbol::{Symbol, SymbolAttribute, SymbolTable},
+    util::{
+      build_random_tree,
+      build_tree,
+      check_arity_symbols,
+      make_arity_symbols,
+      parse_infix,
+      to_infix,
+      to_sexpr_shared,
+      walk,
+      write_tree,
+      ParseError,
+      TreeSpec,
+      TreeStyle
+    }
+  };
+
+  #[test]
+  fn test_walk() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // f(g(a), g(a)) with g(a) shared
+    let a_node = DagNode::new(&a);
+    let g_node = DagNode::with_args(&g, &[a_node], DagNodeKind::default());
+    let f_node = DagNode::with_args(&f, &[g_node, g_node], DagNodeKind::default());
+
+    let mut order = Vec::new();
+    assert_eq!(walk(f_node, 5, |node| order.push(node)), Ok(5));
+    assert_eq!(order, vec![f_node, g_node, a_node, g_node, a_node]);
+    assert!(walk(f_node, 4, |_| {}).is_err());
+  }
+
+  #[test]
+  fn test_walk_stops_on_cycle() {
+    let _heap = lock_global_heap();
+    let g = Symbol::new(IString::from("g"), 1);
+
+    // g(g(g(...))), a node that is its own child
+    let cyclic = DagNode::new(&g);
+    unsafe { &mut *cyclic }.insert_child(cyclic).unwrap();
+
+    let mut visits = 0;
+    assert!(walk(cyclic, 1000, |_| visits += 1).is_err());
+    assert_eq!(visits, 1000);
+  }
+
+  #[test]
+  fn test_build_tree() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    let spec = TreeSpec::new(&f, vec![TreeSpec::new(&g, vec![TreeSpec::leaf(&a)]), TreeSpec::leaf(&b)]);
+    let root = unsafe { &*build_tree(&spec) };
+    assert_eq!(root.symbol().name, f.name);
+
+    let children = root.iter_children().map(|&child| unsafe { &*child }).collect::<Vec<_>>();
+    assert_eq!(children.len(), 2);
+    assert_eq!(children[0].symbol().name, g.name);
+    assert_eq!(children[1].symbol().name, b.name);
+    assert!(children[1].is_leaf());
+
+    let grandchildren = children[0].iter_children().map(|&child| unsafe { &*child }).collect::<Vec<_>>();
+    assert_eq!(grandchildren.len(), 1);
+    assert_eq!(grandchildren[0].symbol().name, a.name);
+    assert!(grandchildren[0].is_leaf());
+  }
+
+  #[test]
+  fn test_ascii_tree_style() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+    let b = Symbol::new(IString::from("b"), 0);
+
+    // f(g(a), b)
+    let g_node = DagNode::with_args(&g, &[DagNode::new(&a)], DagNodeKind::default());
+    let f_node = DagNode::with_args(&f, &[g_node, DagNode::new(&b)], DagNodeKind::default());
+
+    let mut ascii = String::new();
+    write_tree(&mut ascii, f_node, String::new(), false, &TreeStyle::ascii());
+    assert_eq!(ascii, "node<f>\n +--node<g>\n |   `--node<a>\n `--node<b>\n");
+    for glyph in ["╰", "├", "│"] {
+      assert!(!ascii.contains(glyph));
+    }
+
+    let mut unicode = String::new();
+    write_tree(&mut unicode, f_node, String::new(), false, &TreeStyle::unicode());
+    assert_eq!(unicode, "node<f>\n ├──node<g>\n │   ╰──node<a>\n ╰──node<b>\n");
+  }
+
+  #[test]
+  fn test_parse_infix_round_trip() {
+    let _heap = lock_global_heap();
+    let mut symbols = SymbolTable::new();
+    let mut plus = Symbol::new(IString::from("+"), 2).with_precedence(33);
+    plus.attributes.insert(SymbolAttribute::Associative);
+    symbols.register(plus).unwrap();
+    symbols.register(Symbol::new(IString::from("-"), 2).with_precedence(33)).unwrap();
+    symbols.register(Symbol::new(IString::from("*"), 2).with_precedence(31)).unwrap();
+    symbols.intern(IString::from("f"), 1);
+    symbols.intern(IString::from("g"), 2);
+    for name in ["a", "b", "c"] {
+      symbols.intern(IString::from(name), 0);
+    }
+
+    for input in [
+      "a",
+      "a + b * c",
+      "(a + b) * c",
+      "a + b + c",
+      "a - (b - c)",
+      "(a - b) - c",
+      "f(a + b) * g(a, c)",
+      "g(f(a), a * (b - c))",
+    ] {
+      let term = parse_infix(input, &symbols).unwrap();
+      assert_eq!(to_infix(term), input);
+      let reparsed = parse_infix(&to_infix(term), &symbols).unwrap();
+      assert_eq!(unsafe { deref_node(reparsed) }.structural_hash(), unsafe { deref_node(term) }.structural_hash());
+    }
+
+    // Multiplication binds tighter, and an associative chain groups to the right.
+    let term     = unsafe { deref_node(parse_infix("a*b+c+a", &symbols).unwrap()) };
+    let children = term.iter_children().map(|&child| unsafe { deref_node(child) }).collect::<Vec<_>>();
+    assert_eq!(term.symbol().name, IString::from("+"));
+    assert_eq!(children[0].symbol().name, IString::from("*"));
+    assert_eq!(children[1].symbol().name, IString::from("+"));
+    assert_eq!(to_infix(std::ptr::from_ref(term).cast_mut()), "a * b + c + a");
+
+    let error = |input: &str| parse_infix(input, &symbols).unwrap_err();
+    assert_eq!(error("a - b - c").offset, 6);
+    assert_eq!(error("a + ").offset, 4);
+    assert_eq!(error("a b").offset, 2);
+    assert_eq!(error("a ^ b").message, "unknown infix operator ^");
+    assert_eq!(error("f(a"), ParseError { offset: 3, message: "expected `,` or `)`".to_string() });
+    assert_eq!(error("f(a, b)").to_string(), "offset 0: unknown symbol f/2");
+  }
+
+  #[test]
+  fn test_build_random_tree_keeps_every_child() {
+    let _heap = lock_global_heap();
+    let symbols = [("a", 0), ("h", 1), ("g", 2), ("f", 3)].map(|(name, arity)| Symbol::new(IString::from(name), arity));
+    let root    = DagNode::new(&symbols[2]);
+
+    let created = build_random_tree(&symbols, root, 5, 3, 1).unwrap();
+    let mut visited = 0;
+    walk(root, usize::MAX, |node| {
+      let node = unsafe { deref_node(node) };
+      assert_eq!(node.len(), node.arity() as usize);
+      visited += 1;
+    }).unwrap();
+    assert_eq!(visited, created + 1);
+
+    // A leaf parent gets no children.
+    assert_eq!(build_random_tree(&symbols, DagNode::new(&symbols[0]), 5, 3, 1), Ok(0));
+    // A fresh unary parent has no argument storage yet, but still gets its child.
+    assert!(build_random_tree(&symbols, DagNode::new(&symbols[1]), 4, 3, 1).unwrap() > 0);
+  }
+
+  #[test]
+  fn test_build_random_tree_checks_symbols() {
+    let _heap = lock_global_heap();
+    let arranged = [("a", 0), ("h", 1), ("g", 2), ("f", 3)].map(|(name, arity)| Symbol::new(IString::from(name), arity));
+    let shuffled = [("a", 0), ("f", 3), ("g", 2), ("h", 1)].map(|(name, arity)| Symbol::new(IString::from(name), arity));
+
+    assert_eq!(check_arity_symbols(&arranged, 3), Ok(()));
+    assert!(check_arity_symbols(&arranged, 4).is_err());
+    assert_eq!(check_arity_symbols(&shuffled, 3), Err("symbols[1] is f of arity 3, expected arity 1".to_string()));
+    // Only the arities the rules can pick are checked.
+    assert_eq!(check_arity_symbols(&shuffled, 0), Ok(()));
+
+    let root = DagNode::new(&shuffled[2]);
+    assert!(build_random_tree(&shuffled, root, 5, 3, 1).is_err());
+    assert_eq!(unsafe { deref_node(root) }.len(), 0);
+  }
+
+  #[test]
+  fn test_make_arity_symbols() {
+    let symbols = make_arity_symbols(5, |arity| format!("f{}", arity));
+    assert_eq!(symbols.len(), 6);
+    for (arity, symbol) in symbols.iter().enumerate() {
+      assert_eq!(symbol.arity() as usize, arity);
+      assert_eq!(symbol.name, IString::from(format!("f{}", arity).as_str()));
+    }
+    assert_eq!(check_arity_symbols(&symbols, 5), Ok(()));
+  }
+
+  #[test]
+  fn test_to_sexpr_shared() {
+    let _heap = lock_global_heap();
+    let f = Symbol::new(IString::from("f"), 2);
+    let g = Symbol::new(IString::from("g"), 1);
+    let h = Symbol::new(IString::from("h"), 1);
+    let a = Symbol::new(IString::from("a"), 0);
+
+    // A diamond: f(h(g(a)), g(a)) with g(a) shared.
+    let a_node = DagNode::new(&a);
+    let g_node = DagNode::with_args(&g, &[a_node], DagNodeKind::default());
+    let h_node = DagNode::with_args(&h, &[g_node], DagNodeKind::default());
+    let f_node = DagNode::with_args(&f, &[h_node, g_node], DagNodeKind::default());
+    assert_eq!(to_sexpr_shared(f_node), "#1 = g(a)\nf(h(#1), #1)");
+
+    // Doubling at every level keeps the output linear in the number of nodes.
+    let mut term = a_node;
+    for _ in 0..30 {
+      term = DagNode::with_args(&f, &[term, term], DagNodeKind::default());
+    }
+    let printed = to_sexpr_shared(term);
+    assert_eq!(printed.lines().count(), 30);
+    assert!(printed.ends_with("\nf(#29, #29)"));
+
+    // A term deeper than a recursive printer could go on a test thread's stack.
+    let deep = (0..50_000).fold(a_node, |term, _| DagNode::with_args(&g, &[term], DagNodeKind::default()));
+    assert_eq!(to_sexpr_shared(deep), "g(".repeat(50_000) + "a" + &")".repeat(50_000));
+  }
+}